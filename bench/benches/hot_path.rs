@@ -0,0 +1,79 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use vrypt_server::bench::{self, Pool, SlabFixture};
+
+const SMALL_HEAD: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+/// A browser-sized head (~1 KiB) with the terminator at the very end.
+fn large_head() -> Vec<u8> {
+    let mut head = b"GET /api/v1/items?page=2&sort=desc HTTP/1.1\r\nHost: bench.example\r\n".to_vec();
+    for i in 0..16 {
+        head.extend_from_slice(format!("X-Bench-Header-{i}: {}\r\n", "v".repeat(40)).as_bytes());
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+fn scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("request_complete");
+    let large = large_head();
+    let mut full = large.clone();
+    full.resize(8 * 1024, b'a');
+    let inputs: [(&str, &[u8]); 3] = [("small", SMALL_HEAD), ("1k", &large), ("8k_no_terminator", &full[large.len()..])];
+    for (name, input) in inputs {
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), input, |b, input| {
+            b.iter(|| bench::scan_head(black_box(input)))
+        });
+    }
+    group.finish();
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_head");
+    let large = large_head();
+    let inputs: [(&str, &[u8]); 2] = [("small", SMALL_HEAD), ("1k", &large)];
+    for (name, input) in inputs {
+        let head = &input[..input.len() - 4];
+        group.throughput(Throughput::Bytes(head.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), head, |b, head| {
+            b.iter(|| bench::parse_head(black_box(head)))
+        });
+    }
+    group.finish();
+}
+
+fn buf_pool(c: &mut Criterion) {
+    let mut pool = Pool::new();
+    c.bench_function("buf_pool/acquire_release", |b| b.iter(|| pool.cycle()));
+}
+
+fn slab(c: &mut Criterion) {
+    let mut fixture = SlabFixture::new(65536);
+    let mut token = 1;
+    c.bench_function("slab/insert_get_remove", |b| {
+        b.iter(|| {
+            token = token % 65535 + 1;
+            fixture.cycle(black_box(token));
+        })
+    });
+    // One connection at the top of a full-size table: costs the connections, not the table.
+    c.bench_function("slab/iter_one_of_65536", |b| b.iter(|| fixture.scan(black_box(65535))));
+}
+
+fn serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+    for size in [5usize, 1024, 16 * 1024] {
+        let body = vec![b'x'; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("content_length", size), &body, |b, body| {
+            b.iter(|| bench::serialize(black_box(body), false))
+        });
+        group.bench_with_input(BenchmarkId::new("chunked_digest", size), &body, |b, body| {
+            b.iter(|| bench::serialize(black_box(body), true))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scan, parse, buf_pool, slab, serialize);
+criterion_main!(benches);
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vrypt_server::fuzz;
+
+fuzz_target!(|data: &[u8]| {
+    let (cuts, data) = fuzz::split_input(data);
+    fuzz::connection(data, &cuts);
+});
@@ -0,0 +1,156 @@
+//! Accept thread for `--accept-mode thread`: one thread owns the listeners and deals
+//! accepted connections out to the workers in turn.
+
+use crate::config::{ACCEPT_RETRY_LIMIT, LISTENER_TOKEN_BASE, POLL_TIMEOUT, RESTART_RESET, STATS_INTERVAL};
+use crate::counter::AcceptError;
+use crate::error::{self, VryptError};
+use crate::limit::AcceptRate;
+use crate::listen::Listeners;
+use crate::worker::Shared;
+use mio::net::TcpStream;
+use mio::{Events, Poll, Waker};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// Connections accepted for one worker and not yet picked up by it.
+pub struct Handoff {
+    /// Each with its peer and the listed address it was accepted on.
+    queue: Mutex<Vec<(TcpStream, SocketAddr, SocketAddr)>>,
+    /// Set by the worker once its poll exists, and replaced when it restarts; until then
+    /// it finds the queue when it starts polling.
+    waker: Mutex<Option<Arc<Waker>>>,
+}
+
+impl Default for Handoff {
+    fn default() -> Self {
+        Self { queue: Mutex::new(Vec::new()), waker: Mutex::new(None) }
+    }
+}
+
+impl Handoff {
+    pub fn set_waker(&self, waker: Arc<Waker>) {
+        // Anything queued before the waker existed would otherwise wait for the next connection.
+        let _ = waker.wake();
+        *self.waker.lock().unwrap_or_else(|e| e.into_inner()) = Some(waker);
+    }
+
+    fn push(&self, stream: TcpStream, peer: SocketAddr, listener: SocketAddr) {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner()).push((stream, peer, listener));
+        if let Some(waker) = &*self.waker.lock().unwrap_or_else(|e| e.into_inner()) {
+            let _ = waker.wake();
+        }
+    }
+
+    /// Moves the queued connections into `out`.
+    pub fn take(&self, out: &mut Vec<(TcpStream, SocketAddr, SocketAddr)>) {
+        out.append(&mut self.queue.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+}
+
+/// Runs the accept thread. Accept errors and listen queue samples are booked to worker 0.
+/// When its event loop fails it starts a new one after a backoff, keeping the listeners.
+pub fn acceptor(shared: &'static Shared, mut own: Listeners) {
+    let mut next = 0;
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        let err = match Poll::new() {
+            Ok(mut poll) => {
+                own.register(&poll);
+                let err = serve(shared, &mut poll, &mut own, &mut next);
+                own.deregister(&poll);
+                err
+            }
+            Err(e) => VryptError::Poll(e),
+        };
+        if started.elapsed() >= RESTART_RESET {
+            failures = 0;
+        }
+        let pause = error::backoff(failures);
+        failures += 1;
+        eprintln!("[error] accept thread: {err}, restarting in {}ms", pause.as_millis());
+        thread::sleep(pause);
+    }
+}
+
+/// Accepts and deals connections until polling fails.
+fn serve(shared: &'static Shared, poll: &mut Poll, own: &mut Listeners, next: &mut usize) -> VryptError {
+    let mut events = Events::with_capacity(64);
+    let mut deal = |stream, peer, listener| {
+        shared.handoffs[*next].push(stream, peer, listener);
+        *next = (*next + 1) % shared.handoffs.len();
+    };
+    let mut last_sample = Instant::now();
+    // Paced for all workers together; see `Worker::accept_deferred` for the deferral.
+    let workers = shared.handoffs.len() as u32;
+    let mut rate = shared.cfg.accept_rate.map(|n| AcceptRate::new(n.saturating_mul(workers), last_sample));
+    let mut deferred = false;
+    let mut ready = Vec::new();
+
+    loop {
+        let timeout = match &rate {
+            Some(rate) if deferred => rate.wait().min(POLL_TIMEOUT),
+            _ => POLL_TIMEOUT,
+        };
+        if let Err(e) = poll.poll(&mut events, Some(timeout)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return VryptError::Poll(e);
+        }
+        if own.stale() {
+            for bound in own.sync(poll) {
+                while let Ok((stream, peer)) = bound.listener.accept() {
+                    deal(stream, peer, bound.addr);
+                }
+            }
+        }
+        if let Some(tcp) = shared.tcp.filter(|_| last_sample.elapsed() >= STATS_INTERVAL) {
+            tcp.sample_listeners(0, own.iter());
+            last_sample = Instant::now();
+        }
+
+        ready.clear();
+        match std::mem::take(&mut deferred) {
+            true => ready.extend(0..own.slots()),
+            false => ready.extend(events.iter().map(|e| e.token().0 - LISTENER_TOKEN_BASE)),
+        }
+        for &slot in &ready {
+            let Some(bound) = own.get(slot) else { continue };
+            let mut retries = 0;
+            loop {
+                if let Some(rate) = &mut rate {
+                    if !rate.try_take(Instant::now()) {
+                        deferred = true;
+                        break;
+                    }
+                }
+                match bound.listener.accept() {
+                    Ok((stream, peer)) => {
+                        retries = 0;
+                        deal(stream, peer, bound.addr)
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        if let Some(rate) = &mut rate {
+                            rate.refund();
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        let kind = AcceptError::classify(&e);
+                        shared.counter.accept_error(0, kind);
+                        if kind.is_transient() && retries < ACCEPT_RETRY_LIMIT {
+                            retries += 1;
+                            continue;
+                        }
+                        eprintln!("[warn] accept error: {e}");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,20 @@
+use crate::conn::Conn;
+use std::time::Duration;
+
+/// Longest request line kept for the access log; longer targets are cut.
+pub const MAX_LOGGED_LINE: usize = 256;
+
+/// Writes one line for a completed request: peer, request line, status, bytes and latency
+/// from the first byte of the request to the last byte of the response.
+pub fn log<T>(conn: &Conn<T>, elapsed: Duration, slow: bool) {
+    let out = conn.outgoing();
+    let status = out.get(9..12).and_then(|s| std::str::from_utf8(s).ok()).unwrap_or("-");
+    eprintln!(
+        "[{}] {} \"{}\" {status} {} {:.3}ms",
+        if slow { "slow" } else { "access" },
+        conn.peer,
+        String::from_utf8_lossy(&conn.request_line).escape_debug(),
+        out.len(),
+        elapsed.as_secs_f64() * 1_000.0,
+    );
+}
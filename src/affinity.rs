@@ -0,0 +1,188 @@
+//! Per-worker session affinity table for `--split-affinity`: remembers which split group a
+//! client key (a hashed IP, header or cookie) was sent to, so a session keeps its group
+//! until it has been idle for the TTL.
+//!
+//! All memory is allocated when the table is built. Entries live in a fixed array, chained
+//! per hash bucket and threaded on an LRU list by index, so lookups, inserts and evictions
+//! never allocate.
+
+use std::time::{Duration, Instant};
+
+const NIL: u32 = u32::MAX;
+
+struct Entry {
+    key: u64,
+    value: u32,
+    expires: Instant,
+    /// Next entry in the same bucket.
+    chain: u32,
+    /// Neighbours on the LRU list, most recently used first.
+    prev: u32,
+    next: u32,
+}
+
+/// Lookups and removals since the counts were last taken.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct AffinityCounts {
+    pub hits: u64,
+    pub misses: u64,
+    /// Live entries dropped to make room.
+    pub evictions: u64,
+    /// Entries found past their TTL.
+    pub expired: u64,
+}
+
+/// A bounded LRU map from `u64` keys to `u32` values with idle expiry.
+pub struct AffinityTable {
+    entries: Vec<Entry>,
+    buckets: Box<[u32]>,
+    /// Entries not in use, linked through `next`.
+    free: u32,
+    head: u32,
+    tail: u32,
+    ttl: Duration,
+    counts: AffinityCounts,
+}
+
+impl AffinityTable {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        let capacity = capacity.clamp(1, NIL as usize - 1);
+        let now = Instant::now();
+        let entries = (0..capacity)
+            .map(|i| Entry {
+                key: 0,
+                value: 0,
+                expires: now,
+                chain: NIL,
+                prev: NIL,
+                next: if i + 1 < capacity { i as u32 + 1 } else { NIL },
+            })
+            .collect();
+        let buckets = vec![NIL; capacity.next_power_of_two()].into_boxed_slice();
+        Self { entries, buckets, free: 0, head: NIL, tail: NIL, ttl, counts: AffinityCounts::default() }
+    }
+
+    /// The value stored for `key`, unless it has expired; a hit makes it the most recently
+    /// used entry and restarts its TTL.
+    pub fn get(&mut self, key: u64, now: Instant) -> Option<u32> {
+        let Some(i) = self.find(key) else {
+            self.counts.misses += 1;
+            return None;
+        };
+        if self.entries[i as usize].expires <= now {
+            self.remove(i);
+            self.counts.expired += 1;
+            self.counts.misses += 1;
+            return None;
+        }
+        self.unlink(i);
+        self.push_front(i);
+        let entry = &mut self.entries[i as usize];
+        entry.expires = now + self.ttl;
+        self.counts.hits += 1;
+        Some(entry.value)
+    }
+
+    /// Stores `value` for `key`, evicting the least recently used entry when full.
+    pub fn insert(&mut self, key: u64, value: u32, now: Instant) {
+        let i = match self.find(key) {
+            Some(i) => {
+                self.unlink(i);
+                i
+            }
+            None => {
+                if self.free == NIL {
+                    let lru = self.tail;
+                    if self.entries[lru as usize].expires <= now {
+                        self.counts.expired += 1;
+                    } else {
+                        self.counts.evictions += 1;
+                    }
+                    self.remove(lru);
+                }
+                let i = self.free;
+                self.free = self.entries[i as usize].next;
+                let bucket = self.bucket(key);
+                let entry = &mut self.entries[i as usize];
+                entry.key = key;
+                entry.chain = self.buckets[bucket];
+                self.buckets[bucket] = i;
+                i
+            }
+        };
+        let entry = &mut self.entries[i as usize];
+        entry.value = value;
+        entry.expires = now + self.ttl;
+        self.push_front(i);
+    }
+
+    /// Counts since the previous call.
+    pub fn take_counts(&mut self) -> AffinityCounts {
+        std::mem::take(&mut self.counts)
+    }
+
+    /// Bytes held by the table, all of it allocated up front.
+    pub fn bytes(&self) -> u64 {
+        (self.entries.capacity() * size_of::<Entry>() + self.buckets.len() * size_of::<u32>()) as u64
+    }
+
+    #[inline]
+    fn bucket(&self, key: u64) -> usize {
+        // Keys are already hashes; fold the high bits in for tables smaller than 2^32.
+        ((key ^ (key >> 32)) as usize) & (self.buckets.len() - 1)
+    }
+
+    fn find(&self, key: u64) -> Option<u32> {
+        let mut i = self.buckets[self.bucket(key)];
+        while i != NIL {
+            let entry = &self.entries[i as usize];
+            if entry.key == key {
+                return Some(i);
+            }
+            i = entry.chain;
+        }
+        None
+    }
+
+    /// Takes entry `i` out of its bucket and the LRU list and returns it to the free list.
+    fn remove(&mut self, i: u32) {
+        let bucket = self.bucket(self.entries[i as usize].key);
+        let next_in_chain = self.entries[i as usize].chain;
+        if self.buckets[bucket] == i {
+            self.buckets[bucket] = next_in_chain;
+        } else {
+            let mut j = self.buckets[bucket];
+            while self.entries[j as usize].chain != i {
+                j = self.entries[j as usize].chain;
+            }
+            self.entries[j as usize].chain = next_in_chain;
+        }
+        self.unlink(i);
+        self.entries[i as usize].next = self.free;
+        self.free = i;
+    }
+
+    fn unlink(&mut self, i: u32) {
+        let (prev, next) = (self.entries[i as usize].prev, self.entries[i as usize].next);
+        match prev {
+            NIL => self.head = next,
+            p => self.entries[p as usize].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            n => self.entries[n as usize].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, i: u32) {
+        let head = self.head;
+        let entry = &mut self.entries[i as usize];
+        entry.prev = NIL;
+        entry.next = head;
+        match head {
+            NIL => self.tail = i,
+            h => self.entries[h as usize].prev = i,
+        }
+        self.head = i;
+    }
+}
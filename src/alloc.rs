@@ -0,0 +1,84 @@
+//! The global allocator (`jemalloc` or `mimalloc` feature, the system allocator without
+//! either) and its statistics for the admin endpoint.
+
+use std::io::Write;
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the `jemalloc` and `mimalloc` features each select the global allocator; enable one");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+/// The allocator this binary was built with.
+#[cfg(feature = "jemalloc")]
+pub const NAME: &str = "jemalloc";
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+pub const NAME: &str = "mimalloc";
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub const NAME: &str = "system";
+
+/// Appends `name: value` lines describing the allocator's heap.
+pub fn write_stats(out: &mut Vec<u8>) {
+    let _ = writeln!(out, "allocator: {NAME}");
+    write_heap(out);
+}
+
+/// jemalloc's own counters, as of a freshly advanced epoch.
+#[cfg(feature = "jemalloc")]
+fn write_heap(out: &mut Vec<u8>) {
+    use tikv_jemalloc_ctl::{epoch, stats};
+    if let Err(e) = epoch::advance() {
+        let _ = writeln!(out, "heap: unavailable ({e})");
+        return;
+    }
+    let stats = [
+        ("allocated_bytes", stats::allocated::read()),
+        ("active_bytes", stats::active::read()),
+        ("resident_bytes", stats::resident::read()),
+        ("mapped_bytes", stats::mapped::read()),
+        ("retained_bytes", stats::retained::read()),
+        ("metadata_bytes", stats::metadata::read()),
+    ];
+    for (name, value) in stats {
+        let _ = match value {
+            Ok(v) => writeln!(out, "{name}: {v}"),
+            Err(e) => writeln!(out, "{name}: unavailable ({e})"),
+        };
+    }
+}
+
+/// mimalloc's process-wide counters; it estimates RSS from committed memory on Linux.
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+fn write_heap(out: &mut Vec<u8>) {
+    let (mut rss, mut peak_rss, mut commit, mut peak_commit, mut faults) = (0, 0, 0, 0, 0);
+    let null = std::ptr::null_mut();
+    // SAFETY: every out-parameter is a valid `usize` or null, which mimalloc skips.
+    unsafe {
+        libmimalloc_sys::mi_process_info(null, null, null, &mut rss, &mut peak_rss, &mut commit, &mut peak_commit, &mut faults);
+    }
+    let _ = write!(
+        out,
+        "rss_bytes: {rss}\npeak_rss_bytes: {peak_rss}\ncommitted_bytes: {commit}\npeak_committed_bytes: {peak_commit}\npage_faults: {faults}\n"
+    );
+}
+
+#[cfg(all(not(any(feature = "jemalloc", feature = "mimalloc")), target_os = "linux", target_env = "gnu"))]
+fn write_heap(out: &mut Vec<u8>) {
+    // SAFETY: mallinfo2 only reads allocator state and returns it by value.
+    let m = unsafe { libc::mallinfo2() };
+    let _ = write!(
+        out,
+        "arena_bytes: {}\nmmap_bytes: {}\nin_use_bytes: {}\nfree_bytes: {}\ntrimmable_bytes: {}\n",
+        m.arena, m.hblkhd, m.uordblks, m.fordblks, m.keepcost
+    );
+}
+
+#[cfg(all(not(any(feature = "jemalloc", feature = "mimalloc")), not(all(target_os = "linux", target_env = "gnu"))))]
+fn write_heap(out: &mut Vec<u8>) {
+    let _ = writeln!(out, "heap: unavailable on this platform");
+}
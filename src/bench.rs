@@ -0,0 +1,93 @@
+//! Hooks for the Criterion benchmarks in `bench/`. Only compiled with the `bench` feature;
+//! each wraps the real hot-path code without changing it.
+
+use crate::config::{BUF_SIZE, MAX_RECYCLED_BUFS};
+use crate::conn::Conn;
+use crate::http::{self, RequestHead};
+use crate::pool::BufPool;
+use crate::region::BufBacking;
+use crate::response;
+use crate::slab::Slab;
+use crate::transport::Stream;
+use mio::Token;
+use std::net::{TcpListener, TcpStream};
+use std::time::Instant;
+
+/// Head terminator scan as done by `Conn::request_complete`, from the start of `buf`.
+#[inline]
+pub fn scan_head(buf: &[u8]) -> Option<usize> {
+    http::find_head_end(buf, 0)
+}
+
+/// Parses a head (without its CRLFCRLF) and walks its headers; returns the header count.
+#[inline]
+pub fn parse_head(head: &[u8]) -> usize {
+    let Some(head) = RequestHead::parse(head) else { return 0 };
+    let _ = (head.content_length(), head.is_chunked());
+    head.headers().count()
+}
+
+#[inline]
+pub fn serialize(body: &[u8], trailers: bool) -> Vec<u8> {
+    response::build_response("200 OK", "text/plain", body, trailers)
+}
+
+pub struct Pool(BufPool);
+
+impl Pool {
+    pub fn new() -> Self {
+        Self(BufPool::new(1024, MAX_RECYCLED_BUFS, BufBacking::Heap))
+    }
+
+    /// One acquire/release round trip, served from the recycle list after the first call.
+    #[inline]
+    pub fn cycle(&mut self) {
+        let buf = self.0.acquire().expect("pool exhausted");
+        self.0.release(buf);
+    }
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A slab plus one loopback connection to move in and out of it.
+pub struct SlabFixture {
+    slab: Slab,
+    conn: Option<Conn>,
+    _client: TcpStream,
+}
+
+impl SlabFixture {
+    pub fn new(cap: usize) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let client = TcpStream::connect(listener.local_addr().unwrap()).expect("connect");
+        let (server, peer) = listener.accept().expect("accept");
+        server.set_nonblocking(true).unwrap();
+        let stream = Stream::new(mio::net::TcpStream::from_std(server));
+        let conn = Conn::new(stream, peer, Box::new([0u8; BUF_SIZE]).into(), Instant::now());
+        Self { slab: Slab::new(cap), conn: Some(conn), _client: client }
+    }
+
+    /// Inserts the connection at `token`, looks it up and removes it again.
+    #[inline]
+    pub fn cycle(&mut self, token: usize) {
+        let tok = Token(token);
+        self.slab.insert(tok, self.conn.take().expect("connection in slab"));
+        let _ = self.slab.get_mut(tok).map(|c| c.read_len);
+        self.conn = self.slab.remove(tok);
+    }
+
+    /// Inserts the connection at `token`, walks every connection in the slab and removes
+    /// it again; returns how many the walk found.
+    #[inline]
+    pub fn scan(&mut self, token: usize) -> usize {
+        let tok = Token(token);
+        self.slab.insert(tok, self.conn.take().expect("connection in slab"));
+        let found = self.slab.iter().count();
+        self.conn = self.slab.remove(tok);
+        found
+    }
+}
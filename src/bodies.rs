@@ -0,0 +1,105 @@
+use crate::bus::{Bus, Event};
+use crate::routes::Route;
+use arc_swap::ArcSwap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Response bodies that can be replaced at runtime through the admin API.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BodyName {
+    /// Body of the normal `200 OK` response.
+    Default,
+    /// Body of the maintenance-mode `503` response.
+    Maintenance,
+}
+
+impl BodyName {
+    pub const ALL: [BodyName; 2] = [BodyName::Default, BodyName::Maintenance];
+
+    pub fn parse(name: &[u8]) -> Option<Self> {
+        BodyName::ALL.into_iter().find(|n| n.name().as_bytes() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BodyName::Default => "default",
+            BodyName::Maintenance => "maintenance",
+        }
+    }
+}
+
+/// What an admin `PUT` replaces.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Upload {
+    Body(BodyName),
+    /// The static route at this index of `BodyStore::routes`.
+    Route(usize),
+    /// The next long-poll event.
+    Event,
+}
+
+/// What the workers copy their responses from, swapped whole on every replacement.
+#[derive(Clone, Default)]
+struct Contents {
+    bodies: [Option<Arc<[u8]>>; BodyName::ALL.len()],
+    routes: Vec<Arc<Route>>,
+}
+
+/// Process-wide uploaded bodies and rendered static routes, behind an `ArcSwap`: readers
+/// never block, and a replacement publishes a new `Contents` with `rcu`. Workers poll
+/// `version` once per request and only load the contents after it has changed; a
+/// replacement also posts `Event::Bodies`, so idle workers drop their old copies too.
+pub struct BodyStore {
+    version: AtomicU64,
+    contents: ArcSwap<Contents>,
+    bus: &'static Bus,
+}
+
+impl BodyStore {
+    pub fn new(routes: Vec<Route>, bus: &'static Bus) -> &'static Self {
+        let contents = Contents { routes: routes.into_iter().map(Arc::new).collect(), ..Default::default() };
+        Box::leak(Box::new(Self { version: AtomicU64::new(0), contents: ArcSwap::from_pointee(contents), bus }))
+    }
+
+    /// Bumped on every replacement; 0 means nothing has been uploaded.
+    #[inline]
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    pub fn replace(&self, name: BodyName, body: Vec<u8>) {
+        let body: Arc<[u8]> = body.into();
+        self.contents.rcu(|contents| {
+            let mut contents = Contents::clone(contents);
+            contents.bodies[name as usize] = Some(Arc::clone(&body));
+            contents
+        });
+        self.version.fetch_add(1, Ordering::Release);
+        self.bus.post(Event::Bodies);
+    }
+
+    /// The uploaded body for `name`, if any.
+    pub fn get(&self, name: BodyName) -> Option<Arc<[u8]>> {
+        self.contents.load().bodies[name as usize].clone()
+    }
+
+    /// Swaps in a re-rendered static route; `index` is its position in `routes`.
+    pub fn replace_route(&self, index: usize, route: Route) {
+        if index >= self.contents.load().routes.len() {
+            return;
+        }
+        let route = Arc::new(route);
+        self.contents.rcu(|contents| {
+            let mut contents = Contents::clone(contents);
+            contents.routes[index] = Arc::clone(&route);
+            contents
+        });
+        self.version.fetch_add(1, Ordering::Release);
+        self.bus.post(Event::Bodies);
+    }
+
+    /// The static routes, in `--static-route` order.
+    pub fn routes(&self) -> Vec<Arc<Route>> {
+        self.contents.load().routes.clone()
+    }
+}
@@ -0,0 +1,114 @@
+use crate::config::MAX_REQUEST_SIZE;
+use crate::http::find;
+use std::io;
+
+/// How the body of the current request is delimited.
+pub enum Framing {
+    /// `Content-Length`: this many bytes remain.
+    Length(u64),
+    /// `Transfer-Encoding: chunked`.
+    Chunked(ChunkedDecoder),
+}
+
+/// Longest accepted chunk-size line, extensions included.
+const MAX_CHUNK_LINE: usize = 1024;
+/// Decode error returned when the sink rejects chunk data.
+pub const SINK_FAILED: &str = "body sink write failed";
+
+enum ChunkState {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailers,
+}
+
+/// Incremental chunked transfer-coding decoder. Trailer fields are validated and
+/// counted against `MAX_REQUEST_SIZE`, then dropped.
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    trailer_bytes: usize,
+}
+
+pub enum Decoded {
+    /// Consumed this many bytes; more input is needed.
+    Partial(usize),
+    /// Consumed this many bytes and reached the end of the trailer section.
+    Done(usize),
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        Self { state: ChunkState::Size, trailer_bytes: 0 }
+    }
+
+    /// Decodes as much of `buf` as possible, passing chunk data to `sink`.
+    pub fn decode(
+        &mut self,
+        buf: &[u8],
+        mut sink: impl FnMut(&[u8]) -> io::Result<()>,
+    ) -> Result<Decoded, &'static str> {
+        let mut pos = 0;
+        loop {
+            let rest = &buf[pos..];
+            match self.state {
+                ChunkState::Size => {
+                    let eol = find(rest, b"\r\n");
+                    if eol.unwrap_or(rest.len()) > MAX_CHUNK_LINE {
+                        return Err("chunk size line too long");
+                    }
+                    let Some(eol) = eol else {
+                        return Ok(Decoded::Partial(pos));
+                    };
+                    let line = &rest[..eol];
+                    let digits = line.split(|&b| b == b';').next().unwrap_or(&[]);
+                    let digits = std::str::from_utf8(digits).map_err(|_| "invalid chunk size")?.trim_end();
+                    if digits.is_empty() || digits.len() > 15 {
+                        return Err("invalid chunk size");
+                    }
+                    let size = u64::from_str_radix(digits, 16).map_err(|_| "invalid chunk size")?;
+                    pos += eol + 2;
+                    self.state = if size == 0 { ChunkState::Trailers } else { ChunkState::Data(size) };
+                }
+                ChunkState::Data(remaining) => {
+                    if rest.is_empty() {
+                        return Ok(Decoded::Partial(pos));
+                    }
+                    let n = (rest.len() as u64).min(remaining) as usize;
+                    sink(&rest[..n]).map_err(|_| SINK_FAILED)?;
+                    pos += n;
+                    let left = remaining - n as u64;
+                    self.state = if left == 0 { ChunkState::DataEnd } else { ChunkState::Data(left) };
+                }
+                ChunkState::DataEnd => {
+                    if rest.len() < 2 {
+                        return Ok(Decoded::Partial(pos));
+                    }
+                    if &rest[..2] != b"\r\n" {
+                        return Err("missing CRLF after chunk data");
+                    }
+                    pos += 2;
+                    self.state = ChunkState::Size;
+                }
+                ChunkState::Trailers => {
+                    let Some(eol) = find(rest, b"\r\n") else {
+                        if self.trailer_bytes + rest.len() > MAX_REQUEST_SIZE {
+                            return Err("trailer section too large");
+                        }
+                        return Ok(Decoded::Partial(pos));
+                    };
+                    pos += eol + 2;
+                    if eol == 0 {
+                        return Ok(Decoded::Done(pos));
+                    }
+                    if !rest[..eol].contains(&b':') {
+                        return Err("malformed trailer field");
+                    }
+                    self.trailer_bytes += eol + 2;
+                    if self.trailer_bytes > MAX_REQUEST_SIZE {
+                        return Err("trailer section too large");
+                    }
+                }
+            }
+        }
+    }
+}
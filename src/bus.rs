@@ -0,0 +1,68 @@
+//! Cross-worker notices. A thread that changes state the workers keep copies of or act on
+//! posts an `Event`, which is queued for every worker and wakes its poll, so the change is
+//! taken up at once rather than at the worker's next `POLL_TIMEOUT`.
+
+use mio::Waker;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// What changed. Events are notices, not data: the worker reads the current state when it
+/// takes one, so a repeat still queued is dropped rather than queued twice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The `ListenSet` changed: addresses added or removed, or a listener draining or resumed.
+    Listeners,
+    /// A long-poll event was published.
+    LongPoll,
+    /// An uploaded body or static route was replaced.
+    Bodies,
+    /// A per-listener or per-route in-flight slot was freed while requests waited for one.
+    Slots,
+}
+
+struct Slot {
+    events: Mutex<Vec<Event>>,
+    /// Set once the worker's poll exists and replaced when it restarts; events posted in
+    /// between stay queued for it.
+    waker: Mutex<Option<Arc<Waker>>>,
+}
+
+/// One event queue per worker, shared by every thread that posts.
+pub struct Bus {
+    slots: Box<[Slot]>,
+}
+
+impl Bus {
+    pub fn new(workers: usize) -> &'static Self {
+        let slots = (0..workers).map(|_| Slot { events: Mutex::new(Vec::new()), waker: Mutex::new(None) }).collect();
+        Box::leak(Box::new(Self { slots }))
+    }
+
+    pub fn set_waker(&self, thread_id: usize, waker: Arc<Waker>) {
+        // Events posted before the waker existed would otherwise wait for the next one.
+        let _ = waker.wake();
+        *lock(&self.slots[thread_id].waker) = Some(waker);
+    }
+
+    /// Queues `event` for every worker and wakes them.
+    pub fn post(&self, event: Event) {
+        for slot in self.slots.iter() {
+            let mut events = lock(&slot.events);
+            if !events.contains(&event) {
+                events.push(event);
+            }
+            drop(events);
+            if let Some(w) = &*lock(&slot.waker) {
+                let _ = w.wake();
+            }
+        }
+    }
+
+    /// Moves the events queued for `thread_id` into `out`, oldest first.
+    pub fn take(&self, thread_id: usize, out: &mut Vec<Event>) {
+        out.append(&mut lock(&self.slots[thread_id].events));
+    }
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,143 @@
+use crate::config::CAPTURE_MAGIC;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Size of a record header: conn id (u64), direction (u8), micros since start (u64), length (u32).
+pub const RECORD_HEADER_LEN: usize = 8 + 1 + 8 + 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Direction {
+    Request = 0,
+    Response = 1,
+}
+
+/// Raw request/response dump for a sampled fraction of connections.
+///
+/// Records are appended to `path`; once the active segment reaches half of
+/// `max_bytes` it is renamed to `<path>.old` (replacing the previous one) and a
+/// fresh segment is started, so the two files together never exceed the cap.
+pub struct Capture {
+    segment: Mutex<Segment>,
+    sample: f64,
+    segment_cap: u64,
+    epoch: Instant,
+    failed: AtomicBool,
+}
+
+struct Segment {
+    file: File,
+    path: PathBuf,
+    written: u64,
+}
+
+impl Segment {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&path)?;
+        file.write_all(CAPTURE_MAGIC)?;
+        Ok(Self { file, path, written: CAPTURE_MAGIC.len() as u64 })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut old = self.path.clone().into_os_string();
+        old.push(".old");
+        fs::rename(&self.path, old)?;
+        *self = Segment::create(self.path.clone())?;
+        Ok(())
+    }
+}
+
+impl Capture {
+    pub fn open(path: PathBuf, sample: f64, max_bytes: u64) -> io::Result<&'static Self> {
+        let segment = Segment::create(path)?;
+        let segment_cap = (max_bytes / 2).max(CAPTURE_MAGIC.len() as u64 + RECORD_HEADER_LEN as u64);
+        Ok(Box::leak(Box::new(Self {
+            segment: Mutex::new(segment),
+            sample,
+            segment_cap,
+            epoch: Instant::now(),
+            failed: AtomicBool::new(false),
+        })))
+    }
+
+    #[inline]
+    pub fn sample(&self) -> f64 {
+        self.sample
+    }
+
+    pub fn record(&self, conn: u64, dir: Direction, data: &[u8]) {
+        if data.is_empty() || self.failed.load(Ordering::Relaxed) {
+            return;
+        }
+        let micros = self.epoch.elapsed().as_micros() as u64;
+        let mut rec = Vec::with_capacity(RECORD_HEADER_LEN + data.len());
+        rec.extend_from_slice(&conn.to_le_bytes());
+        rec.push(dir as u8);
+        rec.extend_from_slice(&micros.to_le_bytes());
+        rec.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        rec.extend_from_slice(data);
+
+        let mut seg = match self.segment.lock() {
+            Ok(s) => s,
+            Err(p) => p.into_inner(),
+        };
+        let res = (|| {
+            if seg.written + rec.len() as u64 > self.segment_cap && seg.written > CAPTURE_MAGIC.len() as u64 {
+                seg.rotate()?;
+            }
+            seg.file.write_all(&rec)?;
+            seg.written += rec.len() as u64;
+            Ok::<_, io::Error>(())
+        })();
+        if let Err(e) = res {
+            eprintln!("[capture] write to {} failed, disabling capture: {e}", seg.path.display());
+            self.failed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// One record read back from a capture file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub conn: u64,
+    pub dir: Direction,
+    /// Microseconds since the capture was opened.
+    pub micros: u64,
+    pub data: Vec<u8>,
+}
+
+/// Reads every record of a capture file (or its `.old` segment). A record cut short, as by
+/// a crash mid-write, ends the file.
+pub fn read_records(mut r: impl Read) -> io::Result<Vec<Record>> {
+    let mut magic = [0u8; CAPTURE_MAGIC.len()];
+    r.read_exact(&mut magic)?;
+    if magic != CAPTURE_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a vrypt capture file"));
+    }
+    let mut records = Vec::new();
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    loop {
+        match r.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            res => res?,
+        }
+        let u64_at = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().expect("8 bytes"));
+        let dir = match header[8] {
+            0 => Direction::Request,
+            1 => Direction::Response,
+            b => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown direction {b}"))),
+        };
+        let len = u32::from_le_bytes(header[17..21].try_into().expect("4 bytes")) as usize;
+        let mut data = vec![0u8; len];
+        match r.read_exact(&mut data) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            res => res?,
+        }
+        records.push(Record { conn: u64_at(0), dir, micros: u64_at(9), data });
+    }
+    Ok(records)
+}
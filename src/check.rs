@@ -0,0 +1,216 @@
+use crate::config::{Config, MAX_CONNS};
+use crate::daemon::PidFile;
+use crate::error_page::PageBody;
+use crate::listen;
+use crate::region::{self, BufBacking};
+use crate::routes::{Payload, StaticRoute};
+use crate::sink::SinkMode;
+use crate::statsd::Transport;
+use crate::template::Template;
+use crate::xdp::XdpMap;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fs::{self, OpenOptions};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long a split group's or the mirror's upstream has to accept the probe connection.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Validates `cfg` the way a real start would use it, without serving traffic.
+/// Prints one line per check and returns whether all of them passed.
+pub fn run(cfg: &Config, arg_errors: usize) -> bool {
+    let mut ok = true;
+    let mut report = |what: &str, res: Result<(), String>| match res {
+        Ok(()) => println!("ok     {what}"),
+        Err(e) => {
+            eprintln!("error  {what}: {e}");
+            ok = false;
+        }
+    };
+
+    report(
+        "arguments",
+        if arg_errors == 0 { Ok(()) } else { Err(format!("{arg_errors} invalid argument(s), see above")) },
+    );
+    if let Some(path) = &cfg.maintenance_page {
+        report(&format!("maintenance page {}", path.display()), fs::read(path).map(drop).map_err(|e| e.to_string()));
+    }
+    for page in &cfg.error_pages {
+        if let PageBody::File(path) = &page.body {
+            report(&format!("error page {}", path.display()), fs::read(path).map(drop).map_err(|e| e.to_string()));
+        }
+    }
+    for route in &cfg.static_routes {
+        report(&format!("static route {} {}", route.path, route_target(route)), route_source(route));
+        if route.tenant.is_some() {
+            report(&format!("static route {} tenant", route.path), route.tenant_index(&cfg.tenants).map(drop));
+        }
+    }
+    if let Some(path) = &cfg.template {
+        let res = fs::read(path).map_err(|e| e.to_string()).and_then(|src| Template::parse(&src).map(drop));
+        report(&format!("template {}", path.display()), res);
+    }
+    #[cfg(feature = "tls")]
+    if let (Some(cert), Some(key)) = (&cfg.tls_cert, &cfg.tls_key) {
+        report(&format!("TLS certificate {}", cert.display()), crate::tls::server_config(cert, key).map(drop));
+    }
+    if cfg.buf_pool != BufBacking::Heap {
+        let res = region::probe(cfg.buf_pool, MAX_CONNS).map_err(|e| e.to_string());
+        report(&format!("{} buffer pool", cfg.buf_pool.name()), res);
+    }
+    if let Some(path) = &cfg.capture_path {
+        report(&format!("capture file {}", path.display()), writable_file(path));
+    }
+    if let Some(path) = &cfg.xdp_drop_map {
+        report(&format!("XDP drop map {}", path.display()), XdpMap::open(path).map(drop).map_err(|e| e.to_string()));
+    }
+    if let SinkMode::Store(dir) = &cfg.body_sink {
+        let res = match fs::metadata(dir) {
+            Ok(m) if m.is_dir() && !m.permissions().readonly() => Ok(()),
+            Ok(_) => Err("not a writable directory".to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        report(&format!("body store {}", dir.display()), res);
+    }
+    if cfg.daemonize {
+        report(&format!("log file {}", cfg.log_file.display()), writable_file(&cfg.log_file));
+    }
+    if let Some(path) = &cfg.pidfile {
+        let existed = path.exists();
+        let res = PidFile::acquire(path).map(drop).map_err(|e| e.to_string());
+        if !existed {
+            let _ = fs::remove_file(path);
+        }
+        report(&format!("pid file {}", path.display()), res);
+    }
+    for sink in cfg.active_stats_sinks() {
+        if sink.target.transport != Transport::Stdout {
+            let res = sink.target.resolve().map(drop).map_err(|e| e.to_string());
+            report(&format!("stats target {}", sink.target), res);
+        }
+    }
+    for group in &cfg.split.groups {
+        let target = &group.target;
+        let res = TcpStream::connect_timeout(&target.addr, UPSTREAM_TIMEOUT).map(drop).map_err(|e| e.to_string());
+        report(&format!("split group {} upstream {}:{} ({})", group.name, target.host, target.port, target.addr), res);
+    }
+    if let Some(mirror) = &cfg.mirror {
+        let target = &mirror.target;
+        let res = TcpStream::connect_timeout(&target.addr, UPSTREAM_TIMEOUT).map(drop).map_err(|e| e.to_string());
+        report(&format!("mirror upstream {}:{} ({})", target.host, target.port, target.addr), res);
+    }
+    // Destinations are resolved once at startup; a name that no longer resolves would be
+    // unreachable for the life of the process.
+    for target in &cfg.proxy_allow {
+        let res = match (target.host.as_str(), target.port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err("resolves to no address".to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        report(&format!("forward-proxy destination {}:{}", target.host, target.port), res);
+    }
+    let addrs = match &cfg.listen_file {
+        Some(path) => match listen::read_file(path) {
+            Ok(addrs) => {
+                report(&format!("listen file {}", path.display()), Ok(()));
+                addrs
+            }
+            Err(e) => {
+                report(&format!("listen file {}", path.display()), Err(e.to_string()));
+                Vec::new()
+            }
+        },
+        None => listen::fixed_addrs(cfg),
+    };
+    let device = cfg.bind_device.as_deref();
+    if let Some(name) = device {
+        let res = match std::ffi::CString::new(name) {
+            // SAFETY: `name` is a valid NUL-terminated string for the duration of the call.
+            Ok(c) if unsafe { libc::if_nametoindex(c.as_ptr()) } != 0 => Ok(()),
+            _ => Err("no such interface".to_string()),
+        };
+        report(&format!("bind device {name}"), res);
+    }
+    for addr in addrs {
+        report(&format!("listen on {addr}"), bind_and_release(addr, device));
+    }
+    ok
+}
+
+/// What a route's `file` names, for the report line.
+fn route_target(route: &StaticRoute) -> String {
+    let what = match route.payload {
+        Payload::File | Payload::Protobuf | Payload::Grpc => "file",
+        Payload::Exec => "command",
+        Payload::FastCgi | Payload::Scgi => "backend",
+    };
+    format!("{what} {}", route.file.display())
+}
+
+/// Reads a route's file, finds its command's program, or connects to its backend's
+/// socket; a backend that is not accepting yet is reported, since no request would get
+/// through.
+fn route_source(route: &StaticRoute) -> Result<(), String> {
+    let command = route.file.to_string_lossy();
+    let first = command.split_whitespace().next().ok_or("empty command");
+    match route.payload {
+        Payload::File | Payload::Protobuf | Payload::Grpc => fs::read(&route.file).map(drop).map_err(|e| e.to_string()),
+        Payload::Exec => executable(first?),
+        Payload::FastCgi | Payload::Scgi => {
+            let socket = first?;
+            match fs::metadata(socket) {
+                Ok(m) if !m.file_type().is_socket() => Err(format!("{socket} is not a unix socket")),
+                Ok(_) => UnixStream::connect(socket).map(drop).map_err(|e| format!("{socket}: {e}")),
+                Err(e) => Err(format!("{socket}: {e}")),
+            }
+        }
+    }
+}
+
+/// Finds `program` the way `execvp` would: as given when it holds a `/`, otherwise on `PATH`.
+fn executable(program: &str) -> Result<(), String> {
+    let runnable = |path: &Path| fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0);
+    if program.contains('/') {
+        return match fs::metadata(program) {
+            Ok(_) if runnable(Path::new(program)) => Ok(()),
+            Ok(_) => Err("not an executable file".to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+    }
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    if std::env::split_paths(&path).any(|dir| runnable(&dir.join(program))) {
+        Ok(())
+    } else {
+        Err(format!("{program} not found on PATH"))
+    }
+}
+
+/// Opens `path` for appending without truncating it; creates nothing that did not exist.
+fn writable_file(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        return OpenOptions::new().append(true).open(path).map(drop).map_err(|e| e.to_string());
+    }
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    match fs::metadata(dir) {
+        Ok(m) if m.is_dir() && !m.permissions().readonly() => Ok(()),
+        Ok(_) => Err(format!("{} is not a writable directory", dir.display())),
+        Err(e) => Err(format!("{}: {e}", dir.display())),
+    }
+}
+
+/// Binds without SO_REUSEPORT so an instance already serving the port is reported.
+fn bind_and_release(addr: SocketAddr, device: Option<&str>) -> Result<(), String> {
+    let sock = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP)).map_err(|e| e.to_string())?;
+    sock.set_reuse_address(true).map_err(|e| e.to_string())?;
+    if let Some(device) = device {
+        sock.bind_device(Some(device.as_bytes())).map_err(|e| e.to_string())?;
+    }
+    sock.bind(&addr.into()).map_err(|e| e.to_string())?;
+    sock.listen(1).map_err(|e| e.to_string())
+}
@@ -0,0 +1,53 @@
+//! Where workers get the time from. Everything a worker times out on — the timer wheel,
+//! keep-alive and header timeouts, write deadlines, lingering closes, upstream connects,
+//! accept pacing — reads `Shared::clock` rather than `Instant::now()`, so a test can start
+//! a server on a `SimClock` (with the `sim-clock` feature) and move time forward instead of
+//! sleeping through it. Durations that are only reported, such as `Server-Timing` and the
+//! access log, are measured in real time.
+
+#[cfg(feature = "sim-clock")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "sim-clock")]
+use std::time::Duration;
+use std::time::Instant;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic system clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until it is advanced. Starts at the real time it was created.
+#[cfg(feature = "sim-clock")]
+pub struct SimClock {
+    start: Instant,
+    elapsed_ns: AtomicU64,
+}
+
+#[cfg(feature = "sim-clock")]
+impl SimClock {
+    pub fn new() -> &'static Self {
+        Box::leak(Box::new(Self { start: Instant::now(), elapsed_ns: AtomicU64::new(0) }))
+    }
+
+    /// Moves the clock forward by `by`. Workers notice at their next wakeup, at most
+    /// `POLL_TIMEOUT` of real time later.
+    pub fn advance(&self, by: Duration) {
+        self.elapsed_ns.fetch_add(by.as_nanos() as u64, Ordering::AcqRel);
+    }
+}
+
+#[cfg(feature = "sim-clock")]
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        self.start + Duration::from_nanos(self.elapsed_ns.load(Ordering::Acquire))
+    }
+}
@@ -0,0 +1,78 @@
+//! How the server closes connections it decides to close (`--close REASON=MODE`): with a
+//! FIN, with a FIN after draining what the client still sends, or with an RST. Load
+//! balancers and clients react differently to each, so the mode is chosen per reason.
+
+/// Why the server is closing a connection. Closes the client starts are not among them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// A header, body, keep-alive, write or connect timeout ran out.
+    Timeout,
+    /// An error response (400, 413, 431) or a client not speaking HTTP/1.x.
+    Error,
+    /// The server's own choice: a draining listener, or memory reclaimed over `--memory-limit`.
+    Policy,
+}
+
+impl CloseReason {
+    pub const ALL: [CloseReason; 3] = [CloseReason::Timeout, CloseReason::Error, CloseReason::Policy];
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|r| r.name() == s)
+            .ok_or_else(|| format!("unknown close reason '{s}', expected timeout, error or policy"))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CloseReason::Timeout => "timeout",
+            CloseReason::Error => "error",
+            CloseReason::Policy => "policy",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseMode {
+    /// Close at once; the kernel sends a FIN (or an RST if client bytes are still unread).
+    Fin,
+    /// Shut down the write side, then read and discard until the client closes or
+    /// `LINGER_TIMEOUT` passes.
+    Drain,
+    /// Close with `SO_LINGER` 0, sending an RST and leaving no `TIME_WAIT` behind.
+    Rst,
+}
+
+impl CloseMode {
+    pub const ALL: [CloseMode; 3] = [CloseMode::Fin, CloseMode::Drain, CloseMode::Rst];
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|m| m.name() == s)
+            .ok_or_else(|| format!("unknown close mode '{s}', expected fin, drain or rst"))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CloseMode::Fin => "fin",
+            CloseMode::Drain => "drain",
+            CloseMode::Rst => "rst",
+        }
+    }
+}
+
+/// One `--close` rule; a later rule for the same reason wins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CloseRule {
+    pub reason: CloseReason,
+    pub mode: CloseMode,
+}
+
+impl CloseRule {
+    /// Parses `REASON=MODE`, e.g. `timeout=rst`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (reason, mode) = spec.split_once('=').ok_or_else(|| format!("expected 'REASON=MODE', got '{spec}'"))?;
+        Ok(Self { reason: CloseReason::parse(reason)?, mode: CloseMode::parse(mode)? })
+    }
+}
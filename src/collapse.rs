@@ -0,0 +1,156 @@
+//! Request collapsing (`--collapse`): a `GET` to a route backend (`exec:`, `fastcgi:` or
+//! `scgi:` static routes) that asks for what another request on the same worker is
+//! already waiting on — same `Host` and target — does not start a backend request of its
+//! own. It waits on the first one instead, and the worker copies that response to it as
+//! the backend sends it.
+//!
+//! Only requests a shared cache could answer are collapsed: no body, no `Authorization`
+//! or `Cookie`, and no `Cache-Control: no-cache`/`no-store` or `Pragma: no-cache`. A
+//! request only joins while the first has not had any output yet; later ones start their
+//! own. The handler picks leaders and followers; the worker keeps the waiters of each
+//! leader and feeds them.
+
+use crate::exec::Chunk;
+use crate::http::RequestHead;
+use mio::Token;
+use std::collections::HashMap;
+
+/// How far the leader's response has got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum End {
+    /// More is coming.
+    Open,
+    /// The response is complete.
+    Done,
+    /// The backend failed; the bytes are the leader's `502`.
+    Failed,
+    /// The leader's connection went away before its response was complete; `started`
+    /// once any of it had been fed.
+    Abandoned { started: bool },
+}
+
+/// A leader's output since the last `take`, with who it goes to.
+pub struct Fan {
+    pub bytes: Vec<u8>,
+    pub waiters: Vec<Token>,
+    pub end: End,
+    /// The backend renders the response head itself, so waiters got none of their own.
+    pub relays_head: bool,
+}
+
+struct Flight {
+    key: Box<[u8]>,
+    waiters: Vec<Token>,
+    /// Output fed since the last `take`, kept only once there are waiters.
+    buf: Vec<u8>,
+    end: End,
+    started: bool,
+    relays_head: bool,
+}
+
+/// One worker's backend requests that others may wait on.
+#[derive(Default)]
+pub struct Collapse {
+    /// Flights not yet started, by key.
+    open: HashMap<Box<[u8]>, u64>,
+    flights: HashMap<u64, Flight>,
+    next: u64,
+}
+
+impl Collapse {
+    /// What the request `head` is collapsed by, if it may be.
+    pub fn key(head: &RequestHead, bodiless: bool) -> Option<Box<[u8]>> {
+        if head.method != b"GET" || !bodiless {
+            return None;
+        }
+        let mut host = None;
+        for (name, value) in head.headers() {
+            let value = value.to_ascii_lowercase();
+            let uncached = |v: &[u8]| v.windows(8).any(|w| w == b"no-cache" || w == b"no-store");
+            match name.to_ascii_lowercase().as_slice() {
+                b"authorization" | b"cookie" => return None,
+                b"cache-control" | b"pragma" if uncached(&value) => return None,
+                b"host" => host = Some(value),
+                _ => {}
+            }
+        }
+        let mut key = host.unwrap_or_default();
+        key.push(b' ');
+        key.extend_from_slice(head.target);
+        Some(key.into_boxed_slice())
+    }
+
+    /// The flight the request with `key` can wait on, if one has not started yet.
+    pub fn follow(&self, key: &[u8]) -> Option<u64> {
+        self.open.get(key).copied()
+    }
+
+    /// Starts a flight for `key` that later requests can wait on.
+    pub fn lead(&mut self, key: Box<[u8]>, relays_head: bool) -> u64 {
+        let id = self.next;
+        self.next += 1;
+        self.open.insert(key.clone(), id);
+        let flight = Flight { key, waiters: Vec::new(), buf: Vec::new(), end: End::Open, started: false, relays_head };
+        self.flights.insert(id, flight);
+        id
+    }
+
+    /// Adds `token` to the waiters of flight `id`.
+    pub fn join(&mut self, id: u64, token: Token) {
+        if let Some(flight) = self.flights.get_mut(&id) {
+            flight.waiters.push(token);
+        }
+    }
+
+    /// Records `bytes` the leader of `id` read from its backend as `chunk`. The first
+    /// output closes the flight to new waiters.
+    pub fn feed(&mut self, id: u64, bytes: &[u8], chunk: &Chunk) {
+        if matches!(chunk, Chunk::Pending) {
+            return;
+        }
+        let Some(flight) = self.flights.get_mut(&id) else { return };
+        if !flight.started {
+            flight.started = true;
+            self.open.remove(&flight.key);
+        }
+        if !flight.waiters.is_empty() {
+            flight.buf.extend_from_slice(bytes);
+        }
+        match chunk {
+            Chunk::Last => flight.end = End::Done,
+            Chunk::Failed => flight.end = End::Failed,
+            Chunk::Pending | Chunk::Data => {}
+        }
+    }
+
+    /// The output of `id` since the last call, if there is any news; an ended flight is
+    /// forgotten.
+    pub fn take(&mut self, id: u64) -> Option<Fan> {
+        let flight = self.flights.get_mut(&id)?;
+        if flight.end == End::Open && flight.buf.is_empty() {
+            return None;
+        }
+        if flight.end == End::Open {
+            let (bytes, waiters) = (std::mem::take(&mut flight.buf), flight.waiters.clone());
+            return Some(Fan { bytes, waiters, end: End::Open, relays_head: flight.relays_head });
+        }
+        self.flights.remove(&id).map(|f| Fan { bytes: f.buf, waiters: f.waiters, end: f.end, relays_head: f.relays_head })
+    }
+
+    /// Forgets `id`, whose leader has gone away, handing back its waiters.
+    pub fn abandon(&mut self, id: u64) -> Option<Fan> {
+        let flight = self.flights.remove(&id)?;
+        if !flight.started {
+            self.open.remove(&flight.key);
+        }
+        let end = match flight.end {
+            End::Open => End::Abandoned { started: flight.started },
+            end => end,
+        };
+        Some(Fan { bytes: flight.buf, waiters: flight.waiters, end, relays_head: flight.relays_head })
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.flights.values().map(|f| f.buf.capacity() + f.key.len() + f.waiters.capacity() * 8).sum()
+    }
+}
@@ -1,15 +1,513 @@
-use mio::Token;
+use crate::close::{CloseMode, CloseReason, CloseRule};
+use crate::conn::IdleClass;
+use crate::error_page::ErrorPage;
+use crate::fault::FaultRule;
+use crate::headers::{HeaderRule, RouteHeader};
+use crate::limit::{ListenerLimit, RouteLimit};
+use crate::listen::AcceptMode;
+use crate::region::BufBacking;
+use crate::mime::MimeMap;
+use crate::mirror::Mirror;
+use crate::redirect::RedirectRule;
+use crate::rewrite::PathRewrite;
+use crate::routes::StaticRoute;
+use crate::sink::SinkMode;
+use crate::split::Split;
+use crate::statsd::{StatsSink, StatsTarget};
+use crate::tenant::Tenant;
+use crate::tunnel::ProxyTarget;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::time::Duration;
 
-pub const SERVER_TOKEN: Token = Token(0);
 pub const DEFAULT_PORT: u16 = 8080;
-pub const CONN_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_BODY_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_LONG_POLL_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// TLS handshakes a worker runs at once by default; see `Config::tls_max_handshakes`.
+pub const DEFAULT_TLS_MAX_HANDSHAKES: usize = 256;
+/// How long a mirrored request has for the shadow upstream to take it and answer; its
+/// connect is limited by `Config::connect_timeout` as well.
+pub const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+/// Mirrored requests a worker has outstanding at most; past it, more are dropped.
+pub const MIRROR_MAX_IN_FLIGHT: usize = 256;
 pub const POLL_TIMEOUT: Duration = Duration::from_millis(500);
+/// Transient accept errors in a row after which a listener is left until its next event,
+/// in case the error keeps recurring (e.g. ENOBUFS under lasting memory pressure).
+pub const ACCEPT_RETRY_LIMIT: u32 = 64;
+/// Connections `--accept-rate` lets through at once after a quiet spell, as time at the rate.
+pub const ACCEPT_BURST: Duration = Duration::from_millis(100);
+/// How often a thread waiting out an interval checks whether the server is shutting down.
+pub const STOP_POLL: Duration = Duration::from_millis(50);
+/// Bytes one connection may read and write per event-loop round before the others get a turn.
+pub const ROUND_BYTES: usize = 256 * 1024;
+/// Responses one connection may complete per event-loop round, e.g. from a pipelined batch.
+pub const ROUND_REQUESTS: u32 = 32;
 pub const BUF_SIZE: usize = 8 * 1024;
 pub const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// Largest request head accepted by default; heads bigger than `BUF_SIZE` are read into a
+/// one-off spill buffer of this size.
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 64 * 1024;
+/// Largest request head the fast lane answers; bigger ones take the general path.
+pub const FAST_LANE_MAX_HEAD: usize = 1024;
 pub const RESPONSE_BODY: &[u8] = b"Vrypt";
+pub const MAINTENANCE_BODY: &[u8] = b"Vrypt is down for maintenance";
+pub const OVERLOADED_BODY: &[u8] = b"Vrypt is overloaded";
+pub const BAD_REQUEST_BODY: &[u8] = b"Bad request";
+pub const HEAD_TOO_LARGE_BODY: &[u8] = b"Request head too large";
+pub const REQUEST_TIMEOUT_BODY: &[u8] = b"Request timeout";
+pub const UPLOAD_TOO_LARGE_BODY: &[u8] = b"Upload too large";
+pub const UPDATED_BODY: &[u8] = b"Updated";
+pub const HEALTHY_BODY: &[u8] = b"OK";
+pub const FORBIDDEN_BODY: &[u8] = b"Destination not allowed";
+pub const BAD_GATEWAY_BODY: &[u8] = b"Upstream unreachable";
+pub const KEY_REUSED_BODY: &[u8] = b"Idempotency-Key already used for a different request";
+pub const THROTTLED_BODY: &[u8] = b"Request rate exceeded";
+pub const METHOD_NOT_ALLOWED_BODY: &[u8] = b"Method not allowed";
+pub const NOT_IMPLEMENTED_BODY: &[u8] = b"Method not implemented";
+/// Largest response body accepted by the admin upload endpoint.
+pub const MAX_UPLOAD_SIZE: usize = 16 * 1024 * 1024;
+/// Most route backend output a response holds in its spill file; past it the backend is
+/// read no further until the client catches up. See `spill`.
+pub const MAX_SPILL_SIZE: u64 = 1024 * 1024 * 1024;
+/// How long to keep reading and discarding after an error response before closing.
+pub const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
 pub const MAX_CONNS: usize = 65536;
+/// Connection tokens are `generation << TOKEN_INDEX_BITS | slot index`, with generations
+/// starting at 1, so every token below `CONN_TOKEN_MIN` is free for the worker's own use.
+pub const TOKEN_INDEX_BITS: u32 = 16;
+pub const CONN_TOKEN_MIN: usize = 1 << TOKEN_INDEX_BITS;
+/// Wakes a worker when the accept thread queued connections for it or the bus has events for it.
+pub const WAKE_TOKEN: usize = 0;
+/// Listener tokens run from here up to `CONN_TOKEN_MIN`; see `listen::Listeners`.
+pub const LISTENER_TOKEN_BASE: usize = 1;
+const _: () = assert!(MAX_CONNS <= CONN_TOKEN_MIN);
 pub const MAX_RECYCLED_BUFS: usize = 256;
+/// First pause before restarting a failed worker or retrying startup; doubles per failure.
+pub const RESTART_BACKOFF_MIN: Duration = Duration::from_millis(100);
+pub const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(5);
+/// A worker that ran this long before failing starts its backoff over.
+pub const RESTART_RESET: Duration = Duration::from_secs(60);
+/// Startup attempts while binding fails with a transient error.
+pub const STARTUP_ATTEMPTS: u32 = 5;
+/// Token-indexed tables and free lists are not shrunk below this many entries.
+pub const SHRINK_FLOOR: usize = 1024;
+/// How often a stats sink is pushed to unless its `every=` says otherwise.
 pub const STATS_INTERVAL: Duration = Duration::from_secs(1);
+/// The prefix every metric name below starts with; a sink's `prefix=` replaces it.
+pub const STATS_PREFIX: &str = "vrypt";
+/// Collector the stats pusher sends to unless `--stats-target` names another.
 pub const STATS_TARGET: &str = "127.0.0.1:8125";
+/// Largest batch of metric lines sent at once; fits a UDP datagram on an Ethernet MTU.
+pub const STATS_MAX_PACKET: usize = 1432;
+/// Pushgateway port and grouping path used when an `http://` stats target leaves them out.
+pub const STATS_PUSHGATEWAY_PORT: u16 = 9091;
+pub const STATS_PUSHGATEWAY_PATH: &str = "/metrics/job/vrypt";
+/// Connect, read and write timeout for a TCP or HTTP collector, so a stuck one cannot
+/// stall its pusher.
+pub const STATS_IO_TIMEOUT: Duration = Duration::from_secs(1);
 pub const STATS_METRIC: &str = "vrypt.rps";
+pub const STATS_TCP_PREFIX: &str = "vrypt.tcp";
+pub const STATS_WORKER_PREFIX: &str = "vrypt.worker";
+pub const STATS_WRITE_TIMEOUTS: &str = "vrypt.write_timeouts";
+pub const STATS_HEADER_TIMEOUTS: &str = "vrypt.header_timeouts";
+pub const STATS_HEADER_SPILLS: &str = "vrypt.header_spills";
+pub const STATS_COLLAPSED: &str = "vrypt.collapsed";
+pub const STATS_BUF_POOL_PREFIX: &str = "vrypt.buf_pool";
+pub const STATS_ACCEPT_ERRORS_PREFIX: &str = "vrypt.accept_errors";
+pub const STATS_CONNS_PREFIX: &str = "vrypt.conns";
+pub const STATS_MEMORY: &str = "vrypt.memory_bytes";
+pub const STATS_TIMERS: &str = "vrypt.timers";
+pub const STATS_PROTOCOL_ERRORS_PREFIX: &str = "vrypt.protocol_errors";
+pub const STATS_HANDSHAKE_FAILURES_PREFIX: &str = "vrypt.tls_handshake_failures";
+/// Suspicious request heads per `counter::Suspicious`: `vrypt.suspicious.smuggling`.
+pub const STATS_SUSPICIOUS_PREFIX: &str = "vrypt.suspicious";
+/// Connections the server closed, per `CloseReason` and `CloseMode`: `vrypt.closes.timeout.rst`.
+pub const STATS_CLOSES_PREFIX: &str = "vrypt.closes";
+pub const STATS_SIZE_PREFIX: &str = "vrypt.size";
+pub const STATS_SPLIT_PREFIX: &str = "vrypt.split";
+/// Requests sampled for mirroring per `mirror::MirrorOutcome`: `vrypt.mirror.sent`.
+pub const STATS_MIRROR_PREFIX: &str = "vrypt.mirror";
+pub const STATS_TENANT_PREFIX: &str = "vrypt.tenant";
+/// Backend connections per socket and `upstream::UpstreamStat`: `vrypt.upstream.run_php_fpm_sock.busy`.
+pub const STATS_UPSTREAM_PREFIX: &str = "vrypt.upstream";
+/// Connections per second from one address above which `--xdp-drop-map` bans it.
+pub const DEFAULT_BAN_THRESHOLD: u32 = 500;
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(60);
+/// Route commands (`exec:` static routes) running at once across the workers.
+pub const DEFAULT_EXEC_MAX: usize = 16;
+/// How long a request waits for a per-listener or per-route in-flight slot before `503`.
+pub const DEFAULT_INFLIGHT_QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Idle connections each worker keeps per FastCGI backend.
+pub const DEFAULT_UPSTREAM_POOL: usize = 8;
+pub const DEFAULT_UPSTREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Addresses whose connection rate is tracked at once for `--xdp-drop-map`.
+pub const XDP_TRACK_CAPACITY: usize = 64 * 1024;
+/// How often expired bans are lifted and idle addresses forgotten.
+pub const XDP_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Sticky keys each worker's affinity table holds before evicting the least recently used.
+pub const AFFINITY_CAPACITY: usize = 64 * 1024;
+/// Idempotency keys remembered at once across all workers.
+pub const IDEMPOTENCY_CAPACITY: usize = 64 * 1024;
+/// Longest `Idempotency-Key` remembered, in bytes.
+pub const MAX_IDEMPOTENCY_KEY: usize = 256;
+pub const CAPTURE_MAGIC: &[u8] = b"VRYPTCAP\x01";
+pub const DEFAULT_CAPTURE_SAMPLE: f64 = 1.0;
+pub const DEFAULT_CAPTURE_MAX_BYTES: u64 = 64 * 1024 * 1024;
+/// How long `replay` waits to connect, to write, or for more of a response before giving up.
+pub const REPLAY_IO_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long `replay` keeps reading after a connection's last request once nothing more arrives.
+pub const REPLAY_LINGER: Duration = Duration::from_secs(1);
+pub const DEFAULT_LOG_FILE: &str = "vrypt.log";
+pub const SERVER_HEADER: &str = concat!("Server: vrypt/", env!("CARGO_PKG_VERSION"));
+/// Where the server's own endpoints live; `--rewrite-prefix` leaves these paths alone.
+pub const RESERVED_PATH_PREFIX: &[u8] = b"/__vrypt/";
+pub const VERSION_PATH: &[u8] = b"/__vrypt/version";
+/// `GET` for the request's own method, target and headers as JSON (with `--echo-headers`).
+pub const HEADERS_PATH: &[u8] = b"/headers";
+/// `PUT` a body to this prefix plus a `BodyName` to replace it (with `--admin`).
+pub const ADMIN_BODIES_PATH: &[u8] = b"/__vrypt/bodies/";
+/// `PUT` a body to this prefix plus a `--static-route` path to replace it (with `--admin`).
+pub const ADMIN_ROUTES_PATH: &[u8] = b"/__vrypt/routes";
+/// `GET` for global allocator statistics (with `--admin`).
+pub const ADMIN_ALLOCATOR_PATH: &[u8] = b"/__vrypt/allocator";
+/// `GET` for request, connection and error counters as JSON (with `--admin`).
+pub const ADMIN_STATS_PATH: &[u8] = b"/__vrypt/stats";
+/// `PUT` to start the counters `ADMIN_STATS_PATH` reports over from 0; answered with them
+/// as they stood (with `--admin`).
+pub const ADMIN_RESET_STATS_PATH: &[u8] = b"/__vrypt/reset-stats";
+/// `GET` for the open connections of all workers, as last sampled (with `--admin`).
+pub const ADMIN_CONNECTIONS_PATH: &[u8] = b"/__vrypt/connections";
+/// `GET` for the listened addresses and whether each is accepting; `PUT` to this prefix
+/// plus an address (or port) with `?drain` or `?resume` changes that (with `--admin`).
+pub const ADMIN_LISTENERS_PATH: &[u8] = b"/__vrypt/listeners";
+/// `GET` for the runtime toggles; `PUT` to this prefix plus a `Toggle` name flips it, or
+/// sets it with `?on` / `?off` (with `--admin`).
+pub const ADMIN_TOGGLES_PATH: &[u8] = b"/__vrypt/toggles";
+/// `PUT` a body here to publish it as the next long-poll event (with `--admin` and `--long-poll`).
+pub const ADMIN_EVENTS_PATH: &[u8] = b"/__vrypt/events";
+
+/// Runtime settings assembled from the command line. With the `serde` feature, missing
+/// fields take their defaults and rules are written as their command-line specs (see `spec`).
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
+pub struct Config {
+    pub addr: SocketAddr,
+    pub capture_path: Option<PathBuf>,
+    pub capture_sample: f64,
+    pub capture_max_bytes: u64,
+    pub maintenance: bool,
+    /// Initial state of `Toggle::DebugLog` and `Toggle::ExtraMetrics`.
+    pub debug_log: bool,
+    pub extra_metrics: bool,
+    pub maintenance_page: Option<PathBuf>,
+    /// Custom error response bodies; see `error_page`.
+    pub error_pages: Vec<ErrorPage>,
+    /// Files served on fixed paths from pre-rendered responses; see `routes`.
+    pub static_routes: Vec<StaticRoute>,
+    /// Re-render static routes when their files change on disk; see `watch`.
+    pub watch_routes: bool,
+    /// Request slices with their own routes, limits and metrics; see `tenant`.
+    pub tenants: Vec<Tenant>,
+    pub template: Option<PathBuf>,
+    pub faults: Vec<FaultRule>,
+    pub body_sink: SinkMode,
+    pub trailers: bool,
+    pub server_timing: bool,
+    pub redirects: Vec<RedirectRule>,
+    pub header_rules: Vec<HeaderRule>,
+    /// Header edits for one static route's responses, after `header_rules`.
+    pub route_headers: Vec<RouteHeader>,
+    /// Header edits for the requests relayed to one static route's backend.
+    pub route_request_headers: Vec<RouteHeader>,
+    /// Request path prefixes replaced before dispatch; see `rewrite`.
+    pub path_rewrites: Vec<PathRewrite>,
+    pub max_inflight: Option<usize>,
+    /// In-flight caps per listener and per static route; see `limit::ScopedLimits`.
+    pub listener_limits: Vec<ListenerLimit>,
+    pub route_limits: Vec<RouteLimit>,
+    /// Requests that may wait for a slot of each per-listener or per-route limit; 0 answers
+    /// `503` at once.
+    pub inflight_queue: usize,
+    pub inflight_queue_timeout: Duration,
+    /// Cap on route commands running at once; see `exec`.
+    pub exec_max: usize,
+    /// Idle backend connections kept per backend and worker; 0 closes each after its
+    /// request. See `upstream`.
+    pub upstream_pool: usize,
+    pub upstream_idle_timeout: Duration,
+    /// Age past which a backend connection is closed rather than pooled.
+    pub upstream_max_lifetime: Option<Duration>,
+    /// Route backend output held in memory per response before the rest goes to a file in
+    /// `spill_dir`; without it output is read only as fast as the client takes it. See `spill`.
+    pub spill_threshold: Option<usize>,
+    pub spill_dir: PathBuf,
+    /// Concurrent `GET`s for the same route backend response share one backend request;
+    /// see `collapse`.
+    pub collapse: bool,
+    /// New connections each worker takes on per second; the rest wait in the accept queue.
+    pub accept_rate: Option<u32>,
+    /// Connections a worker holds before it stops accepting; see `conn_watermarks`.
+    pub max_conns: Option<u32>,
+    /// Connections a paused worker has to fall back to before accepting again; defaults to
+    /// nine tenths of `max_conns`.
+    pub max_conns_low: Option<u32>,
+    /// Connections a worker accepts past `max_conns` for health checks and the admin API;
+    /// other requests on them get a `503` and the connection is closed.
+    pub priority_reserve: Option<u32>,
+    /// Path answered `200 OK` ahead of every limit, for orchestrators' health checks.
+    pub health_path: Option<String>,
+    pub tcp_stats: bool,
+    /// Record request and response size histograms.
+    pub size_stats: bool,
+    /// Path prefixes the size histograms are kept per; see `sizes::route`.
+    pub size_routes: Vec<String>,
+    /// StatsD collector the stats pusher sends to.
+    pub stats_target: StatsTarget,
+    /// Sinks the stats pusher sends to, each on its own interval; when empty, `stats_target`
+    /// alone. Disabled ones are kept but not pushed to.
+    pub stats_sinks: Vec<StatsSink>,
+    /// On shutdown, push every gauge once more as 0 after the final interval.
+    pub stats_zero_on_exit: bool,
+    pub daemonize: bool,
+    /// User to switch to once the listeners are bound; see `privilege`.
+    pub user: Option<String>,
+    /// Group to switch to; defaults to `user`'s primary group.
+    pub group: Option<String>,
+    pub log_file: PathBuf,
+    pub pidfile: Option<PathBuf>,
+    /// Addresses to listen on instead of `addr`, re-read on SIGHUP.
+    pub listen_file: Option<PathBuf>,
+    pub accept_mode: AcceptMode,
+    /// Ports to listen on instead of `addr`'s, all on its IP. Ignored with a listen file.
+    pub port_range: Option<RangeInclusive<u16>>,
+    /// Network interface or VRF the listeners are bound to (`SO_BINDTODEVICE`).
+    pub bind_device: Option<String>,
+    /// Where the per-worker read buffer pools get their buffers from.
+    pub buf_pool: BufBacking,
+    /// Largest request head accepted; past `BUF_SIZE` it costs a spill allocation of this size.
+    pub max_header_size: usize,
+    /// Waiting for the first byte or the rest of a request head.
+    pub header_timeout: Duration,
+    /// Waiting for more of a request body.
+    pub body_timeout: Duration,
+    /// Idle between keep-alive requests.
+    pub keepalive_timeout: Duration,
+    /// Establishing an outbound connection, such as a forward-proxy upstream.
+    pub connect_timeout: Duration,
+    /// Path whose `GET`s are held open until the next long-poll event; see `longpoll`.
+    pub long_poll_path: Option<String>,
+    /// How long a long poll is held before it is answered `204`.
+    pub long_poll_timeout: Duration,
+    /// Deadline for writing a whole response, measured from when it was armed.
+    pub write_timeout: Option<Duration>,
+    /// Minimum average send rate in bytes per second once a response has been pending for a second.
+    pub min_send_rate: Option<u64>,
+    /// Close with SO_LINGER 0 (RST) so no TIME_WAIT is left behind on connection churn.
+    pub abortive_close: bool,
+    /// How connections are closed per reason; see `close_mode`.
+    pub close_rules: Vec<CloseRule>,
+    /// DSCP code point marked on accepted connections' traffic.
+    pub dscp: Option<u8>,
+    /// `SO_PRIORITY` of accepted connections.
+    pub socket_priority: Option<u32>,
+    /// Pinned BPF map that addresses over `ban_threshold` are written to; see `xdp`.
+    pub xdp_drop_map: Option<PathBuf>,
+    /// New connections per second from one address before it is banned.
+    pub ban_threshold: u32,
+    /// Suspicious requests per second from one address before it is banned; see `counter::Suspicious`.
+    pub ban_suspicious: Option<u32>,
+    pub ban_duration: Duration,
+    /// Add a `Date` header to every response.
+    pub date: bool,
+    /// Process-wide ceiling, in bytes, on the memory workers account for; above it idle
+    /// connections are reaped and recycled buffers freed.
+    pub memory_limit: Option<u64>,
+    /// Content types by file extension, and the charset appended to `text/*`.
+    pub mime: MimeMap,
+    /// Fraction of completed requests written to the access log.
+    pub access_log_sample: f64,
+    /// Requests taking at least this long are always logged.
+    pub slow_request: Option<Duration>,
+    /// How long responses to requests with an `Idempotency-Key` are replayed; see `idempotency`.
+    pub idempotency_ttl: Option<Duration>,
+    /// Warm-up after startup: read buffers are pre-touched, stats pushes held back, and the
+    /// counters reset once it is over.
+    pub warmup: Option<Duration>,
+    /// Serve the admin endpoints under `/__vrypt/bodies/`.
+    pub admin: bool,
+    /// Answer `HEADERS_PATH` with the request's headers.
+    pub echo_headers: bool,
+    /// Accept an optional PROXY protocol v1/v2 preamble at the start of each connection.
+    pub proxy_protocol: bool,
+    /// Certificate chain and private key (PEM) to terminate TLS with, on every listener
+    /// next to plaintext HTTP; only used with the `tls` feature.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// How long a client has to finish its TLS handshake, from its ClientHello on.
+    pub tls_handshake_timeout: Duration,
+    /// TLS handshakes one worker runs at once; a ClientHello past them is refused.
+    pub tls_max_handshakes: usize,
+    /// Status (301 or 308) plaintext requests are redirected to `https://` with while TLS
+    /// is terminated; `None` serves them as they are.
+    pub force_https: Option<u16>,
+    /// Destinations `CONNECT` and absolute-form requests are forwarded to; empty unless
+    /// running as a forward proxy.
+    pub proxy_allow: Vec<ProxyTarget>,
+    /// Upstream groups origin-form requests are split between; see `split`.
+    pub split: Split,
+    /// Shadow upstream a share of the relayed requests is copied to; see `mirror`.
+    pub mirror: Option<Mirror>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)),
+            capture_path: None,
+            capture_sample: DEFAULT_CAPTURE_SAMPLE,
+            capture_max_bytes: DEFAULT_CAPTURE_MAX_BYTES,
+            maintenance: false,
+            debug_log: false,
+            extra_metrics: false,
+            maintenance_page: None,
+            error_pages: Vec::new(),
+            static_routes: Vec::new(),
+            watch_routes: false,
+            tenants: Vec::new(),
+            template: None,
+            faults: Vec::new(),
+            body_sink: SinkMode::Discard,
+            trailers: false,
+            server_timing: false,
+            redirects: Vec::new(),
+            header_rules: vec![HeaderRule::set(SERVER_HEADER).expect("valid Server header")],
+            route_headers: Vec::new(),
+            route_request_headers: Vec::new(),
+            path_rewrites: Vec::new(),
+            max_inflight: None,
+            listener_limits: Vec::new(),
+            route_limits: Vec::new(),
+            inflight_queue: 0,
+            inflight_queue_timeout: DEFAULT_INFLIGHT_QUEUE_TIMEOUT,
+            exec_max: DEFAULT_EXEC_MAX,
+            upstream_pool: DEFAULT_UPSTREAM_POOL,
+            upstream_idle_timeout: DEFAULT_UPSTREAM_IDLE_TIMEOUT,
+            upstream_max_lifetime: None,
+            spill_threshold: None,
+            spill_dir: std::env::temp_dir(),
+            collapse: false,
+            accept_rate: None,
+            max_conns: None,
+            max_conns_low: None,
+            priority_reserve: None,
+            health_path: None,
+            tcp_stats: false,
+            size_stats: false,
+            size_routes: Vec::new(),
+            stats_target: StatsTarget::parse(STATS_TARGET).expect("valid default stats target"),
+            stats_sinks: Vec::new(),
+            stats_zero_on_exit: false,
+            daemonize: false,
+            user: None,
+            group: None,
+            log_file: PathBuf::from(DEFAULT_LOG_FILE),
+            pidfile: None,
+            listen_file: None,
+            port_range: None,
+            bind_device: None,
+            accept_mode: AcceptMode::ReusePort,
+            buf_pool: BufBacking::Heap,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            header_timeout: DEFAULT_HEADER_TIMEOUT,
+            body_timeout: DEFAULT_BODY_TIMEOUT,
+            keepalive_timeout: DEFAULT_KEEPALIVE_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            long_poll_path: None,
+            long_poll_timeout: DEFAULT_LONG_POLL_TIMEOUT,
+            write_timeout: None,
+            min_send_rate: None,
+            abortive_close: false,
+            close_rules: Vec::new(),
+            dscp: None,
+            socket_priority: None,
+            xdp_drop_map: None,
+            ban_threshold: DEFAULT_BAN_THRESHOLD,
+            ban_suspicious: None,
+            ban_duration: DEFAULT_BAN_DURATION,
+            date: true,
+            memory_limit: None,
+            mime: MimeMap::default(),
+            access_log_sample: 0.0,
+            slow_request: None,
+            idempotency_ttl: None,
+            warmup: None,
+            admin: false,
+            echo_headers: false,
+            proxy_protocol: false,
+            tls_cert: None,
+            tls_key: None,
+            tls_handshake_timeout: DEFAULT_TLS_HANDSHAKE_TIMEOUT,
+            tls_max_handshakes: DEFAULT_TLS_MAX_HANDSHAKES,
+            force_https: None,
+            proxy_allow: Vec::new(),
+            split: Split::default(),
+            mirror: None,
+        }
+    }
+}
+
+impl Config {
+    /// Whether request lines and latencies have to be tracked for logging.
+    pub fn logs_requests(&self) -> bool {
+        self.access_log_sample > 0.0 || self.slow_request.is_some()
+    }
+
+    /// The sinks to push stats to: the enabled `stats_sinks`, or `stats_target` on the
+    /// default interval if none are configured.
+    pub fn active_stats_sinks(&self) -> Vec<StatsSink> {
+        if self.stats_sinks.is_empty() {
+            return vec![StatsSink::new(self.stats_target.clone())];
+        }
+        self.stats_sinks.iter().filter(|s| s.enabled).cloned().collect()
+    }
+
+    /// Whether a TLS ClientHello is handed to rustls rather than refused with an alert.
+    pub fn terminates_tls(&self) -> bool {
+        cfg!(feature = "tls") && self.tls_cert.is_some() && self.tls_key.is_some()
+    }
+
+    /// How to close a connection for `reason`: the last `--close` rule for it, else `Drain`
+    /// after errors and policy decisions and `Fin` (`Rst` with `--abortive-close`) on timeouts.
+    pub fn close_mode(&self, reason: CloseReason) -> CloseMode {
+        let default = match reason {
+            CloseReason::Timeout if self.abortive_close => CloseMode::Rst,
+            CloseReason::Timeout => CloseMode::Fin,
+            CloseReason::Error | CloseReason::Policy => CloseMode::Drain,
+        };
+        self.close_rules.iter().rev().find(|r| r.reason == reason).map_or(default, |r| r.mode)
+    }
+
+    /// The (high, low) connection counts a worker pauses and resumes accepting at, if capped.
+    pub fn conn_watermarks(&self) -> Option<(u64, u64)> {
+        let high = u64::from(self.max_conns?);
+        let low = self.max_conns_low.map_or(high * 9 / 10, u64::from);
+        Some((high, low.min(high.saturating_sub(1))))
+    }
+
+    pub fn idle_timeout(&self, class: IdleClass) -> Duration {
+        match class {
+            IdleClass::Header | IdleClass::Write => self.header_timeout,
+            IdleClass::Body => self.body_timeout,
+            IdleClass::KeepAlive => self.keepalive_timeout,
+            IdleClass::Linger => LINGER_TIMEOUT,
+            IdleClass::Connect => self.connect_timeout,
+            IdleClass::LongPoll => self.long_poll_timeout,
+            IdleClass::Handshake => self.tls_handshake_timeout,
+        }
+    }
+}
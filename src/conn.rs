@@ -1,63 +1,538 @@
+use crate::bodies::Upload;
+use crate::body::Framing;
+use crate::close::CloseReason;
 use crate::config::BUF_SIZE;
-use std::time::Instant;
+use crate::exec::Exec;
+use crate::fault::Fault;
+use crate::http;
+use crate::limit::ScopedSlots;
+use crate::region::PoolBuf;
+use crate::sink::BodySink;
+use crate::spill::Spill;
+use crate::transport::Stream;
+use crate::tunnel::Tunnel;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub struct Conn {
-    pub stream: mio::net::TcpStream,
-    pub read_buf: Box<[u8; BUF_SIZE]>,
+/// Phase timestamps for the `Server-Timing` header; only filled in when it is enabled.
+#[derive(Default)]
+pub struct PhaseTimes {
+    pub request_start: Option<Instant>,
+    pub head_done: Option<Instant>,
+    pub write_start: Option<Instant>,
+    /// Write duration of the previous response on this connection.
+    pub last_write: Option<Duration>,
+}
+
+/// Bytes of a selected response: built once at startup, or swapped in at runtime and
+/// kept alive by the connections still writing them.
+pub enum Payload {
+    Static(&'static [u8]),
+    Shared(Arc<[u8]>),
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        match self {
+            Payload::Static(b) => b,
+            Payload::Shared(b) => b,
+        }
+    }
+}
+
+/// A connection's read buffer: its pooled `BUF_SIZE` block or, while a request head too big
+/// for that is being read, a one-off spill allocation of up to `--max-header-size` bytes.
+/// Pooled blocks are not cleared between connections: bytes past `Conn::read_len` may be
+/// another connection's and must never be looked at.
+pub struct ReadBuf {
+    pooled: PoolBuf,
+    spill: Option<Box<[u8]>>,
+}
+
+impl ReadBuf {
+    pub fn new(pooled: PoolBuf) -> Self {
+        Self { pooled, spill: None }
+    }
+
+    /// Switches to a spill buffer of `size` bytes, keeping the first `len` bytes.
+    pub fn spill(&mut self, len: usize, size: usize) {
+        let mut spill = vec![0u8; size].into_boxed_slice();
+        spill[..len].copy_from_slice(&self[..len]);
+        self.spill = Some(spill);
+    }
+
+    /// Goes back to the pooled block once the `len` bytes still buffered fit in it.
+    pub fn unspill(&mut self, len: usize) {
+        if let Some(spill) = self.spill.take_if(|_| len <= BUF_SIZE) {
+            self.pooled[..len].copy_from_slice(&spill[..len]);
+        }
+    }
+
+    /// Size of the spill buffer, 0 if there is none.
+    pub fn spilled(&self) -> usize {
+        self.spill.as_ref().map_or(0, |s| s.len())
+    }
+
+    /// The pooled block, for handing back to the pool.
+    pub fn into_pooled(self) -> PoolBuf {
+        self.pooled
+    }
+}
+
+impl Deref for ReadBuf {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        match &self.spill {
+            Some(spill) => spill,
+            None => &self.pooled[..],
+        }
+    }
+}
+
+impl DerefMut for ReadBuf {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match &mut self.spill {
+            Some(spill) => spill,
+            None => &mut self.pooled[..],
+        }
+    }
+}
+
+/// Where a connection is in its request/response cycle. Changed only through the
+/// transition methods on `Conn`; also reported as per-state connection gauges.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum ConnState {
+    /// Waiting for the client to finish the TLS handshake its ClientHello began, until
+    /// `parked_until`.
+    Handshaking,
+    /// Waiting for the first byte or the rest of a request head.
+    ReadingHeaders,
+    /// Feeding a request body (framed by `Conn::body`) into the sink.
+    ReadingBody,
+    /// Head parsed, response being selected.
+    Handling,
+    /// A response is armed; `write_pos` is the next byte to send.
+    Writing,
+    /// Keep-alive connection with nothing buffered, waiting for the next request.
+    Idle,
+    /// Write side shut down after an error response; discarding input until `linger_until`.
+    Draining,
+    /// Queued to be closed at the end of the current poll round.
+    Closing,
+    /// Forward-proxy tunnel; bytes are copied between the client and `Conn::tunnel`.
+    Tunneling,
+    /// Forward-proxy tunnel whose upstream connect is still in progress.
+    Connecting,
+    /// Long poll waiting for the next event or `parked_until`.
+    Parked,
+    /// Request head waiting in `Conn::queued` for a per-listener or per-route in-flight
+    /// slot, until `parked_until`.
+    Queued,
+    /// Waiting for more output of the route command in `Conn::exec`, sent as chunks, or
+    /// sending what `Conn::spill` holds of it or of the request it waits on.
+    Streaming,
+}
+
+impl ConnState {
+    pub const ALL: [ConnState; 13] = [
+        ConnState::Handshaking,
+        ConnState::ReadingHeaders,
+        ConnState::ReadingBody,
+        ConnState::Handling,
+        ConnState::Writing,
+        ConnState::Idle,
+        ConnState::Draining,
+        ConnState::Closing,
+        ConnState::Tunneling,
+        ConnState::Connecting,
+        ConnState::Parked,
+        ConnState::Queued,
+        ConnState::Streaming,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ConnState::Handshaking => "handshaking",
+            ConnState::ReadingHeaders => "reading_headers",
+            ConnState::ReadingBody => "reading_body",
+            ConnState::Handling => "handling",
+            ConnState::Writing => "writing",
+            ConnState::Idle => "idle",
+            ConnState::Draining => "draining",
+            ConnState::Closing => "closing",
+            ConnState::Tunneling => "tunneling",
+            ConnState::Connecting => "connecting",
+            ConnState::Parked => "parked",
+            ConnState::Queued => "queued",
+            ConnState::Streaming => "streaming",
+        }
+    }
+}
+
+/// What a connection is waiting for, which decides how long it may stay idle.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IdleClass {
+    /// First byte or the rest of a request head.
+    Header,
+    /// More of a request body.
+    Body,
+    /// The client to accept the rest of a response.
+    Write,
+    /// The next request after a completed response.
+    KeepAlive,
+    /// Discarding client bytes after an error response, until EOF or the linger deadline.
+    Linger,
+    /// An upstream connect to succeed or fail.
+    Connect,
+    /// The next long-poll event.
+    LongPoll,
+    /// The rest of a TLS handshake.
+    Handshake,
+}
+
+/// A client connection over `T`: a `Stream` in the server, a scripted one in tests.
+pub struct Conn<T = Stream> {
+    pub stream: T,
+    pub peer: SocketAddr,
+    /// Listed address of the listener the connection was accepted on; `None` outside the server.
+    pub listener: Option<SocketAddr>,
+    pub read_buf: ReadBuf,
     pub read_len: usize,
     pub scan_offset: usize,
-    pub write_buf: &'static [u8],
-    pub write_pos: Option<usize>,
+    pub write_buf: Payload,
+    /// Per-connection response buffer for rendered responses; kept across requests.
+    pub out: Vec<u8>,
+    pub owned: bool,
+    pub write_pos: usize,
     pub last_active: Instant,
-    pub generation: u64,
+    /// Bumped on every activity; timer entries carrying an older epoch are stale.
+    pub epoch: u64,
+    pub capture_id: Option<u64>,
+    pub fault: Option<Fault>,
+    /// Framing of the current request body, set once its head has been consumed.
+    pub body: Option<Framing>,
+    pub sink: Option<BodySink>,
+    pub timing: PhaseTimes,
+    /// Holds a slot of the in-flight request limit until the response is written.
+    pub inflight: bool,
+    /// Tenant whose in-flight limit this holds a slot of until the response is written.
+    pub tenant_slot: Option<usize>,
+    /// Slots of the listener's and the route's in-flight limits held until the response is
+    /// written, and the queue the request waits in for one.
+    pub scoped: ScopedSlots,
+    /// Accepted into `Config::priority_reserve`, past the connection cap.
+    pub reserved: bool,
+    /// The current request is a health check or admin request, which no limit or fault applies to.
+    pub priority: bool,
+    /// Responses fully written on this connection.
+    pub requests: u64,
+    /// Request bytes consumed since the last response was written; for `--size-stats`.
+    pub request_bytes: u64,
+    /// `sizes::route` of the current request.
+    pub route: usize,
+    /// When the pending response was armed; only tracked if a write deadline is configured.
+    pub write_started: Option<Instant>,
+    /// Close once the pending response has been written, for `close_reason`.
+    pub close_after_write: bool,
+    /// Why the server closes the connection, once it has decided to; the `CloseMode` for it
+    /// has been applied. `None` for closes the client starts.
+    pub close_reason: Option<CloseReason>,
+    /// Deadline of the `Draining` state.
+    pub linger_until: Option<Instant>,
+    /// Deadline of the `Parked` state, when the long poll is answered `204`, of the
+    /// `Queued` state, when the request is answered `503`, and of the `Handshaking` state.
+    pub parked_until: Option<Instant>,
+    /// Long-poll event the parked request waits to be newer than.
+    pub poll_since: u64,
+    /// `METHOD target` of the current request, kept only when requests are logged.
+    pub request_line: Vec<u8>,
+    /// Admin upload the current request body is read into.
+    pub upload: Option<Upload>,
+    /// `Idempotency-Key` and request fingerprint the current response is remembered under.
+    pub idempotency: Option<(Box<[u8]>, u64)>,
+    /// The PROXY protocol preamble (or its absence) has been dealt with.
+    pub proxy_checked: bool,
+    /// Upstream side of a forward-proxy tunnel.
+    pub tunnel: Option<Box<Tunnel>>,
+    /// Route command whose output is the current response body.
+    pub exec: Option<Box<Exec>>,
+    /// The route command's output read ahead of the client; only with `--spill-threshold`.
+    pub spill: Option<Box<Spill>>,
+    /// Flight in `Handler::collapse` the backend request of `exec` feeds.
+    pub flight: Option<u64>,
+    /// Registered for writable events as well as readable ones. Only set while a response
+    /// is waiting for socket space, so most responses never change the registration.
+    pub write_interest: bool,
+    /// Queued to be driven again next round after using up its round budget.
+    pub yielded: bool,
+    state: ConnState,
 }
 
-impl Conn {
-    pub fn new(stream: mio::net::TcpStream, response: &'static [u8], buf: Box<[u8; BUF_SIZE]>) -> Self {
+impl<T> Conn<T> {
+    pub fn new(stream: T, peer: SocketAddr, buf: PoolBuf, now: Instant) -> Self {
         Self {
             stream,
-            read_buf: buf,
+            peer,
+            listener: None,
+            read_buf: ReadBuf::new(buf),
             read_len: 0,
             scan_offset: 0,
-            write_buf: response,
-            write_pos: None,
-            last_active: Instant::now(),
-            generation: 0,
+            write_buf: Payload::Static(&[]),
+            out: Vec::new(),
+            owned: false,
+            write_pos: 0,
+            last_active: now,
+            epoch: 0,
+            capture_id: None,
+            fault: None,
+            body: None,
+            sink: None,
+            timing: PhaseTimes::default(),
+            inflight: false,
+            tenant_slot: None,
+            scoped: ScopedSlots::default(),
+            reserved: false,
+            priority: false,
+            requests: 0,
+            request_bytes: 0,
+            route: 0,
+            write_started: None,
+            close_after_write: false,
+            close_reason: None,
+            linger_until: None,
+            parked_until: None,
+            poll_since: 0,
+            request_line: Vec::new(),
+            upload: None,
+            idempotency: None,
+            proxy_checked: false,
+            tunnel: None,
+            exec: None,
+            spill: None,
+            flight: None,
+            write_interest: false,
+            yielded: false,
+            state: ConnState::ReadingHeaders,
         }
     }
 
+    /// Returns the length of the request head (including the terminating CRLFCRLF) once complete.
+    /// Bytes are searched only once; `scan_offset` marks how far the search has got.
     #[inline]
-    pub fn request_complete(&mut self) -> bool {
-        let start = self.scan_offset.saturating_sub(3);
-        let found = self.read_buf[start..self.read_len]
-            .windows(4)
-            .any(|w| w == b"\r\n\r\n");
-        if self.read_len >= 3 {
-            self.scan_offset = self.read_len - 3;
-        }
+    pub fn request_complete(&mut self) -> Option<usize> {
+        let found = http::find_head_end(&self.read_buf[..self.read_len], self.scan_offset);
+        self.scan_offset = self.read_len;
         found
     }
 
+    #[inline]
+    pub fn outgoing(&self) -> &[u8] {
+        if self.owned { &self.out } else { &self.write_buf }
+    }
+
+    #[inline]
+    pub fn state(&self) -> ConnState {
+        self.state
+    }
+
     #[inline]
     pub fn has_pending_write(&self) -> bool {
-        matches!(self.write_pos, Some(pos) if pos < self.write_buf.len())
+        self.state == ConnState::Writing && self.write_pos < self.outgoing().len()
+    }
+
+    /// Drops the first `n` buffered bytes, keeping anything after them (pipelined data).
+    #[inline]
+    pub fn consume(&mut self, n: usize) {
+        self.read_buf.copy_within(n..self.read_len, 0);
+        self.read_len -= n;
+        self.read_buf.unspill(self.read_len);
+        self.request_bytes += n as u64;
+        self.scan_offset = 0;
+    }
+
+    #[inline]
+    pub fn set_response(&mut self, response: &'static [u8]) {
+        self.write_buf = Payload::Static(response);
+        self.owned = false;
+    }
+
+    #[inline]
+    pub fn set_response_shared(&mut self, response: Arc<[u8]>) {
+        self.write_buf = Payload::Shared(response);
+        self.owned = false;
+    }
+
+    /// Selects whatever has been rendered into `out` as the response.
+    #[inline]
+    pub fn set_response_owned(&mut self) {
+        self.owned = true;
+    }
+
+    /// New bytes arrived; an idle keep-alive connection starts reading its next request.
+    #[inline]
+    pub fn on_data(&mut self) {
+        if self.state == ConnState::Idle {
+            self.state = ConnState::ReadingHeaders;
+        }
+    }
+
+    /// A complete request head is being handled.
+    #[inline]
+    pub fn begin_handling(&mut self) {
+        self.state = ConnState::Handling;
+    }
+
+    /// The head announced a body; it is read before the response is armed.
+    #[inline]
+    pub fn begin_body(&mut self, framing: Framing, sink: BodySink) {
+        self.body = Some(framing);
+        self.sink = Some(sink);
+        self.state = ConnState::ReadingBody;
+    }
+
+    /// The body has been read completely; hands back its sink.
+    #[inline]
+    pub fn end_body(&mut self) -> Option<BodySink> {
+        self.body = None;
+        self.state = ConnState::Handling;
+        self.sink.take()
+    }
+
+    /// The request has gone to a backend that sends the whole response, head included.
+    #[inline]
+    pub fn begin_streaming(&mut self) {
+        self.state = ConnState::Streaming;
     }
 
     #[inline]
     pub fn arm_write(&mut self) {
-        self.read_len = 0;
+        self.write_pos = 0;
+        self.state = ConnState::Writing;
+    }
+
+    /// The response has been written; read the next (possibly already buffered) request.
+    /// With a route command running, or its output still read ahead or to be copied from
+    /// another request's, only part of it has: wait for more of its output.
+    #[inline]
+    pub fn finish_write(&mut self) {
+        self.write_started = None;
+        self.fault = None;
+        if self.spill.as_ref().is_some_and(|s| s.finished()) {
+            self.spill = None;
+        }
+        self.state = match self.exec {
+            Some(_) => ConnState::Streaming,
+            None if self.spill.is_some() => ConnState::Streaming,
+            None if self.read_len > 0 => ConnState::ReadingHeaders,
+            None => ConnState::Idle,
+        };
+    }
+
+    /// Write side has been shut down; discard input until `until`.
+    #[inline]
+    pub fn begin_draining(&mut self, until: Instant) {
+        self.linger_until = Some(until);
+        self.state = ConnState::Draining;
+    }
+
+    /// From here on the connection only relays bytes to and from `tunnel`.
+    #[inline]
+    pub fn begin_tunnel(&mut self, tunnel: Box<Tunnel>) {
+        self.tunnel = Some(tunnel);
+        self.state = ConnState::Connecting;
+    }
+
+    /// The tunnel's upstream connect is over; bytes flow from now on.
+    pub fn tunnel_connected(&mut self) {
+        debug_assert_eq!(self.state, ConnState::Connecting);
+        self.state = ConnState::Tunneling;
+    }
+
+    /// Holds the request until an event newer than `since` is published or `until` passes.
+    pub fn park(&mut self, until: Instant, since: u64) {
+        self.parked_until = Some(until);
+        self.poll_since = since;
+        self.state = ConnState::Parked;
+    }
+
+    /// The long poll is being answered.
+    pub fn unpark(&mut self) {
+        debug_assert_eq!(self.state, ConnState::Parked);
+        self.parked_until = None;
+        self.state = ConnState::Handling;
+    }
+
+    /// Holds the request head, unconsumed, until a slot frees or `until` passes.
+    pub fn queue(&mut self, until: Instant) {
+        self.parked_until = Some(until);
         self.scan_offset = 0;
-        self.write_pos = Some(0);
+        self.state = ConnState::Queued;
+    }
+
+    /// The queued request is tried again, or answered.
+    pub fn dequeue(&mut self) {
+        debug_assert_eq!(self.state, ConnState::Queued);
+        self.state = ConnState::ReadingHeaders;
+    }
+
+    /// The client's ClientHello has been handed to TLS; the handshake must be over by `until`.
+    pub fn begin_handshake(&mut self, until: Instant) {
+        self.parked_until = Some(until);
+        self.state = ConnState::Handshaking;
+    }
+
+    /// The handshake is complete; requests are read through it from here on.
+    pub fn end_handshake(&mut self) {
+        debug_assert_eq!(self.state, ConnState::Handshaking);
+        self.parked_until = None;
+        self.state = ConnState::ReadingHeaders;
     }
 
     #[inline]
-    pub fn reset_for_read(&mut self) {
-        self.write_pos = None;
+    pub fn mark_closing(&mut self) {
+        self.state = ConnState::Closing;
+    }
+
+    pub fn idle_class(&self) -> IdleClass {
+        match self.state {
+            ConnState::ReadingHeaders | ConnState::Handling | ConnState::Queued | ConnState::Closing => IdleClass::Header,
+            ConnState::ReadingBody | ConnState::Tunneling | ConnState::Streaming => IdleClass::Body,
+            ConnState::Writing => IdleClass::Write,
+            ConnState::Idle => IdleClass::KeepAlive,
+            ConnState::Draining => IdleClass::Linger,
+            ConnState::Connecting => IdleClass::Connect,
+            ConnState::Parked => IdleClass::LongPoll,
+            ConnState::Handshaking => IdleClass::Handshake,
+        }
+    }
+
+    /// When the pending response will have missed its deadline or fallen below
+    /// `min_rate` bytes per second if no further progress is made.
+    pub fn write_deadline(&self, timeout: Option<Duration>, min_rate: Option<u64>) -> Option<Instant> {
+        let start = self.write_started?;
+        let by_timeout = timeout.map(|t| start + t);
+        let by_rate = min_rate.map(|r| {
+            let sent = self.write_pos as u64;
+            start + Duration::from_secs_f64(sent as f64 / r as f64).max(Duration::from_secs(1))
+        });
+        match (by_timeout, by_rate) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
     }
 
     #[inline]
-    pub fn touch(&mut self) {
-        self.last_active = Instant::now();
-        self.generation = self.generation.wrapping_add(1);
+    pub fn touch(&mut self, now: Instant) {
+        self.last_active = now;
+        self.epoch = self.epoch.wrapping_add(1);
     }
 }
@@ -0,0 +1,88 @@
+//! Per-worker snapshots of open connections for the admin connection dump.
+
+use crate::conn::{Conn, ConnState};
+use crate::json::Json;
+use mio::Token;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnInfo {
+    pub worker: usize,
+    /// The connection's poll token within its worker.
+    pub token: usize,
+    pub peer: SocketAddr,
+    pub state: ConnState,
+    pub requests: u64,
+    pub idle: Duration,
+}
+
+/// Open connections as each worker last published them. Workers refresh their entry at
+/// their stats sample, so the dump is at most one `STATS_INTERVAL` old and a worker never
+/// waits on another to answer it.
+pub struct ConnList {
+    workers: Box<[Mutex<Vec<ConnInfo>>]>,
+}
+
+impl ConnList {
+    pub fn new(num_threads: usize) -> &'static Self {
+        Box::leak(Box::new(Self { workers: (0..num_threads).map(|_| Mutex::new(Vec::new())).collect() }))
+    }
+
+    /// Replaces a worker's snapshot with `conns`, idle times taken as of `now`.
+    pub fn publish<'a>(&self, thread_id: usize, conns: impl Iterator<Item = (Token, &'a Conn)>, now: Instant) {
+        let mut list = self.workers[thread_id].lock().unwrap_or_else(|e| e.into_inner());
+        list.clear();
+        list.extend(conns.map(|(token, c)| ConnInfo {
+            worker: thread_id,
+            token: token.0,
+            peer: c.peer,
+            state: c.state(),
+            requests: c.requests,
+            idle: now.saturating_duration_since(c.last_active),
+        }));
+    }
+
+    /// Appends one line per connection: worker, token, peer, state, completed requests and
+    /// milliseconds since the connection was last active.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        for list in self.workers.iter() {
+            for c in list.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+                let _ = writeln!(
+                    out,
+                    "worker={} token={} peer={} state={} requests={} idle_ms={}",
+                    c.worker,
+                    c.token,
+                    c.peer,
+                    c.state.name(),
+                    c.requests,
+                    c.idle.as_millis()
+                );
+            }
+        }
+    }
+
+    /// Every worker's last published connections.
+    pub fn snapshot(&self) -> Vec<ConnInfo> {
+        self.workers.iter().flat_map(|list| list.lock().unwrap_or_else(|e| e.into_inner()).clone()).collect()
+    }
+
+    /// The same as `write`, as a JSON array of objects.
+    pub fn write_json(&self, out: &mut Vec<u8>) {
+        let mut json = Json::new(out);
+        json.begin_array();
+        for list in self.workers.iter() {
+            for c in list.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+                json.begin_object();
+                json.key("worker").u64(c.worker as u64).key("token").u64(c.token as u64);
+                json.key("peer").display(c.peer).key("state").str(c.state.name().as_bytes());
+                json.key("requests").u64(c.requests).key("idle_ms").u64(c.idle.as_millis() as u64);
+                json.end_object();
+            }
+        }
+        json.end_array();
+    }
+}
@@ -1,26 +1,424 @@
-use crate::config::{STATS_INTERVAL, STATS_METRIC, STATS_TARGET};
-use std::io::Write;
-use std::net::{SocketAddr, UdpSocket};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::thread;
+use crate::config::{
+    STATS_METRIC, STATS_TCP_PREFIX, STATS_WORKER_PREFIX, STATS_WRITE_TIMEOUTS,
+    STATS_ACCEPT_ERRORS_PREFIX, STATS_CONNS_PREFIX, STATS_PROTOCOL_ERRORS_PREFIX, STATS_HANDSHAKE_FAILURES_PREFIX, STATS_MEMORY, STATS_HEADER_TIMEOUTS, STATS_HEADER_SPILLS, STATS_COLLAPSED,
+    STATS_CLOSES_PREFIX, STATS_SUSPICIOUS_PREFIX, STATS_SIZE_PREFIX, STATS_SPLIT_PREFIX, STATS_MIRROR_PREFIX, STATS_TENANT_PREFIX, STATS_TIMERS, STATS_UPSTREAM_PREFIX, STATS_BUF_POOL_PREFIX, STOP_POLL,
+};
+use crate::close::{CloseMode, CloseReason};
+use crate::conn::ConnState;
+use crate::json::Json;
+use crate::mirror::{MirrorOutcome, MirrorStats};
+use crate::sizes::{SizeStats, SIZE_BUCKET_NAMES};
+use crate::split::{Split, SplitStats};
+use crate::statsd::{StatsClient, StatsSink};
+use crate::tcpinfo::{self, TcpStats, RTT_BUCKET_NAMES};
+use crate::tenant::{Tenant, TenantStat, Tenants};
+use crate::toggle::Toggle;
+use crate::upstream::{UpstreamStat, UpstreamStats};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Accept failures worth telling apart; socket churn shows up as `FdLimit` or `NoMemory`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AcceptError {
+    /// EMFILE / ENFILE: out of file descriptors.
+    FdLimit,
+    /// ENOBUFS / ENOMEM: out of socket buffer or kernel memory.
+    NoMemory,
+    /// ECONNABORTED: the client reset the connection while it waited in the accept queue.
+    Aborted,
+    /// EPROTO: a protocol error on the queued connection.
+    Protocol,
+    /// EINTR: a signal arrived during the call.
+    Interrupted,
+    Other,
+}
+
+impl AcceptError {
+    pub const ALL: [AcceptError; 6] = [
+        AcceptError::FdLimit,
+        AcceptError::NoMemory,
+        AcceptError::Aborted,
+        AcceptError::Protocol,
+        AcceptError::Interrupted,
+        AcceptError::Other,
+    ];
+
+    pub fn classify(e: &io::Error) -> Self {
+        match e.raw_os_error() {
+            Some(libc::EMFILE | libc::ENFILE) => AcceptError::FdLimit,
+            Some(libc::ENOBUFS | libc::ENOMEM) => AcceptError::NoMemory,
+            Some(libc::ECONNABORTED) => AcceptError::Aborted,
+            Some(libc::EPROTO) => AcceptError::Protocol,
+            Some(libc::EINTR) => AcceptError::Interrupted,
+            _ => AcceptError::Other,
+        }
+    }
+
+    /// Concerns one queued connection or one call rather than the listener, so the accept
+    /// loop goes on to the next connection instead of waiting for the next readiness event.
+    pub fn is_transient(self) -> bool {
+        matches!(self, AcceptError::NoMemory | AcceptError::Aborted | AcceptError::Protocol | AcceptError::Interrupted)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AcceptError::FdLimit => "fd_limit",
+            AcceptError::NoMemory => "no_memory",
+            AcceptError::Aborted => "aborted",
+            AcceptError::Protocol => "protocol",
+            AcceptError::Interrupted => "interrupted",
+            AcceptError::Other => "other",
+        }
+    }
+}
+
+/// Requests refused because the client is not speaking HTTP/1.x at all.
+#[derive(Clone, Copy)]
+pub enum ProtocolError {
+    /// TLS ClientHello on the plaintext port.
+    Tls,
+    /// First byte that cannot start a request line.
+    Garbage,
+    /// HTTP/2 with prior knowledge (`h2c`), which is not served.
+    H2c,
+    /// HTTP/2 over TLS, negotiated with ALPN or sent with prior knowledge.
+    H2,
+}
+
+impl ProtocolError {
+    pub const ALL: [ProtocolError; 4] = [ProtocolError::Tls, ProtocolError::Garbage, ProtocolError::H2c, ProtocolError::H2];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ProtocolError::Tls => "tls",
+            ProtocolError::Garbage => "garbage",
+            ProtocolError::H2c => "h2c",
+            ProtocolError::H2 => "h2",
+        }
+    }
+}
+
+/// Why a TLS handshake did not complete; see `tls`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    /// Not finished within `--tls-handshake-timeout` of the ClientHello.
+    Timeout,
+    /// Refused at once: the worker already had `--tls-max-handshakes` in progress.
+    Busy,
+    /// The client closed or reset the connection mid-handshake.
+    Closed,
+    /// The client sent a fatal alert, typically because it does not trust the certificate.
+    Alert,
+    /// No protocol version, cipher suite or key exchange in common with the client.
+    Incompatible,
+    /// Anything else rustls refused: malformed or unexpected handshake messages.
+    Malformed,
+}
+
+impl HandshakeFailure {
+    pub const ALL: [HandshakeFailure; 6] = [
+        HandshakeFailure::Timeout,
+        HandshakeFailure::Busy,
+        HandshakeFailure::Closed,
+        HandshakeFailure::Alert,
+        HandshakeFailure::Incompatible,
+        HandshakeFailure::Malformed,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HandshakeFailure::Timeout => "timeout",
+            HandshakeFailure::Busy => "busy",
+            HandshakeFailure::Closed => "closed",
+            HandshakeFailure::Alert => "alert",
+            HandshakeFailure::Incompatible => "incompatible",
+            HandshakeFailure::Malformed => "malformed",
+        }
+    }
+}
+
+/// Request heads refused as junk or as attempts to confuse a parser in front of the server,
+/// or served but flagged as such (`BadUtf8`). With `--ban-suspicious` each one also counts
+/// against the peer address.
+#[derive(Clone, Copy)]
+pub enum Suspicious {
+    /// A request line whose method is not a token.
+    BadMethod,
+    /// A request line longer than `--max-header-size` on its own.
+    LongUri,
+    /// A head longer than `--max-header-size`, past its request line.
+    LongHead,
+    /// A NUL byte anywhere in the head.
+    Nul,
+    /// Request line or header bytes that are not UTF-8; the request is still served.
+    BadUtf8,
+    /// Conflicting or unsupported body framing: duplicate `Content-Length`s that differ, both
+    /// `Transfer-Encoding` and `Content-Length`, or a transfer coding other than `chunked`.
+    Smuggling,
+    /// Any other head RFC 9112 says to reject, such as a header line without a colon.
+    Malformed,
+}
+
+impl Suspicious {
+    pub const ALL: [Suspicious; 7] = [
+        Suspicious::BadMethod,
+        Suspicious::LongUri,
+        Suspicious::LongHead,
+        Suspicious::Nul,
+        Suspicious::BadUtf8,
+        Suspicious::Smuggling,
+        Suspicious::Malformed,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Suspicious::BadMethod => "bad_method",
+            Suspicious::LongUri => "long_uri",
+            Suspicious::LongHead => "long_head",
+            Suspicious::Nul => "nul",
+            Suspicious::BadUtf8 => "bad_utf8",
+            Suspicious::Smuggling => "smuggling",
+            Suspicious::Malformed => "malformed",
+        }
+    }
+}
+
+/// What a worker's read buffer pool has done since startup, for tuning `MAX_RECYCLED_BUFS`.
+#[derive(Clone, Copy)]
+pub enum PoolStat {
+    /// Buffers lent to connections.
+    Acquires,
+    /// Buffers handed back by closed connections.
+    Releases,
+    /// Acquires served from the recycle list.
+    Recycled,
+    /// Acquires that had to allocate a fresh, zeroed buffer.
+    Fresh,
+    /// Releases freed because the recycle list was full.
+    Dropped,
+    /// Nanoseconds spent allocating and zeroing fresh buffers.
+    ZeroingNs,
+}
+
+impl PoolStat {
+    pub const ALL: [PoolStat; 6] = [
+        PoolStat::Acquires,
+        PoolStat::Releases,
+        PoolStat::Recycled,
+        PoolStat::Fresh,
+        PoolStat::Dropped,
+        PoolStat::ZeroingNs,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PoolStat::Acquires => "acquires",
+            PoolStat::Releases => "releases",
+            PoolStat::Recycled => "recycled",
+            PoolStat::Fresh => "fresh",
+            PoolStat::Dropped => "dropped",
+            PoolStat::ZeroingNs => "zeroing_ns",
+        }
+    }
+}
 
 #[repr(align(64))]
 pub struct Slot {
     pub count: AtomicU64,
-    _pad: [u8; 56],
+    pub active: AtomicU64,
+    /// Connections the worker has taken on, however they were accepted.
+    pub accepted: AtomicU64,
+    pub write_timeouts: AtomicU64,
+    pub header_timeouts: AtomicU64,
+    /// Request heads that outgrew the pooled read buffer.
+    pub header_spills: AtomicU64,
+    /// Requests that waited on another's backend request; see `collapse`.
+    pub collapsed: AtomicU64,
+    pub accept_errors: [AtomicU64; AcceptError::ALL.len()],
+    pub protocol_errors: [AtomicU64; ProtocolError::ALL.len()],
+    pub handshake_failures: [AtomicU64; HandshakeFailure::ALL.len()],
+    pub suspicious: [AtomicU64; Suspicious::ALL.len()],
+    /// Connections the server closed, per `CloseReason` and then `CloseMode`.
+    pub closes: [[AtomicU64; CloseMode::ALL.len()]; CloseReason::ALL.len()],
+    /// Connections per `ConnState`, sampled by the worker once per stats interval.
+    pub states: [AtomicU64; ConnState::ALL.len()],
+    /// Approximate bytes owned by the worker's pools, slab and buffers.
+    pub memory: AtomicU64,
+    /// Entries in the worker's timer wheel, sampled with `states`.
+    pub timers: AtomicU64,
+    /// The worker's buffer pool totals per `PoolStat`, sampled with `states`.
+    pub buf_pool: [AtomicU64; PoolStat::ALL.len()],
+    /// Poll wakeups and the events they returned; counted only while `Toggle::ExtraMetrics` is on.
+    pub wakeups: AtomicU64,
+    pub events: AtomicU64,
+}
+
+/// Counters since startup and gauges as of each worker's last stats sample, for embedders
+/// that read stats in-process rather than from the stats push or `/__vrypt/stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StatsSnapshot {
+    pub requests: u64,
+    pub accepted: u64,
+    pub active: u64,
+    pub memory_bytes: u64,
+    pub timers: u64,
+    pub write_timeouts: u64,
+    pub header_timeouts: u64,
+    pub header_spills: u64,
+    pub collapsed: u64,
+    /// Connections per `ConnState` name.
+    pub conns: BTreeMap<String, u64>,
+    pub accept_errors: BTreeMap<String, u64>,
+    pub protocol_errors: BTreeMap<String, u64>,
+    /// Failed TLS handshakes per `HandshakeFailure` name.
+    pub handshake_failures: BTreeMap<String, u64>,
+    /// Suspicious request heads per `Suspicious` name.
+    pub suspicious: BTreeMap<String, u64>,
+    /// Server-side closes per `reason.mode`, e.g. `timeout.rst`.
+    pub closes: BTreeMap<String, u64>,
+    /// Buffer pool totals per `PoolStat` name.
+    pub buf_pool: BTreeMap<String, u64>,
+    /// Milliseconds the counters cover: since startup or the last reset.
+    pub window_ms: u64,
+    pub workers: Vec<WorkerSnapshot>,
+}
+
+impl StatsSnapshot {
+    /// These counters less those in `base`, covering the `window` since it was taken.
+    /// Gauges are kept as they are.
+    fn since(mut self, base: &StatsSnapshot, window: Duration) -> Self {
+        let sub = |n: &mut u64, b: u64| *n = n.saturating_sub(b);
+        sub(&mut self.requests, base.requests);
+        sub(&mut self.accepted, base.accepted);
+        sub(&mut self.write_timeouts, base.write_timeouts);
+        sub(&mut self.header_timeouts, base.header_timeouts);
+        sub(&mut self.header_spills, base.header_spills);
+        sub(&mut self.collapsed, base.collapsed);
+        let maps = [
+            (&mut self.accept_errors, &base.accept_errors),
+            (&mut self.protocol_errors, &base.protocol_errors),
+            (&mut self.handshake_failures, &base.handshake_failures),
+            (&mut self.suspicious, &base.suspicious),
+            (&mut self.closes, &base.closes),
+            (&mut self.buf_pool, &base.buf_pool),
+        ];
+        for (counts, base) in maps {
+            for (name, n) in counts.iter_mut() {
+                sub(n, base.get(name).copied().unwrap_or(0));
+            }
+        }
+        for (worker, base) in self.workers.iter_mut().zip(&base.workers) {
+            sub(&mut worker.requests, base.requests);
+            sub(&mut worker.accepted, base.accepted);
+        }
+        self.window_ms = window.as_millis() as u64;
+        self
+    }
+
+    /// The snapshot as a JSON object, for the admin stats endpoints.
+    pub fn write_json(&self, out: &mut Vec<u8>) {
+        let mut json = Json::new(out);
+        json.begin_object();
+        json.key("requests").u64(self.requests);
+        json.key("accepted").u64(self.accepted);
+        json.key("active").u64(self.active);
+        json.key("memory_bytes").u64(self.memory_bytes);
+        json.key("timers").u64(self.timers);
+        json.key("write_timeouts").u64(self.write_timeouts);
+        json.key("header_timeouts").u64(self.header_timeouts);
+        json.key("header_spills").u64(self.header_spills);
+        json.key("collapsed").u64(self.collapsed);
+        json.key("conns").begin_object();
+        for state in ConnState::ALL {
+            json.key(state.name()).u64(self.conns[state.name()]);
+        }
+        json.end_object().key("accept_errors").begin_object();
+        for kind in AcceptError::ALL {
+            json.key(kind.name()).u64(self.accept_errors[kind.name()]);
+        }
+        json.end_object().key("protocol_errors").begin_object();
+        for kind in ProtocolError::ALL {
+            json.key(kind.name()).u64(self.protocol_errors[kind.name()]);
+        }
+        json.end_object().key("handshake_failures").begin_object();
+        for kind in HandshakeFailure::ALL {
+            json.key(kind.name()).u64(self.handshake_failures[kind.name()]);
+        }
+        json.end_object().key("suspicious").begin_object();
+        for kind in Suspicious::ALL {
+            json.key(kind.name()).u64(self.suspicious[kind.name()]);
+        }
+        json.end_object().key("closes").begin_object();
+        for (name, n) in &self.closes {
+            json.key(name).u64(*n);
+        }
+        json.end_object().key("buf_pool").begin_object();
+        for stat in PoolStat::ALL {
+            json.key(stat.name()).u64(self.buf_pool[stat.name()]);
+        }
+        json.end_object().key("window_ms").u64(self.window_ms);
+        json.key("workers").begin_array();
+        for worker in &self.workers {
+            json.begin_object();
+            json.key("requests").u64(worker.requests);
+            json.key("accepted").u64(worker.accepted);
+            json.key("active").u64(worker.active);
+            json.key("memory_bytes").u64(worker.memory_bytes);
+            json.end_object();
+        }
+        json.end_array().end_object();
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WorkerSnapshot {
+    pub requests: u64,
+    pub accepted: u64,
+    pub active: u64,
+    pub memory_bytes: u64,
 }
 
 pub struct RpsCounter {
     slots: Box<[Slot]>,
+    /// Counters as they stood at the last `reset`, and when that was.
+    base: Mutex<(Instant, StatsSnapshot)>,
+    /// Set during `--warmup`; stats pushes are held back meanwhile.
+    warming: AtomicBool,
 }
 
 impl RpsCounter {
     pub fn new(num_threads: usize) -> &'static Self {
         let slots = (0..num_threads)
-            .map(|_| Slot { count: AtomicU64::new(0), _pad: [0u8; 56] })
+            .map(|_| Slot {
+                count: AtomicU64::new(0),
+                active: AtomicU64::new(0),
+                accepted: AtomicU64::new(0),
+                write_timeouts: AtomicU64::new(0),
+                header_timeouts: AtomicU64::new(0),
+                header_spills: AtomicU64::new(0),
+                collapsed: AtomicU64::new(0),
+                accept_errors: Default::default(),
+                protocol_errors: Default::default(),
+                handshake_failures: Default::default(),
+                suspicious: Default::default(),
+                closes: Default::default(),
+                states: Default::default(),
+                memory: AtomicU64::new(0),
+                timers: AtomicU64::new(0),
+                buf_pool: Default::default(),
+                wakeups: AtomicU64::new(0),
+                events: AtomicU64::new(0),
+            })
             .collect::<Vec<_>>()
             .into_boxed_slice();
-        Box::leak(Box::new(Self { slots }))
+        let base = Mutex::new((Instant::now(), StatsSnapshot::default()));
+        Box::leak(Box::new(Self { slots, base, warming: AtomicBool::new(false) }))
     }
 
     #[inline]
@@ -28,49 +426,466 @@ impl RpsCounter {
         self.slots[thread_id].count.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Publishes the number of open connections owned by a worker.
+    #[inline]
+    pub fn set_active(&self, thread_id: usize, n: u64) {
+        self.slots[thread_id].active.store(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn accepted(&self, thread_id: usize) {
+        self.slots[thread_id].accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn wakeup(&self, thread_id: usize, events: usize) {
+        let slot = &self.slots[thread_id];
+        slot.wakeups.fetch_add(1, Ordering::Relaxed);
+        slot.events.fetch_add(events as u64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn write_timeout(&self, thread_id: usize) {
+        self.slots[thread_id].write_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn write_timeouts(&self) -> u64 {
+        self.slots.iter().map(|s| s.write_timeouts.load(Ordering::Relaxed)).sum()
+    }
+
+    #[inline]
+    pub fn header_timeout(&self, thread_id: usize) {
+        self.slots[thread_id].header_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn header_timeouts(&self) -> u64 {
+        self.slots.iter().map(|s| s.header_timeouts.load(Ordering::Relaxed)).sum()
+    }
+
+    #[inline]
+    pub fn header_spill(&self, thread_id: usize) {
+        self.slots[thread_id].header_spills.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn header_spills(&self) -> u64 {
+        self.slots.iter().map(|s| s.header_spills.load(Ordering::Relaxed)).sum()
+    }
+
+    #[inline]
+    pub fn collapsed(&self, thread_id: usize) {
+        self.slots[thread_id].collapsed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn collapsed_requests(&self) -> u64 {
+        self.slots.iter().map(|s| s.collapsed.load(Ordering::Relaxed)).sum()
+    }
+
+    #[inline]
+    pub fn accept_error(&self, thread_id: usize, kind: AcceptError) {
+        self.slots[thread_id].accept_errors[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn accept_errors(&self, kind: AcceptError) -> u64 {
+        self.slots.iter().map(|s| s.accept_errors[kind as usize].load(Ordering::Relaxed)).sum()
+    }
+
+    #[inline]
+    pub fn protocol_error(&self, thread_id: usize, kind: ProtocolError) {
+        self.slots[thread_id].protocol_errors[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn handshake_failure(&self, thread_id: usize, kind: HandshakeFailure) {
+        self.slots[thread_id].handshake_failures[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn suspicious_request(&self, thread_id: usize, kind: Suspicious) {
+        self.slots[thread_id].suspicious[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn closed(&self, thread_id: usize, reason: CloseReason, mode: CloseMode) {
+        self.slots[thread_id].closes[reason as usize][mode as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn closes(&self, reason: CloseReason, mode: CloseMode) -> u64 {
+        self.slots.iter().map(|s| s.closes[reason as usize][mode as usize].load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn protocol_errors(&self, kind: ProtocolError) -> u64 {
+        self.slots.iter().map(|s| s.protocol_errors[kind as usize].load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn handshake_failures(&self, kind: HandshakeFailure) -> u64 {
+        self.slots.iter().map(|s| s.handshake_failures[kind as usize].load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn suspicious_requests(&self, kind: Suspicious) -> u64 {
+        self.slots.iter().map(|s| s.suspicious[kind as usize].load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn set_states(&self, thread_id: usize, counts: [u64; ConnState::ALL.len()]) {
+        for (slot, n) in self.slots[thread_id].states.iter().zip(counts) {
+            slot.store(n, Ordering::Relaxed);
+        }
+    }
+
+    pub fn states(&self, state: ConnState) -> u64 {
+        self.slots.iter().map(|s| s.states[state as usize].load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn set_memory(&self, thread_id: usize, bytes: u64) {
+        self.slots[thread_id].memory.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn memory(&self) -> u64 {
+        self.slots.iter().map(|s| s.memory.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn set_timers(&self, thread_id: usize, n: u64) {
+        self.slots[thread_id].timers.store(n, Ordering::Relaxed);
+    }
+
+    pub fn timers(&self) -> u64 {
+        self.slots.iter().map(|s| s.timers.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn set_buf_pool(&self, thread_id: usize, stats: [u64; PoolStat::ALL.len()]) {
+        for (slot, n) in self.slots[thread_id].buf_pool.iter().zip(stats) {
+            slot.store(n, Ordering::Relaxed);
+        }
+    }
+
+    pub fn buf_pool(&self, stat: PoolStat) -> u64 {
+        self.slots.iter().map(|s| s.buf_pool[stat as usize].load(Ordering::Relaxed)).sum()
+    }
+
     pub fn total(&self) -> u64 {
         self.slots.iter().map(|s| s.count.load(Ordering::Relaxed)).sum()
     }
-}
 
-pub fn spawn_stats_pusher(counter: &'static RpsCounter) {
-    thread::spawn(move || {
-        let sock = match UdpSocket::bind("0.0.0.0:0") {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("[stats] failed to bind udp socket: {e}");
-                return;
-            }
-        };
+    pub fn slots(&self) -> &[Slot] {
+        &self.slots
+    }
 
-        let target: SocketAddr = match STATS_TARGET.parse() {
-            Ok(a) => a,
-            Err(e) => {
-                eprintln!("[stats] invalid target address: {e}");
-                return;
-            }
-        };
+    /// Counters since startup or the last `reset`, and gauges as of each worker's last stats sample.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let raw = self.raw_snapshot();
+        let base = self.base.lock().unwrap_or_else(|e| e.into_inner());
+        raw.since(&base.1, base.0.elapsed())
+    }
+
+    /// Starts the counters `snapshot` and the admin stats endpoint report over from 0 and
+    /// returns what they reported until now. The stats push, which sends differences per
+    /// interval, is not affected.
+    pub fn reset(&self) -> StatsSnapshot {
+        let raw = self.raw_snapshot();
+        let mut base = self.base.lock().unwrap_or_else(|e| e.into_inner());
+        let before = raw.clone().since(&base.1, base.0.elapsed());
+        *base = (Instant::now(), raw);
+        before
+    }
+
+    /// Holds back stats pushes until `end_warmup`.
+    pub fn begin_warmup(&self) {
+        self.warming.store(true, Ordering::Relaxed);
+    }
+
+    /// Resets the counters, so nothing counted during the warm-up is reported, and lets
+    /// stats pushes through again.
+    pub fn end_warmup(&self) {
+        self.reset();
+        self.warming.store(false, Ordering::Relaxed);
+    }
 
-        let mut buf = [0u8; 64];
+    pub fn warming(&self) -> bool {
+        self.warming.load(Ordering::Relaxed)
+    }
+
+    fn raw_snapshot(&self) -> StatsSnapshot {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        let workers: Vec<_> = self
+            .slots
+            .iter()
+            .map(|s| WorkerSnapshot {
+                requests: load(&s.count),
+                accepted: load(&s.accepted),
+                active: load(&s.active),
+                memory_bytes: load(&s.memory),
+            })
+            .collect();
+        StatsSnapshot {
+            requests: self.total(),
+            accepted: workers.iter().map(|w| w.accepted).sum(),
+            active: workers.iter().map(|w| w.active).sum(),
+            memory_bytes: self.memory(),
+            timers: self.timers(),
+            write_timeouts: self.write_timeouts(),
+            header_timeouts: self.header_timeouts(),
+            header_spills: self.header_spills(),
+            collapsed: self.collapsed_requests(),
+            conns: ConnState::ALL.into_iter().map(|s| (s.name().to_string(), self.states(s))).collect(),
+            accept_errors: AcceptError::ALL.into_iter().map(|k| (k.name().to_string(), self.accept_errors(k))).collect(),
+            protocol_errors: ProtocolError::ALL
+                .into_iter()
+                .map(|k| (k.name().to_string(), self.protocol_errors(k)))
+                .collect(),
+            handshake_failures: HandshakeFailure::ALL
+                .into_iter()
+                .map(|k| (k.name().to_string(), self.handshake_failures(k)))
+                .collect(),
+            suspicious: Suspicious::ALL
+                .into_iter()
+                .map(|k| (k.name().to_string(), self.suspicious_requests(k)))
+                .collect(),
+            closes: CloseReason::ALL
+                .into_iter()
+                .flat_map(|r| CloseMode::ALL.map(|m| (format!("{}.{}", r.name(), m.name()), self.closes(r, m))))
+                .collect(),
+            buf_pool: PoolStat::ALL.into_iter().map(|s| (s.name().to_string(), self.buf_pool(s))).collect(),
+            window_ms: 0,
+            workers,
+        }
+    }
+
+    /// `snapshot` as a JSON object, for the admin stats endpoint.
+    pub fn write_json(&self, out: &mut Vec<u8>) {
+        self.snapshot().write_json(out);
+    }
+}
+
+/// What the stats pusher reports besides the `RpsCounter`, each only when configured.
+#[derive(Clone, Copy, Default)]
+pub struct StatsSources {
+    pub tcp: Option<&'static TcpStats>,
+    pub sizes: Option<&'static SizeStats>,
+    pub split: Option<(&'static SplitStats, &'static Split)>,
+    pub mirror: Option<&'static MirrorStats>,
+    pub tenants: Option<(&'static Tenants, &'static [Tenant])>,
+    pub backends: Option<&'static UpstreamStats>,
+}
+
+/// Pushes every metric to `sink` once per its interval, batched into as few packets as
+/// they fit in; counts such as `vrypt.rps` cover that interval. Nothing is pushed while the
+/// server warms up. Once `stop` is set, pushes what the interval so far has counted and,
+/// with `zero_on_exit`, every gauge once more as 0 so dashboards do not keep showing the
+/// last values, then returns.
+pub fn spawn_stats_pusher(
+    counter: &'static RpsCounter,
+    sources: StatsSources,
+    sink: StatsSink,
+    stop: &'static AtomicBool,
+    zero_on_exit: bool,
+) -> JoinHandle<()> {
+    let StatsSources { tcp, sizes, split, mirror, tenants, backends } = sources;
+    thread::spawn(move || {
+        let mut stats = StatsClient::new(sink.target).with_prefix(&sink.prefix);
         let mut prev: u64 = 0;
+        let mut prev_per_worker = vec![0u64; counter.slots().len()];
+        let mut prev_accepted = vec![0u64; counter.slots().len()];
+        let mut prev_loop = vec![(0u64, 0u64); counter.slots().len()];
+        let mut prev_write_timeouts: u64 = 0;
+        let mut prev_header_timeouts: u64 = 0;
+        let mut prev_header_spills: u64 = 0;
+        let mut prev_collapsed: u64 = 0;
+        let mut prev_accept_errors = [0u64; AcceptError::ALL.len()];
+        let mut prev_protocol_errors = [0u64; ProtocolError::ALL.len()];
+        let mut prev_handshake_failures = [0u64; HandshakeFailure::ALL.len()];
+        let mut prev_suspicious = [0u64; Suspicious::ALL.len()];
+        let mut prev_closes = [[0u64; CloseMode::ALL.len()]; CloseReason::ALL.len()];
+        let mut prev_buf_pool = [0u64; PoolStat::ALL.len()];
+        let mut prev_overflows = tcpinfo::listen_overflows();
+        let mut zeroed = false;
 
         loop {
-            thread::sleep(STATS_INTERVAL);
+            let stopping = pause(stop, sink.interval);
+            stats.mute(counter.warming());
 
             let total = counter.total();
             let rps = total.wrapping_sub(prev);
             prev = total;
+            stats.gauge(format_args!("{STATS_METRIC}"), rps);
+
+            let write_timeouts = counter.write_timeouts();
+            let delta = write_timeouts.wrapping_sub(prev_write_timeouts);
+            prev_write_timeouts = write_timeouts;
+            stats.gauge(format_args!("{STATS_WRITE_TIMEOUTS}"), delta);
 
-            let mut cursor = std::io::Cursor::new(&mut buf[..]);
-            if write!(cursor, "{}:{}|g", STATS_METRIC, rps).is_err() {
-                eprintln!(
-                    "[stats] message too long for buffer (metric='{}', rps={rps}); skipping",
-                    STATS_METRIC
-                );
-                continue;
+            let header_timeouts = counter.header_timeouts();
+            let delta = header_timeouts.wrapping_sub(prev_header_timeouts);
+            prev_header_timeouts = header_timeouts;
+            stats.gauge(format_args!("{STATS_HEADER_TIMEOUTS}"), delta);
+
+            let header_spills = counter.header_spills();
+            let delta = header_spills.wrapping_sub(prev_header_spills);
+            prev_header_spills = header_spills;
+            stats.gauge(format_args!("{STATS_HEADER_SPILLS}"), delta);
+
+            let collapsed = counter.collapsed_requests();
+            let delta = collapsed.wrapping_sub(prev_collapsed);
+            prev_collapsed = collapsed;
+            stats.gauge(format_args!("{STATS_COLLAPSED}"), delta);
+
+            for (kind, prev) in AcceptError::ALL.into_iter().zip(prev_accept_errors.iter_mut()) {
+                let n = counter.accept_errors(kind);
+                let delta = n.wrapping_sub(*prev);
+                *prev = n;
+                stats.gauge(format_args!("{STATS_ACCEPT_ERRORS_PREFIX}.{}", kind.name()), delta);
+            }
+
+            for (kind, prev) in ProtocolError::ALL.into_iter().zip(prev_protocol_errors.iter_mut()) {
+                let n = counter.protocol_errors(kind);
+                let delta = n.wrapping_sub(*prev);
+                *prev = n;
+                stats.gauge(format_args!("{STATS_PROTOCOL_ERRORS_PREFIX}.{}", kind.name()), delta);
+            }
+
+            for (kind, prev) in HandshakeFailure::ALL.into_iter().zip(prev_handshake_failures.iter_mut()) {
+                let n = counter.handshake_failures(kind);
+                let delta = n.wrapping_sub(*prev);
+                *prev = n;
+                stats.gauge(format_args!("{STATS_HANDSHAKE_FAILURES_PREFIX}.{}", kind.name()), delta);
+            }
+
+            for (kind, prev) in Suspicious::ALL.into_iter().zip(prev_suspicious.iter_mut()) {
+                let n = counter.suspicious_requests(kind);
+                let delta = n.wrapping_sub(*prev);
+                *prev = n;
+                stats.gauge(format_args!("{STATS_SUSPICIOUS_PREFIX}.{}", kind.name()), delta);
+            }
+
+            for (reason, prev) in CloseReason::ALL.into_iter().zip(prev_closes.iter_mut()) {
+                for (mode, prev) in CloseMode::ALL.into_iter().zip(prev.iter_mut()) {
+                    let n = counter.closes(reason, mode);
+                    let delta = n.wrapping_sub(*prev);
+                    *prev = n;
+                    stats.gauge(format_args!("{STATS_CLOSES_PREFIX}.{}.{}", reason.name(), mode.name()), delta);
+                }
+            }
+
+            for (stat, prev) in PoolStat::ALL.into_iter().zip(prev_buf_pool.iter_mut()) {
+                let n = counter.buf_pool(stat);
+                let delta = n.wrapping_sub(*prev);
+                *prev = n;
+                stats.gauge(format_args!("{STATS_BUF_POOL_PREFIX}.{}", stat.name()), delta);
+            }
+
+            for state in ConnState::ALL {
+                let n = counter.states(state);
+                stats.gauge(format_args!("{STATS_CONNS_PREFIX}.{}", state.name()), n);
+            }
+
+            stats.gauge(format_args!("{STATS_MEMORY}"), counter.memory());
+            stats.gauge(format_args!("{STATS_TIMERS}"), counter.timers());
+
+            let per_worker = prev_per_worker.iter_mut().zip(prev_accepted.iter_mut());
+            for (id, (slot, (prev, prev_accepted))) in counter.slots().iter().zip(per_worker).enumerate() {
+                let count = slot.count.load(Ordering::Relaxed);
+                let rps = count.wrapping_sub(*prev);
+                *prev = count;
+                stats.gauge(format_args!("{STATS_WORKER_PREFIX}.{id}.rps"), rps);
+                let accepted = slot.accepted.load(Ordering::Relaxed);
+                let delta = accepted.wrapping_sub(*prev_accepted);
+                *prev_accepted = accepted;
+                stats.gauge(format_args!("{STATS_WORKER_PREFIX}.{id}.accepts"), delta);
+                let active = slot.active.load(Ordering::Relaxed);
+                stats.gauge(format_args!("{STATS_WORKER_PREFIX}.{id}.active_conns"), active);
+                let memory = slot.memory.load(Ordering::Relaxed);
+                stats.gauge(format_args!("{STATS_WORKER_PREFIX}.{id}.memory_bytes"), memory);
+            }
+
+            if Toggle::ExtraMetrics.enabled() {
+                for (id, (slot, prev)) in counter.slots().iter().zip(prev_loop.iter_mut()).enumerate() {
+                    let now = (slot.wakeups.load(Ordering::Relaxed), slot.events.load(Ordering::Relaxed));
+                    let (wakeups, events) = (now.0.wrapping_sub(prev.0), now.1.wrapping_sub(prev.1));
+                    *prev = now;
+                    stats.gauge(format_args!("{STATS_WORKER_PREFIX}.{id}.wakeups"), wakeups);
+                    stats.gauge(format_args!("{STATS_WORKER_PREFIX}.{id}.events"), events);
+                }
+            }
+
+            if let Some(tcp) = tcp {
+                stats.gauge(format_args!("{STATS_TCP_PREFIX}.listen_queue"), tcp.listen_queue());
+                let overflows = tcpinfo::listen_overflows();
+                if let (Some(now), Some(before)) = (overflows, prev_overflows) {
+                    let delta = now.wrapping_sub(before);
+                    stats.gauge(format_args!("{STATS_TCP_PREFIX}.listen_overflows"), delta);
+                }
+                prev_overflows = overflows;
+                stats.gauge(format_args!("{STATS_TCP_PREFIX}.retrans"), tcp.take_retrans());
+                for (name, n) in RTT_BUCKET_NAMES.iter().zip(tcp.take_rtt()) {
+                    stats.gauge(format_args!("{STATS_TCP_PREFIX}.rtt_{name}"), n);
+                }
+            }
+
+            if let Some(sizes) = sizes {
+                for (route, name) in sizes.names().iter().enumerate() {
+                    let (requests, responses) = sizes.take(route);
+                    for (bucket, n) in SIZE_BUCKET_NAMES.iter().zip(requests) {
+                        stats.gauge(format_args!("{STATS_SIZE_PREFIX}.request.{name}.{bucket}"), n);
+                    }
+                    for (bucket, n) in SIZE_BUCKET_NAMES.iter().zip(responses) {
+                        stats.gauge(format_args!("{STATS_SIZE_PREFIX}.response.{name}.{bucket}"), n);
+                    }
+                }
+            }
+
+            if let Some((stats_split, split)) = split {
+                for (i, group) in split.groups.iter().enumerate() {
+                    stats.gauge(format_args!("{STATS_SPLIT_PREFIX}.{}.requests", group.name), stats_split.take(i));
+                }
+                if split.affinity.is_some() {
+                    let counts = stats_split.take_affinity();
+                    stats.gauge(format_args!("{STATS_SPLIT_PREFIX}.affinity.hits"), counts.hits);
+                    stats.gauge(format_args!("{STATS_SPLIT_PREFIX}.affinity.misses"), counts.misses);
+                    stats.gauge(format_args!("{STATS_SPLIT_PREFIX}.affinity.evictions"), counts.evictions);
+                    stats.gauge(format_args!("{STATS_SPLIT_PREFIX}.affinity.expired"), counts.expired);
+                }
+            }
+            if let Some(mirror) = mirror {
+                for outcome in MirrorOutcome::ALL {
+                    stats.gauge(format_args!("{STATS_MIRROR_PREFIX}.{}", outcome.name()), mirror.take(outcome));
+                }
+            }
+            if let Some((counts, tenants)) = tenants {
+                for (i, tenant) in tenants.iter().enumerate() {
+                    for stat in TenantStat::ALL {
+                        let n = counts.take(i, stat);
+                        stats.gauge(format_args!("{STATS_TENANT_PREFIX}.{}.{}", tenant.name, stat.name()), n);
+                    }
+                }
+            }
+            if let Some(backends) = backends {
+                for (i, name) in backends.names().iter().enumerate() {
+                    for stat in UpstreamStat::ALL {
+                        let n = backends.take(i, stat);
+                        stats.gauge(format_args!("{STATS_UPSTREAM_PREFIX}.{name}.{}", stat.name()), n);
+                    }
+                }
+            }
+            if stopping && (zeroed || !zero_on_exit) {
+                break;
+            }
+            stats.flush();
+            if stopping {
+                zeroed = true;
+                stats.zero_gauges();
             }
-            let n = cursor.position() as usize;
-            let _ = sock.send_to(&buf[..n], target);
         }
-    });
+        stats.finish();
+    })
+}
+
+/// Sleeps for `interval` or until `stop` is set; returns whether it was.
+fn pause(stop: &AtomicBool, interval: Duration) -> bool {
+    let until = Instant::now() + interval;
+    while !stop.load(Ordering::Relaxed) {
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return false;
+        }
+        thread::sleep(left.min(STOP_POLL));
+    }
+    true
 }
@@ -0,0 +1,73 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// An exclusively locked pid file. The lock lives as long as the process, so a
+/// file left behind by a dead process is detected as stale and taken over.
+pub struct PidFile {
+    file: File,
+}
+
+impl PidFile {
+    pub fn acquire(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(err);
+            }
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("already running (pid {})", pid.trim()),
+            ));
+        }
+        Ok(Self { file })
+    }
+
+    /// Replaces the file contents with the current pid.
+    pub fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        writeln!(self.file, "{}", std::process::id())?;
+        self.file.flush()
+    }
+}
+
+fn fork() -> io::Result<bool> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(true),
+        _ => Ok(false),
+    }
+}
+
+/// Detaches from the terminal: fork, setsid, fork again, then point stdin at
+/// `/dev/null` and stdout/stderr at `log`. Must run before any thread is spawned.
+/// The working directory is kept so relative paths on the command line stay valid.
+pub fn daemonize(log: &Path) -> io::Result<()> {
+    let log = OpenOptions::new().create(true).append(true).open(log)?;
+    let null = File::open("/dev/null")?;
+
+    if !fork()? {
+        std::process::exit(0);
+    }
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if !fork()? {
+        std::process::exit(0);
+    }
+
+    unsafe {
+        if libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
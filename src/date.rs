@@ -0,0 +1,105 @@
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Broken-down UTC time.
+pub struct Civil {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// Day of the week, 0 = Sunday.
+    pub weekday: u32,
+}
+
+impl Civil {
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / 86_400) as i64;
+        let rem = (secs % 86_400) as u32;
+        // Howard Hinnant's civil_from_days.
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        Self {
+            year,
+            month,
+            day,
+            hour: rem / 3_600,
+            minute: rem / 60 % 60,
+            second: rem % 60,
+            // 1970-01-01 was a Thursday.
+            weekday: ((days + 4).rem_euclid(7)) as u32,
+        }
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Appends `secs` as RFC 3339 UTC, e.g. `2024-05-01T12:00:00Z`.
+pub fn write_rfc3339(out: &mut Vec<u8>, secs: u64) {
+    let c = Civil::from_unix(secs);
+    let _ = write!(
+        out,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        c.year, c.month, c.day, c.hour, c.minute, c.second
+    );
+}
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Appends `secs` as an HTTP IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn write_imf_fixdate(out: &mut Vec<u8>, secs: u64) {
+    let c = Civil::from_unix(secs);
+    let _ = write!(
+        out,
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[c.weekday as usize],
+        c.day,
+        MONTHS[c.month as usize - 1],
+        c.year,
+        c.hour,
+        c.minute,
+        c.second
+    );
+}
+
+/// A complete `Date` header line, reformatted only when the second changes.
+pub struct DateHeader {
+    secs: u64,
+    line: Vec<u8>,
+}
+
+impl DateHeader {
+    pub fn new() -> Self {
+        let mut d = Self { secs: u64::MAX, line: Vec::with_capacity(40) };
+        d.refresh();
+        d
+    }
+
+    pub fn refresh(&mut self) {
+        let now = unix_now();
+        if now == self.secs {
+            return;
+        }
+        self.secs = now;
+        self.line.clear();
+        self.line.extend_from_slice(b"Date: ");
+        write_imf_fixdate(&mut self.line, now);
+        self.line.extend_from_slice(b"\r\n");
+    }
+
+    #[inline]
+    pub fn line(&self) -> &[u8] {
+        &self.line
+    }
+}
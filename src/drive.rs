@@ -0,0 +1,560 @@
+//! One connection's turn in an event-loop round: read, process and write until the socket
+//! would block, the connection must be closed or it has used up its round budget, then
+//! arm its timer. Workers drive their `Stream`s with `drive`, and `transport::Machine`
+//! drives scripted streams with the same function, so tests exercise the worker's loop
+//! rather than a copy of it.
+//!
+//! Whatever a turn does beyond the connection's own bytes goes through `Host`: the poll
+//! registration, the timer wheel, the close and yield queues, and the work only a real
+//! socket can take on (TLS, tunnels, route backends, `sendfile`).
+
+use crate::access;
+use crate::capture::{Capture, Direction};
+use crate::close::{CloseMode, CloseReason};
+use crate::config::{LINGER_TIMEOUT, ROUND_BYTES, ROUND_REQUESTS};
+use crate::conn::{Conn, ConnState};
+use crate::counter::HandshakeFailure;
+use crate::exec::{Chunk, Exec};
+use crate::fault::FaultAction;
+use crate::handler::{Handler, Progress};
+use crate::sizes::SizeStats;
+use crate::spill::Next;
+use crate::transport::Transport;
+use crate::upstream::UpstreamPool;
+use mio::Token;
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// What driving a connection needs from around it. The socket-only operations default to
+/// failing, as they do on a transport that cannot take them on.
+pub(crate) trait Host<T: Transport> {
+    fn capture(&self) -> Option<&'static Capture> {
+        None
+    }
+
+    fn sizes(&self) -> Option<&'static SizeStats> {
+        None
+    }
+
+    /// Marks `conn` closing and has it closed once this round's events are handled.
+    fn close_later(&mut self, conn: &mut Conn<T>, token: Token);
+
+    /// Has `conn` driven again next round, since no new edge will report what it has buffered.
+    fn yield_later(&mut self, conn: &mut Conn<T>, token: Token);
+
+    /// Watches `conn` for writable events as well as readable ones, or for readable ones only.
+    fn set_writable(&mut self, conn: &mut Conn<T>, token: Token, writable: bool) -> io::Result<()>;
+
+    /// Fires the timeout of `token` after `timeout`, unless its epoch has moved on by then.
+    fn arm_timer(&mut self, token: Token, epoch: u64, timeout: Duration);
+
+    /// Has the socket reset rather than closed.
+    fn abort(&mut self, _conn: &Conn<T>) {}
+
+    /// Takes the connection's bytes read so far as a ClientHello and starts a handshake.
+    fn start_tls(&mut self, _conn: &mut Conn<T>) -> Result<(), HandshakeFailure> {
+        Err(HandshakeFailure::Incompatible)
+    }
+
+    /// Moves the handshake on; true once it is complete.
+    fn handshake(&mut self, _conn: &mut Conn<T>) -> io::Result<bool> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Connects to `addr` and switches `conn` to relaying; see `Progress::Tunnel`.
+    fn open_tunnel(
+        &mut self,
+        _conn: &mut Conn<T>,
+        _token: Token,
+        _addr: SocketAddr,
+        _established: bool,
+        _group: Option<usize>,
+        _mirror: Option<usize>,
+    ) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Watches the route backend of the connection `token` for output.
+    fn watch_backend(&mut self, _exec: &mut Exec, _token: Token) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Stops watching a route backend and drops it; a connection fit for another request
+    /// goes back to `pool`.
+    fn end_backend(&mut self, _exec: Exec, _pool: &mut UpstreamPool, _now: Instant) {}
+
+    /// Sends the spill file of `conn` as `do_write` sends a response.
+    fn send_spilled(&mut self, _conn: &mut Conn<T>, _token: Token, _budget: &mut Budget) -> io::Result<Flushed> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Reads, processes and writes on `token` until it would block, must be closed or has
+/// used up its round budget, then arms its timer for the state it was left in.
+pub(crate) fn drive<T: Transport>(conn: &mut Conn<T>, token: Token, handler: &mut Handler, host: &mut impl Host<T>, now: Instant) {
+    conn.touch(now);
+    turn(conn, token, handler, host, now);
+    let cfg = handler.cfg;
+    let deadline = match conn.state() {
+        ConnState::Draining => conn.linger_until,
+        ConnState::Connecting => conn.tunnel.as_ref().and_then(|t| t.upstream.deadline()),
+        ConnState::Writing => conn.write_deadline(cfg.write_timeout, cfg.min_send_rate),
+        ConnState::Parked | ConnState::Queued | ConnState::Handshaking => conn.parked_until,
+        _ => None,
+    };
+    let timeout = match deadline {
+        Some(deadline) => deadline.saturating_duration_since(now),
+        None => cfg.idle_timeout(conn.idle_class()),
+    };
+    host.arm_timer(token, conn.epoch, timeout);
+}
+
+fn turn<T: Transport>(conn: &mut Conn<T>, token: Token, handler: &mut Handler, host: &mut impl Host<T>, now: Instant) {
+    let mut budget = Budget::round();
+    let mut drained = false;
+    loop {
+        match conn.state() {
+            ConnState::Closing => return,
+            ConnState::Draining => {
+                if !discard(conn, now) {
+                    host.close_later(conn, token);
+                }
+                return;
+            }
+            ConnState::Parked | ConnState::Queued => {
+                // Only read to notice the client going away; anything it pipelines
+                // stays buffered until the request has been answered.
+                if fill(conn, token, host.capture(), &mut budget).is_none() {
+                    host.close_later(conn, token);
+                }
+                return;
+            }
+            ConnState::Handshaking => match host.handshake(conn) {
+                Ok(true) => conn.end_handshake(),
+                Ok(false) => return,
+                Err(e) => {
+                    let failure = handshake_failure(&e);
+                    eprintln!("[info] TLS handshake with {} failed ({}): {e}, closing", conn.peer, failure.name());
+                    handler.counter.handshake_failure(handler.thread_id, failure);
+                    host.close_later(conn, token);
+                    return;
+                }
+            },
+            ConnState::Connecting | ConnState::Tunneling => {
+                let tunnel = conn.tunnel.as_mut().expect("tunneling connection without a tunnel");
+                match tunnel.pump(&mut conn.stream, now) {
+                    Ok(false) => {
+                        if !tunnel.connecting() && conn.state() == ConnState::Connecting {
+                            conn.tunnel_connected();
+                        }
+                    }
+                    Ok(true) => host.close_later(conn, token),
+                    Err(e) => {
+                        eprintln!("[info] tunnel on {:?} failed: {e}", token);
+                        host.close_later(conn, token);
+                    }
+                }
+                return;
+            }
+            ConnState::Streaming => {
+                // As when parked, the client is read only to notice it going away.
+                if fill(conn, token, host.capture(), &mut budget).is_none() {
+                    host.close_later(conn, token);
+                    return;
+                }
+                if let Some(exec) = conn.exec.as_mut().filter(|e| !e.registered()) {
+                    if let Err(e) = host.watch_backend(exec, token) {
+                        eprintln!("[warn] cannot watch route backend on {:?}: {e}", token);
+                        host.close_later(conn, token);
+                        return;
+                    }
+                }
+                if conn.spill.is_some() {
+                    match read_ahead(conn, handler, host, now) {
+                        Ok(true) => host.yield_later(conn, token),
+                        Ok(false) => {}
+                        Err(e) => {
+                            eprintln!("[warn] reading route backend output on {:?} failed: {e}", token);
+                            host.close_later(conn, token);
+                            return;
+                        }
+                    }
+                    let spill = conn.spill.as_mut().expect("spilling connection without its spill");
+                    match spill.take(&mut conn.out) {
+                        Next::Memory => {}
+                        Next::File => match host.send_spilled(conn, token, &mut budget) {
+                            Ok(Flushed::Done) => continue,
+                            Ok(Flushed::Blocked) => return,
+                            Ok(Flushed::Paused) => {
+                                host.yield_later(conn, token);
+                                return;
+                            }
+                            Err(e) => {
+                                eprintln!("[warn] write error on {:?}: {e}", token);
+                                host.close_later(conn, token);
+                                return;
+                            }
+                        },
+                        Next::Nothing => return,
+                    }
+                    conn.set_response_owned();
+                    conn.arm_write();
+                    continue;
+                }
+                let exec = conn.exec.as_mut().expect("streaming connection without a backend");
+                let chunk = exec.read_chunk(&mut conn.out);
+                if let (Ok(chunk), Some(id)) = (&chunk, conn.flight) {
+                    handler.collapse.feed(id, &conn.out, chunk);
+                }
+                match chunk {
+                    Ok(Chunk::Pending) => return,
+                    Ok(Chunk::Data) => {}
+                    Ok(Chunk::Last) => {
+                        if let Some(exec) = conn.exec.take() {
+                            host.end_backend(*exec, &mut handler.upstream_pool, now);
+                        }
+                    }
+                    Ok(Chunk::Failed) => {
+                        if let Some(exec) = conn.exec.take() {
+                            host.end_backend(*exec, &mut handler.upstream_pool, now);
+                        }
+                        conn.close_after_write = true;
+                        conn.close_reason = Some(CloseReason::Error);
+                    }
+                    Err(e) => {
+                        eprintln!("[warn] reading route backend output on {:?} failed: {e}", token);
+                        host.close_later(conn, token);
+                        return;
+                    }
+                }
+                conn.set_response_owned();
+                conn.arm_write();
+                continue;
+            }
+            ConnState::Writing => {
+                if let Err(e) = do_write(conn, token, host, &mut budget) {
+                    eprintln!("[warn] write error on {:?}: {e}", token);
+                    host.close_later(conn, token);
+                    return;
+                }
+                if conn.state() == ConnState::Writing {
+                    if let Some(f) = conn.fault.filter(|f| conn.write_pos >= f.at) {
+                        match f.action {
+                            FaultAction::Stall => {}
+                            FaultAction::Reset => {
+                                host.abort(conn);
+                                host.close_later(conn, token);
+                            }
+                            FaultAction::Close => host.close_later(conn, token),
+                        }
+                    } else if budget.spent() {
+                        host.yield_later(conn, token);
+                    }
+                    if conn.spill.is_some() {
+                        // Keep taking the backend's output while the client is slow.
+                        match read_ahead(conn, handler, host, now) {
+                            Ok(true) => host.yield_later(conn, token),
+                            Ok(false) => {}
+                            Err(e) => {
+                                eprintln!("[warn] reading route backend output on {:?} failed: {e}", token);
+                                host.close_later(conn, token);
+                            }
+                        }
+                    }
+                    return;
+                }
+                if conn.state() == ConnState::Streaming {
+                    // Only part of the response: a route command's output goes on.
+                    if budget.spent() {
+                        host.yield_later(conn, token);
+                        return;
+                    }
+                    continue;
+                }
+                if let Some(start) = conn.timing.write_start.take() {
+                    conn.timing.last_write = Some(start.elapsed());
+                }
+                handler.release_slot(conn);
+                if let Some(start) = conn.timing.request_start.take() {
+                    let cfg = handler.cfg;
+                    let elapsed = start.elapsed();
+                    let slow = cfg.slow_request.is_some_and(|t| elapsed >= t);
+                    if slow || handler.rng.chance(cfg.access_log_sample) {
+                        access::log(conn, elapsed, slow);
+                    }
+                }
+                if let Some(sizes) = host.sizes() {
+                    sizes.record(conn.route, conn.request_bytes, conn.outgoing().len() as u64);
+                }
+                conn.request_bytes = 0;
+                conn.requests += 1;
+                handler.counter.increment(handler.thread_id);
+                if conn.close_after_write {
+                    let reason = conn.close_reason.unwrap_or(CloseReason::Error);
+                    let mode = handler.cfg.close_mode(reason);
+                    handler.counter.closed(handler.thread_id, reason, mode);
+                    close_as(host, conn, token, reason, mode, now);
+                }
+                budget.requests = budget.requests.saturating_sub(1);
+                if budget.spent() && !matches!(conn.state(), ConnState::Draining | ConnState::Closing) {
+                    host.yield_later(conn, token);
+                    return;
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        match handler.process(conn) {
+            // Written right away; `do_write` asks for writable events only if it blocks.
+            Progress::Armed => continue,
+            Progress::Close => {
+                let reason = CloseReason::Error;
+                let mode = handler.cfg.close_mode(reason);
+                handler.counter.closed(handler.thread_id, reason, mode);
+                close_as(host, conn, token, reason, mode, now);
+                return;
+            }
+            Progress::Tunnel { addr, established, group, mirror } => {
+                if let Err(e) = host.open_tunnel(conn, token, addr, established, group, mirror) {
+                    eprintln!("[warn] forward proxy: cannot connect to {addr}: {e}");
+                    host.close_later(conn, token);
+                    return;
+                }
+                continue;
+            }
+            Progress::Parked | Progress::Queued => return,
+            Progress::Tls => {
+                if let Err(failure) = host.start_tls(conn) {
+                    handler.counter.handshake_failure(handler.thread_id, failure);
+                    host.close_later(conn, token);
+                    return;
+                }
+                (conn.read_len, conn.scan_offset) = (0, 0);
+                conn.begin_handshake(now + handler.cfg.tls_handshake_timeout);
+                continue;
+            }
+            Progress::Follow(id) => {
+                handler.collapse.join(id, token);
+                continue;
+            }
+            Progress::NeedMore if drained => return,
+            Progress::NeedMore if budget.spent() => {
+                host.yield_later(conn, token);
+                return;
+            }
+            Progress::NeedMore => {}
+        }
+
+        match fill(conn, token, host.capture(), &mut budget) {
+            Some(d) => drained = d,
+            None => {
+                host.close_later(conn, token);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+fn handshake_failure(e: &io::Error) -> HandshakeFailure {
+    crate::tls::failure(e)
+}
+
+#[cfg(not(feature = "tls"))]
+fn handshake_failure(_: &io::Error) -> HandshakeFailure {
+    HandshakeFailure::Malformed
+}
+
+/// Reads the route backend's output into `conn.spill` until it would block, the spill is
+/// full or `ROUND_BYTES` have been read; true in the last case, with more to read. A backend
+/// done is handed to `Host::end_backend`.
+fn read_ahead<T: Transport>(conn: &mut Conn<T>, handler: &mut Handler, host: &mut impl Host<T>, now: Instant) -> io::Result<bool> {
+    let (Some(exec), Some(spill)) = (conn.exec.as_mut(), conn.spill.as_mut()) else { return Ok(false) };
+    let mut read = 0;
+    let failed = loop {
+        if spill.full() {
+            return Ok(false);
+        }
+        if read >= ROUND_BYTES {
+            return Ok(true);
+        }
+        let chunk = exec.read_chunk(&mut spill.chunk)?;
+        if let Some(id) = conn.flight {
+            handler.collapse.feed(id, &spill.chunk, &chunk);
+        }
+        match chunk {
+            Chunk::Pending => return Ok(false),
+            Chunk::Data => {
+                read += spill.chunk.len();
+                spill.push_chunk()?;
+            }
+            Chunk::Last => break false,
+            Chunk::Failed => break true,
+        }
+    };
+    spill.push_last();
+    if failed {
+        conn.close_after_write = true;
+        conn.close_reason = Some(CloseReason::Error);
+    }
+    if let Some(exec) = conn.exec.take() {
+        host.end_backend(*exec, &mut handler.upstream_pool, now);
+    }
+    Ok(false)
+}
+
+/// What one connection may still do this round before the others get a turn; without it a
+/// client that keeps its socket full would be served until it stopped sending.
+pub(crate) struct Budget {
+    pub(crate) bytes: usize,
+    pub(crate) requests: u32,
+}
+
+impl Budget {
+    pub(crate) fn round() -> Self {
+        Self { bytes: ROUND_BYTES, requests: ROUND_REQUESTS }
+    }
+
+    pub(crate) fn spent(&self) -> bool {
+        self.bytes == 0 || self.requests == 0
+    }
+}
+
+/// Reads until the socket would block, the buffer is full or the budget is spent.
+/// Returns whether the socket was drained, or `None` if the connection must be closed.
+pub(crate) fn fill<T: Transport>(conn: &mut Conn<T>, token: Token, capture: Option<&'static Capture>, budget: &mut Budget) -> Option<bool> {
+    loop {
+        if conn.read_len >= conn.read_buf.len() || budget.spent() {
+            return Some(false);
+        }
+        let dst = &mut conn.read_buf[conn.read_len..];
+        match conn.stream.read(dst) {
+            Ok(0) => return None,
+            Ok(n) => {
+                if let (Some(cap), Some(id)) = (capture, conn.capture_id) {
+                    cap.record(id, Direction::Request, &conn.read_buf[conn.read_len..conn.read_len + n]);
+                }
+                conn.read_len += n;
+                budget.bytes = budget.bytes.saturating_sub(n);
+                conn.on_data();
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Some(true),
+            Err(e) => {
+                eprintln!("[warn] read error on {:?}: {e}", token);
+                return None;
+            }
+        }
+    }
+}
+
+/// Closes `conn` for `reason` in `mode`: at once, with an RST for `CloseMode::Rst`, or
+/// after discarding what the client still sends until `LINGER_TIMEOUT` from `now`.
+pub(crate) fn close_as<T: Transport>(
+    host: &mut impl Host<T>,
+    conn: &mut Conn<T>,
+    token: Token,
+    reason: CloseReason,
+    mode: CloseMode,
+    now: Instant,
+) {
+    conn.close_reason = Some(reason);
+    match mode {
+        CloseMode::Drain => {
+            let _ = conn.stream.shutdown(Shutdown::Write);
+            conn.begin_draining(now + LINGER_TIMEOUT);
+        }
+        CloseMode::Rst => {
+            host.abort(conn);
+            host.close_later(conn, token);
+        }
+        CloseMode::Fin => host.close_later(conn, token),
+    }
+}
+
+/// Reads and throws away whatever the client still sends after an error response.
+/// Returns false once the connection should be closed (EOF, error or linger deadline passed).
+pub(crate) fn discard<T: Transport>(conn: &mut Conn<T>, now: Instant) -> bool {
+    if conn.linger_until.is_some_and(|until| now >= until) {
+        return false;
+    }
+    loop {
+        match conn.stream.read(&mut conn.read_buf[..]) {
+            Ok(0) => return false,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+            Err(_) => return false,
+        }
+    }
+}
+
+/// How far `write_out` got.
+pub(crate) enum Flushed {
+    /// The response is out.
+    Done,
+    /// The socket would block.
+    Blocked,
+    /// Stopped at a fault's cut-off point or by the budget, or nothing is being written.
+    Paused,
+}
+
+/// Writes until the response is out, the socket would block or the budget is spent, and
+/// asks for writable events only while it blocks.
+fn do_write<T: Transport>(conn: &mut Conn<T>, token: Token, host: &mut impl Host<T>, budget: &mut Budget) -> io::Result<()> {
+    match write_out(conn, host.capture(), budget)? {
+        Flushed::Done if conn.write_interest => {
+            conn.write_interest = false;
+            let _ = host.set_writable(conn, token, false);
+        }
+        Flushed::Blocked if !conn.write_interest => {
+            conn.write_interest = true;
+            host.set_writable(conn, token, true)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+pub(crate) fn write_out<T: Transport>(
+    conn: &mut Conn<T>,
+    capture: Option<&'static Capture>,
+    budget: &mut Budget,
+) -> io::Result<Flushed> {
+    if conn.state() != ConnState::Writing {
+        return Ok(Flushed::Paused);
+    }
+    let mut current_pos = conn.write_pos;
+
+    let end = conn.fault.map_or(usize::MAX, |f| f.at);
+    loop {
+        if current_pos >= end || budget.bytes == 0 {
+            return Ok(Flushed::Paused);
+        }
+        let len = if conn.owned { conn.out.len() } else { conn.write_buf.len() };
+        let limit = len.min(end).min(current_pos + budget.bytes);
+        let slice = if conn.owned { &conn.out[current_pos..limit] } else { &conn.write_buf[current_pos..limit] };
+        match conn.stream.write(slice) {
+            Ok(n) => {
+                if let (Some(cap), Some(id)) = (capture, conn.capture_id) {
+                    cap.record(id, Direction::Response, &slice[..n]);
+                }
+                current_pos += n;
+                conn.write_pos = current_pos;
+                budget.bytes = budget.bytes.saturating_sub(n);
+                if !conn.has_pending_write() {
+                    // Records a TLS stream could not send yet go out before the next response.
+                    match conn.stream.flush() {
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Flushed::Blocked),
+                        Err(e) => return Err(e),
+                        Ok(()) => {}
+                    }
+                    conn.finish_write();
+                    return Ok(Flushed::Done);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Flushed::Blocked),
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use crate::http::RequestHead;
+
+/// Content codings a file may be precompressed with, in order of preference.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Br,
+    Gzip,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 2] = [Encoding::Br, Encoding::Gzip];
+
+    /// File name suffix of the precompressed sibling.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Encoding::Br => ".br",
+            Encoding::Gzip => ".gz",
+        }
+    }
+
+    pub fn token(self) -> &'static str {
+        match self {
+            Encoding::Br => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Whether `Accept-Encoding` allows `enc`: listed (or covered by `*`) with a non-zero q-value.
+pub fn accepts(head: &RequestHead, enc: Encoding) -> bool {
+    let mut wildcard = None;
+    for (_, value) in head.headers().filter(|(n, _)| n.eq_ignore_ascii_case(b"accept-encoding")) {
+        for item in value.split(|&b| b == b',') {
+            let mut params = item.split(|&b| b == b';');
+            let coding = params.next().unwrap_or(&[]).trim_ascii();
+            let allowed = params.all(|p| !is_zero_q(p.trim_ascii()));
+            if coding.eq_ignore_ascii_case(enc.token().as_bytes())
+                || (enc == Encoding::Gzip && coding.eq_ignore_ascii_case(b"x-gzip"))
+            {
+                return allowed;
+            }
+            if coding == b"*" {
+                wildcard = Some(allowed);
+            }
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+/// `q=0`, `q=0.`, `q=0.0`, ... — the coding is explicitly refused.
+fn is_zero_q(param: &[u8]) -> bool {
+    let Some(v) = param.strip_prefix(b"q=").or_else(|| param.strip_prefix(b"Q=")) else {
+        return false;
+    };
+    match v.strip_prefix(b"0") {
+        Some(rest) => rest.is_empty() || (rest[0] == b'.' && rest[1..].iter().all(|&b| b == b'0')),
+        None => false,
+    }
+}
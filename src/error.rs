@@ -0,0 +1,66 @@
+use crate::config::{RESTART_BACKOFF_MAX, RESTART_BACKOFF_MIN};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Failures in setting up or running the server that are reported rather than panicked on.
+#[derive(Debug)]
+pub enum VryptError {
+    /// The listen file could not be read or parsed.
+    ListenFile { path: PathBuf, source: io::Error },
+    /// Binding or listening on an address failed.
+    Bind { addr: SocketAddr, source: io::Error },
+    /// Creating, registering with or waiting on an epoll instance failed.
+    Poll(io::Error),
+    /// A worker or accept thread could not be started.
+    Spawn(io::Error),
+    /// Switching to `--user` / `--group` or dropping capabilities failed.
+    Privileges(io::Error),
+    /// The `--tls-cert` / `--tls-key` files could not be read or used.
+    Tls(String),
+}
+
+impl VryptError {
+    /// Whether retrying after a pause may succeed: an address still held by a previous
+    /// instance, or the process briefly out of descriptors or memory.
+    pub fn is_transient(&self) -> bool {
+        let source = match self {
+            VryptError::ListenFile { .. } | VryptError::Privileges(_) | VryptError::Tls(_) => return false,
+            VryptError::Bind { source, .. } => source,
+            VryptError::Poll(source) | VryptError::Spawn(source) => source,
+        };
+        matches!(source.kind(), io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable)
+            || matches!(source.raw_os_error(), Some(libc::EMFILE | libc::ENFILE | libc::ENOMEM | libc::EAGAIN))
+    }
+}
+
+impl fmt::Display for VryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VryptError::ListenFile { path, source } => write!(f, "cannot read listen file {}: {source}", path.display()),
+            VryptError::Bind { addr, source } => write!(f, "cannot listen on {addr}: {source}"),
+            VryptError::Poll(source) => write!(f, "event loop failed: {source}"),
+            VryptError::Spawn(source) => write!(f, "cannot start thread: {source}"),
+            VryptError::Privileges(source) => write!(f, "cannot drop privileges: {source}"),
+            VryptError::Tls(reason) => write!(f, "cannot set up TLS: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for VryptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VryptError::ListenFile { source, .. } | VryptError::Bind { source, .. } => Some(source),
+            VryptError::Poll(source) | VryptError::Spawn(source) | VryptError::Privileges(source) => Some(source),
+            VryptError::Tls(_) => None,
+        }
+    }
+}
+
+/// Pause before the next attempt after `failures` consecutive failures: doubling from
+/// `RESTART_BACKOFF_MIN` up to `RESTART_BACKOFF_MAX`.
+pub fn backoff(failures: u32) -> Duration {
+    RESTART_BACKOFF_MIN.saturating_mul(1 << failures.min(16)).min(RESTART_BACKOFF_MAX)
+}
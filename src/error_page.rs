@@ -0,0 +1,93 @@
+//! Custom bodies for the error responses (`--error-page`), globally or per virtual host.
+//!
+//! vrypt has no notion of a missing resource or a handler failure, so there is no 404 or
+//! 500 to customise; the statuses below are the ones its error paths send.
+
+use crate::mime::MimeMap;
+use std::io;
+use std::path::PathBuf;
+
+/// Error statuses that can be given a custom page, with their status lines.
+pub const STATUSES: [(u16, &str); 8] = [
+    (400, "400 Bad Request"),
+    (403, "403 Forbidden"),
+    (408, "408 Request Timeout"),
+    (413, "413 Content Too Large"),
+    (429, "429 Too Many Requests"),
+    (431, "431 Request Header Fields Too Large"),
+    (502, "502 Bad Gateway"),
+    (503, "503 Service Unavailable"),
+];
+
+/// Where a page's body comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PageBody {
+    Inline(String),
+    /// Read once at startup; the `Content-Type` follows the file's extension.
+    File(PathBuf),
+}
+
+/// One `--error-page` rule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorPage {
+    /// Host name the page applies to, lowercase and without a port; `None` for every host.
+    pub host: Option<String>,
+    pub status: u16,
+    pub body: PageBody,
+}
+
+impl ErrorPage {
+    /// Parses `[host/]status=@file` or `[host/]status=text`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let bad = || format!("expected '[host/]status=@file' or '[host/]status=text', got '{spec}'");
+        let (key, body) = spec.split_once('=').ok_or_else(bad)?;
+        let (host, status) = match key.rsplit_once('/') {
+            Some(("", _)) => return Err(format!("empty host in '{spec}'")),
+            Some((host, status)) => (Some(host.to_ascii_lowercase()), status),
+            None => (None, key),
+        };
+        let status = status
+            .parse::<u16>()
+            .ok()
+            .filter(|s| STATUSES.iter().any(|(code, _)| code == s))
+            .ok_or_else(|| format!("status must be one of {}, got '{status}'", status_list()))?;
+        let body = match body.strip_prefix('@') {
+            Some("") => return Err(format!("missing file name in '{spec}'")),
+            Some(path) => PageBody::File(PathBuf::from(path)),
+            None => PageBody::Inline(body.to_string()),
+        };
+        Ok(Self { host, status, body })
+    }
+
+    /// The page's body and `Content-Type`.
+    pub fn load(&self, mime: &MimeMap) -> io::Result<(Vec<u8>, String)> {
+        match &self.body {
+            PageBody::Inline(text) => Ok((text.as_bytes().to_vec(), mime.text_plain())),
+            PageBody::File(path) => Ok((std::fs::read(path)?, mime.for_path(path))),
+        }
+    }
+}
+
+/// The status line for `status`, if it is one of `STATUSES`.
+pub fn status_line(status: u16) -> Option<&'static str> {
+    STATUSES.iter().find(|(code, _)| *code == status).map(|(_, line)| *line)
+}
+
+fn status_list() -> String {
+    STATUSES.iter().map(|(code, _)| code.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Whether a request whose `Host` header is `host` is for the virtual host `name`.
+pub fn host_matches(name: &str, host: &[u8]) -> bool {
+    host_name(host).eq_ignore_ascii_case(name.as_bytes())
+}
+
+/// `Host` header value without its port; bracketed IPv6 literals keep their brackets.
+fn host_name(host: &[u8]) -> &[u8] {
+    let host = host.trim_ascii();
+    let end = match host.iter().rposition(|&b| b == b':') {
+        Some(colon) if !host[colon..].contains(&b']') => colon,
+        _ => host.len(),
+    };
+    &host[..end]
+}
@@ -0,0 +1,260 @@
+//! Command routes (`--static-route exec:PATH=COMMAND`): each request runs `COMMAND` and its
+//! stdout, read through a pipe registered with the worker's poll, is sent as the chunked
+//! response body. Commands run at most `--exec-max` at once across the workers; requests
+//! past that get `503`.
+//!
+//! The command is started without a shell and gets a CGI-style environment
+//! (`REQUEST_METHOD`, `QUERY_STRING`, `HTTP_*`, ...) and no stdin: the request body is not
+//! passed on. It is done once it closes its stdout; one still running then is killed.
+//!
+//! FastCGI and SCGI routes (see `fastcgi`) are relayed the same way and share the cap.
+
+use crate::config::{BUF_SIZE, SERVER_HEADER};
+use crate::fastcgi::Gateway;
+use crate::headers::HeaderRule;
+use crate::http::RequestHead;
+use crate::limit::InflightLimit;
+use crate::routes::{Payload, Route};
+use crate::upstream::Upstream;
+use mio::unix::pipe;
+use mio::{Interest, Registry, Token};
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::process::{Child, Command, Stdio};
+
+/// What a read of the backend's output produced, in `out`.
+pub enum Chunk {
+    /// Nothing to read yet; the next readable event continues.
+    Pending,
+    /// Part of the response.
+    Data,
+    /// The rest of the response: the backend is done.
+    Last,
+    /// The backend failed before its response head: `out` holds a `502`, after which the
+    /// connection is closed.
+    Failed,
+}
+
+enum Backend {
+    Command(Running),
+    Gateway(Box<Gateway>),
+}
+
+/// A command started for a request; one still running when dropped is killed.
+struct Running {
+    child: Child,
+    stdout: pipe::Receiver,
+    path: String,
+}
+
+/// One of the `--exec-max` slots, given back when dropped.
+struct Slot(&'static InflightLimit);
+
+/// A running command or FastCGI/SCGI request whose output is a response body.
+pub struct Exec {
+    backend: Backend,
+    /// Set once the worker has registered the backend with its poll.
+    token: Option<Token>,
+    /// The route's path, for logging.
+    path: String,
+    _slot: Slot,
+    buf: Box<[u8]>,
+}
+
+impl Exec {
+    /// Starts the command of the `exec:` route `route` for the request `head`, taking one
+    /// of `slots`. `None` if they are all taken.
+    pub fn spawn(
+        slots: &'static InflightLimit,
+        route: &Route,
+        head: &RequestHead,
+        peer: SocketAddr,
+    ) -> io::Result<Option<Self>> {
+        Self::start(slots, route, || {
+            let mut child = start(route, head, peer)?;
+            let stdout = pipe::Receiver::from(child.stdout.take().expect("stdout is piped"));
+            if let Err(e) = stdout.set_nonblocking(true) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e);
+            }
+            Ok(Backend::Command(Running { child, stdout, path: route.path.clone() }))
+        })
+    }
+
+    /// Relays a request to the backend of the FastCGI or SCGI route `route` over the
+    /// connection `gateway` makes, taking one of `slots` first. `None` if they are all taken.
+    pub fn connect(
+        slots: &'static InflightLimit,
+        route: &Route,
+        gateway: impl FnOnce() -> io::Result<Gateway>,
+    ) -> io::Result<Option<Self>> {
+        Self::start(slots, route, || gateway().map(|g| Backend::Gateway(Box::new(g))))
+    }
+
+    fn start(
+        slots: &'static InflightLimit,
+        route: &Route,
+        backend: impl FnOnce() -> io::Result<Backend>,
+    ) -> io::Result<Option<Self>> {
+        if !slots.try_acquire() {
+            return Ok(None);
+        }
+        let slot = Slot(slots);
+        let backend = backend()?;
+        let buf = vec![0; BUF_SIZE].into_boxed_slice();
+        Ok(Some(Self { backend, token: None, path: route.path.clone(), _slot: slot, buf }))
+    }
+
+    /// The backend renders the response head itself, from its CGI response; a command's
+    /// output is only the body of the route's head.
+    pub fn relays_head(&self) -> bool {
+        matches!(self.backend, Backend::Gateway(_))
+    }
+
+    /// Completes the request sent to a FastCGI or SCGI backend with its `body`.
+    pub fn finish_request(&mut self, body: &[u8]) {
+        if let Backend::Gateway(g) = &mut self.backend {
+            g.finish_request(body);
+        }
+    }
+
+    pub fn registered(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Registers the backend's output (and a gateway's request side) under `token`.
+    pub fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        self.token = Some(token);
+        match &mut self.backend {
+            Backend::Command(c) => registry.register(&mut c.stdout, token, Interest::READABLE),
+            Backend::Gateway(g) => {
+                registry.register(&mut g.upstream.stream, token, Interest::READABLE | Interest::WRITABLE)
+            }
+        }
+    }
+
+    /// Undoes `register`, handing back the token it was given.
+    pub fn deregister(&mut self, registry: &Registry) -> Option<Token> {
+        let token = self.token.take()?;
+        let _ = match &mut self.backend {
+            Backend::Command(c) => registry.deregister(&mut c.stdout),
+            Backend::Gateway(g) => registry.deregister(&mut g.upstream.stream),
+        };
+        Some(token)
+    }
+
+    /// The backend connection, for the next request to it, if the response ended cleanly
+    /// on a connection the backend was asked to keep.
+    pub fn into_upstream(self) -> Option<Upstream> {
+        match self.backend {
+            Backend::Gateway(g) => g.into_upstream(),
+            Backend::Command(_) => None,
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Reads what the backend has sent so far and frames it as part of the response in `out`.
+    pub fn read_chunk(&mut self, out: &mut Vec<u8>) -> io::Result<Chunk> {
+        out.clear();
+        let stdout = match &mut self.backend {
+            Backend::Command(c) => &mut c.stdout,
+            Backend::Gateway(g) => return g.read_chunk(&mut self.buf, out, &self.path),
+        };
+        loop {
+            return match stdout.read(&mut self.buf) {
+                Ok(0) => {
+                    out.extend_from_slice(b"0\r\n\r\n");
+                    Ok(Chunk::Last)
+                }
+                Ok(n) => {
+                    let _ = write!(out, "{n:x}\r\n");
+                    out.extend_from_slice(&self.buf[..n]);
+                    out.extend_from_slice(b"\r\n");
+                    Ok(Chunk::Data)
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Chunk::Pending),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        match self.child.try_wait() {
+            Ok(Some(status)) if !status.success() => eprintln!("[warn] command for {}: {status}", self.path),
+            Ok(Some(_)) => {}
+            _ => {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+            }
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// Whether requests to routes with `payload` are answered by an `Exec`.
+pub fn runs(payload: Payload) -> bool {
+    matches!(payload, Payload::Exec | Payload::FastCgi | Payload::Scgi)
+}
+
+/// Passes the CGI variables of the request `head` to `route` to `var`, its request headers
+/// edited by the route's `--route-request-header` rules.
+pub(crate) fn cgi_vars(route: &Route, head: &RequestHead, peer: SocketAddr, mut var: impl FnMut(&[u8], &[u8])) {
+    var(b"GATEWAY_INTERFACE", b"CGI/1.1");
+    var(b"SERVER_PROTOCOL", b"HTTP/1.1");
+    var(b"SERVER_SOFTWARE", SERVER_HEADER.trim_start_matches("Server: ").as_bytes());
+    var(b"REQUEST_METHOD", head.method);
+    var(b"REQUEST_URI", head.target);
+    var(b"SCRIPT_NAME", route.path.as_bytes());
+    var(b"QUERY_STRING", head.query().unwrap_or_default());
+    var(b"REMOTE_ADDR", peer.ip().to_string().as_bytes());
+    var(b"REMOTE_PORT", peer.port().to_string().as_bytes());
+    let rules = route.request_headers();
+    let mut name = Vec::new();
+    for (header, value) in head.headers() {
+        // A `Proxy` header would become `HTTP_PROXY`, which many HTTP clients take as theirs.
+        if header.eq_ignore_ascii_case(b"proxy") || rules.iter().any(|r| r.name().eq_ignore_ascii_case(header)) {
+            continue;
+        }
+        var(http_var(&mut name, header), value);
+    }
+    for rule in rules {
+        if let HeaderRule::Set(header, value) = rule {
+            var(http_var(&mut name, header), value);
+        }
+    }
+}
+
+/// The `HTTP_*` variable for the request header `header`, built in `name`.
+fn http_var<'a>(name: &'a mut Vec<u8>, header: &[u8]) -> &'a [u8] {
+    name.clear();
+    name.extend_from_slice(b"HTTP_");
+    name.extend(header.iter().map(|&b| if b == b'-' { b'_' } else { b.to_ascii_uppercase() }));
+    name
+}
+
+fn start(route: &Route, head: &RequestHead, peer: SocketAddr) -> io::Result<Child> {
+    let (program, args) = route.backend().unwrap_or_default().split_first().ok_or_else(|| io::Error::other("empty command"))?;
+    let mut cmd = Command::new(program);
+    cmd.args(args).env_clear().stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::inherit());
+    if let Some(p) = std::env::var_os("PATH") {
+        cmd.env("PATH", p);
+    }
+    cgi_vars(route, head, peer, |name, value| {
+        cmd.env(OsStr::from_bytes(name), OsStr::from_bytes(value));
+    });
+    cmd.spawn()
+}
@@ -0,0 +1,379 @@
+//! FastCGI and SCGI routes (`--static-route fastcgi:PATH=SOCKET [SCRIPT]`, `scgi:`): each
+//! request is relayed to a backend such as php-fpm over a unix socket, and the backend's
+//! CGI response — `Status` and headers, then the body — is turned into the HTTP response,
+//! its body sent chunked as it arrives.
+//!
+//! A connection carries one request at a time, taken from the worker's pool (see
+//! `upstream`). FastCGI requests all have id 1 and set `FCGI_KEEP_CONN` while pooling is
+//! on, so the connection can carry the next request after `END_REQUEST`; none are
+//! multiplexed, as php-fpm does not support it (`FCGI_MPXS_CONNS` is 0).
+
+use crate::config::Config;
+use crate::date;
+use crate::exec::{self, Chunk};
+use crate::headers::{self, HeaderRule};
+use crate::http::{self, RequestHead};
+use crate::routes::{Payload, Route};
+use crate::upstream::{Upstream, UpstreamPool};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u8 = 1;
+const KEEP_CONN: u8 = 1;
+const HEADER_LEN: usize = 8;
+const MAX_CONTENT: usize = 0xffff;
+
+#[derive(PartialEq)]
+enum Progress {
+    /// The socket would block.
+    Blocked,
+    /// A buffer's worth was read; there may be more.
+    Full,
+    /// The response is complete.
+    Ended,
+}
+
+/// Response headers the relayed response frames itself.
+const FRAMING: [&[u8]; 4] = [b"content-length", b"transfer-encoding", b"connection", b"keep-alive"];
+
+/// One request relayed to a FastCGI or SCGI backend.
+pub struct Gateway {
+    pub upstream: Upstream,
+    scgi: bool,
+    /// The backend is asked to keep the connection open after the response.
+    keep: bool,
+    /// The response has ended with `END_REQUEST`.
+    ended: bool,
+    /// The request's CGI variables, encoded for the protocol; `CONTENT_LENGTH` is added by
+    /// `finish_request`.
+    vars: Vec<u8>,
+    /// Request bytes still to be written.
+    request: Vec<u8>,
+    written: usize,
+    /// FastCGI bytes read but not yet parsed into records.
+    input: Vec<u8>,
+    /// The CGI response head while it is being read; `None` once relayed.
+    head: Option<Vec<u8>>,
+    /// The request was a `HEAD`, or the response is a `204` or `304`: no body is sent.
+    bodiless: bool,
+    /// Response body bytes read in the current call.
+    data: Vec<u8>,
+    cfg: &'static Config,
+    /// The route's `--route-header` rules, applied after the global ones.
+    route_headers: Vec<HeaderRule>,
+    bad_gateway: &'static [u8],
+}
+
+impl Gateway {
+    /// Takes a connection to the backend of `route` from `pool` and encodes the variables
+    /// of the request `head`.
+    pub fn connect(
+        pool: &mut UpstreamPool,
+        route: &Route,
+        head: &RequestHead,
+        peer: SocketAddr,
+        now: Instant,
+        cfg: &'static Config,
+        bad_gateway: &'static [u8],
+    ) -> io::Result<Self> {
+        let (socket, script) = match route.backend() {
+            Some([socket, rest @ ..]) => (socket, rest.first()),
+            _ => return Err(io::Error::other("no backend socket")),
+        };
+        let scgi = route.payload() == Payload::Scgi;
+        let mut vars = Vec::with_capacity(1024);
+        let mut var = |name: &[u8], value: &[u8]| match scgi {
+            true => scgi_pair(&mut vars, name, value),
+            false => fastcgi_pair(&mut vars, name, value),
+        };
+        exec::cgi_vars(route, head, peer, &mut var);
+        if let Some(script) = script {
+            var(b"SCRIPT_FILENAME", script.as_bytes());
+        }
+        var(b"DOCUMENT_URI", head.path());
+        if let Some(ty) = head.header(b"content-type") {
+            var(b"CONTENT_TYPE", ty);
+        }
+        Ok(Self {
+            upstream: pool.connect(socket, now)?,
+            scgi,
+            keep: !scgi && pool.keeps(),
+            ended: false,
+            vars,
+            request: Vec::new(),
+            written: 0,
+            input: Vec::new(),
+            head: Some(Vec::new()),
+            bodiless: head.method == b"HEAD",
+            data: Vec::new(),
+            cfg,
+            route_headers: route.headers().to_vec(),
+            bad_gateway,
+        })
+    }
+
+    /// Encodes the whole request, ending with `body`, to be written once the socket takes it.
+    pub fn finish_request(&mut self, body: &[u8]) {
+        let out = &mut self.request;
+        let length = body.len().to_string();
+        if self.scgi {
+            // `CONTENT_LENGTH` comes first, then `SCGI`, in a netstring of all the headers.
+            let mut headers = Vec::with_capacity(self.vars.len() + 32);
+            scgi_pair(&mut headers, b"CONTENT_LENGTH", length.as_bytes());
+            scgi_pair(&mut headers, b"SCGI", b"1");
+            headers.extend_from_slice(&self.vars);
+            let _ = write!(out, "{}:", headers.len());
+            out.extend_from_slice(&headers);
+            out.push(b',');
+            out.extend_from_slice(body);
+            return;
+        }
+        let flags = if self.keep { KEEP_CONN } else { 0 };
+        record(out, BEGIN_REQUEST, &[0, RESPONDER, flags, 0, 0, 0, 0, 0]);
+        fastcgi_pair(&mut self.vars, b"CONTENT_LENGTH", length.as_bytes());
+        for (kind, content) in [(PARAMS, &self.vars[..]), (STDIN, body)] {
+            for part in content.chunks(MAX_CONTENT) {
+                record(out, kind, part);
+            }
+            record(out, kind, &[]);
+        }
+    }
+
+    /// The connection, if the backend was asked to keep it and the exchange ended with
+    /// nothing left over on it.
+    pub fn into_upstream(self) -> Option<Upstream> {
+        let clean = self.ended && self.input.is_empty() && self.written == self.request.len();
+        (self.keep && clean).then_some(self.upstream)
+    }
+
+    /// Writes what the backend takes of the request, then reads what it has sent and
+    /// relays it as part of the response in `out`, using `buf` to read into.
+    pub fn read_chunk(&mut self, buf: &mut [u8], out: &mut Vec<u8>, path: &str) -> io::Result<Chunk> {
+        loop {
+            let read = match self.exchange(buf, path) {
+                Ok(read) => read,
+                Err(e) if self.head.is_some() => return Ok(self.fail(out, path, &e.to_string())),
+                Err(e) => return Err(e),
+            };
+            match self.relay(read == Progress::Ended, out, path) {
+                // The socket is edge-triggered: only a drained one gets another event.
+                Chunk::Pending if read == Progress::Full => continue,
+                chunk => return Ok(chunk),
+            }
+        }
+    }
+
+    /// Moves request bytes out and response bytes into `data` until the socket would block,
+    /// a chunk's worth has been read or the response has ended.
+    fn exchange(&mut self, buf: &mut [u8], path: &str) -> io::Result<Progress> {
+        while self.written < self.request.len() {
+            match self.upstream.stream.write(&self.request[self.written..]) {
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.data.clear();
+        while self.data.len() < buf.len() {
+            let n = match self.upstream.stream.read(buf) {
+                Ok(0) if self.scgi => return Ok(Progress::Ended),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Progress::Blocked),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if self.scgi {
+                self.data.extend_from_slice(&buf[..n]);
+                continue;
+            }
+            self.input.extend_from_slice(&buf[..n]);
+            if self.records(path)? {
+                self.ended = true;
+                return Ok(Progress::Ended);
+            }
+        }
+        Ok(Progress::Full)
+    }
+
+    /// Parses the complete FastCGI records in `input`, keeping `STDOUT` content in `data`
+    /// and logging `STDERR`. Returns whether `END_REQUEST` was among them.
+    fn records(&mut self, path: &str) -> io::Result<bool> {
+        let mut pos = 0;
+        let mut ended = false;
+        while !ended && self.input.len() - pos >= HEADER_LEN {
+            let header = &self.input[pos..pos + HEADER_LEN];
+            if header[0] != 1 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "not a FastCGI record"));
+            }
+            let (kind, len, padding) = (header[1], u16::from_be_bytes([header[4], header[5]]) as usize, header[6]);
+            let end = pos + HEADER_LEN + len;
+            if self.input.len() < end + padding as usize {
+                break;
+            }
+            let content = &self.input[pos + HEADER_LEN..end];
+            match kind {
+                STDOUT => self.data.extend_from_slice(content),
+                STDERR => {
+                    for line in String::from_utf8_lossy(content).lines().filter(|l| !l.trim().is_empty()) {
+                        eprintln!("[fastcgi] {path}: {line}");
+                    }
+                }
+                END_REQUEST if content.len() >= 5 && content[4] != 0 => {
+                    let status = content[4];
+                    return Err(io::Error::other(format!("request refused (protocol status {status})")));
+                }
+                END_REQUEST => ended = true,
+                _ => {}
+            }
+            pos = end + padding as usize;
+        }
+        self.input.drain(..pos);
+        Ok(ended)
+    }
+
+    /// Frames the body bytes in `data` into `out`, behind the response head once the CGI
+    /// head is complete.
+    fn relay(&mut self, ended: bool, out: &mut Vec<u8>, path: &str) -> Chunk {
+        if let Some(mut head) = self.head.take() {
+            head.extend_from_slice(&self.data);
+            match head_end(&head) {
+                Some((end, body)) if self.render_head(&head[..end], out) => {
+                    self.data.clear();
+                    self.data.extend_from_slice(&head[body..]);
+                }
+                None if !ended && head.len() <= self.cfg.max_header_size => {
+                    self.head = Some(head);
+                    return Chunk::Pending;
+                }
+                _ => return self.fail(out, path, "no valid response head"),
+            }
+        }
+        if !self.bodiless && !self.data.is_empty() {
+            let _ = write!(out, "{:x}\r\n", self.data.len());
+            out.extend_from_slice(&self.data);
+            out.extend_from_slice(b"\r\n");
+        }
+        match ended {
+            true if !self.bodiless => {
+                out.extend_from_slice(b"0\r\n\r\n");
+                Chunk::Last
+            }
+            true => Chunk::Last,
+            false if out.is_empty() => Chunk::Pending,
+            false => Chunk::Data,
+        }
+    }
+
+    /// Answers `502` for a backend that failed before its response head.
+    fn fail(&mut self, out: &mut Vec<u8>, path: &str, why: &str) -> Chunk {
+        eprintln!("[warn] backend for {path}: {why}");
+        self.head = None;
+        out.clear();
+        out.extend_from_slice(self.bad_gateway);
+        Chunk::Failed
+    }
+
+    /// Renders the CGI response head `cgi` as an HTTP one with a chunked body into `out`;
+    /// false if it is not a valid head.
+    fn render_head(&mut self, cgi: &[u8], out: &mut Vec<u8>) -> bool {
+        let mut status: Option<&[u8]> = None;
+        let mut location = false;
+        let mut headers = Vec::with_capacity(cgi.len() + 128);
+        let lines = cgi.split(|&b| b == b'\n').map(|l| l.strip_suffix(b"\r").unwrap_or(l));
+        for line in lines.filter(|l| !l.is_empty()) {
+            let Some(colon) = line.iter().position(|&b| b == b':') else { return false };
+            let (name, value) = (&line[..colon], line[colon + 1..].trim_ascii());
+            if !http::is_token(name) || value.iter().any(|&b| b == b'\r' || b == 0) {
+                return false;
+            }
+            if name.eq_ignore_ascii_case(b"status") {
+                status = Some(value);
+                continue;
+            }
+            location |= name.eq_ignore_ascii_case(b"location");
+            if !FRAMING.iter().any(|f| name.eq_ignore_ascii_case(f)) {
+                for part in [name, b": ", value, b"\r\n"] {
+                    headers.extend_from_slice(part);
+                }
+            }
+        }
+        // Like a CGI script, a backend redirects by sending `Location` alone.
+        let status = status.unwrap_or(if location { b"302 Found" } else { b"200 OK" });
+        let code = match status.get(..3).and_then(|c| std::str::from_utf8(c).ok()?.parse::<u16>().ok()) {
+            Some(code @ 200..=599) if status.len() == 3 || status[3] == b' ' => code,
+            _ => return false,
+        };
+        let bare = matches!(code, 204 | 304);
+        self.bodiless |= bare;
+        let mut head = Vec::with_capacity(headers.len() + 128);
+        head.extend_from_slice(b"HTTP/1.1 ");
+        head.extend_from_slice(status);
+        if status.len() == 3 {
+            head.push(b' ');
+        }
+        head.extend_from_slice(b"\r\n");
+        head.extend_from_slice(&headers);
+        if self.cfg.date {
+            head.extend_from_slice(b"Date: ");
+            date::write_imf_fixdate(&mut head, date::unix_now());
+            head.extend_from_slice(b"\r\n");
+        }
+        if !bare {
+            head.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+        }
+        head.extend_from_slice(b"Connection: keep-alive\r\n\r\n");
+        let mut edited = Vec::with_capacity(head.len() + 64);
+        headers::rewrite(&mut edited, &head, &self.cfg.header_rules);
+        headers::rewrite(out, &edited, &self.route_headers);
+        true
+    }
+}
+
+/// Where the blank line ending the CGI head at the start of `out` begins, and where the
+/// body after it starts; lines may end in CRLF or a bare LF.
+fn head_end(out: &[u8]) -> Option<(usize, usize)> {
+    let mut start = 0;
+    while let Some(nl) = out[start..].iter().position(|&b| b == b'\n') {
+        let line = &out[start..start + nl];
+        if line.is_empty() || line == b"\r" {
+            return Some((start, start + nl + 1));
+        }
+        start += nl + 1;
+    }
+    None
+}
+
+/// Appends a FastCGI record of `kind` for request id 1.
+fn record(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    let len = (content.len() as u16).to_be_bytes();
+    out.extend_from_slice(&[1, kind, 0, 1, len[0], len[1], 0, 0]);
+    out.extend_from_slice(content);
+}
+
+/// Appends a FastCGI name-value pair: lengths under 128 in one byte, others in four.
+fn fastcgi_pair(out: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    for len in [name.len(), value.len()] {
+        match len {
+            0..=127 => out.push(len as u8),
+            _ => out.extend_from_slice(&(len as u32 | 1 << 31).to_be_bytes()),
+        }
+    }
+    out.extend_from_slice(name);
+    out.extend_from_slice(value);
+}
+
+/// Appends an SCGI header: name and value, each NUL-terminated.
+fn scgi_pair(out: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    for part in [name, b"\0", value, b"\0"] {
+        out.extend_from_slice(part);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::http::find;
+use crate::rng::Rng;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultKind {
+    /// Send the status line, then stop writing until the client gives up or the conn times out.
+    Stall,
+    /// Send the head plus this many body bytes, then reset the connection (SO_LINGER 0).
+    Reset(usize),
+    /// Send half of the response head, then close normally.
+    CloseHeaders,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultAction {
+    Stall,
+    Reset,
+    Close,
+}
+
+/// A fault armed on a single response: once `at` bytes are written, `action` is taken.
+#[derive(Clone, Copy, Debug)]
+pub struct Fault {
+    pub action: FaultAction,
+    pub at: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FaultRule {
+    pub kind: FaultKind,
+    pub probability: f64,
+}
+
+impl FaultRule {
+    /// Parses `stall:P`, `reset:N:P` or `close-headers:P`, where `P` is a probability in `[0, 1]`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        let prob = |s: &str| -> Result<f64, String> {
+            match s.parse::<f64>() {
+                Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+                _ => Err(format!("invalid probability '{s}'")),
+            }
+        };
+        let (kind, probability) = match parts.as_slice() {
+            ["stall", p] => (FaultKind::Stall, prob(p)?),
+            ["reset", n, p] => {
+                let n = n.parse::<usize>().map_err(|_| format!("invalid byte count '{n}'"))?;
+                (FaultKind::Reset(n), prob(p)?)
+            }
+            ["close-headers", p] => (FaultKind::CloseHeaders, prob(p)?),
+            _ => return Err(format!("unrecognised fault '{spec}'")),
+        };
+        Ok(Self { kind, probability })
+    }
+}
+
+/// Rolls each rule in order and returns the first that fires.
+pub fn pick(rules: &[FaultRule], rng: &mut Rng) -> Option<FaultKind> {
+    rules.iter().find(|r| rng.chance(r.probability)).map(|r| r.kind)
+}
+
+impl FaultKind {
+    pub fn arm(self, response: &[u8]) -> Fault {
+        let head_len = find(response, b"\r\n\r\n").map_or(response.len(), |p| p + 4);
+        match self {
+            FaultKind::Stall => Fault {
+                action: FaultAction::Stall,
+                at: find(response, b"\r\n").map_or(0, |p| p + 2),
+            },
+            FaultKind::Reset(n) => Fault {
+                action: FaultAction::Reset,
+                at: (head_len + n).min(response.len()),
+            },
+            FaultKind::CloseHeaders => Fault { action: FaultAction::Close, at: head_len / 2 },
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Entry points for the targets in `fuzz/`. Only compiled with `--cfg fuzzing`, which
+//! `cargo fuzz` sets.
+
+use crate::body::{ChunkedDecoder, Decoded};
+use crate::config::{Config, BUF_SIZE, RESPONSE_BODY, MAINTENANCE_BODY};
+use crate::conn::Conn;
+use crate::bodies::BodyStore;
+use crate::bus::Bus;
+use crate::counter::RpsCounter;
+use crate::handler::{Handler, Progress};
+use crate::http::RequestHead;
+use crate::response::Responses;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+/// Splits fuzz input into piece sizes (from the first up to 8 bytes) and the payload.
+pub fn split_input(data: &[u8]) -> (Vec<usize>, &[u8]) {
+    let Some((&first, rest)) = data.split_first() else { return (Vec::new(), data) };
+    let (cuts, payload) = rest.split_at((first as usize % 8).min(rest.len()));
+    (cuts.iter().map(|&b| b as usize + 1).collect(), payload)
+}
+
+fn pieces<'a>(data: &'a [u8], cuts: &[usize]) -> Vec<&'a [u8]> {
+    let mut out = Vec::new();
+    let mut rest = data;
+    for &c in cuts {
+        let (a, b) = rest.split_at(c.min(rest.len()));
+        out.push(a);
+        rest = b;
+    }
+    out.push(rest);
+    out
+}
+
+/// Exercises every accessor of a parsed request head.
+pub fn request_head(data: &[u8]) {
+    let Some(head) = RequestHead::parse(data) else { return };
+    let _ = (head.path(), head.query(), head.header(b"host"));
+    for (name, value) in head.headers() {
+        assert!(!name.contains(&b'\n') && !value.contains(&b'\n'));
+    }
+    let _ = (head.content_length(), head.is_chunked(), head.expects_continue());
+}
+
+#[derive(Debug, PartialEq)]
+enum ChunkedOutcome {
+    Error,
+    Done { body: Vec<u8>, consumed: usize },
+    Incomplete { body: Vec<u8> },
+}
+
+fn decode_pieces(data: &[u8], cuts: &[usize]) -> ChunkedOutcome {
+    let mut dec = ChunkedDecoder::new();
+    let mut pending = Vec::new();
+    let mut body = Vec::new();
+    let mut consumed = 0;
+    for piece in pieces(data, cuts) {
+        pending.extend_from_slice(piece);
+        let res = dec.decode(&pending, |d| {
+            body.extend_from_slice(d);
+            Ok(())
+        });
+        match res {
+            Ok(Decoded::Partial(n)) => {
+                assert!(n <= pending.len());
+                consumed += n;
+                pending.drain(..n);
+            }
+            Ok(Decoded::Done(n)) => return ChunkedOutcome::Done { body, consumed: consumed + n },
+            Err(_) => return ChunkedOutcome::Error,
+        }
+    }
+    ChunkedOutcome::Incomplete { body }
+}
+
+/// Decoding must not depend on where the input was split.
+pub fn chunked(data: &[u8], cuts: &[usize]) {
+    assert_eq!(decode_pieces(data, &[]), decode_pieces(data, cuts));
+}
+
+struct Fixtures {
+    cfg: Config,
+    responses: &'static Responses,
+    counter: &'static RpsCounter,
+    bodies: &'static BodyStore,
+    listener: TcpListener,
+}
+
+fn fixtures() -> &'static Fixtures {
+    static FIXTURES: OnceLock<Fixtures> = OnceLock::new();
+    FIXTURES.get_or_init(|| {
+        // No Date header: it could tick between the two runs being compared.
+        let cfg = Config { date: false, ..Config::default() };
+        let responses = Responses::new(&cfg, RESPONSE_BODY, MAINTENANCE_BODY, "text/plain", &[], None, &[]);
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        Fixtures { cfg, responses, counter: RpsCounter::new(1), bodies: BodyStore::new(Vec::new(), Bus::new(1)), listener }
+    })
+}
+
+/// Feeds `data` to a connection the way the worker reads it and returns every armed response;
+/// an empty entry marks the point where the connection was closed.
+fn serve_pieces(data: &[u8], cuts: &[usize]) -> Vec<Vec<u8>> {
+    let Fixtures { cfg, responses, counter, bodies, listener } = fixtures();
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).expect("connect");
+    let (server, peer): (TcpStream, SocketAddr) = listener.accept().expect("accept");
+    server.set_nonblocking(true).unwrap();
+    let stream = mio::net::TcpStream::from_std(server);
+    let mut conn = Conn::new(stream, peer, Box::new([0u8; BUF_SIZE]).into(), Instant::now());
+    let mut handler = Handler::new(0, cfg, responses, None, counter, bodies, None);
+    let mut out = Vec::new();
+
+    for mut piece in pieces(data, cuts) {
+        while !piece.is_empty() {
+            let n = piece.len().min(BUF_SIZE - conn.read_len);
+            conn.read_buf[conn.read_len..conn.read_len + n].copy_from_slice(&piece[..n]);
+            conn.read_len += n;
+            conn.on_data();
+            piece = &piece[n..];
+            loop {
+                match handler.process(&mut conn) {
+                    Progress::Armed => {
+                        out.push(conn.outgoing().to_vec());
+                        handler.release_slot(&mut conn);
+                        if conn.close_after_write {
+                            out.push(Vec::new());
+                            return out;
+                        }
+                        conn.finish_write();
+                    }
+                    Progress::NeedMore => {
+                        assert!(conn.read_len < BUF_SIZE, "handler wants more input with a full buffer");
+                        break;
+                    }
+                    Progress::Close
+                    | Progress::Tunnel { .. }
+                    | Progress::Parked
+                    | Progress::Queued
+                    | Progress::Follow(_)
+                    | Progress::Tls => {
+                        out.push(Vec::new());
+                        return out;
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+/// The responses produced for a byte stream must not depend on how it was split into reads.
+pub fn connection(data: &[u8], cuts: &[usize]) {
+    assert_eq!(serve_pieces(data, &[]), serve_pieces(data, cuts));
+}
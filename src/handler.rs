@@ -0,0 +1,1258 @@
+use crate::access::MAX_LOGGED_LINE;
+use crate::affinity::{AffinityCounts, AffinityTable};
+use crate::alloc;
+use crate::bodies::{BodyName, BodyStore, Upload};
+use crate::body::{self, ChunkedDecoder, Decoded, Framing};
+use crate::clock::{Clock, SystemClock};
+use crate::collapse::Collapse;
+use crate::close::CloseReason;
+use crate::config::{
+    Config, ADMIN_ALLOCATOR_PATH, ADMIN_BODIES_PATH, ADMIN_CONNECTIONS_PATH, ADMIN_EVENTS_PATH, ADMIN_LISTENERS_PATH,
+    ADMIN_ROUTES_PATH,
+    ADMIN_RESET_STATS_PATH, ADMIN_STATS_PATH,
+    ADMIN_TOGGLES_PATH, AFFINITY_CAPACITY, BUF_SIZE, FAST_LANE_MAX_HEAD, HEADERS_PATH,
+    MAX_UPLOAD_SIZE, RESERVED_PATH_PREFIX, VERSION_PATH,
+};
+use crate::conn::{Conn, ConnState};
+use crate::connlist::ConnList;
+use crate::counter::{ProtocolError, RpsCounter, Suspicious};
+use crate::date::DateHeader;
+use crate::encoding;
+use crate::exec::{self, Exec};
+use crate::fastcgi::Gateway;
+use crate::fault;
+use crate::headers;
+use crate::idempotency::{self, IdempotencyStore, Lookup};
+use crate::http::{self, Preface, RequestHead};
+use crate::json::Json;
+use crate::limit::{AcceptRate, Admission, InflightLimit, ScopedLimits};
+use crate::listen::ListenSet;
+use crate::longpoll::{self, LongPoll};
+use crate::mirror::{MirrorOutcome, MirrorStats};
+use crate::proxy::{self, Preamble};
+use crate::response::{self, Responses};
+use crate::rewrite;
+use crate::rng::Rng;
+use crate::routes::{Payload, Route};
+use crate::sha256;
+use crate::signal;
+use crate::sizes;
+use crate::sink::BodySink;
+use crate::spill::Spill;
+use crate::tenant::{self, TenantStat, Tenants};
+use crate::toggle::{self, Toggle};
+use crate::transport::Transport;
+use crate::tunnel;
+use crate::upstream::{UpstreamPool, UpstreamStats};
+use crate::xdp::AbuseTracker;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+/// Fatal `handshake_failure` alert, so a TLS client fails at once instead of waiting for a ServerHello.
+const TLS_ALERT: &[u8] = &[0x15, 0x03, 0x01, 0x00, 0x02, 0x02, 0x28];
+/// Answer to an HTTP/2 prior-knowledge client: the server preface (an empty SETTINGS
+/// frame) and a GOAWAY with `HTTP_1_1_REQUIRED`, so it fails fast or falls back to HTTP/1.1.
+const H2_GOAWAY: &[u8] = &[
+    0, 0, 0, 0x04, 0, 0, 0, 0, 0, // SETTINGS, no parameters
+    0, 0, 8, 0x07, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x0d, // GOAWAY, last stream 0
+];
+
+pub enum Progress {
+    /// A response has been selected and armed for writing.
+    Armed,
+    /// More input is needed before anything can be answered.
+    NeedMore,
+    /// The connection must be closed.
+    Close,
+    /// Forward-proxy request: connect to `addr` and relay from here on, sending upstream
+    /// whatever is left in `Conn::out` first. `established` answers a `CONNECT`; `group`
+    /// is the split group `addr` belongs to, if any. `mirror` is set for a request sampled
+    /// for `--mirror`: `Conn::out` and this many bytes of body in `Conn::read_buf` are
+    /// copied to the shadow upstream.
+    Tunnel { addr: SocketAddr, established: bool, group: Option<usize>, mirror: Option<usize> },
+    /// A long poll is waiting for the next event; `Handler::unpark` answers it.
+    Parked,
+    /// The request waits on the backend request of flight `id` in `Handler::collapse`; the
+    /// worker adds the connection to its waiters.
+    Follow(u64),
+    /// The request waits for a per-listener or per-route in-flight slot; the worker tries it
+    /// again when one frees or its deadline passes.
+    Queued,
+    /// The client opened with a TLS ClientHello; the worker hands the connection, and the
+    /// bytes read so far, to rustls.
+    Tls,
+}
+
+/// Server state the admin endpoints read and change beyond the config; only kept with `--admin`.
+#[derive(Clone, Copy)]
+pub struct Admin {
+    pub conns: &'static ConnList,
+    pub listen: &'static ListenSet,
+}
+
+/// Turns buffered request bytes into armed responses. One per worker.
+pub struct Handler {
+    pub thread_id: usize,
+    pub cfg: &'static Config,
+    pub responses: &'static Responses,
+    pub rng: Rng,
+    pub limit: Option<&'static InflightLimit>,
+    pub counter: &'static RpsCounter,
+    pub date: DateHeader,
+    bodies: &'static BodyStore,
+    admin: Option<Admin>,
+    idempotency: Option<&'static IdempotencyStore>,
+    clock: &'static dyn Clock,
+    /// `BodyStore` version the `swapped` responses were built from.
+    bodies_seen: u64,
+    /// This worker's copies of responses built from uploaded bodies, by `BodyName`.
+    swapped: [Option<Arc<[u8]>>; BodyName::ALL.len()],
+    /// This worker's copy of the static routes, refreshed with `swapped`.
+    routes: Vec<Arc<Route>>,
+    scratch: Vec<u8>,
+    stored: u64,
+    /// Nothing configured needs more than the default response for a plain `GET`; see `fast_get`.
+    fast_lane: bool,
+    /// Split groups of recently seen sticky keys; only with `--split-affinity`.
+    affinity: Option<AffinityTable>,
+    /// Only with `--tenant`.
+    tenants: Option<&'static Tenants>,
+    /// This worker's budget for each tenant with a `rate`, by index in `Config::tenants`.
+    tenant_rates: Vec<Option<AcceptRate>>,
+    /// Only with `--long-poll`.
+    long_poll: Option<&'static LongPoll>,
+    /// Only with `--xdp-drop-map`.
+    abuse: Option<&'static AbuseTracker>,
+    /// Only with `--mirror`.
+    mirror: Option<&'static MirrorStats>,
+    /// Slots of `--exec-max`; only with `exec:` static routes.
+    exec: Option<&'static InflightLimit>,
+    /// Only with `--listener-max-inflight` or `--route-max-inflight`.
+    scoped: Option<&'static ScopedLimits>,
+    /// This worker's idle FastCGI backend connections.
+    pub upstream_pool: UpstreamPool,
+    /// This worker's backend requests others may wait on; only used with `--collapse`.
+    pub collapse: Collapse,
+}
+
+impl Handler {
+    pub fn new(
+        thread_id: usize,
+        cfg: &'static Config,
+        responses: &'static Responses,
+        limit: Option<&'static InflightLimit>,
+        counter: &'static RpsCounter,
+        bodies: &'static BodyStore,
+        admin: Option<Admin>,
+    ) -> Self {
+        Self {
+            thread_id,
+            cfg,
+            responses,
+            rng: Rng::seeded(thread_id as u64),
+            limit,
+            counter,
+            date: DateHeader::new(),
+            bodies,
+            admin,
+            idempotency: None,
+            clock: &SystemClock,
+            bodies_seen: 0,
+            swapped: Default::default(),
+            routes: bodies.routes(),
+            scratch: Vec::new(),
+            stored: 0,
+            fast_lane: !cfg.admin
+                && !cfg.proxy_protocol
+                && cfg.proxy_allow.is_empty()
+                && cfg.redirects.is_empty()
+                && cfg.force_https.is_none()
+                && cfg.path_rewrites.is_empty()
+                && !cfg.server_timing
+                && !cfg.logs_requests()
+                && limit.is_none()
+                && cfg.size_routes.is_empty()
+                && cfg.split.groups.is_empty()
+                && responses.template.is_none(),
+            affinity: match (&cfg.split.sticky, cfg.split.affinity) {
+                (Some(_), Some(ttl)) if !cfg.split.groups.is_empty() => {
+                    Some(AffinityTable::new(AFFINITY_CAPACITY, ttl))
+                }
+                _ => None,
+            },
+            tenants: None,
+            tenant_rates: Vec::new(),
+            long_poll: None,
+            abuse: None,
+            mirror: None,
+            exec: None,
+            scoped: None,
+            upstream_pool: UpstreamPool::new(cfg, None),
+            collapse: Collapse::default(),
+        }
+    }
+
+    /// Remembers responses to requests with an `Idempotency-Key` in `store`, which takes
+    /// plain `GET`s off the fast lane.
+    pub fn with_idempotency(mut self, store: Option<&'static IdempotencyStore>) -> Self {
+        self.fast_lane &= store.is_none();
+        self.idempotency = store;
+        self
+    }
+
+    /// Takes write deadlines and idempotency expiries from `clock`.
+    pub fn with_clock(mut self, clock: &'static dyn Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Routes, limits and counts requests per `Config::tenants`, which takes plain `GET`s
+    /// off the fast lane.
+    pub fn with_tenants(mut self, tenants: Option<&'static Tenants>) -> Self {
+        self.fast_lane &= tenants.is_none();
+        self.tenants = tenants;
+        let now = self.clock.now();
+        self.tenant_rates = self.cfg.tenants.iter().map(|t| t.rate.map(|n| AcceptRate::new(n, now))).collect();
+        self
+    }
+
+    /// Parks `GET`s to `Config::long_poll_path` until the next event in `long_poll`, which
+    /// takes plain `GET`s off the fast lane.
+    pub fn with_long_poll(mut self, long_poll: Option<&'static LongPoll>) -> Self {
+        self.fast_lane &= long_poll.is_none();
+        self.long_poll = long_poll;
+        self
+    }
+
+    /// Counts the peers of suspicious requests against `abuse`, which bans them past
+    /// `--ban-suspicious`.
+    pub fn with_abuse(mut self, abuse: Option<&'static AbuseTracker>) -> Self {
+        self.abuse = abuse;
+        self
+    }
+
+    /// Samples relayed requests for `--mirror`, counting those it cannot copy in `mirror`.
+    pub fn with_mirror(mut self, mirror: Option<&'static MirrorStats>) -> Self {
+        self.mirror = mirror;
+        self
+    }
+
+    /// Runs the commands of `exec:` routes and the requests of `fastcgi:` and `scgi:` ones in
+    /// `slots`, which takes plain `GET`s off the fast lane, pooling connections to `backends`.
+    pub fn with_exec(mut self, slots: Option<&'static InflightLimit>, backends: Option<&'static UpstreamStats>) -> Self {
+        self.fast_lane &= slots.is_none();
+        self.exec = slots;
+        self.upstream_pool = UpstreamPool::new(self.cfg, backends);
+        self
+    }
+
+    /// Limits the requests in flight per listener and per route by `scoped`, which takes
+    /// plain `GET`s off the fast lane.
+    pub fn with_scoped_limits(mut self, scoped: Option<&'static ScopedLimits>) -> Self {
+        self.fast_lane &= scoped.is_none();
+        self.scoped = scoped;
+        self
+    }
+
+    pub fn process<T: Transport>(&mut self, conn: &mut Conn<T>) -> Progress {
+        if conn.state() != ConnState::ReadingBody {
+            match self.process_head(conn) {
+                Progress::NeedMore => {}
+                other => return other,
+            }
+            if conn.state() != ConnState::ReadingBody {
+                return Progress::NeedMore;
+            }
+        }
+        self.process_body(conn)
+    }
+
+    fn process_head<T: Transport>(&mut self, conn: &mut Conn<T>) -> Progress {
+        if conn.stream.alpn() == Some(http::ALPN_H2) {
+            // No HTTP/2 here: the client is told at once, as one with prior knowledge would be.
+            eprintln!("[warn] HTTP/2 negotiated over TLS by {}, closing", conn.peer);
+            self.counter.protocol_error(self.thread_id, ProtocolError::H2);
+            let _ = conn.stream.write(H2_GOAWAY);
+            return Progress::Close;
+        }
+        if self.fast_lane {
+            if let Some(progress) = self.fast_get(conn) {
+                return progress;
+            }
+        }
+        let timed = self.cfg.server_timing || self.cfg.logs_requests();
+        if timed && conn.timing.request_start.is_none() && conn.read_len > 0 {
+            conn.timing.request_start = Some(Instant::now());
+        }
+        if self.cfg.proxy_protocol && !conn.proxy_checked {
+            match proxy::parse(&conn.read_buf[..conn.read_len]) {
+                Ok(Preamble::Incomplete) => return Progress::NeedMore,
+                Ok(Preamble::Absent) => {}
+                Ok(Preamble::Present { len, source }) => {
+                    if let Some(source) = source {
+                        conn.peer = source;
+                    }
+                    conn.consume(len);
+                    // The preamble is not part of the first request.
+                    conn.request_bytes = 0;
+                }
+                Err(e) => {
+                    eprintln!("[warn] {e} from {}, closing", conn.peer);
+                    return Progress::Close;
+                }
+            }
+            conn.proxy_checked = true;
+        }
+        if conn.scan_offset == 0 && conn.read_len > 0 {
+            match http::sniff(&conn.read_buf[..conn.read_len]) {
+                Preface::Http => {}
+                Preface::Tls if self.cfg.terminates_tls() && !conn.stream.encrypted() => return Progress::Tls,
+                Preface::Tls => {
+                    eprintln!("[warn] TLS handshake on plaintext port from {}, closing", conn.peer);
+                    self.counter.protocol_error(self.thread_id, ProtocolError::Tls);
+                    let _ = conn.stream.write(TLS_ALERT);
+                    return Progress::Close;
+                }
+                Preface::Garbage => {
+                    eprintln!("[warn] request from {} does not start with a method, closing", conn.peer);
+                    self.counter.protocol_error(self.thread_id, ProtocolError::Garbage);
+                    return self.reject(conn, self.responses.bad_request);
+                }
+            }
+        }
+        let Some(head_len) = conn.request_complete() else {
+            if conn.read_len >= conn.read_buf.len() {
+                let cap = self.cfg.max_header_size;
+                if conn.read_len >= cap {
+                    eprintln!("[warn] request too large (>{cap} bytes), closing");
+                    let line_ended = conn.read_buf[..conn.read_len].contains(&b'\n');
+                    self.flag(conn.peer, if line_ended { Suspicious::LongHead } else { Suspicious::LongUri });
+                    return self.reject(conn, self.responses.head_too_large);
+                }
+                conn.read_buf.spill(conn.read_len, cap);
+                self.counter.header_spill(self.thread_id);
+            }
+            return Progress::NeedMore;
+        };
+        if conn.read_buf[..head_len] == *http::H2_PREFACE_HEAD {
+            eprintln!("[warn] HTTP/2 prior-knowledge connection from {}, closing", conn.peer);
+            let kind = if conn.stream.encrypted() { ProtocolError::H2 } else { ProtocolError::H2c };
+            self.counter.protocol_error(self.thread_id, kind);
+            let _ = conn.stream.write(H2_GOAWAY);
+            return Progress::Close;
+        }
+
+        // A request tried again after waiting in an in-flight queue has been counted once.
+        let retry = conn.scoped.queued.is_some();
+        conn.begin_handling();
+        if self.cfg.server_timing {
+            conn.timing.head_done = Some(Instant::now());
+        }
+        let head = RequestHead::parse(&conn.read_buf[..head_len]);
+        let framing = match &head {
+            Some(h) => match framing(h) {
+                Ok(f) => f,
+                Err(e) => {
+                    eprintln!("[warn] {e}, closing");
+                    self.flag(conn.peer, Suspicious::Smuggling);
+                    let response = self.responses.error_for(h.header(b"host"), 400, self.responses.bad_request);
+                    return self.reject(conn, response);
+                }
+            },
+            None => {
+                eprintln!("[warn] malformed request head from {}, closing", conn.peer);
+                self.flag(conn.peer, malformed(&conn.read_buf[..head_len]));
+                return self.reject(conn, self.responses.bad_request);
+            }
+        };
+        if !retry && std::str::from_utf8(&conn.read_buf[..head_len]).is_err() {
+            self.flag(conn.peer, Suspicious::BadUtf8);
+        }
+        let expect_continue = head.as_ref().is_some_and(|h| h.expects_continue());
+        if let Some(h) = head.as_ref().filter(|_| self.cfg.size_stats) {
+            conn.route = sizes::route(&self.cfg.size_routes, h.path());
+        }
+        if self.cfg.logs_requests() {
+            conn.request_line.clear();
+            match &head {
+                Some(h) => {
+                    conn.request_line.extend_from_slice(h.method);
+                    conn.request_line.push(b' ');
+                    conn.request_line.extend_from_slice(h.target);
+                    conn.request_line.truncate(MAX_LOGGED_LINE);
+                }
+                None => conn.request_line.push(b'-'),
+            }
+        }
+
+        let proxied = head.as_ref().filter(|_| !self.cfg.proxy_allow.is_empty()).and_then(proxy_request);
+        if let Some(ProxyRequest { authority, default_port, relayed }) = proxied {
+            let Some(addr) = tunnel::lookup(&self.cfg.proxy_allow, authority, default_port) else {
+                let dest = String::from_utf8_lossy(authority);
+                eprintln!("[warn] forward proxy: {dest} is not allowed for {}, refusing", conn.peer);
+                let host = head.as_ref().and_then(|h| h.header(b"host"));
+                return self.reject(conn, self.responses.error_for(host, 403, self.responses.forbidden));
+            };
+            conn.out.clear();
+            let mut mirror = None;
+            if let (Some(h), Some(target)) = (&head, relayed) {
+                tunnel::write_relayed_head(&mut conn.out, h.method, target, authority, h.headers());
+                mirror = self.mirror_body(h, conn.read_len - head_len);
+            }
+            let established = relayed.is_none();
+            conn.consume(head_len);
+            return Progress::Tunnel { addr, established, group: None, mirror };
+        }
+
+        // The target a `--rewrite-prefix` rule turned the request's into.
+        let mut rewritten = Vec::new();
+        let head = match head {
+            Some(h)
+                if !h.path().starts_with(RESERVED_PATH_PREFIX)
+                    && !self.is_health_check(&h)
+                    && rewrite::apply(&self.cfg.path_rewrites, h.path(), h.query(), &mut rewritten) =>
+            {
+                Some(h.with_target(&rewritten))
+            }
+            head => head,
+        };
+
+        if let Some(h) = head.as_ref().filter(|h| self.cfg.split.applies(h.path())) {
+            let group = self.cfg.split.choose(h, conn.peer, self.affinity.as_mut(), &mut self.rng);
+            let target = &self.cfg.split.groups[group].target;
+            conn.out.clear();
+            tunnel::write_relayed_head(&mut conn.out, h.method, h.target, target.host.as_bytes(), h.headers());
+            let mirror = self.mirror_body(h, conn.read_len - head_len);
+            conn.consume(head_len);
+            return Progress::Tunnel { addr: target.addr, established: false, group: Some(group), mirror };
+        }
+
+        let version = head.as_ref().is_some_and(|h| h.path() == VERSION_PATH);
+        let upload = head.as_ref().and_then(|h| self.upload_target(h));
+        let allocator = self.cfg.admin && head.as_ref().is_some_and(|h| h.path() == ADMIN_ALLOCATOR_PATH);
+        let stats = self.cfg.admin && head.as_ref().is_some_and(|h| h.path() == ADMIN_STATS_PATH);
+        let reset_stats =
+            self.cfg.admin && head.as_ref().is_some_and(|h| h.path() == ADMIN_RESET_STATS_PATH && h.method == b"PUT");
+        let conns = self.cfg.admin && head.as_ref().is_some_and(|h| h.path() == ADMIN_CONNECTIONS_PATH);
+        let toggles = self.cfg.admin && head.as_ref().is_some_and(|h| apply_toggle(h));
+        let listeners = match (&head, self.admin) {
+            (Some(h), Some(admin)) if self.cfg.admin => apply_listener(admin.listen, h),
+            _ => None,
+        };
+        let admin =
+            version || upload.is_some() || allocator || stats || reset_stats || conns || toggles || listeners.is_some();
+        let health = head.as_ref().is_some_and(|h| self.is_health_check(h));
+        // Health checks and admin requests are served past every limit, so an overloaded
+        // instance is not taken for a dead one.
+        let priority = admin || health;
+        conn.priority = priority;
+        let echo = self.cfg.echo_headers && head.as_ref().is_some_and(|h| h.path() == HEADERS_PATH);
+        let json = head.as_ref().is_some_and(wants_json);
+        let maintenance = !priority && signal::maintenance();
+        let encrypted = conn.stream.encrypted();
+        let redirected =
+            !priority && !maintenance && head.as_ref().is_some_and(|h| self.redirect(h, encrypted, &mut conn.out));
+        let tenant = match (self.tenants, &head) {
+            (Some(tenants), Some(h)) if !priority => tenant::find(&self.cfg.tenants, h).inspect(|&(i, _)| {
+                if !retry {
+                    tenants.count(i, TenantStat::Requests);
+                }
+            }),
+            _ => None,
+        };
+        let throttled =
+            !retry && !maintenance && !redirected && tenant.is_some_and(|(i, _)| !self.take_tenant_rate(i));
+        self.refresh_bodies();
+        let mut route = head.as_ref().and_then(|h| self.find_route(h, tenant));
+        let limited = !priority && !maintenance && !redirected && !throttled;
+        let shed = limited
+            && (conn.reserved
+                || !self.acquire_slot(&mut conn.inflight)
+                || !self.acquire_tenant_slot(tenant, &mut conn.tenant_slot)
+                || match self.scoped.map(|l| l.acquire(&mut conn.scoped, conn.listener, route.as_deref(), self.clock.now())) {
+                    Some(Admission::Queued(until)) => {
+                        conn.queue(until);
+                        return Progress::Queued;
+                    }
+                    Some(Admission::Refused) => true,
+                    Some(Admission::Admitted) | None => false,
+                });
+        conn.scoped.leave_queue();
+        if conn.reserved && !priority {
+            // A reserved connection is only held for priority requests.
+            conn.close_after_write = true;
+            conn.close_reason = Some(CloseReason::Policy);
+        }
+        let mut replay = None;
+        let mut reused_key = false;
+        conn.idempotency = None;
+        if let (Some(store), Some(h)) = (self.idempotency, head.as_ref()) {
+            if let Some(key) = idempotency::key(h).filter(|_| !priority && !maintenance && !shed) {
+                let fingerprint = store.fingerprint(h);
+                match store.lookup(key, fingerprint, self.clock.now()) {
+                    Lookup::Miss => conn.idempotency = Some((key.into(), fingerprint)),
+                    Lookup::Replay(response) => replay = Some(response),
+                    Lookup::Mismatch => reused_key = true,
+                }
+            }
+        }
+        let answered = replay.is_some() || reused_key;
+        let long_poll = self.long_poll.is_some()
+            && framing.is_none()
+            && !priority
+            && !maintenance
+            && !redirected
+            && !throttled
+            && !shed
+            && !answered
+            && head.as_ref().is_some_and(|h| {
+                h.method == b"GET" && self.cfg.long_poll_path.as_ref().is_some_and(|p| h.path() == p.as_bytes())
+            });
+        if long_poll {
+            let since = head.as_ref().and_then(longpoll::since);
+            conn.idempotency = None;
+            conn.consume(head_len);
+            return self.park(conn, since);
+        }
+        // Flight this request waits on, and whether its backend sends the response head.
+        let mut follow = None;
+        let host = head.as_ref().and_then(|h| h.header(b"host"));
+        let precompressed = match &head {
+            Some(h) if maintenance => self.precompressed_maintenance(h),
+            _ => None,
+        };
+        match (&self.responses.template, &head) {
+            _ if version => conn.set_response(self.responses.version),
+            _ if allocator => {
+                self.scratch.clear();
+                alloc::write_stats(&mut self.scratch);
+                self.respond_scratch(conn, "200 OK", false);
+            }
+            _ if stats => {
+                self.scratch.clear();
+                self.counter.write_json(&mut self.scratch);
+                self.respond_scratch(conn, "200 OK", true);
+            }
+            _ if reset_stats => {
+                self.scratch.clear();
+                self.counter.reset().write_json(&mut self.scratch);
+                eprintln!("[admin] stats reset");
+                self.respond_scratch(conn, "200 OK", true);
+            }
+            _ if conns => {
+                self.scratch.clear();
+                match self.admin {
+                    Some(admin) if json => admin.conns.write_json(&mut self.scratch),
+                    Some(admin) => admin.conns.write(&mut self.scratch),
+                    None => {}
+                }
+                self.respond_scratch(conn, "200 OK", json);
+            }
+            _ if toggles => {
+                self.scratch.clear();
+                if json {
+                    toggle::write_states_json(&mut self.scratch);
+                } else {
+                    toggle::write_states(&mut self.scratch);
+                }
+                self.respond_scratch(conn, "200 OK", json);
+            }
+            _ if listeners.is_some() => {
+                self.scratch.clear();
+                let status = match (&listeners, self.admin) {
+                    (Some(Err(e)), _) if json => {
+                        Json::new(&mut self.scratch).begin_object().key("error").str(e.as_bytes()).end_object();
+                        "404 Not Found"
+                    }
+                    (Some(Err(e)), _) => {
+                        let _ = writeln!(self.scratch, "{e}");
+                        "404 Not Found"
+                    }
+                    (_, Some(admin)) if json => {
+                        admin.listen.write_states_json(&mut self.scratch);
+                        "200 OK"
+                    }
+                    (_, admin) => {
+                        if let Some(admin) = admin {
+                            admin.listen.write_states(&mut self.scratch);
+                        }
+                        "200 OK"
+                    }
+                };
+                self.respond_scratch(conn, status, json);
+            }
+            _ if upload.is_some() => {
+                if matches!(framing, Some(Framing::Length(n)) if n > MAX_UPLOAD_SIZE as u64) {
+                    eprintln!("[warn] upload larger than {MAX_UPLOAD_SIZE} bytes, closing");
+                    let response = self.responses.error_for(host, 413, self.responses.upload_too_large);
+                    conn.consume(head_len);
+                    return self.reject(conn, response);
+                }
+                conn.set_response(self.responses.updated);
+            }
+            _ if maintenance => match precompressed {
+                Some(response) => conn.set_response(response),
+                None => self.select(conn, BodyName::Maintenance, self.responses.maintenance),
+            },
+            _ if health => conn.set_response(self.responses.healthy),
+            _ if redirected => conn.set_response_owned(),
+            _ if throttled => conn.set_response(self.responses.error_for(host, 429, self.responses.throttled)),
+            _ if shed => conn.set_response(self.responses.error_for(host, 503, self.responses.overloaded)),
+            _ if reused_key => conn.set_response(self.responses.key_reused),
+            _ if replay.is_some() => {
+                if let Some(response) = replay.take() {
+                    conn.set_response_shared(response);
+                }
+            }
+            (_, Some(head)) if route.is_some() => {
+                if let Some(route) = route.take() {
+                    let too_large = matches!(framing, Some(Framing::Length(n)) if n > MAX_UPLOAD_SIZE as u64);
+                    let key = Some(head)
+                        .filter(|h| self.cfg.collapse && exec::runs(route.payload()) && route.answers(h.method))
+                        .and_then(|h| Collapse::key(h, framing.is_none()));
+                    follow = key.as_deref().and_then(|k| self.collapse.follow(k)).map(|id| (id, route.payload() != Payload::Exec));
+                    match route.backend().filter(|_| route.answers(head.method)) {
+                        Some(_) if too_large && route.payload() != Payload::Exec => {
+                            eprintln!("[warn] request body for {} larger than {MAX_UPLOAD_SIZE} bytes, closing", route.path);
+                            let response = self.responses.error_for(host, 413, self.responses.upload_too_large);
+                            conn.consume(head_len);
+                            return self.reject(conn, response);
+                        }
+                        Some(_) if follow.is_some() => {
+                            // Another request on this worker has asked the backend for the
+                            // same; the worker copies its response here as it arrives.
+                            if route.payload() == Payload::Exec {
+                                conn.set_response_shared(route.select(head).clone());
+                            }
+                            // Spilled output goes out with `sendfile`, which cannot encrypt.
+                            let threshold = self.cfg.spill_threshold.filter(|_| !conn.stream.encrypted()).unwrap_or(usize::MAX);
+                            conn.spill = Some(Box::new(Spill::new(threshold, &self.cfg.spill_dir)));
+                            conn.idempotency = None;
+                            self.counter.collapsed(self.thread_id);
+                        }
+                        Some(_) => {
+                            // The worker streams the backend's output, after the route's head
+                            // for a command; a FastCGI or SCGI backend sends its own once it
+                            // has the request body.
+                            let response = route.select(head).clone();
+                            let (cfg, bad_gateway, now) = (self.cfg, self.responses.bad_gateway, self.clock.now());
+                            let pool = &mut self.upstream_pool;
+                            let start = |slots| match route.payload() {
+                                Payload::Exec => Exec::spawn(slots, &route, head, conn.peer),
+                                _ => Exec::connect(slots, &route, || {
+                                    Gateway::connect(pool, &route, head, conn.peer, now, cfg, bad_gateway)
+                                }),
+                            };
+                            match self.exec.map(start) {
+                                Some(Ok(Some(exec))) => {
+                                    if !exec.relays_head() {
+                                        conn.set_response_shared(response);
+                                    }
+                                    if let Some(key) = key {
+                                        conn.flight = Some(self.collapse.lead(key, exec.relays_head()));
+                                    }
+                                    conn.exec = Some(Box::new(exec));
+                                    conn.spill = (self.cfg.spill_threshold)
+                                        .filter(|_| !conn.stream.encrypted())
+                                        .map(|t| Box::new(Spill::new(t, &self.cfg.spill_dir)));
+                                    // Each run's output is its own; there is nothing to replay.
+                                    conn.idempotency = None;
+                                }
+                                Some(Ok(None)) | None => {
+                                    conn.set_response(self.responses.error_for(host, 503, self.responses.overloaded))
+                                }
+                                Some(Err(e)) => {
+                                    eprintln!("[warn] cannot start the backend for {}: {e}", route.path);
+                                    conn.close_after_write = true;
+                                    conn.close_reason = Some(CloseReason::Error);
+                                    conn.set_response(self.responses.error_for(host, 502, self.responses.bad_gateway));
+                                }
+                            }
+                        }
+                        None => conn.set_response_shared(route.respond(self.cfg, head).clone()),
+                    }
+                }
+            }
+            (_, Some(head)) if echo => {
+                self.scratch.clear();
+                write_echo(&mut self.scratch, head, conn.peer);
+                self.respond_scratch(conn, "200 OK", true);
+            }
+            (Some(tpl), Some(head)) => {
+                self.scratch.clear();
+                tpl.render(head, conn.peer, &mut self.scratch);
+                conn.out.clear();
+                let ty = &self.responses.template_type;
+                response::write_response(&mut conn.out, "200 OK", ty, &self.scratch, self.cfg.trailers);
+                conn.set_response_owned();
+            }
+            _ => self.select(conn, BodyName::Default, self.responses.ok),
+        }
+        conn.consume(head_len);
+
+        let Some(framing) = framing else {
+            if let Some(target) = upload {
+                self.store_upload(target, Vec::new());
+            }
+            if let Some((id, relays_head)) = follow {
+                if relays_head {
+                    conn.begin_streaming();
+                } else {
+                    self.arm(conn);
+                }
+                return Progress::Follow(id);
+            }
+            if let Some(exec) = conn.exec.as_mut().filter(|e| e.relays_head()) {
+                exec.finish_request(&[]);
+                conn.begin_streaming();
+                return Progress::Armed;
+            }
+            self.arm(conn);
+            return Progress::Armed;
+        };
+
+        let (thread_id, seq) = (self.thread_id, self.stored);
+        let sink = match BodySink::open(&self.cfg.body_sink, || format!("vrypt-{thread_id}-{seq}.body")) {
+            _ if upload.is_some() || conn.exec.as_ref().is_some_and(|e| e.relays_head()) => BodySink::Collect(Vec::new()),
+            _ if answered => BodySink::Discard,
+            Ok(BodySink::Hash(_)) if priority || maintenance || redirected || throttled || shed => BodySink::Discard,
+            Ok(sink) => sink,
+            Err(e) => {
+                eprintln!("[warn] cannot open body sink: {e}, closing");
+                return Progress::Close;
+            }
+        };
+        if matches!(sink, BodySink::Store(_)) {
+            self.stored += 1;
+        }
+        conn.upload = upload;
+        conn.begin_body(framing, sink);
+        if expect_continue && conn.read_len == 0 {
+            let _ = conn.stream.write(CONTINUE);
+        }
+        Progress::NeedMore
+    }
+
+    fn process_body<T: Transport>(&mut self, conn: &mut Conn<T>) -> Progress {
+        let mut sink = |data: &[u8]| match conn.sink.as_mut() {
+            Some(s) => s.write(data),
+            None => Ok(()),
+        };
+        let (consumed, done) = match conn.body.as_mut() {
+            Some(Framing::Length(remaining)) => {
+                let n = (conn.read_len as u64).min(*remaining) as usize;
+                if let Err(e) = sink(&conn.read_buf[..n]) {
+                    eprintln!("[warn] body sink write failed: {e}, closing");
+                    return Progress::Close;
+                }
+                *remaining -= n as u64;
+                (n, *remaining == 0)
+            }
+            Some(Framing::Chunked(dec)) => match dec.decode(&conn.read_buf[..conn.read_len], sink) {
+                Ok(Decoded::Partial(n)) => (n, false),
+                Ok(Decoded::Done(n)) => (n, true),
+                Err(body::SINK_FAILED) if conn.upload.is_some() || conn.exec.is_some() => {
+                    eprintln!("[warn] request body larger than {MAX_UPLOAD_SIZE} bytes, closing");
+                    return self.reject(conn, self.responses.upload_too_large);
+                }
+                Err(e) => {
+                    eprintln!("[warn] {e}, closing");
+                    return self.reject(conn, self.responses.bad_request);
+                }
+            },
+            None => (0, true),
+        };
+        conn.consume(consumed);
+        if !done {
+            if conn.read_len >= BUF_SIZE {
+                eprintln!("[warn] chunk framing does not fit the read buffer, closing");
+                return self.reject(conn, self.responses.bad_request);
+            }
+            return Progress::NeedMore;
+        }
+
+        match conn.end_body() {
+            Some(BodySink::Hash(h)) => {
+                self.scratch.clear();
+                sha256::write_hex(&mut self.scratch, &h.finish());
+                conn.out.clear();
+                response::write_response(&mut conn.out, "200 OK", &self.responses.text_plain, &self.scratch, self.cfg.trailers);
+                conn.set_response_owned();
+            }
+            Some(BodySink::Collect(body)) => {
+                if let Some(target) = conn.upload.take() {
+                    self.store_upload(target, body);
+                } else if let Some(exec) = conn.exec.as_mut() {
+                    exec.finish_request(&body);
+                    conn.begin_streaming();
+                    return Progress::Armed;
+                }
+            }
+            _ => {}
+        }
+        self.arm(conn);
+        Progress::Armed
+    }
+
+    /// Answers a small bodiless `GET` that arrived whole in one read, with nothing pipelined
+    /// behind it, straight from the default response or a static route's. With `fast_lane`
+    /// set, none of the routing `process_head` does (PROXY preamble, forward proxy, admin, redirects, limits, tenants,
+    /// templates, logging) can apply to it. `None` leaves the request to the general path.
+    fn fast_get<T: Transport>(&mut self, conn: &mut Conn<T>) -> Option<Progress> {
+        let buf = &conn.read_buf[..conn.read_len];
+        if conn.scan_offset != 0 || buf.len() > FAST_LANE_MAX_HEAD || !buf.starts_with(b"GET ") {
+            return None;
+        }
+        // Non-ASCII heads go the general way, which counts those that are not UTF-8.
+        if !buf.is_ascii() {
+            return None;
+        }
+        let head_len = http::find_head_end(buf, 0).filter(|&n| n == buf.len())?;
+        let head = RequestHead::parse(buf)?;
+        if !matches!(framing(&head), Ok(None)) || head.expects_continue() || head.path() == VERSION_PATH {
+            return None;
+        }
+        if self.cfg.echo_headers && head.path() == HEADERS_PATH {
+            return None;
+        }
+        if signal::maintenance() || conn.reserved || self.is_health_check(&head) {
+            return None;
+        }
+        conn.priority = false;
+        let route = self.find_route(&head, None).map(|r| r.respond(self.cfg, &head).clone());
+        conn.begin_handling();
+        match route {
+            Some(response) => conn.set_response_shared(response),
+            None => self.select(conn, BodyName::Default, self.responses.ok),
+        }
+        conn.consume(head_len);
+        self.arm(conn);
+        Some(Progress::Armed)
+    }
+
+    /// Arms `scratch` as the body of a `status` response, plain text or JSON.
+    fn respond_scratch<T: Transport>(&mut self, conn: &mut Conn<T>, status: &str, json: bool) {
+        let ty = if json { &self.responses.json } else { &self.responses.text_plain };
+        conn.out.clear();
+        response::write_response(&mut conn.out, status, ty, &self.scratch, self.cfg.trailers);
+        conn.set_response_owned();
+    }
+
+    /// Bytes held by this handler's scratch buffer and uploaded-response copies.
+    pub fn bytes(&self) -> u64 {
+        let swapped: usize = self.swapped.iter().flatten().map(|r| r.len()).sum();
+        (self.scratch.capacity() + swapped + self.collapse.bytes()) as u64
+            + self.affinity.as_ref().map_or(0, AffinityTable::bytes)
+    }
+
+    /// Affinity table lookups since the previous call, if there is a table.
+    pub fn take_affinity_counts(&mut self) -> Option<AffinityCounts> {
+        self.affinity.as_mut().map(AffinityTable::take_counts)
+    }
+
+    /// How much body to mirror along with a relayed request, `buffered` bytes of which have
+    /// been read past its head: `None` unless it is sampled for `--mirror`, and then also if
+    /// the body is not all there, which is counted as skipped.
+    fn mirror_body(&mut self, head: &RequestHead, buffered: usize) -> Option<usize> {
+        let (mirror, stats) = (self.cfg.mirror.as_ref()?, self.mirror?);
+        if !self.rng.chance(mirror.percent / 100.0) {
+            return None;
+        }
+        let body = match framing(head) {
+            Ok(None) => Some(0),
+            Ok(Some(Framing::Length(n))) => usize::try_from(n).ok().filter(|&n| n <= buffered),
+            _ => None,
+        };
+        if body.is_none() {
+            stats.add(MirrorOutcome::Skipped);
+        }
+        body
+    }
+
+    /// The body an admin `PUT` replaces, if this request is one.
+    fn upload_target(&self, head: &RequestHead) -> Option<Upload> {
+        if !self.cfg.admin || head.method != b"PUT" {
+            return None;
+        }
+        if head.path() == ADMIN_EVENTS_PATH && self.long_poll.is_some() {
+            return Some(Upload::Event);
+        }
+        if let Some(name) = head.path().strip_prefix(ADMIN_BODIES_PATH) {
+            return BodyName::parse(name).map(Upload::Body);
+        }
+        let path = head.path().strip_prefix(ADMIN_ROUTES_PATH)?;
+        let tenants = &self.cfg.tenants;
+        // A tenant's route is replaced under the tenant's name: `ADMIN_ROUTES_PATH/NAME/PATH`.
+        let named = |t: usize| path.strip_prefix(b"/")?.strip_prefix(tenants[t].name.as_bytes());
+        // An `exec:` route has no body to replace.
+        let matches = |r: &Arc<Route>| {
+            r.backend().is_none() && r.tenant.map_or(Some(path), named) == Some(r.path.as_bytes())
+        };
+        self.routes.iter().position(matches).map(Upload::Route)
+    }
+
+    fn store_upload(&mut self, target: Upload, body: Vec<u8>) {
+        match target {
+            Upload::Body(name) => {
+                eprintln!("[admin] replaced '{}' body ({} bytes)", name.name(), body.len());
+                self.bodies.replace(name, body);
+            }
+            Upload::Route(index) => {
+                let route = self.routes[index].replaced(self.cfg, &body);
+                eprintln!("[admin] replaced static route {} ({} bytes)", route.path, body.len());
+                self.bodies.replace_route(index, route);
+            }
+            Upload::Event => {
+                if let Some(long_poll) = self.long_poll {
+                    let n = long_poll.publish(self.cfg, &self.responses.text_plain, &body);
+                    eprintln!("[admin] published long-poll event {n} ({} bytes)", body.len());
+                }
+            }
+        }
+    }
+
+    /// The static route `head` asks for, if there is one. With a `tenant`, only that
+    /// tenant's routes are looked at, matched against the path asked of it.
+    fn find_route(&self, head: &RequestHead, tenant: Option<(usize, &[u8])>) -> Option<Arc<Route>> {
+        let (tenant, path) = tenant.map_or((None, head.path()), |(i, path)| (Some(i), path));
+        self.routes.iter().find(|r| r.tenant == tenant && r.path.as_bytes() == path).cloned()
+    }
+
+    /// Rebuilds this worker's copies of uploaded responses after the store has changed.
+    pub fn refresh_bodies(&mut self) {
+        let version = self.bodies.version();
+        if version == self.bodies_seen {
+            return;
+        }
+        self.bodies_seen = version;
+        let (cfg, bodies, responses) = (self.cfg, self.bodies, self.responses);
+        self.routes = bodies.routes();
+        self.swapped = BodyName::ALL.map(|name| {
+            let body = bodies.get(name)?;
+            let (status, ty) = match name {
+                BodyName::Default => ("200 OK", &responses.text_plain),
+                BodyName::Maintenance => ("503 Service Unavailable", &responses.maintenance_type),
+            };
+            let built = response::build_response(status, ty, &body, cfg.trailers);
+            Some(response::with_header_rules(cfg, built).into())
+        });
+    }
+
+    /// Selects the uploaded version of `name` if there is one, else the built-in response.
+    fn select<T: Transport>(&self, conn: &mut Conn<T>, name: BodyName, builtin: &'static [u8]) {
+        match &self.swapped[name as usize] {
+            Some(r) => conn.set_response_shared(r.clone()),
+            None => conn.set_response(builtin),
+        }
+    }
+
+    /// The first precompressed maintenance page the client accepts, unless one has been uploaded.
+    fn precompressed_maintenance(&self, head: &RequestHead) -> Option<&'static [u8]> {
+        if self.swapped[BodyName::Maintenance as usize].is_some() {
+            return None;
+        }
+        let responses = self.responses;
+        responses.maintenance_encoded.iter().find(|(enc, _)| encoding::accepts(head, *enc)).map(|(_, r)| *r)
+    }
+
+    /// Parks a long poll until an event newer than `since` (by default the latest) is
+    /// published, answering it at once if there is one already.
+    fn park<T: Transport>(&mut self, conn: &mut Conn<T>, since: Option<u64>) -> Progress {
+        let latest = self.long_poll.map_or(0, LongPoll::generation);
+        conn.park(self.clock.now() + self.cfg.long_poll_timeout, since.unwrap_or(latest));
+        if latest > conn.poll_since {
+            self.unpark(conn);
+            return Progress::Armed;
+        }
+        Progress::Parked
+    }
+
+    /// Answers a parked long poll with the latest event if it is newer than the one the
+    /// poll waits on, else (timed out) with a `204` carrying the latest event's number.
+    pub fn unpark<T: Transport>(&mut self, conn: &mut Conn<T>) {
+        conn.unpark();
+        match self.long_poll.and_then(LongPoll::latest) {
+            Some((n, response)) if n > conn.poll_since => conn.set_response_shared(response),
+            latest => {
+                let n = latest.map_or(0, |(n, _)| n);
+                conn.out.clear();
+                let _ = write!(
+                    conn.out,
+                    "HTTP/1.1 204 No Content\r\nVrypt-Event: {n}\r\nConnection: keep-alive\r\n\r\n"
+                );
+                conn.set_response_owned();
+            }
+        }
+        self.arm(conn);
+    }
+
+    fn is_health_check(&self, head: &RequestHead) -> bool {
+        self.cfg.health_path.as_ref().is_some_and(|p| head.path() == p.as_bytes())
+    }
+
+    /// Counts a suspicious request from `peer`.
+    fn flag(&self, peer: SocketAddr, kind: Suspicious) {
+        self.counter.suspicious_request(self.thread_id, kind);
+        if let Some(abuse) = self.abuse {
+            abuse.record_suspicious(peer.ip(), self.clock.now());
+        }
+    }
+
+    /// Answers with an error response, after which the connection is closed with lingering.
+    fn reject<T: Transport>(&mut self, conn: &mut Conn<T>, response: &'static [u8]) -> Progress {
+        conn.read_len = 0;
+        conn.scan_offset = 0;
+        conn.end_body();
+        conn.upload = None;
+        conn.idempotency = None;
+        conn.exec = None;
+        conn.spill = None;
+        conn.close_after_write = true;
+        conn.close_reason = Some(CloseReason::Error);
+        conn.set_response(response);
+        self.arm(conn);
+        Progress::Armed
+    }
+
+    fn acquire_slot(&mut self, held: &mut bool) -> bool {
+        match self.limit {
+            Some(l) if !*held => {
+                *held = l.try_acquire();
+                *held
+            }
+            _ => true,
+        }
+    }
+
+    /// Takes a slot of `tenant`'s in-flight limit unless one is held already.
+    fn acquire_tenant_slot(&mut self, tenant: Option<(usize, &[u8])>, held: &mut Option<usize>) -> bool {
+        let (Some(tenants), Some((i, _))) = (self.tenants, tenant) else { return true };
+        if held.is_none() {
+            if !tenants.try_acquire(i) {
+                tenants.count(i, TenantStat::Shed);
+                return false;
+            }
+            *held = Some(i);
+        }
+        true
+    }
+
+    /// Takes one request of `tenant`'s rate budget on this worker, counting it if there is none left.
+    fn take_tenant_rate(&mut self, tenant: usize) -> bool {
+        let Some(rate) = self.tenant_rates.get_mut(tenant).and_then(Option::as_mut) else { return true };
+        if rate.try_take(self.clock.now()) {
+            return true;
+        }
+        if let Some(tenants) = self.tenants {
+            tenants.count(tenant, TenantStat::Throttled);
+        }
+        false
+    }
+
+    /// Gives back the in-flight slots held by `conn`, if any.
+    pub fn release_slot<T: Transport>(&mut self, conn: &mut Conn<T>) {
+        if let (Some(l), true) = (self.limit, conn.inflight) {
+            l.release();
+            conn.inflight = false;
+        }
+        if let (Some(tenants), Some(i)) = (self.tenants, conn.tenant_slot.take()) {
+            tenants.release(i);
+        }
+        if let Some(limits) = self.scoped {
+            limits.release(&mut conn.scoped);
+        }
+    }
+
+    /// Renders a redirect into `out` if any configured rule matches the request path, or
+    /// to `https://` first for a plaintext request under `--force-https`.
+    fn redirect(&mut self, head: &RequestHead, encrypted: bool, out: &mut Vec<u8>) -> bool {
+        let query = head.query();
+        let upgrade = self.cfg.force_https.filter(|_| !encrypted && self.cfg.terminates_tls());
+        if let (Some(status), Some(host)) = (upgrade, head.header(b"host")) {
+            self.scratch.clear();
+            self.scratch.extend_from_slice(b"https://");
+            self.scratch.extend_from_slice(host);
+            self.scratch.extend_from_slice(head.path());
+            if let Some(q) = query {
+                self.scratch.push(b'?');
+                self.scratch.extend_from_slice(q);
+            }
+            out.clear();
+            response::write_redirect(out, status, &self.scratch);
+            return true;
+        }
+        for rule in &self.cfg.redirects {
+            self.scratch.clear();
+            if rule.apply(head.path(), query, &mut self.scratch) {
+                out.clear();
+                response::write_redirect(out, rule.status, &self.scratch);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn arm<T: Transport>(&mut self, conn: &mut Conn<T>) {
+        if conn.owned && !self.cfg.header_rules.is_empty() {
+            self.scratch.clear();
+            headers::rewrite(&mut self.scratch, &conn.out, &self.cfg.header_rules);
+            std::mem::swap(&mut conn.out, &mut self.scratch);
+        }
+        if let (Some(store), Some((key, fingerprint))) = (self.idempotency, conn.idempotency.take()) {
+            let response = conn.outgoing();
+            if !conn.close_after_write && response.starts_with(b"HTTP/1.1 2") {
+                store.remember(key, fingerprint, response, self.clock.now());
+            }
+        }
+        if self.cfg.date {
+            self.scratch.clear();
+            response::insert_header(&mut self.scratch, conn.outgoing(), self.date.line());
+            std::mem::swap(&mut conn.out, &mut self.scratch);
+            conn.set_response_owned();
+        }
+        if self.cfg.server_timing {
+            self.add_server_timing(conn);
+        }
+        conn.arm_write();
+        if self.cfg.write_timeout.is_some() || self.cfg.min_send_rate.is_some() {
+            conn.write_started = Some(self.clock.now());
+        }
+        conn.fault = match conn.priority {
+            true => None,
+            false => fault::pick(&self.cfg.faults, &mut self.rng).map(|k| k.arm(conn.outgoing())),
+        };
+    }
+
+    /// Re-renders the selected response into `conn.out` with a `Server-Timing` header.
+    fn add_server_timing<T: Transport>(&mut self, conn: &mut Conn<T>) {
+        let now = Instant::now();
+        let t = &mut conn.timing;
+        let ms = |d: Duration| d.as_secs_f64() * 1_000.0;
+        let (start, head_done) = (t.request_start, t.head_done.take());
+        let mut line = String::with_capacity(96);
+        let _ = write!(line, "Server-Timing: ");
+        if let (Some(s), Some(h)) = (start, head_done) {
+            let _ = write!(line, "parse;dur={:.3}, ", ms(h - s));
+        }
+        if let Some(h) = head_done {
+            let _ = write!(line, "handler;dur={:.3}", ms(now - h));
+        }
+        if let Some(w) = t.last_write {
+            let _ = write!(line, ", write;dur={:.3};desc=\"previous response\"", ms(w));
+        }
+        line.push_str("\r\n");
+        t.write_start = Some(now);
+
+        self.scratch.clear();
+        response::insert_header(&mut self.scratch, conn.outgoing(), line.as_bytes());
+        std::mem::swap(&mut conn.out, &mut self.scratch);
+        conn.set_response_owned();
+    }
+}
+
+fn framing(head: &RequestHead) -> Result<Option<Framing>, &'static str> {
+    let chunked = head.is_chunked()?;
+    let length = head.content_length()?;
+    if chunked {
+        if head.header(b"content-length").is_some() {
+            return Err("both Transfer-Encoding and Content-Length present");
+        }
+        return Ok(Some(Framing::Chunked(ChunkedDecoder::new())));
+    }
+    Ok((length > 0).then_some(Framing::Length(length)))
+}
+
+/// Why `RequestHead::parse` refused `head`, as far as the counters tell causes apart.
+fn malformed(head: &[u8]) -> Suspicious {
+    if head.contains(&0) {
+        return Suspicious::Nul;
+    }
+    let line = head.split(|&b| b == b'\n').find(|l| l.iter().any(|&b| b != b'\r')).unwrap_or(&[]);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if !line.split(|&b| b == b' ').next().is_some_and(http::is_token) {
+        return Suspicious::BadMethod;
+    }
+    Suspicious::Malformed
+}
+
+/// A request the forward proxy handles.
+struct ProxyRequest<'a> {
+    authority: &'a [u8],
+    default_port: Option<u16>,
+    /// Path and query to relay; `None` for a `CONNECT`.
+    relayed: Option<&'a [u8]>,
+}
+
+fn proxy_request<'a>(head: &RequestHead<'a>) -> Option<ProxyRequest<'a>> {
+    if head.method == b"CONNECT" {
+        return Some(ProxyRequest { authority: head.target, default_port: None, relayed: None });
+    }
+    // `https` targets are for `CONNECT`; there is no TLS here to relay them with.
+    match http::split_absolute(head.target)? {
+        (scheme, authority, rest) if scheme.eq_ignore_ascii_case(b"http") => {
+            Some(ProxyRequest { authority, default_port: Some(80), relayed: Some(rest) })
+        }
+        _ => None,
+    }
+}
+
+/// Whether `head` is a listeners admin request, applying it if it is a change: `GET` lists
+/// the listeners, `PUT` on an address drains it with `?drain` or resumes it with `?resume`.
+/// Naming no listed address is an error for the response to report.
+fn apply_listener(listen: &ListenSet, head: &RequestHead) -> Option<Result<(), String>> {
+    let rest = head.path().strip_prefix(ADMIN_LISTENERS_PATH)?;
+    if rest.is_empty() {
+        return (head.method == b"GET").then_some(Ok(()));
+    }
+    let spec = rest.strip_prefix(b"/").filter(|_| head.method == b"PUT")?;
+    let drain = match head.query() {
+        Some(b"drain") => true,
+        Some(b"resume") => false,
+        _ => return None,
+    };
+    let spec = String::from_utf8_lossy(spec);
+    Some(listen.set_draining(&spec, drain).map(|addr| {
+        eprintln!("[admin] listener {addr} {}", if drain { "draining" } else { "accepting" });
+    }))
+}
+
+/// Whether the client asked for JSON from an endpoint that also speaks plain text.
+fn wants_json(head: &RequestHead) -> bool {
+    head.header(b"accept").is_some_and(|v| http::find(v, b"application/json").is_some())
+}
+
+/// `{"method", "target", "peer", "headers": [[name, value], ...]}` for `HEADERS_PATH`;
+/// headers stay in request order, repeats included.
+fn write_echo(out: &mut Vec<u8>, head: &RequestHead, peer: SocketAddr) {
+    let mut json = Json::new(out);
+    json.begin_object();
+    json.key("method").str(head.method).key("target").str(head.target).key("peer").display(peer);
+    json.key("headers").begin_array();
+    for (name, value) in head.headers() {
+        json.begin_array().str(name).str(value).end_array();
+    }
+    json.end_array().end_object();
+}
+
+/// Whether `head` is a toggles admin request, applying it if it is a change: `GET` lists
+/// the toggles, `PUT` on a toggle flips it or sets it to its `?on` / `?off` query.
+fn apply_toggle(head: &RequestHead) -> bool {
+    let Some(rest) = head.path().strip_prefix(ADMIN_TOGGLES_PATH) else { return false };
+    if rest.is_empty() {
+        return head.method == b"GET";
+    }
+    let Some(t) = rest.strip_prefix(b"/").and_then(Toggle::parse).filter(|_| head.method == b"PUT") else {
+        return false;
+    };
+    match head.query() {
+        Some(b"on") => t.set(true),
+        Some(b"off") => t.set(false),
+        _ => t.flip(),
+    }
+    eprintln!("[admin] {} {}", t.name(), if t.enabled() { "on" } else { "off" });
+    true
+}
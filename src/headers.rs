@@ -0,0 +1,104 @@
+use crate::http::find;
+use crate::routes::StaticRoute;
+
+/// Header edit: on every response (`--set-header`, `--remove-header`), or on one static
+/// route's responses or the requests relayed to its backend (`RouteHeader`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeaderRule {
+    /// Replace any existing header of this name, or add it.
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+/// Headers that carry message framing and must not be edited.
+const PROTECTED: [&[u8]; 3] = [b"content-length", b"transfer-encoding", b"trailer"];
+
+impl HeaderRule {
+    /// Parses `Name: value`, which sets a header, or `-Name`, which removes it.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec.strip_prefix('-') {
+            Some(name) => HeaderRule::remove(name),
+            None => HeaderRule::set(spec),
+        }
+    }
+
+    /// Parses `Name: value`.
+    pub fn set(spec: &str) -> Result<Self, String> {
+        let (name, value) = spec.split_once(':').ok_or_else(|| format!("expected 'Name: value', got '{spec}'"))?;
+        Ok(HeaderRule::Set(check_name(name.trim())?, value.trim().as_bytes().to_vec()))
+    }
+
+    pub fn remove(name: &str) -> Result<Self, String> {
+        Ok(HeaderRule::Remove(check_name(name.trim())?))
+    }
+
+    pub fn name(&self) -> &[u8] {
+        match self {
+            HeaderRule::Set(n, _) | HeaderRule::Remove(n) => n,
+        }
+    }
+}
+
+/// A header edit for one `--static-route` (`--route-header`, `--route-request-header`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteHeader {
+    /// Tenant and path of the route, as `--static-route` names them.
+    pub tenant: Option<String>,
+    pub path: String,
+    pub rule: HeaderRule,
+}
+
+impl RouteHeader {
+    /// Parses `[TENANT]PATH=Name: value` or `[TENANT]PATH=-Name`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let bad = || format!("expected '[TENANT]PATH=Name: value' or '[TENANT]PATH=-Name', got '{spec}'");
+        let (route, rule) = spec.split_once('=').ok_or_else(bad)?;
+        let (tenant, path) = match route.find('/') {
+            Some(0) => (None, route),
+            Some(slash) => (Some(route[..slash].to_string()), &route[slash..]),
+            None => return Err(bad()),
+        };
+        Ok(Self { tenant, path: path.to_string(), rule: HeaderRule::parse(rule)? })
+    }
+
+    pub fn applies(&self, route: &StaticRoute) -> bool {
+        self.tenant == route.tenant && self.path == route.path
+    }
+}
+
+fn check_name(name: &str) -> Result<Vec<u8>, String> {
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)) {
+        return Err(format!("invalid header name '{name}'"));
+    }
+    if PROTECTED.iter().any(|p| p.eq_ignore_ascii_case(name.as_bytes())) {
+        return Err(format!("'{name}' controls message framing and cannot be changed"));
+    }
+    Ok(name.as_bytes().to_vec())
+}
+
+/// Appends `response` to `out` with `rules` applied to its head.
+pub fn rewrite(out: &mut Vec<u8>, response: &[u8], rules: &[HeaderRule]) {
+    let Some(head_end) = find(response, b"\r\n\r\n") else {
+        out.extend_from_slice(response);
+        return;
+    };
+    let mut lines = response[..head_end + 2].split_inclusive(|&b| b == b'\n');
+    if let Some(status) = lines.next() {
+        out.extend_from_slice(status);
+    }
+    for line in lines {
+        let name = line.split(|&b| b == b':').next().unwrap_or(&[]);
+        if !rules.iter().any(|r| r.name().eq_ignore_ascii_case(name)) {
+            out.extend_from_slice(line);
+        }
+    }
+    for rule in rules {
+        if let HeaderRule::Set(name, value) = rule {
+            out.extend_from_slice(name);
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+    out.extend_from_slice(&response[head_end + 2..]);
+}
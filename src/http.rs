@@ -0,0 +1,240 @@
+/// What the first byte of a request head says about the protocol the client speaks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Preface {
+    Http,
+    /// A TLS record (0x16 = handshake): an HTTPS client on the plaintext port.
+    Tls,
+    /// Neither a method token nor an empty line.
+    Garbage,
+}
+
+/// The part of the HTTP/2 connection preface (RFC 9113 §3.4) that reads as a request head.
+pub const H2_PREFACE_HEAD: &[u8] = b"PRI * HTTP/2.0\r\n\r\n";
+
+/// The ALPN protocol ID of HTTP/2 over TLS.
+pub const ALPN_H2: &[u8] = b"h2";
+
+/// Classifies a non-empty buffer by its first byte, which is all a request line needs to be rejected.
+pub fn sniff(buf: &[u8]) -> Preface {
+    match buf.first() {
+        Some(0x16) => Preface::Tls,
+        Some(b'\r' | b'\n') | None => Preface::Http,
+        Some(&b) if is_tchar(b) => Preface::Http,
+        Some(_) => Preface::Garbage,
+    }
+}
+
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Whether `s` is an RFC 9110 token, as methods and header names must be.
+pub fn is_token(s: &[u8]) -> bool {
+    !s.is_empty() && s.iter().all(|&b| is_tchar(b))
+}
+
+/// Borrowed view of a request head (request line plus header block).
+pub struct RequestHead<'a> {
+    pub method: &'a [u8],
+    pub target: &'a [u8],
+    headers: &'a [u8],
+}
+
+impl<'a> RequestHead<'a> {
+    /// Parses a request head, with or without its terminating empty line. Lines may end in
+    /// CRLF or a bare LF, and empty lines before the request line are skipped. Returns
+    /// `None` for anything RFC 9112 says to reject: a malformed request line, a header line
+    /// that is not `token ":" value` (which covers obsolete line folding and whitespace
+    /// before the colon), or a CR or NUL inside a value.
+    pub fn parse(head: &'a [u8]) -> Option<Self> {
+        let start = head.iter().position(|&b| b != b'\r' && b != b'\n')?;
+        let end = head.iter().rposition(|&b| b != b'\r' && b != b'\n')? + 1;
+        let head = &head[start..end];
+        let line_end = find_byte(head, b'\n').unwrap_or(head.len());
+        let line = &head[..line_end];
+        let mut parts = line.strip_suffix(b"\r").unwrap_or(line).split(|&b| b == b' ').filter(|p| !p.is_empty());
+        let method = parts.next().filter(|m| is_token(m))?;
+        let target = parts.next().filter(|t| t.iter().all(|&b| b > b' ' && b != 0x7f))?;
+        let version = parts.next()?;
+        if !is_version(version) || parts.next().is_some() {
+            return None;
+        }
+        let headers = head.get(line_end + 1..).unwrap_or(&[]);
+        let lines = (!headers.is_empty()).then(|| headers.split(|&b| b == b'\n'));
+        for l in lines.into_iter().flatten() {
+            let l = l.strip_suffix(b"\r").unwrap_or(l);
+            let colon = l.iter().position(|&b| b == b':')?;
+            let (name, value) = (&l[..colon], &l[colon + 1..]);
+            if name.is_empty() || !name.iter().all(|&b| is_tchar(b)) || value.iter().any(|&b| b == b'\r' || b == 0) {
+                return None;
+            }
+        }
+        Some(Self { method, target, headers })
+    }
+
+    /// Request target without the query string. An absolute-form target
+    /// (`http://host/path`) is reduced to its path.
+    pub fn path(&self) -> &'a [u8] {
+        let target = origin_form(self.target);
+        match target.iter().position(|&b| b == b'?') {
+            Some(q) => &target[..q],
+            None => target,
+        }
+    }
+
+    /// The same request asking for `target` instead.
+    pub fn with_target<'b>(&self, target: &'b [u8]) -> RequestHead<'b>
+    where
+        'a: 'b,
+    {
+        RequestHead { method: self.method, target, headers: self.headers }
+    }
+
+    /// Query string without the leading `?`, if any.
+    pub fn query(&self) -> Option<&'a [u8]> {
+        let q = self.target.iter().position(|&b| b == b'?')?;
+        Some(&self.target[q + 1..])
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&'a [u8], &'a [u8])> {
+        self.headers
+            .split(|&b| b == b'\n')
+            .map(|l| l.strip_suffix(b"\r").unwrap_or(l))
+            .filter_map(|l| {
+                let colon = l.iter().position(|&b| b == b':')?;
+                Some((&l[..colon], trim(&l[colon + 1..])))
+            })
+    }
+
+    /// First value of header `name`, compared case-insensitively.
+    pub fn header(&self, name: &[u8]) -> Option<&'a [u8]> {
+        self.headers().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v)
+    }
+
+    /// Declared body length; zero when there is no `Content-Length`.
+    pub fn content_length(&self) -> Result<u64, &'static str> {
+        let mut found: Option<u64> = None;
+        for (_, v) in self.headers().filter(|(n, _)| n.eq_ignore_ascii_case(b"content-length")) {
+            let n = std::str::from_utf8(v)
+                .ok()
+                .filter(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|s| s.parse::<u64>().ok())
+                .ok_or("invalid Content-Length")?;
+            if found.is_some_and(|f| f != n) {
+                return Err("conflicting Content-Length headers");
+            }
+            found = Some(n);
+        }
+        Ok(found.unwrap_or(0))
+    }
+
+    /// Whether the final transfer coding is `chunked`; any other transfer coding is an error.
+    pub fn is_chunked(&self) -> Result<bool, &'static str> {
+        let mut chunked = false;
+        for (_, v) in self.headers().filter(|(n, _)| n.eq_ignore_ascii_case(b"transfer-encoding")) {
+            for coding in v.split(|&b| b == b',').map(trim).filter(|c| !c.is_empty()) {
+                if chunked {
+                    return Err("chunked must be the final transfer coding");
+                }
+                if !coding.eq_ignore_ascii_case(b"chunked") {
+                    return Err("unsupported transfer coding");
+                }
+                chunked = true;
+            }
+        }
+        Ok(chunked)
+    }
+
+    pub fn expects_continue(&self) -> bool {
+        self.header(b"expect").is_some_and(|v| v.eq_ignore_ascii_case(b"100-continue"))
+    }
+}
+
+/// Length of the request head in `buf` including the empty line that ends it, which may
+/// end in CRLF or a bare LF like the lines before it. The first `from` bytes have already
+/// been searched, so only line feeds after them are looked at; each is checked against
+/// the two bytes before it, which may lie in the searched part.
+#[inline]
+pub fn find_head_end(buf: &[u8], from: usize) -> Option<usize> {
+    let mut i = from;
+    while let Some(p) = find_byte(&buf[i..], b'\n') {
+        let lf = i + p;
+        if (lf >= 1 && buf[lf - 1] == b'\n') || (lf >= 2 && &buf[lf - 2..lf] == b"\n\r") {
+            return Some(lf + 1);
+        }
+        i = lf + 1;
+    }
+    None
+}
+
+/// Position of the first `needle` in `hay`, comparing 16 bytes at a time with SSE2.
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn find_byte(hay: &[u8], needle: u8) -> Option<usize> {
+    use std::arch::x86_64::{__m128i, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_set1_epi8};
+
+    let mut i = 0;
+    // SSE2 is part of the x86_64 baseline, and every load stays within `hay`.
+    unsafe {
+        let n = _mm_set1_epi8(needle as i8);
+        while i + 16 <= hay.len() {
+            let chunk = _mm_loadu_si128(hay.as_ptr().add(i) as *const __m128i);
+            let mask = _mm_movemask_epi8(_mm_cmpeq_epi8(chunk, n));
+            if mask != 0 {
+                return Some(i + mask.trailing_zeros() as usize);
+            }
+            i += 16;
+        }
+    }
+    hay[i..].iter().position(|&b| b == needle).map(|p| i + p)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+#[inline]
+fn find_byte(hay: &[u8], needle: u8) -> Option<usize> {
+    hay.iter().position(|&b| b == needle)
+}
+
+pub fn find(hay: &[u8], needle: &[u8]) -> Option<usize> {
+    hay.windows(needle.len()).position(|w| w == needle)
+}
+
+/// `HTTP/` followed by a single-digit major and minor version.
+fn is_version(v: &[u8]) -> bool {
+    matches!(v, [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit())
+}
+
+/// Splits an absolute-form `http` or `https` target into scheme, authority and the path
+/// and query after it (empty when there is neither).
+pub fn split_absolute(target: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    if target.first() == Some(&b'/') {
+        return None;
+    }
+    let scheme_end = find(target, b"://")?;
+    let scheme = &target[..scheme_end];
+    if !scheme.eq_ignore_ascii_case(b"http") && !scheme.eq_ignore_ascii_case(b"https") {
+        return None;
+    }
+    let rest = &target[scheme_end + 3..];
+    let split = rest.iter().position(|&b| b == b'/' || b == b'?').unwrap_or(rest.len());
+    Some((scheme, &rest[..split], &rest[split..]))
+}
+
+/// The path and query of an absolute-form target; other targets are returned as they are.
+fn origin_form(target: &[u8]) -> &[u8] {
+    match split_absolute(target) {
+        Some((_, _, b"")) => b"/",
+        Some((_, _, rest)) => rest,
+        None => target,
+    }
+}
+
+fn trim(mut v: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = v {
+        v = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = v {
+        v = rest;
+    }
+    v
+}
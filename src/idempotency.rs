@@ -0,0 +1,107 @@
+//! Idempotency keys (`--idempotency-ttl`): a successful response to a request carrying an
+//! `Idempotency-Key` header is remembered for the TTL, and a retry with the same key gets
+//! it back with `Idempotent-Replayed: true` instead of being handled again. Reusing a key
+//! for a different method or target is refused with 422. Requests are told apart by
+//! method and target only; bodies are not compared.
+
+use crate::config::{IDEMPOTENCY_CAPACITY, MAX_IDEMPOTENCY_KEY};
+use crate::http::RequestHead;
+use crate::response;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+const SHARDS: usize = 16;
+const REPLAYED: &[u8] = b"Idempotent-Replayed: true\r\n";
+
+/// What the store holds for a key.
+pub enum Lookup {
+    /// Nothing yet; remember the response once it is ready.
+    Miss,
+    /// The response to replay, `Idempotent-Replayed` included.
+    Replay(Arc<[u8]>),
+    /// The key was used for a different request.
+    Mismatch,
+}
+
+struct Entry {
+    fingerprint: u64,
+    response: Arc<[u8]>,
+    expires: Instant,
+}
+
+/// Remembered responses by key, shared by all workers so a retry on another connection
+/// finds them. At most `IDEMPOTENCY_CAPACITY` keys are held; when a shard is full, expired
+/// entries are dropped and, failing that, new keys are not remembered.
+pub struct IdempotencyStore {
+    shards: [Mutex<HashMap<Box<[u8]>, Entry>>; SHARDS],
+    hasher: RandomState,
+    ttl: Duration,
+}
+
+impl IdempotencyStore {
+    pub fn new(ttl: Duration) -> &'static Self {
+        Box::leak(Box::new(Self {
+            shards: std::array::from_fn(|_| Mutex::new(HashMap::new())),
+            hasher: RandomState::new(),
+            ttl,
+        }))
+    }
+
+    /// Identifies the request a key was first used for.
+    pub fn fingerprint(&self, head: &RequestHead) -> u64 {
+        self.hasher.hash_one((head.method, head.target))
+    }
+
+    pub fn lookup(&self, key: &[u8], fingerprint: u64, now: Instant) -> Lookup {
+        let mut shard = self.shard(key);
+        match shard.get(key) {
+            Some(e) if e.expires <= now => {
+                shard.remove(key);
+                Lookup::Miss
+            }
+            Some(e) if e.fingerprint != fingerprint => Lookup::Mismatch,
+            Some(e) => Lookup::Replay(e.response.clone()),
+            None => Lookup::Miss,
+        }
+    }
+
+    /// Remembers `response` for `key` unless a concurrent request got there first.
+    pub fn remember(&self, key: Box<[u8]>, fingerprint: u64, response: &[u8], now: Instant) {
+        let mut shard = self.shard(&key);
+        if shard.get(&key).is_some_and(|e| e.expires > now) {
+            return;
+        }
+        if shard.len() >= IDEMPOTENCY_CAPACITY / SHARDS && !shard.contains_key(&key) {
+            shard.retain(|_, e| e.expires > now);
+            if shard.len() >= IDEMPOTENCY_CAPACITY / SHARDS {
+                return;
+            }
+        }
+        let mut replayed = Vec::with_capacity(response.len() + REPLAYED.len());
+        response::insert_header(&mut replayed, response, REPLAYED);
+        shard.insert(key, Entry { fingerprint, response: replayed.into(), expires: now + self.ttl });
+    }
+
+    /// Keys currently held, expired ones included until they are next looked at.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap_or_else(PoisonError::into_inner).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &[u8]) -> MutexGuard<'_, HashMap<Box<[u8]>, Entry>> {
+        let shard = &self.shards[self.hasher.hash_one(key) as usize % SHARDS];
+        shard.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The request's `Idempotency-Key`, if it has a usable one: non-empty and at most
+/// `MAX_IDEMPOTENCY_KEY` bytes. Longer keys are ignored rather than truncated.
+pub fn key<'a>(head: &RequestHead<'a>) -> Option<&'a [u8]> {
+    let key = head.header(b"idempotency-key")?.trim_ascii();
+    (!key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY).then_some(key)
+}
@@ -0,0 +1,142 @@
+//! Minimal JSON writer for the structured responses workers build per request (`/headers`,
+//! the admin endpoints). Values are appended straight to the output buffer, so a response
+//! costs no allocation beyond that buffer's growth.
+
+use std::fmt::{self, Write as _};
+use std::io::Write as _;
+
+/// Appends one JSON value to `out`. Containers and keys are opened and closed explicitly;
+/// commas between members are inserted automatically. Nesting is not checked.
+pub struct Json<'a> {
+    out: &'a mut Vec<u8>,
+    /// Whether the next member needs a comma before it.
+    comma: bool,
+}
+
+impl<'a> Json<'a> {
+    pub fn new(out: &'a mut Vec<u8>) -> Self {
+        Self { out, comma: false }
+    }
+
+    pub fn begin_object(&mut self) -> &mut Self {
+        self.open(b'{')
+    }
+
+    pub fn end_object(&mut self) -> &mut Self {
+        self.close(b'}')
+    }
+
+    pub fn begin_array(&mut self) -> &mut Self {
+        self.open(b'[')
+    }
+
+    pub fn end_array(&mut self) -> &mut Self {
+        self.close(b']')
+    }
+
+    /// Starts an object member; the next call writes its value.
+    pub fn key(&mut self, name: &str) -> &mut Self {
+        self.separate();
+        escape(self.out, name.as_bytes());
+        self.out.push(b':');
+        self.comma = false;
+        self
+    }
+
+    /// A string value. Invalid UTF-8 is replaced with U+FFFD, as `String::from_utf8_lossy` would.
+    pub fn str(&mut self, value: &[u8]) -> &mut Self {
+        self.separate();
+        escape(self.out, value);
+        self.comma = true;
+        self
+    }
+
+    /// A string value rendered with `Display`, e.g. an address.
+    pub fn display(&mut self, value: impl fmt::Display) -> &mut Self {
+        self.separate();
+        self.out.push(b'"');
+        let _ = write!(Escaper(self.out), "{value}");
+        self.out.push(b'"');
+        self.comma = true;
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.separate();
+        let _ = write!(self.out, "{value}");
+        self.comma = true;
+        self
+    }
+
+    pub fn bool(&mut self, value: bool) -> &mut Self {
+        self.separate();
+        self.out.extend_from_slice(if value { b"true" } else { b"false" });
+        self.comma = true;
+        self
+    }
+
+    fn open(&mut self, bracket: u8) -> &mut Self {
+        self.separate();
+        self.out.push(bracket);
+        self.comma = false;
+        self
+    }
+
+    fn close(&mut self, bracket: u8) -> &mut Self {
+        self.out.push(bracket);
+        self.comma = true;
+        self
+    }
+
+    fn separate(&mut self) {
+        if self.comma {
+            self.out.push(b',');
+        }
+    }
+}
+
+/// `fmt::Write` adapter escaping what is written into a JSON string body.
+struct Escaper<'a>(&'a mut Vec<u8>);
+
+impl fmt::Write for Escaper<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        escape_into(self.0, s);
+        Ok(())
+    }
+}
+
+/// Appends `bytes` as a quoted JSON string.
+fn escape(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.push(b'"');
+    for chunk in bytes.utf8_chunks() {
+        escape_into(out, chunk.valid());
+        if !chunk.invalid().is_empty() {
+            out.extend_from_slice("\u{fffd}".as_bytes());
+        }
+    }
+    out.push(b'"');
+}
+
+fn escape_into(out: &mut Vec<u8>, s: &str) {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let bytes = s.as_bytes();
+    let mut start = 0;
+    for (i, &b) in bytes.iter().enumerate() {
+        let short = match b {
+            b'"' => b'"',
+            b'\\' => b'\\',
+            b'\n' => b'n',
+            b'\r' => b'r',
+            b'\t' => b't',
+            0..=0x1f => 0,
+            _ => continue,
+        };
+        out.extend_from_slice(&bytes[start..i]);
+        start = i + 1;
+        match short {
+            0 => out.extend_from_slice(&[b'\\', b'u', b'0', b'0', HEX[usize::from(b >> 4)], HEX[usize::from(b & 0xf)]]),
+            c => out.extend_from_slice(&[b'\\', c]),
+        }
+    }
+    out.extend_from_slice(&bytes[start..]);
+}
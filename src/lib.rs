@@ -0,0 +1,76 @@
+mod access;
+pub mod affinity;
+mod acceptor;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod alloc;
+pub mod bodies;
+pub mod bus;
+pub mod capture;
+pub mod check;
+pub mod clock;
+pub mod collapse;
+pub mod close;
+pub mod config;
+mod body;
+pub mod conn;
+mod drive;
+pub mod connlist;
+pub mod counter;
+pub mod daemon;
+mod date;
+mod encoding;
+pub mod error;
+pub mod error_page;
+pub mod exec;
+pub mod fastcgi;
+pub mod fault;
+#[cfg(fuzzing)]
+pub mod fuzz;
+mod handler;
+pub mod headers;
+pub mod idempotency;
+mod http;
+pub mod json;
+pub mod limit;
+pub mod listen;
+pub mod longpoll;
+mod pool;
+pub mod privilege;
+mod proxy;
+mod pushgateway;
+pub mod mime;
+pub mod mirror;
+pub mod outbound;
+pub mod redirect;
+pub mod region;
+pub mod replay;
+mod response;
+pub mod rewrite;
+mod rng;
+mod scrape;
+pub mod routes;
+pub mod server;
+mod sha256;
+pub mod sizes;
+pub mod spec;
+pub mod split;
+pub mod signal;
+pub mod sink;
+pub mod spill;
+mod slab;
+pub mod sockopt;
+pub mod statsd;
+pub mod tcpinfo;
+pub mod template;
+pub mod tenant;
+pub mod timer;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod toggle;
+pub mod transport;
+pub mod tunnel;
+pub mod upstream;
+mod watch;
+pub mod worker;
+pub mod xdp;
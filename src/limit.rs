@@ -0,0 +1,276 @@
+use crate::bus::{Bus, Event};
+use crate::config::{Config, ACCEPT_BURST};
+use crate::routes::Route;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Cap on requests in flight (head received, response not yet fully written), shared by
+/// all workers: server-wide, or for one listener, route or tenant.
+pub struct InflightLimit {
+    current: AtomicUsize,
+    max: usize,
+    /// Requests waiting for a slot, and how many may; with 0 a request over the cap is
+    /// refused at once.
+    queued: AtomicUsize,
+    queue: usize,
+}
+
+impl InflightLimit {
+    pub fn new(max: usize) -> &'static Self {
+        Self::with_queue(max, 0)
+    }
+
+    pub fn with_queue(max: usize, queue: usize) -> &'static Self {
+        Box::leak(Box::new(Self { current: AtomicUsize::new(0), max, queued: AtomicUsize::new(0), queue }))
+    }
+
+    // Sequentially consistent with `try_queue` and `waiting`, so a slot released while a
+    // request joins the queue is either taken by it or wakes it.
+    #[inline]
+    pub fn try_acquire(&self) -> bool {
+        if self.current.fetch_add(1, Ordering::SeqCst) >= self.max {
+            self.current.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    #[inline]
+    pub fn release(&self) {
+        self.current.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Takes a place in the queue if there is room.
+    pub fn try_queue(&self) -> bool {
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.queue {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    pub fn unqueue(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Whether requests are waiting for a slot.
+    pub fn waiting(&self) -> bool {
+        self.queued.load(Ordering::SeqCst) > 0
+    }
+}
+
+/// One `--listener-max-inflight` rule: at most `max` requests in flight on the listener
+/// listed as `addr`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenerLimit {
+    pub addr: SocketAddr,
+    pub max: usize,
+}
+
+impl ListenerLimit {
+    /// Parses `ADDR=N`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (addr, max) = spec.split_once('=').ok_or_else(|| format!("expected 'ADDR=N', got '{spec}'"))?;
+        let addr = addr.parse().map_err(|_| format!("invalid listen address '{addr}'"))?;
+        Ok(Self { addr, max: parse_max(max)? })
+    }
+}
+
+/// One `--route-max-inflight` rule: at most `max` requests in flight on the static route
+/// with `path` (and `tenant`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteLimit {
+    pub tenant: Option<String>,
+    pub path: String,
+    pub max: usize,
+}
+
+impl RouteLimit {
+    /// Parses `[TENANT]PATH=N`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let bad = || format!("expected '[TENANT]PATH=N', got '{spec}'");
+        let (route, max) = spec.rsplit_once('=').ok_or_else(bad)?;
+        let (tenant, path) = match route.find('/') {
+            Some(0) => (None, route),
+            Some(slash) => (Some(route[..slash].to_string()), &route[slash..]),
+            None => return Err(bad()),
+        };
+        Ok(Self { tenant, path: path.to_string(), max: parse_max(max)? })
+    }
+}
+
+fn parse_max(max: &str) -> Result<usize, String> {
+    match max.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("the limit must be a positive number, got '{max}'")),
+    }
+}
+
+/// The per-listener and per-route in-flight limits, shared by all workers. A request over
+/// one waits in its queue (`--inflight-queue`) for up to `--inflight-queue-timeout`; every
+/// worker is told when a slot frees while requests wait, and retries its waiting ones.
+pub struct ScopedLimits {
+    listeners: Vec<(SocketAddr, &'static InflightLimit)>,
+    /// By the route's tenant index and path.
+    routes: Vec<(Option<usize>, String, &'static InflightLimit)>,
+    /// How long a request may wait in a queue.
+    timeout: Duration,
+    bus: &'static Bus,
+}
+
+/// A request's hold on the per-listener and per-route in-flight limits.
+#[derive(Default)]
+pub struct ScopedSlots {
+    pub listener: Option<&'static InflightLimit>,
+    pub route: Option<&'static InflightLimit>,
+    /// The limit whose queue the request waits in, and until when.
+    pub queued: Option<(&'static InflightLimit, Instant)>,
+}
+
+impl ScopedSlots {
+    /// Gives up the request's place in a queue, if it has one.
+    pub fn leave_queue(&mut self) {
+        if let Some((limit, _)) = self.queued.take() {
+            limit.unqueue();
+        }
+    }
+}
+
+/// What `ScopedLimits::acquire` made of a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Admitted,
+    /// Waiting in a queue until the deadline.
+    Queued(Instant),
+    Refused,
+}
+
+impl ScopedLimits {
+    /// `None` without any per-listener or per-route limit. Limits for a tenant that is not
+    /// configured are skipped with a warning.
+    pub fn new(cfg: &Config, bus: &'static Bus) -> Option<&'static Self> {
+        if cfg.listener_limits.is_empty() && cfg.route_limits.is_empty() {
+            return None;
+        }
+        let queue = cfg.inflight_queue;
+        let listeners = cfg.listener_limits.iter().map(|l| (l.addr, InflightLimit::with_queue(l.max, queue))).collect();
+        let routes = cfg
+            .route_limits
+            .iter()
+            .filter_map(|l| {
+                let tenant = match &l.tenant {
+                    Some(name) => match cfg.tenants.iter().position(|t| t.name == *name) {
+                        Some(i) => Some(i),
+                        None => {
+                            eprintln!("[warn] no tenant '{name}' for the in-flight limit of {}, not limiting it", l.path);
+                            return None;
+                        }
+                    },
+                    None => None,
+                };
+                Some((tenant, l.path.clone(), InflightLimit::with_queue(l.max, queue)))
+            })
+            .collect();
+        let timeout = cfg.inflight_queue_timeout;
+        Some(Box::leak(Box::new(Self { listeners, routes, timeout, bus })))
+    }
+
+    /// Takes slots of the limits of `listener` and `route` that `slots` does not hold yet.
+    /// Over one of them, the request waits in its queue if there is room and it has not
+    /// waited its time already; it keeps the slots it got meanwhile.
+    pub fn acquire(
+        &self,
+        slots: &mut ScopedSlots,
+        listener: Option<SocketAddr>,
+        route: Option<&Route>,
+        now: Instant,
+    ) -> Admission {
+        let listener = listener.and_then(|a| self.listeners.iter().find(|(l, _)| *l == a)).map(|(_, l)| *l);
+        let route = route.and_then(|r| self.routes.iter().find(|(t, p, _)| *t == r.tenant && *p == r.path));
+        let mut full = None;
+        for (limit, held) in [(listener, &mut slots.listener), (route.map(|(_, _, l)| *l), &mut slots.route)] {
+            let Some(limit) = limit.filter(|_| held.is_none()) else { continue };
+            if !limit.try_acquire() {
+                full = Some(limit);
+                break;
+            }
+            *held = Some(limit);
+        }
+        let Some(limit) = full else { return Admission::Admitted };
+        match slots.queued {
+            Some((_, until)) if until <= now => Admission::Refused,
+            Some((queue, until)) if std::ptr::eq(queue, limit) => Admission::Queued(until),
+            queued => {
+                // Moving on to the next limit's queue keeps the deadline.
+                let until = queued.map_or(now + self.timeout, |(_, until)| until);
+                slots.leave_queue();
+                if !limit.try_queue() {
+                    return Admission::Refused;
+                }
+                slots.queued = Some((limit, until));
+                // A slot freed before the place was taken woke no one; hand it back so it does.
+                if limit.try_acquire() {
+                    self.release_one(limit);
+                }
+                Admission::Queued(until)
+            }
+        }
+    }
+
+    /// Gives back the slots and the queue place `slots` holds.
+    pub fn release(&self, slots: &mut ScopedSlots) {
+        for limit in [slots.listener.take(), slots.route.take()].into_iter().flatten() {
+            self.release_one(limit);
+        }
+        slots.leave_queue();
+    }
+
+    /// Gives back a slot of `limit`, waking the workers if requests wait for one.
+    fn release_one(&self, limit: &InflightLimit) {
+        limit.release();
+        if limit.waiting() {
+            self.bus.post(Event::Slots);
+        }
+    }
+}
+
+/// Token bucket pacing how fast one thread takes on new connections (`--accept-rate`).
+/// Connections beyond the rate stay in the kernel's accept queue until tokens refill. A
+/// tenant's `rate` paces its requests with one per worker the same way.
+pub struct AcceptRate {
+    per_sec: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl AcceptRate {
+    pub fn new(per_sec: u32, now: Instant) -> Self {
+        let per_sec = per_sec as f64;
+        let burst = (per_sec * ACCEPT_BURST.as_secs_f64()).max(1.0);
+        Self { per_sec, burst, tokens: burst, last: now }
+    }
+
+    /// Takes a token if one is available.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.burst);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Returns a token taken for an accept that found nothing queued.
+    pub fn refund(&mut self) {
+        self.tokens = (self.tokens + 1.0).min(self.burst);
+    }
+
+    /// Time until the next token is available.
+    pub fn wait(&self) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.per_sec).max(0.0))
+    }
+}
@@ -0,0 +1,504 @@
+use crate::bus::{Bus, Event};
+use crate::config::{Config, CONN_TOKEN_MIN, LISTENER_TOKEN_BASE};
+use crate::error::VryptError;
+use crate::json::Json;
+use crate::worker::{bind_listener, clone_listener};
+use mio::net::TcpListener;
+use mio::{Interest, Poll, Token};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Reads a listen file: one `ip:port` (or bare port, meaning all IPv4 interfaces) per line;
+/// blank lines and `#` comments are ignored.
+pub fn read_file(path: &Path) -> io::Result<Vec<SocketAddr>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut addrs = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let addr = match line.parse::<u16>() {
+            Ok(port) => SocketAddr::from(([0, 0, 0, 0], port)),
+            Err(_) => line.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: invalid address '{line}'", n + 1))
+            })?,
+        };
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no addresses listed"));
+    }
+    Ok(addrs)
+}
+
+/// How connections get from the listening sockets to the workers.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AcceptMode {
+    /// Every worker binds its own `SO_REUSEPORT` listener; the kernel spreads connections
+    /// by hashing the 4-tuple.
+    ReusePort,
+    /// One listener per address shared by all workers, registered with `EPOLLEXCLUSIVE`
+    /// so a new connection wakes one worker rather than all of them.
+    Shared,
+    /// A dedicated thread accepts and hands connections to the workers in turn.
+    Thread,
+}
+
+impl AcceptMode {
+    pub const ALL: [AcceptMode; 3] = [AcceptMode::ReusePort, AcceptMode::Shared, AcceptMode::Thread];
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        AcceptMode::ALL.into_iter().find(|m| m.name() == name).ok_or_else(|| {
+            format!("unrecognised accept mode '{name}' (expected reuseport, shared or thread)")
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AcceptMode::ReusePort => "reuseport",
+            AcceptMode::Shared => "shared",
+            AcceptMode::Thread => "thread",
+        }
+    }
+
+    /// How many threads own listeners: every worker, or just the accept thread.
+    pub fn owners(self, workers: usize) -> usize {
+        match self {
+            AcceptMode::ReusePort | AcceptMode::Shared => workers,
+            AcceptMode::Thread => 1,
+        }
+    }
+}
+
+/// Most ports `--port-range` may span; every port is bound once per worker.
+pub const MAX_PORT_RANGE: usize = 1024;
+
+/// Parses `first-last` for `--port-range`.
+pub fn parse_range(spec: &str) -> Result<RangeInclusive<u16>, String> {
+    let (first, last) = spec.split_once('-').ok_or_else(|| format!("expected 'first-last', got '{spec}'"))?;
+    let port = |s: &str| s.trim().parse::<u16>().ok().filter(|&p| p > 0);
+    let (Some(first), Some(last)) = (port(first), port(last)) else {
+        return Err(format!("invalid port range '{spec}'"));
+    };
+    if first > last || (last - first) as usize >= MAX_PORT_RANGE {
+        return Err(format!("port range '{spec}' must be ascending and span at most {MAX_PORT_RANGE} ports"));
+    }
+    Ok(first..=last)
+}
+
+/// Addresses to listen on without a listen file: every port of `--port-range` on the
+/// address's IP, or just `cfg.addr`.
+pub fn fixed_addrs(cfg: &Config) -> Vec<SocketAddr> {
+    match &cfg.port_range {
+        Some(range) => range.clone().map(|port| SocketAddr::new(cfg.addr.ip(), port)).collect(),
+        None => vec![cfg.addr],
+    }
+}
+
+/// Listener for one address, held by one worker (or the accept thread). `addr` is the address as listed,
+/// which is what removals are matched against.
+pub struct Bound {
+    pub addr: SocketAddr,
+    pub listener: TcpListener,
+    /// Taken off the poll while its address drains; see `ListenSet::set_draining`.
+    pub paused: bool,
+}
+
+struct Inner {
+    addrs: Vec<SocketAddr>,
+    /// Where each listed address ended up bound, which differs for port 0.
+    local: HashMap<SocketAddr, SocketAddr>,
+    /// Listed addresses that accept nothing new while their connections wind down.
+    draining: Vec<SocketAddr>,
+    /// Listeners bound for each owner but not yet picked up by it.
+    incoming: Vec<Vec<Bound>>,
+}
+
+/// One listed address as the admin listener endpoint reports it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ListenerState {
+    /// As listed.
+    pub addr: SocketAddr,
+    /// As bound; differs from `addr` for port 0.
+    pub local: SocketAddr,
+    pub draining: bool,
+}
+
+/// The set of addresses the server listens on. Changing it binds the new addresses for
+/// every owner up front; workers are told with `Event::Listeners` (the accept thread
+/// notices the new `generation` at its next poll round), register what was bound for them
+/// and drain and close listeners no longer listed.
+pub struct ListenSet {
+    mode: AcceptMode,
+    /// Interface or VRF every listener is bound to (`SO_BINDTODEVICE`).
+    device: Option<String>,
+    generation: AtomicU64,
+    inner: Mutex<Inner>,
+    bus: &'static Bus,
+}
+
+impl ListenSet {
+    /// Binds `addrs` for each of `owners` threads: separately under `ReusePort`, otherwise
+    /// once and shared. A port 0 address is bound to the same ephemeral port for all owners.
+    /// With `device`, listeners only take connections arriving on that interface or VRF.
+    /// Changes are posted to `bus`. Returns the set and the listeners for each owner.
+    pub fn bind(
+        addrs: &[SocketAddr],
+        owners: usize,
+        mode: AcceptMode,
+        device: Option<String>,
+        bus: &'static Bus,
+    ) -> Result<(&'static Self, Vec<Vec<Bound>>), VryptError> {
+        let per_owner = bind_all(addrs, owners, mode, device.as_deref())?;
+        let inner = Inner {
+            addrs: addrs.to_vec(),
+            local: local_addrs(&per_owner),
+            draining: Vec::new(),
+            incoming: (0..owners).map(|_| Vec::new()).collect(),
+        };
+        let set = Self { mode, device, generation: AtomicU64::new(0), inner: Mutex::new(inner), bus };
+        Ok((Box::leak(Box::new(set)), per_owner))
+    }
+
+    pub fn mode(&self) -> AcceptMode {
+        self.mode
+    }
+
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.lock().addrs.clone()
+    }
+
+    /// Switches to `addrs`. Nothing changes if any new address fails to bind.
+    /// Returns the addresses added and removed.
+    pub fn update(&self, addrs: Vec<SocketAddr>) -> Result<(Vec<SocketAddr>, Vec<SocketAddr>), VryptError> {
+        let mut inner = self.lock();
+        let added: Vec<_> = addrs.iter().copied().filter(|a| !inner.addrs.contains(a)).collect();
+        let removed: Vec<_> = inner.addrs.iter().copied().filter(|a| !addrs.contains(a)).collect();
+        let owners = inner.incoming.len();
+        let per_owner = bind_all(&added, owners, self.mode, self.device.as_deref())?;
+        let local = local_addrs(&per_owner);
+        for (queue, bound) in inner.incoming.iter_mut().zip(per_owner) {
+            queue.retain(|b| addrs.contains(&b.addr));
+            queue.extend(bound);
+        }
+        inner.local.retain(|a, _| addrs.contains(a));
+        inner.local.extend(local);
+        inner.draining.retain(|a| addrs.contains(a));
+        inner.addrs = addrs;
+        self.generation.fetch_add(1, Ordering::Release);
+        drop(inner);
+        self.bus.post(Event::Listeners);
+        Ok((added, removed))
+    }
+
+    /// The current addresses and the listeners newly bound for owner `id`.
+    pub fn take(&self, id: usize) -> (Vec<SocketAddr>, Vec<Bound>) {
+        let mut inner = self.lock();
+        let incoming = std::mem::take(&mut inner.incoming[id]);
+        (inner.addrs.clone(), incoming)
+    }
+
+    /// Listed addresses currently draining.
+    pub fn draining(&self) -> Vec<SocketAddr> {
+        self.lock().draining.clone()
+    }
+
+    /// Stops (`drain`) or resumes accepting on the listed address `spec` names: as listed,
+    /// as bound, or by port alone. The other addresses keep serving. Connections that came
+    /// in through a draining address are closed when idle, or once their response is written.
+    /// Returns the listed address.
+    pub fn set_draining(&self, spec: &str, drain: bool) -> Result<SocketAddr, String> {
+        let mut inner = self.lock();
+        let port = spec.parse::<u16>().ok();
+        let addr = spec.parse::<SocketAddr>().ok();
+        let found = inner.addrs.iter().copied().find(|a| {
+            let local = inner.local.get(a).copied().unwrap_or(*a);
+            match (addr, port) {
+                (Some(addr), _) => addr == *a || addr == local,
+                (_, Some(port)) => port == a.port() || port == local.port(),
+                _ => false,
+            }
+        });
+        let addr = found.ok_or_else(|| format!("no listener at '{spec}'"))?;
+        if inner.draining.contains(&addr) != drain {
+            match drain {
+                true => inner.draining.push(addr),
+                false => inner.draining.retain(|a| *a != addr),
+            }
+            self.generation.fetch_add(1, Ordering::Release);
+            self.bus.post(Event::Listeners);
+        }
+        Ok(addr)
+    }
+
+    /// Appends one line per listed address: as listed, as bound, and whether it is
+    /// accepting or draining.
+    pub fn write_states(&self, out: &mut Vec<u8>) {
+        for l in self.states() {
+            let state = if l.draining { "draining" } else { "accepting" };
+            let _ = writeln!(out, "addr={} local={} state={state}", l.addr, l.local);
+        }
+    }
+
+    /// The same as `write_states`, as a JSON array of objects.
+    pub fn write_states_json(&self, out: &mut Vec<u8>) {
+        let mut json = Json::new(out);
+        json.begin_array();
+        for l in self.states() {
+            json.begin_object().key("addr").display(l.addr).key("local").display(l.local);
+            json.key("draining").bool(l.draining).end_object();
+        }
+        json.end_array();
+    }
+
+    /// Every listed address with where it is bound and whether it is draining.
+    pub fn states(&self) -> Vec<ListenerState> {
+        let inner = self.lock();
+        let state = |addr: &SocketAddr| ListenerState {
+            addr: *addr,
+            local: *inner.local.get(addr).unwrap_or(addr),
+            draining: inner.draining.contains(addr),
+        };
+        inner.addrs.iter().map(state).collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The listeners one thread polls, by slot; slot `i` is registered as `LISTENER_TOKEN_BASE + i`.
+pub struct Listeners {
+    set: &'static ListenSet,
+    owner: usize,
+    slots: Vec<Option<Bound>>,
+    /// `ListenSet` generation the slots were last synced with.
+    generation: u64,
+    /// Taken off the poll by `suspend`, all of them, until `resume`.
+    suspended: bool,
+}
+
+impl Listeners {
+    /// Takes `bound`, the listeners `ListenSet::bind` returned for `owner`, not yet
+    /// registered with any poll; see `register`.
+    pub fn new(set: &'static ListenSet, owner: usize, bound: Vec<Bound>) -> Self {
+        let slots = bound.into_iter().map(Some).collect();
+        Self { set, owner, slots, generation: set.generation(), suspended: false }
+    }
+
+    /// Registers every listener with `poll`, as when a thread (re)starts its event loop.
+    /// A listener that cannot be registered is dropped.
+    pub fn register(&mut self, poll: &Poll) {
+        self.suspended = false;
+        for (slot, entry) in self.slots.iter_mut().enumerate() {
+            let Some(bound) = entry.as_mut().filter(|b| !b.paused) else { continue };
+            if let Err(e) = register_slot(self.set.mode, poll, bound, slot) {
+                eprintln!("[warn] cannot register listener {}: {e}", bound.addr);
+                *entry = None;
+            }
+        }
+    }
+
+    /// Undoes `register` before `poll` is dropped, so the listeners can join a new one;
+    /// suspended listeners are off it already.
+    pub fn deregister(&mut self, poll: &Poll) {
+        if std::mem::take(&mut self.suspended) {
+            return;
+        }
+        for bound in self.slots.iter_mut().flatten().filter(|b| !b.paused) {
+            deregister_slot(self.set.mode, poll, bound);
+        }
+    }
+
+    /// Stops accepting on every listener without closing any: new connections queue in the
+    /// kernel's accept backlog until `resume`.
+    pub fn suspend(&mut self, poll: &Poll) {
+        if !self.suspended {
+            self.deregister(poll);
+            self.suspended = true;
+        }
+    }
+
+    /// Undoes `suspend`. Registering reports what queued meanwhile as a fresh edge.
+    pub fn resume(&mut self, poll: &Poll) {
+        if self.suspended {
+            self.register(poll);
+        }
+    }
+
+    #[inline]
+    pub fn suspended(&self) -> bool {
+        self.suspended
+    }
+
+    fn add(&mut self, poll: &Poll, mut bound: Bound) -> io::Result<()> {
+        let slot = self.slots.iter().position(Option::is_none).unwrap_or(self.slots.len());
+        register_slot(self.set.mode, poll, &mut bound, slot)?;
+        if slot == self.slots.len() {
+            self.slots.push(None);
+        }
+        self.slots[slot] = Some(bound);
+        Ok(())
+    }
+
+    /// Whether the listen set changed since the last `sync`.
+    #[inline]
+    pub fn stale(&self) -> bool {
+        self.set.generation() != self.generation
+    }
+
+    /// Registers listeners newly bound for this owner and deregisters those no longer
+    /// listed. The removed ones are returned still open, so what is already queued on
+    /// them can be accepted before they are dropped. Draining listeners are deregistered
+    /// but kept, queueing new connections until they are resumed.
+    pub fn sync(&mut self, poll: &Poll) -> Vec<Bound> {
+        // Synced as registered, then suspended again as a whole.
+        let suspended = self.suspended;
+        self.resume(poll);
+        self.generation = self.set.generation();
+        let (addrs, incoming) = self.set.take(self.owner);
+        let mut removed = Vec::new();
+        for slot in self.slots.iter_mut() {
+            if slot.as_ref().is_some_and(|b| !addrs.contains(&b.addr)) {
+                if let Some(mut bound) = slot.take() {
+                    if !bound.paused {
+                        deregister_slot(self.set.mode, poll, &mut bound);
+                    }
+                    removed.push(bound);
+                }
+            }
+        }
+        for bound in incoming {
+            let addr = bound.addr;
+            if let Err(e) = self.add(poll, bound) {
+                eprintln!("[warn] cannot register listener {addr}: {e}");
+            }
+        }
+        let draining = self.set.draining();
+        for (slot, entry) in self.slots.iter_mut().enumerate() {
+            let Some(bound) = entry.as_mut().filter(|b| b.paused != draining.contains(&b.addr)) else { continue };
+            if bound.paused {
+                if let Err(e) = register_slot(self.set.mode, poll, bound, slot) {
+                    eprintln!("[warn] cannot register listener {}: {e}", bound.addr);
+                    *entry = None;
+                    continue;
+                }
+            } else {
+                deregister_slot(self.set.mode, poll, bound);
+            }
+            bound.paused = !bound.paused;
+        }
+        if suspended {
+            self.suspend(poll);
+        }
+        removed
+    }
+
+    /// The listener in `slot`, unless it is paused or all are suspended.
+    #[inline]
+    pub fn get(&self, slot: usize) -> Option<&Bound> {
+        if self.suspended {
+            return None;
+        }
+        self.slots.get(slot)?.as_ref().filter(|b| !b.paused)
+    }
+
+    /// Number of slots, including empty ones; bounds the slots `get` may return.
+    pub fn slots(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TcpListener> {
+        self.slots.iter().flatten().map(|b| &b.listener)
+    }
+}
+
+fn register_slot(mode: AcceptMode, poll: &Poll, bound: &mut Bound, slot: usize) -> io::Result<()> {
+    let token = Token(LISTENER_TOKEN_BASE + slot);
+    if token.0 >= CONN_TOKEN_MIN {
+        return Err(io::Error::other("out of listener tokens"));
+    }
+    match mode {
+        AcceptMode::Shared => register_exclusive(poll, &bound.listener, token),
+        _ => poll.registry().register(&mut bound.listener, token, Interest::READABLE),
+    }
+}
+
+fn deregister_slot(mode: AcceptMode, poll: &Poll, bound: &mut Bound) {
+    match mode {
+        AcceptMode::Shared => deregister_raw(poll, &bound.listener),
+        _ => drop(poll.registry().deregister(&mut bound.listener)),
+    }
+}
+
+/// mio has no way to ask for `EPOLLEXCLUSIVE`, so shared listeners go into the epoll
+/// instance directly, edge-triggered like everything mio registers and with the token in
+/// the event data where mio reads it back.
+fn register_exclusive(poll: &Poll, listener: &TcpListener, token: Token) -> io::Result<()> {
+    let mut event = libc::epoll_event {
+        events: (libc::EPOLLIN | libc::EPOLLET | libc::EPOLLEXCLUSIVE) as u32,
+        u64: token.0 as u64,
+    };
+    // SAFETY: both fds are open for the duration of the call and `event` is a valid epoll_event.
+    let rc = unsafe { libc::epoll_ctl(poll.as_raw_fd(), libc::EPOLL_CTL_ADD, listener.as_raw_fd(), &mut event) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Counterpart of `register_exclusive`. Closing the fd alone would not do: the other
+/// workers' clones keep the socket, and with it this registration, alive.
+fn deregister_raw(poll: &Poll, listener: &TcpListener) {
+    // SAFETY: as above; EPOLL_CTL_DEL ignores the event argument.
+    unsafe { libc::epoll_ctl(poll.as_raw_fd(), libc::EPOLL_CTL_DEL, listener.as_raw_fd(), std::ptr::null_mut()) };
+}
+
+/// The address each listed one was bound to, read off the first owner's listeners.
+fn local_addrs(per_owner: &[Vec<Bound>]) -> HashMap<SocketAddr, SocketAddr> {
+    let first = per_owner.first().map_or(&[][..], Vec::as_slice);
+    first.iter().map(|b| (b.addr, b.listener.local_addr().unwrap_or(b.addr))).collect()
+}
+
+/// Gives every owner a listener for every address; the result is indexed by owner.
+fn bind_all(
+    addrs: &[SocketAddr],
+    owners: usize,
+    mode: AcceptMode,
+    device: Option<&str>,
+) -> Result<Vec<Vec<Bound>>, VryptError> {
+    let mut per_owner: Vec<Vec<Bound>> = (0..owners).map(|_| Vec::with_capacity(addrs.len())).collect();
+    for &addr in addrs {
+        let context = |source| VryptError::Bind { addr, source };
+        let first = bind_listener(addr, device).map_err(context)?;
+        let actual = first.local_addr().map_err(context)?;
+        let mut listeners = Vec::with_capacity(owners);
+        for _ in 1..owners {
+            let listener = match mode {
+                AcceptMode::ReusePort => bind_listener(actual, device),
+                AcceptMode::Shared | AcceptMode::Thread => clone_listener(&first),
+            };
+            listeners.push(listener.map_err(context)?);
+        }
+        listeners.insert(0, first);
+        for (bound, listener) in per_owner.iter_mut().zip(listeners) {
+            bound.push(Bound { addr, listener, paused: false });
+        }
+    }
+    Ok(per_owner)
+}
@@ -0,0 +1,58 @@
+//! Long polling (`--long-poll PATH`): a `GET` to the path is held open until an event is
+//! published with an admin `PUT` to `ADMIN_EVENTS_PATH`, which answers it with the event,
+//! or until `--long-poll-timeout`, which answers `204`. Events are numbered; a poll with
+//! `?since=N` is answered at once with the latest event if that is newer than `N`.
+
+use crate::bus::{Bus, Event};
+use crate::config::Config;
+use crate::http::RequestHead;
+use crate::response;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The latest published event, shared by all workers.
+pub struct LongPoll {
+    /// Number of the latest event; 0 before the first.
+    generation: AtomicU64,
+    latest: Mutex<Option<Arc<[u8]>>>,
+    bus: &'static Bus,
+}
+
+impl LongPoll {
+    pub fn new(bus: &'static Bus) -> &'static Self {
+        Box::leak(Box::new(Self { generation: AtomicU64::new(0), latest: Mutex::new(None), bus }))
+    }
+
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Renders `body` as the next event, with its number in a `Vrypt-Event` header, and
+    /// tells every worker to answer its parked polls. Returns the event's number.
+    pub fn publish(&self, cfg: &Config, content_type: &str, body: &[u8]) -> u64 {
+        let mut latest = self.latest.lock().unwrap_or_else(|e| e.into_inner());
+        let n = self.generation.load(Ordering::Relaxed) + 1;
+        let built = response::build_response("200 OK", content_type, body, cfg.trailers);
+        let mut numbered = Vec::with_capacity(built.len() + 32);
+        response::insert_header(&mut numbered, &built, format!("Vrypt-Event: {n}\r\n").as_bytes());
+        *latest = Some(response::with_header_rules(cfg, numbered).into());
+        self.generation.store(n, Ordering::Release);
+        drop(latest);
+        self.bus.post(Event::LongPoll);
+        n
+    }
+
+    /// The latest event's number and response, if one has been published.
+    pub fn latest(&self) -> Option<(u64, Arc<[u8]>)> {
+        let latest = self.latest.lock().unwrap_or_else(|e| e.into_inner());
+        latest.clone().map(|r| (self.generation.load(Ordering::Relaxed), r))
+    }
+}
+
+/// The event number in a poll's `?since=N`, if it has one.
+pub fn since(head: &RequestHead) -> Option<u64> {
+    let query = head.query()?;
+    let value = query.split(|&b| b == b'&').find_map(|p| p.strip_prefix(b"since="))?;
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
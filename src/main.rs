@@ -1,64 +1,824 @@
-mod config;
-mod conn;
-mod counter;
-mod pool;
-mod slab;
-mod timer;
-mod worker;
-
-use config::{DEFAULT_PORT, RESPONSE_BODY, STATS_INTERVAL, STATS_TARGET};
-use counter::{RpsCounter, spawn_stats_pusher};
-use worker::worker;
-use std::net::SocketAddr;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
+use std::time::Duration;
+use vrypt_server::close::{CloseReason, CloseRule};
+use vrypt_server::config::{
+    Config, BUF_SIZE, DEFAULT_PORT, DEFAULT_WRITE_TIMEOUT, STARTUP_ATTEMPTS, STOP_POLL,
+};
+use vrypt_server::counter::{spawn_stats_pusher, StatsSources};
+use vrypt_server::daemon::{self, PidFile};
+use vrypt_server::error::{self, VryptError};
+use vrypt_server::error_page::ErrorPage;
+use vrypt_server::exec;
+use vrypt_server::fault::FaultRule;
+use vrypt_server::headers::{HeaderRule, RouteHeader};
+use vrypt_server::limit::{ListenerLimit, RouteLimit};
+use vrypt_server::listen::{self, AcceptMode, ListenSet};
+use vrypt_server::redirect::RedirectRule;
+use vrypt_server::region::BufBacking;
+use vrypt_server::rewrite::PathRewrite;
+use vrypt_server::routes::{Payload, StaticRoute};
+use vrypt_server::server::Server;
+use vrypt_server::sink::SinkMode;
+use vrypt_server::mirror::Mirror;
+use vrypt_server::split::{SplitGroup, Sticky};
+use vrypt_server::statsd::{StatsSink, StatsTarget};
+use vrypt_server::tenant::Tenant;
+use vrypt_server::toggle::Toggle;
+use vrypt_server::tunnel::ProxyTarget;
+use vrypt_server::{capture, check, replay, signal, sockopt, timer};
 
-fn build_response(body: &[u8]) -> Vec<u8> {
-    let mut res = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: keep-alive\r\n\r\n",
-        body.len()
-    )
-    .into_bytes();
-    res.extend_from_slice(body);
-    res
+/// Number of arguments that were rejected and replaced by a default; `check` fails on any.
+static ARG_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+macro_rules! invalid {
+    ($($t:tt)*) => {{
+        ARG_ERRORS.fetch_add(1, Ordering::Relaxed);
+        eprintln!($($t)*);
+    }};
 }
 
-fn parse_args() -> SocketAddr {
-    let mut args = std::env::args().skip(1);
-    let port = match args.next().as_deref() {
-        Some("-p") | Some("--port") => args
-            .next()
-            .and_then(|v| v.parse::<u16>().ok())
-            .unwrap_or_else(|| {
-                eprintln!("Invalid port, using default {DEFAULT_PORT}");
-                DEFAULT_PORT
-            }),
+fn parse_port(v: Option<&str>) -> u16 {
+    match v {
         Some(v) => v.parse::<u16>().unwrap_or_else(|_| {
-            eprintln!("Invalid port '{v}', using default {DEFAULT_PORT}");
+            invalid!("Invalid port '{v}', using default {DEFAULT_PORT}");
             DEFAULT_PORT
         }),
-        None => DEFAULT_PORT,
+        None => {
+            invalid!("Invalid port, using default {DEFAULT_PORT}");
+            DEFAULT_PORT
+        }
+    }
+}
+
+fn parse_or<T: std::str::FromStr + std::fmt::Display + Copy>(flag: &str, v: Option<String>, default: T) -> T {
+    match v.as_deref().map(str::parse::<T>) {
+        Some(Ok(x)) => x,
+        _ => {
+            invalid!("Invalid value for {flag}, using default {default}");
+            default
+        }
+    }
+}
+
+fn parse_timeout(flag: &str, v: Option<String>, default: Duration) -> Duration {
+    match v.as_deref().map(str::parse::<u64>) {
+        Some(Ok(secs)) if secs > 0 && Duration::from_secs(secs) <= timer::MAX_TIMEOUT => Duration::from_secs(secs),
+        _ => {
+            invalid!(
+                "{flag} requires whole seconds between 1 and {}, using {}s",
+                timer::MAX_TIMEOUT.as_secs(),
+                default.as_secs()
+            );
+            default
+        }
+    }
+}
+
+/// Appends a header rule; a rule for `Server` replaces the built-in one.
+fn add_header_rule(cfg: &mut Config, rule: HeaderRule) {
+    if rule.name().eq_ignore_ascii_case(b"server") {
+        cfg.header_rules.retain(|r| !r.name().eq_ignore_ascii_case(b"server"));
+    }
+    cfg.header_rules.push(rule);
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Config {
+    let mut cfg = Config::default();
+    let mut port = DEFAULT_PORT;
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" | "--port" => port = parse_port(args.next().as_deref()),
+            "--capture" => match args.next() {
+                Some(p) => cfg.capture_path = Some(PathBuf::from(p)),
+                None => invalid!("--capture requires a file path, capture disabled"),
+            },
+            "--capture-sample" => {
+                cfg.capture_sample = parse_or("--capture-sample", args.next(), cfg.capture_sample).clamp(0.0, 1.0)
+            }
+            "--capture-max-bytes" => {
+                cfg.capture_max_bytes = parse_or("--capture-max-bytes", args.next(), cfg.capture_max_bytes)
+            }
+            "--access-log-sample" => {
+                cfg.access_log_sample = parse_or("--access-log-sample", args.next(), cfg.access_log_sample).clamp(0.0, 1.0)
+            }
+            "--slow-request-ms" => match args.next().map(|v| v.parse::<u64>()) {
+                Some(Ok(ms)) if ms > 0 => cfg.slow_request = Some(Duration::from_millis(ms)),
+                _ => invalid!("--slow-request-ms requires a positive number of milliseconds"),
+            },
+            "--idempotency-ttl" => match args.next().map(|v| v.parse::<u64>()) {
+                Some(Ok(secs)) if secs > 0 => cfg.idempotency_ttl = Some(Duration::from_secs(secs)),
+                _ => invalid!("--idempotency-ttl requires a positive number of seconds"),
+            },
+            "--warmup" => match args.next().map(|v| v.parse::<u64>()) {
+                Some(Ok(secs)) if secs > 0 => cfg.warmup = Some(Duration::from_secs(secs)),
+                _ => invalid!("--warmup requires a positive number of seconds"),
+            },
+            "--maintenance" => cfg.maintenance = true,
+            "--debug-log" => cfg.debug_log = true,
+            "--extra-metrics" => cfg.extra_metrics = true,
+            "--maintenance-page" => match args.next() {
+                Some(p) => cfg.maintenance_page = Some(PathBuf::from(p)),
+                None => invalid!("--maintenance-page requires a file path, using built-in page"),
+            },
+            "--error-page" => match args.next().as_deref().map(ErrorPage::parse) {
+                Some(Ok(page)) => cfg.error_pages.push(page),
+                Some(Err(e)) => invalid!("Ignoring --error-page: {e}"),
+                None => invalid!("--error-page requires '[host/]status=@file' or '[host/]status=text'"),
+            },
+            "--static-route" => match args.next().as_deref().map(StaticRoute::parse) {
+                Some(Ok(route)) => cfg.static_routes.push(route),
+                Some(Err(e)) => invalid!("Ignoring --static-route: {e}"),
+                None => invalid!("--static-route requires '[protobuf:|grpc:|exec:|fastcgi:|scgi:][METHOD,... ][TENANT]PATH=FILE'"),
+            },
+            "--watch-routes" => cfg.watch_routes = true,
+            "--tenant" => match args.next().as_deref().map(Tenant::parse) {
+                Some(Ok(tenant)) if cfg.tenants.iter().any(|t| t.name == tenant.name) => {
+                    invalid!("Ignoring --tenant: '{}' is already defined", tenant.name)
+                }
+                Some(Ok(tenant)) => cfg.tenants.push(tenant),
+                Some(Err(e)) => invalid!("Ignoring --tenant: {e}"),
+                None => invalid!("--tenant requires 'NAME=prefix:/PATH' or 'NAME=host:HOST'"),
+            },
+            "--template" => match args.next() {
+                Some(p) => cfg.template = Some(PathBuf::from(p)),
+                None => invalid!("--template requires a file path, serving the static body"),
+            },
+            "--fault" => match args.next().as_deref().map(FaultRule::parse) {
+                Some(Ok(rule)) => cfg.faults.push(rule),
+                Some(Err(e)) => invalid!("Ignoring --fault: {e}"),
+                None => invalid!("--fault requires a spec (stall:P, reset:N:P, close-headers:P)"),
+            },
+            "--trailers" => cfg.trailers = true,
+            "--server-timing" => cfg.server_timing = true,
+            "--redirect" => {
+                let (status, pattern, target) = (args.next(), args.next(), args.next());
+                match (status, pattern, target) {
+                    (Some(s), Some(p), Some(t)) => match RedirectRule::parse(&s, &p, &t) {
+                        Ok(rule) => cfg.redirects.push(rule),
+                        Err(e) => invalid!("Ignoring --redirect {s} {p} {t}: {e}"),
+                    },
+                    _ => invalid!("--redirect requires STATUS PATTERN TARGET"),
+                }
+            }
+            "--set-header" => match args.next().as_deref().map(HeaderRule::set) {
+                Some(Ok(rule)) => add_header_rule(&mut cfg, rule),
+                Some(Err(e)) => invalid!("Ignoring --set-header: {e}"),
+                None => invalid!("--set-header requires 'Name: value'"),
+            },
+            "--remove-header" => match args.next().as_deref().map(HeaderRule::remove) {
+                Some(Ok(rule)) => add_header_rule(&mut cfg, rule),
+                Some(Err(e)) => invalid!("Ignoring --remove-header: {e}"),
+                None => invalid!("--remove-header requires a header name"),
+            },
+            "--route-header" => match args.next().as_deref().map(RouteHeader::parse) {
+                Some(Ok(rule)) => cfg.route_headers.push(rule),
+                Some(Err(e)) => invalid!("Ignoring --route-header: {e}"),
+                None => invalid!("--route-header requires '[TENANT]PATH=Name: value' or '[TENANT]PATH=-Name'"),
+            },
+            "--route-request-header" => match args.next().as_deref().map(RouteHeader::parse) {
+                Some(Ok(rule)) => cfg.route_request_headers.push(rule),
+                Some(Err(e)) => invalid!("Ignoring --route-request-header: {e}"),
+                None => invalid!("--route-request-header requires '[TENANT]PATH=Name: value' or '[TENANT]PATH=-Name'"),
+            },
+            "--rewrite-prefix" => match args.next().as_deref().map(PathRewrite::parse) {
+                Some(Ok(rule)) => cfg.path_rewrites.push(rule),
+                Some(Err(e)) => invalid!("Ignoring --rewrite-prefix: {e}"),
+                None => invalid!("--rewrite-prefix requires 'FROM=TO'"),
+            },
+            "--max-inflight" => match args.next().map(|v| v.parse::<usize>()) {
+                Some(Ok(n)) if n > 0 => cfg.max_inflight = Some(n),
+                _ => invalid!("--max-inflight requires a positive number, no limit applied"),
+            },
+            "--listener-max-inflight" => match args.next().as_deref().map(ListenerLimit::parse) {
+                Some(Ok(limit)) => cfg.listener_limits.push(limit),
+                Some(Err(e)) => invalid!("Ignoring --listener-max-inflight: {e}"),
+                None => invalid!("--listener-max-inflight requires 'ADDR=N'"),
+            },
+            "--route-max-inflight" => match args.next().as_deref().map(RouteLimit::parse) {
+                Some(Ok(limit)) => cfg.route_limits.push(limit),
+                Some(Err(e)) => invalid!("Ignoring --route-max-inflight: {e}"),
+                None => invalid!("--route-max-inflight requires '[TENANT]PATH=N'"),
+            },
+            "--inflight-queue" => match args.next().map(|v| v.parse::<usize>()) {
+                Some(Ok(n)) => cfg.inflight_queue = n,
+                _ => invalid!("--inflight-queue requires a number, requests over a limit get 503 at once"),
+            },
+            "--inflight-queue-timeout" => {
+                cfg.inflight_queue_timeout =
+                    parse_timeout("--inflight-queue-timeout", args.next(), cfg.inflight_queue_timeout)
+            }
+            "--exec-max" => match args.next().map(|v| v.parse::<usize>()) {
+                Some(Ok(n)) if n > 0 => cfg.exec_max = n,
+                _ => invalid!("--exec-max requires a positive number, using {}", cfg.exec_max),
+            },
+            "--upstream-pool" => match args.next().map(|v| v.parse::<usize>()) {
+                Some(Ok(n)) => cfg.upstream_pool = n,
+                _ => invalid!("--upstream-pool requires a number of idle connections, using {}", cfg.upstream_pool),
+            },
+            "--upstream-idle-timeout" => match args.next().map(|v| v.parse::<u64>()) {
+                Some(Ok(secs)) if secs > 0 => cfg.upstream_idle_timeout = Duration::from_secs(secs),
+                _ => invalid!(
+                    "--upstream-idle-timeout requires a positive number of seconds, using {}s",
+                    cfg.upstream_idle_timeout.as_secs()
+                ),
+            },
+            "--upstream-max-lifetime" => match args.next().map(|v| v.parse::<u64>()) {
+                Some(Ok(secs)) if secs > 0 => cfg.upstream_max_lifetime = Some(Duration::from_secs(secs)),
+                _ => invalid!("--upstream-max-lifetime requires a positive number of seconds, not limiting it"),
+            },
+            "--spill-threshold" => match args.next().map(|v| v.parse::<usize>()) {
+                Some(Ok(n)) => cfg.spill_threshold = Some(n),
+                _ => invalid!("--spill-threshold requires a number of bytes, not spilling route output"),
+            },
+            "--spill-dir" => match args.next() {
+                Some(dir) => cfg.spill_dir = PathBuf::from(dir),
+                None => invalid!("--spill-dir requires a directory, using {}", cfg.spill_dir.display()),
+            },
+            "--accept-rate" => match args.next().map(|v| v.parse::<u32>()) {
+                Some(Ok(n)) if n > 0 => cfg.accept_rate = Some(n),
+                _ => invalid!("--accept-rate requires a positive number of connections per second, not pacing accepts"),
+            },
+            "--max-conns" => match args.next().map(|v| v.parse::<u32>()) {
+                Some(Ok(n)) if n > 0 => cfg.max_conns = Some(n),
+                _ => invalid!("--max-conns requires a positive number of connections, no cap applied"),
+            },
+            "--max-conns-low" => match args.next().map(|v| v.parse::<u32>()) {
+                Some(Ok(n)) => cfg.max_conns_low = Some(n),
+                _ => invalid!("--max-conns-low requires a number of connections, using the default"),
+            },
+            "--priority-reserve" => match args.next().map(|v| v.parse::<u32>()) {
+                Some(Ok(n)) if n > 0 => cfg.priority_reserve = Some(n),
+                _ => invalid!("--priority-reserve requires a positive number of connections, no reserve kept"),
+            },
+            "--health-path" => match args.next() {
+                Some(path) if path.starts_with('/') => cfg.health_path = Some(path),
+                _ => invalid!("--health-path requires a path starting with '/'"),
+            },
+            "--memory-limit" => match args.next().map(|v| v.parse::<u64>()) {
+                Some(Ok(mib)) if mib > 0 => cfg.memory_limit = Some(mib << 20),
+                _ => invalid!("--memory-limit requires a size in MiB, no limit applied"),
+            },
+            "--collapse" => cfg.collapse = true,
+            "--tcp-stats" => cfg.tcp_stats = true,
+            "--size-stats" => cfg.size_stats = true,
+            "--size-route" => match args.next() {
+                Some(prefix) if prefix.starts_with('/') => {
+                    cfg.size_stats = true;
+                    cfg.size_routes.push(prefix);
+                }
+                _ => invalid!("--size-route requires a path prefix starting with '/'"),
+            },
+            "--stats-target" => match args.next().as_deref().map(StatsTarget::parse) {
+                Some(Ok(target)) => cfg.stats_target = target,
+                Some(Err(e)) => invalid!("Ignoring --stats-target: {e}"),
+                None => invalid!("--stats-target requires a collector ([udp://|tcp://]host:port or http://host[:port][/path])"),
+            },
+            "--stats-sink" => match args.next().as_deref().map(StatsSink::parse) {
+                Some(Ok(sink)) => cfg.stats_sinks.push(sink),
+                Some(Err(e)) => invalid!("Ignoring --stats-sink: {e}"),
+                None => invalid!("--stats-sink requires a sink (TARGET[,every=SECS][,prefix=NAME][,off])"),
+            },
+            "--stats-zero-on-exit" => cfg.stats_zero_on_exit = true,
+            "--abortive-close" => cfg.abortive_close = true,
+            "--close" => match args.next().as_deref().map(CloseRule::parse) {
+                Some(Ok(rule)) => cfg.close_rules.push(rule),
+                Some(Err(e)) => invalid!("Ignoring --close: {e}"),
+                None => invalid!("--close requires 'REASON=MODE' (timeout, error or policy; fin, drain or rst)"),
+            },
+            "--dscp" => match args.next().as_deref().map(sockopt::parse_dscp) {
+                Some(Ok(dscp)) => cfg.dscp = Some(dscp),
+                Some(Err(e)) => invalid!("Ignoring --dscp: {e}"),
+                None => invalid!("--dscp requires a DSCP value 0-63 or a class name"),
+            },
+            "--socket-priority" => match args.next().map(|v| v.parse::<u32>()) {
+                Some(Ok(p)) => cfg.socket_priority = Some(p),
+                _ => invalid!("--socket-priority requires a number (0-6 without CAP_NET_ADMIN)"),
+            },
+            "--xdp-drop-map" => match args.next() {
+                Some(path) => cfg.xdp_drop_map = Some(PathBuf::from(path)),
+                None => invalid!("--xdp-drop-map requires the path of a pinned BPF map"),
+            },
+            "--ban-threshold" => match args.next().map(|v| v.parse::<u32>()) {
+                Some(Ok(n)) if n > 0 => cfg.ban_threshold = n,
+                _ => invalid!("--ban-threshold requires a positive number of connections per second"),
+            },
+            "--ban-suspicious" => match args.next().map(|v| v.parse::<u32>()) {
+                Some(Ok(n)) if n > 0 => cfg.ban_suspicious = Some(n),
+                _ => invalid!("--ban-suspicious requires a positive number of requests per second, not banning on them"),
+            },
+            "--ban-duration" => {
+                cfg.ban_duration = parse_timeout("--ban-duration", args.next(), cfg.ban_duration)
+            }
+            "--no-date" => cfg.date = false,
+            "--proxy-protocol" => cfg.proxy_protocol = true,
+            "--tls-cert" => match args.next() {
+                Some(p) => cfg.tls_cert = Some(PathBuf::from(p)),
+                None => invalid!("--tls-cert requires a file path, TLS disabled"),
+            },
+            "--tls-key" => match args.next() {
+                Some(p) => cfg.tls_key = Some(PathBuf::from(p)),
+                None => invalid!("--tls-key requires a file path, TLS disabled"),
+            },
+            "--force-https" => match args.next_if(|a| !a.starts_with('-')).as_deref().map(str::parse::<u16>) {
+                None => cfg.force_https = Some(301),
+                Some(Ok(status @ (301 | 308))) => cfg.force_https = Some(status),
+                Some(_) => {
+                    invalid!("--force-https takes a status of 301 or 308, using 301");
+                    cfg.force_https = Some(301);
+                }
+            },
+            "--tls-handshake-timeout" => {
+                cfg.tls_handshake_timeout =
+                    parse_timeout("--tls-handshake-timeout", args.next(), cfg.tls_handshake_timeout)
+            }
+            "--tls-max-handshakes" => match args.next().map(|v| v.parse::<usize>()) {
+                Some(Ok(n)) if n > 0 => cfg.tls_max_handshakes = n,
+                _ => invalid!("--tls-max-handshakes requires a positive number, using {}", cfg.tls_max_handshakes),
+            },
+            "--forward-proxy" => match args.next().as_deref().map(ProxyTarget::parse) {
+                Some(Ok(target)) => cfg.proxy_allow.push(target),
+                Some(Err(e)) => invalid!("Ignoring --forward-proxy: {e}"),
+                None => invalid!("--forward-proxy requires an allowed destination (host:port)"),
+            },
+            "--split-group" => match args.next().as_deref().map(SplitGroup::parse) {
+                Some(Ok(group)) => cfg.split.groups.push(group),
+                Some(Err(e)) => invalid!("Ignoring --split-group: {e}"),
+                None => invalid!("--split-group requires 'name=weight@host:port'"),
+            },
+            "--split-path" => match args.next() {
+                Some(prefix) if prefix.starts_with('/') => cfg.split.paths.push(prefix),
+                _ => invalid!("--split-path requires a path prefix starting with '/'"),
+            },
+            "--split-sticky" => match args.next().as_deref().map(Sticky::parse) {
+                Some(Ok(sticky)) => cfg.split.sticky = Some(sticky),
+                Some(Err(e)) => invalid!("Ignoring --split-sticky: {e}"),
+                None => invalid!("--split-sticky requires 'ip', 'header:NAME' or 'cookie:NAME'"),
+            },
+            "--split-affinity" => match args.next().map(|v| v.parse::<u64>()) {
+                Some(Ok(secs)) if secs > 0 => cfg.split.affinity = Some(Duration::from_secs(secs)),
+                _ => invalid!("--split-affinity requires a TTL in seconds, hashing sticky keys instead"),
+            },
+            "--mirror" => match args.next().as_deref().map(Mirror::parse) {
+                Some(Ok(mirror)) => cfg.mirror = Some(mirror),
+                Some(Err(e)) => invalid!("Ignoring --mirror: {e}"),
+                None => invalid!("--mirror requires 'percent@host:port'"),
+            },
+            "--admin" => cfg.admin = true,
+            "--echo-headers" => cfg.echo_headers = true,
+            "--long-poll" => match args.next() {
+                Some(path) if path.starts_with('/') => cfg.long_poll_path = Some(path),
+                _ => invalid!("--long-poll requires a path starting with '/'"),
+            },
+            "--long-poll-timeout" => {
+                cfg.long_poll_timeout = parse_timeout("--long-poll-timeout", args.next(), cfg.long_poll_timeout)
+            }
+            "--mime-type" => match args.next().map(|spec| cfg.mime.set(&spec)) {
+                Some(Ok(())) => {}
+                Some(Err(e)) => invalid!("Ignoring --mime-type: {e}"),
+                None => invalid!("--mime-type requires 'ext=type/subtype'"),
+            },
+            "--charset" => match args.next() {
+                Some(cs) if cs == "none" => cfg.mime.set_charset(None),
+                Some(cs) if !cs.is_empty() && cs.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b)) => {
+                    cfg.mime.set_charset(Some(cs))
+                }
+                _ => invalid!("--charset requires a charset name or 'none'"),
+            },
+            "--daemonize" => cfg.daemonize = true,
+            "--user" => match args.next() {
+                Some(name) if !name.is_empty() => cfg.user = Some(name),
+                _ => invalid!("--user requires a user name or uid"),
+            },
+            "--group" => match args.next() {
+                Some(name) if !name.is_empty() => cfg.group = Some(name),
+                _ => invalid!("--group requires a group name or gid"),
+            },
+            "--accept-mode" => match args.next().as_deref().map(AcceptMode::parse) {
+                Some(Ok(mode)) => cfg.accept_mode = mode,
+                Some(Err(e)) => invalid!("Ignoring --accept-mode: {e}"),
+                None => invalid!("--accept-mode requires a mode (reuseport, shared, thread)"),
+            },
+            "--buf-pool" => match args.next().as_deref().map(BufBacking::parse) {
+                Some(Ok(backing)) => cfg.buf_pool = backing,
+                Some(Err(e)) => invalid!("Ignoring --buf-pool: {e}"),
+                None => invalid!("--buf-pool requires a kind (heap, mmap, thp, hugetlb)"),
+            },
+            "--port-range" => match args.next().as_deref().map(listen::parse_range) {
+                Some(Ok(range)) => cfg.port_range = Some(range),
+                Some(Err(e)) => invalid!("Ignoring --port-range: {e}"),
+                None => invalid!("--port-range requires a range of ports (first-last)"),
+            },
+            "--bind-device" => match args.next() {
+                Some(name) if !name.is_empty() && name.len() < libc::IFNAMSIZ && !name.contains(['/', ' ']) => {
+                    cfg.bind_device = Some(name)
+                }
+                _ => invalid!("--bind-device requires an interface or VRF name (at most {} bytes)", libc::IFNAMSIZ - 1),
+            },
+            "--listen-file" => match args.next() {
+                Some(p) => cfg.listen_file = Some(PathBuf::from(p)),
+                None => invalid!("--listen-file requires a path"),
+            },
+            "--log-file" => match args.next() {
+                Some(p) => cfg.log_file = PathBuf::from(p),
+                None => invalid!("--log-file requires a file path, using {}", cfg.log_file.display()),
+            },
+            "--pidfile" => match args.next() {
+                Some(p) => cfg.pidfile = Some(PathBuf::from(p)),
+                None => invalid!("--pidfile requires a file path, no pid file written"),
+            },
+            "--header-timeout" => cfg.header_timeout = parse_timeout("--header-timeout", args.next(), cfg.header_timeout),
+            "--body-timeout" => cfg.body_timeout = parse_timeout("--body-timeout", args.next(), cfg.body_timeout),
+            "--max-header-size" => match args.next().map(|v| v.parse::<usize>()) {
+                Some(Ok(kib)) if kib << 10 >= BUF_SIZE && kib <= 1024 => cfg.max_header_size = kib << 10,
+                _ => invalid!(
+                    "--max-header-size requires a size in KiB between {} and 1024, using {}",
+                    BUF_SIZE >> 10,
+                    cfg.max_header_size >> 10
+                ),
+            },
+            "--connect-timeout" => {
+                cfg.connect_timeout = parse_timeout("--connect-timeout", args.next(), cfg.connect_timeout)
+            }
+            "--keepalive-timeout" => {
+                cfg.keepalive_timeout = parse_timeout("--keepalive-timeout", args.next(), cfg.keepalive_timeout)
+            }
+            "--write-timeout" => {
+                cfg.write_timeout = Some(parse_timeout("--write-timeout", args.next(), DEFAULT_WRITE_TIMEOUT))
+            }
+            "--min-send-rate" => match args.next().map(|v| v.parse::<u64>()) {
+                Some(Ok(n)) if n > 0 => cfg.min_send_rate = Some(n),
+                _ => invalid!("--min-send-rate requires a positive number of bytes per second, no minimum applied"),
+            },
+            "--body-sink" => match args.next().as_deref().map(SinkMode::parse) {
+                Some(Ok(mode)) => cfg.body_sink = mode,
+                Some(Err(e)) => invalid!("Ignoring --body-sink: {e}"),
+                None => invalid!("--body-sink requires a mode (discard, hash, store:<dir>)"),
+            },
+            v => port = parse_port(Some(v)),
+        }
+    }
+    cfg.addr = SocketAddr::from(([0, 0, 0, 0], port));
+    cfg
+}
+
+/// Re-reads the listen file after a SIGHUP and applies the difference.
+fn reload_listeners(path: &Path, listen: &ListenSet) {
+    let res = listen::read_file(path)
+        .map_err(|source| VryptError::ListenFile { path: path.to_path_buf(), source })
+        .and_then(|addrs| listen.update(addrs));
+    match res {
+        Ok((added, removed)) => eprintln!("[info] listeners reloaded: added {added:?}, removed {removed:?}"),
+        Err(e) => eprintln!("[warn] listen file not applied: {e}"),
+    }
+}
+
+/// Starts the server, retrying with backoff while it fails for reasons that may pass, such
+/// as an address still held by the instance being replaced.
+fn start_with_retries(cfg: &'static Config, cpus: usize) -> Result<Server, VryptError> {
+    let mut failures = 0;
+    loop {
+        match Server::start(cfg, cpus) {
+            Err(e) if e.is_transient() && failures + 1 < STARTUP_ATTEMPTS => {
+                let pause = error::backoff(failures);
+                eprintln!("[warn] {e}, retrying in {}ms", pause.as_millis());
+                thread::sleep(pause);
+                failures += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// `replay CAPTURE_FILE --target HOST:PORT [--speed X]`; see `vrypt_server::replay`.
+fn replay_main(mut args: impl Iterator<Item = String>) -> ! {
+    const USAGE: &str = "usage: vrypt-server replay CAPTURE_FILE --target HOST:PORT [--speed X]";
+    let fail = |msg: &str| -> ! {
+        eprintln!("{msg}");
+        std::process::exit(1);
+    };
+    let (mut file, mut target, mut speed) = (None, None, 1.0);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--target" => target = args.next(),
+            "--speed" => match args.next().map(|v| v.parse::<f64>()) {
+                Some(Ok(x)) if x >= 0.0 => speed = x,
+                _ => fail("--speed requires a factor of at least 0 (0 replays without waiting)"),
+            },
+            _ if file.is_none() && !arg.starts_with('-') => file = Some(PathBuf::from(arg)),
+            _ => fail(&format!("Unknown replay argument '{arg}'\n{USAGE}")),
+        }
+    }
+    let (Some(file), Some(target)) = (file, target) else { fail(USAGE) };
+    let Ok(Some(addr)) = target.to_socket_addrs().map(|mut addrs| addrs.next()) else {
+        fail(&format!("Cannot resolve replay target '{target}'"))
     };
-    format!("0.0.0.0:{port}").parse().unwrap()
+    let records = File::open(&file).and_then(|f| capture::read_records(BufReader::new(f))).unwrap_or_else(|e| {
+        fail(&format!("Cannot read capture {}: {e}", file.display()));
+    });
+    let report = replay::replay(&records, addr, speed);
+    println!(
+        "Replayed {} connection(s) to {addr}: {} write(s), {} bytes sent, {} bytes received, {} failed",
+        report.conns, report.writes, report.sent_bytes, report.received_bytes, report.failed
+    );
+    std::process::exit(if report.failed == 0 { 0 } else { 1 });
 }
 
 fn main() {
-    let addr = parse_args();
-    let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
-    let response: &'static [u8] = Box::leak(build_response(RESPONSE_BODY).into_boxed_slice());
-    let counter: &'static RpsCounter = RpsCounter::new(cpus);
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if(|a| a == "replay").is_some() {
+        replay_main(args);
+    }
+    let check = args.next_if(|a| a == "check").is_some();
+    let cfg: &'static Config = Box::leak(Box::new(parse_args(args)));
+    if check {
+        let passed = check::run(cfg, ARG_ERRORS.load(Ordering::Relaxed));
+        println!("{}", if passed { "configuration OK" } else { "configuration invalid" });
+        std::process::exit(if passed { 0 } else { 1 });
+    }
 
-    spawn_stats_pusher(counter);
+    let mut pidfile = cfg.pidfile.as_ref().map(|path| {
+        PidFile::acquire(path).unwrap_or_else(|e| {
+            eprintln!("Cannot lock pid file {}: {e}", path.display());
+            std::process::exit(1);
+        })
+    });
+    if cfg.daemonize {
+        if let Err(e) = daemon::daemonize(&cfg.log_file) {
+            eprintln!("Cannot daemonize: {e}");
+            std::process::exit(1);
+        }
+    }
+    if let (Some(pf), Some(path)) = (pidfile.as_mut(), &cfg.pidfile) {
+        if let Err(e) = pf.write_pid() {
+            eprintln!("Cannot write pid file {}: {e}", path.display());
+            std::process::exit(1);
+        }
+    }
 
-    println!("Vrypt listening on {addr} ({cpus} threads)");
-    println!("Stats pushing to {STATS_TARGET} every {}s", STATS_INTERVAL.as_secs());
+    let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+    signal::MAINTENANCE.store(cfg.maintenance, Ordering::Relaxed);
+    Toggle::DebugLog.set(cfg.debug_log);
+    Toggle::ExtraMetrics.set(cfg.extra_metrics);
+    if let Err(e) = signal::install_handlers() {
+        eprintln!("[warn] cannot install signal handlers: {e}");
+    }
 
-    let handles: Vec<_> = (0..cpus)
-        .map(|i| thread::spawn(move || worker(addr, response, counter, i)))
+    let server = start_with_retries(cfg, cpus).unwrap_or_else(|e| {
+        eprintln!("Cannot start: {e}");
+        std::process::exit(1);
+    });
+    let shared = server.shared;
+    let sources = StatsSources {
+        tcp: shared.tcp,
+        sizes: shared.sizes,
+        split: shared.split.map(|stats| (stats, &cfg.split)),
+        mirror: shared.mirror,
+        tenants: shared.tenants.map(|stats| (stats, &cfg.tenants[..])),
+        backends: shared.backends,
+    };
+    let sinks = cfg.active_stats_sinks();
+    let pushers: Vec<_> = sinks
+        .iter()
+        .map(|sink| {
+            spawn_stats_pusher(shared.counter, sources, sink.clone(), &signal::SHUTDOWN, cfg.stats_zero_on_exit)
+        })
+        .collect();
+    // Set up before the handlers, so a signal arriving right after them still wakes it.
+    let mut watch = signal::watch()
+        .map_err(|e| eprintln!("[warn] cannot watch for signals, checking every {}ms: {e}", STOP_POLL.as_millis()))
+        .ok();
+    let shutdown = signal::install_shutdown_handler();
+    if let Err(e) = &shutdown {
+        eprintln!("[warn] cannot install SIGTERM/SIGINT handlers, the last stats interval will be lost: {e}");
+    }
+    let reload = cfg.listen_file.as_deref().filter(|_| match signal::install_reload_handler() {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("[warn] cannot install SIGHUP handler, listeners will not reload: {e}");
+            false
+        }
+    });
+
+    println!("Vrypt listening on {} ({cpus} threads)", server.addr);
+    match (&cfg.user, &cfg.group) {
+        (Some(user), Some(group)) => println!("Running as {user}:{group} without capabilities"),
+        (Some(user), None) => println!("Running as {user} without capabilities"),
+        (None, Some(group)) => println!("Running with group {group} without capabilities"),
+        (None, None) => {}
+    }
+    if let Some(path) = &cfg.listen_file {
+        let addrs: Vec<String> = shared.listen.addrs().iter().map(|a| a.to_string()).collect();
+        println!("Listening on {} from {} (send SIGHUP to reload)", addrs.join(", "), path.display());
+    } else if let Some(range) = &cfg.port_range {
+        println!("Listening on ports {}-{} ({} listeners per worker)", range.start(), range.end(), range.clone().count());
+    }
+    for sink in &sinks {
+        println!("Stats pushing to {} every {}s as {}.*", sink.target, sink.interval.as_secs(), sink.prefix);
+    }
+    if let (Some(path), Some(cap)) = (&cfg.capture_path, shared.capture) {
+        println!("Capturing {:.0}% of connections to {}", cap.sample() * 100.0, path.display());
+    }
+    if cfg.maintenance {
+        println!("Starting in maintenance mode (send SIGUSR1 to toggle)");
+    }
+    if cfg.debug_log {
+        println!("Event loop debug logging on (send SIGUSR2 to toggle)");
+    }
+    if let Some(warmup) = cfg.warmup {
+        println!("Warming up for {}s; stats are reset when it is over", warmup.as_secs());
+    }
+    if let Some(ttl) = cfg.idempotency_ttl {
+        println!("Replaying responses to Idempotency-Key retries for {}s", ttl.as_secs());
+    }
+    if !cfg.static_routes.is_empty() {
+        let tenant_path = |r: &StaticRoute| {
+            let methods = if r.methods.is_empty() { String::new() } else { format!("{} ", r.methods.join(",")) };
+            format!("{methods}{}{}", r.tenant.as_deref().unwrap_or_default(), r.path)
+        };
+        let paths: Vec<String> = cfg.static_routes.iter().map(tenant_path).collect();
+        println!("Serving static routes {}", paths.join(", "));
+        if cfg.static_routes.iter().any(|r| exec::runs(r.payload)) {
+            println!("Running at most {} route commands and FastCGI/SCGI requests at once; excess gets 503", cfg.exec_max);
+        }
+        if cfg.static_routes.iter().any(|r| r.payload == Payload::FastCgi) {
+            let lifetime = cfg.upstream_max_lifetime.map(|t| format!(", none older than {}s", t.as_secs()));
+            match cfg.upstream_pool {
+                0 => println!("Closing each FastCGI backend connection after its request"),
+                n => println!(
+                    "Keeping up to {n} idle FastCGI connections per backend and worker for {}s{}",
+                    cfg.upstream_idle_timeout.as_secs(),
+                    lifetime.unwrap_or_default()
+                ),
+            }
+        }
+        if cfg.collapse && cfg.static_routes.iter().any(|r| exec::runs(r.payload)) {
+            println!("Collapsing concurrent GETs for the same route backend response into one backend request");
+        }
+        if let Some(threshold) = cfg.spill_threshold {
+            println!(
+                "Reading route output ahead of slow clients, spilling past {threshold} bytes per response to {}",
+                cfg.spill_dir.display()
+            );
+        }
+    }
+    if cfg.spill_threshold.is_some() && !cfg.static_routes.iter().any(|r| exec::runs(r.payload)) {
+        eprintln!("[warn] --spill-threshold has no effect without exec:, fastcgi: or scgi: static routes");
+    }
+    if cfg.collapse && !cfg.static_routes.iter().any(|r| exec::runs(r.payload)) {
+        eprintln!("[warn] --collapse has no effect without exec:, fastcgi: or scgi: static routes");
+    }
+    for (flag, rules) in [("--route-header", &cfg.route_headers), ("--route-request-header", &cfg.route_request_headers)] {
+        for rule in rules.iter().filter(|h| !cfg.static_routes.iter().any(|r| h.applies(r))) {
+            let route = format!("{}{}", rule.tenant.as_deref().unwrap_or_default(), rule.path);
+            eprintln!("[warn] {flag} for {route} has no effect: no static route has that path");
+        }
+    }
+    for limit in cfg.route_limits.iter().filter(|l| !cfg.static_routes.iter().any(|r| r.tenant == l.tenant && r.path == l.path)) {
+        let route = format!("{}{}", limit.tenant.as_deref().unwrap_or_default(), limit.path);
+        eprintln!("[warn] --route-max-inflight for {route} has no effect: no static route has that path");
+    }
+    let unrelayed = |h: &RouteHeader| cfg.static_routes.iter().any(|r| h.applies(r) && !exec::runs(r.payload));
+    if cfg.route_request_headers.iter().any(unrelayed) {
+        eprintln!("[warn] --route-request-header only edits requests relayed to exec:, fastcgi: or scgi: routes");
+    }
+    for tenant in &cfg.tenants {
+        println!("Tenant {tenant}");
+    }
+    if let Some(path) = &cfg.long_poll_path {
+        println!("Holding GET {path} for up to {}s or until the next event", cfg.long_poll_timeout.as_secs());
+        if !cfg.admin {
+            eprintln!("[warn] long-poll events are published through --admin; without it polls only time out");
+        }
+    }
+    if cfg.body_sink != SinkMode::Discard {
+        println!("Request bodies: {:?}", cfg.body_sink);
+    }
+    if cfg.tcp_stats {
+        println!("Reporting TCP_INFO accept-queue, retransmit and RTT stats");
+    }
+    if cfg.size_stats {
+        let routes = if cfg.size_routes.is_empty() { "all requests".to_string() } else { cfg.size_routes.join(", ") };
+        println!("Reporting request and response size histograms for {routes}");
+    }
+    if let Some(n) = cfg.accept_rate {
+        println!("Accepting at most {n} connections per second per worker");
+    }
+    if let Some((high, low)) = cfg.conn_watermarks() {
+        println!("Pausing accepts at {high} connections per worker, resuming at {low}");
+    }
+    match (cfg.priority_reserve, cfg.max_conns) {
+        (Some(n), Some(_)) => println!("Keeping {n} connections per worker past the cap for health checks and admin"),
+        (Some(_), None) => eprintln!("[warn] --priority-reserve has no effect without --max-conns"),
+        _ => {}
+    }
+    if let Some(path) = &cfg.health_path {
+        println!("Answering health checks on {path} ahead of every limit");
+    }
+    if let Some(path) = &cfg.xdp_drop_map {
+        println!(
+            "Banning addresses over {} connections/s for {}s via XDP map {}",
+            cfg.ban_threshold,
+            cfg.ban_duration.as_secs(),
+            path.display()
+        );
+        if let Some(n) = cfg.ban_suspicious {
+            println!("Banning addresses over {n} suspicious requests/s as well");
+        }
+    } else if cfg.ban_suspicious.is_some() {
+        eprintln!("[warn] --ban-suspicious has no effect without --xdp-drop-map");
+    }
+    if !cfg.close_rules.is_empty() {
+        let modes: Vec<String> =
+            CloseReason::ALL.iter().map(|&r| format!("{} {}", r.name(), cfg.close_mode(r).name())).collect();
+        println!("Closing connections by reason: {}", modes.join(", "));
+    }
+    if let Some(n) = cfg.max_inflight {
+        println!("At most {n} requests in flight; excess gets 503");
+    }
+    let scoped: Vec<String> = (cfg.listener_limits.iter().map(|l| format!("{} {}", l.addr, l.max)))
+        .chain(cfg.route_limits.iter().map(|l| format!("{}{} {}", l.tenant.as_deref().unwrap_or_default(), l.path, l.max)))
         .collect();
+    if !scoped.is_empty() {
+        let excess = match cfg.inflight_queue {
+            0 => "excess gets 503".to_string(),
+            n => format!("up to {n} more wait for {}s each, then 503", cfg.inflight_queue_timeout.as_secs()),
+        };
+        println!("In-flight limits on {}; {excess}", scoped.join(", "));
+    } else if cfg.inflight_queue > 0 {
+        eprintln!("[warn] --inflight-queue has no effect without --listener-max-inflight or --route-max-inflight");
+    }
+    match (&cfg.tls_cert, &cfg.tls_key) {
+        (Some(cert), Some(_)) if cfg.terminates_tls() => {
+            println!("Terminating TLS with {} on every listener, next to plaintext HTTP", cert.display());
+            println!(
+                "TLS handshakes must finish within {}s; each worker runs at most {} at once",
+                cfg.tls_handshake_timeout.as_secs(),
+                cfg.tls_max_handshakes
+            );
+        }
+        (Some(_), Some(_)) => eprintln!("[warn] --tls-cert has no effect: built without the tls feature"),
+        (Some(_), None) => eprintln!("[warn] --tls-cert has no effect without --tls-key"),
+        (None, Some(_)) => eprintln!("[warn] --tls-key has no effect without --tls-cert"),
+        (None, None) => {}
+    }
+    match cfg.force_https {
+        Some(status) if cfg.terminates_tls() => println!("Redirecting plaintext requests to https:// with {status}"),
+        Some(_) => eprintln!("[warn] --force-https has no effect without TLS termination"),
+        None => {}
+    }
+    if !cfg.proxy_allow.is_empty() {
+        let allowed: Vec<String> = cfg.proxy_allow.iter().map(|t| format!("{}:{}", t.host, t.port)).collect();
+        println!("Forward proxy to {}", allowed.join(", "));
+    }
+    if !cfg.split.groups.is_empty() {
+        let groups: Vec<String> = cfg.split.groups.iter().map(|g| format!("{} {}", g.name, g.weight)).collect();
+        let sticky = cfg.split.sticky.as_ref().map_or(String::new(), |s| format!(", sticky on {s}"));
+        println!("Splitting traffic by weight between {}{sticky}", groups.join(", "));
+        match (&cfg.split.sticky, cfg.split.affinity) {
+            (Some(_), Some(ttl)) => println!("Remembering sticky groups for {}s after a key's last request", ttl.as_secs()),
+            (None, Some(_)) => eprintln!("[warn] --split-affinity has no effect without --split-sticky"),
+            _ => {}
+        }
+    }
+    match &cfg.mirror {
+        Some(_) if cfg.proxy_allow.is_empty() && cfg.split.groups.is_empty() => {
+            eprintln!("[warn] --mirror has no effect without --forward-proxy or --split-group")
+        }
+        Some(m) => println!("Mirroring {}% of relayed requests to {}:{}", m.percent, m.target.host, m.target.port),
+        None => {}
+    }
+    if !cfg.redirects.is_empty() {
+        println!("{} redirect rule(s) loaded", cfg.redirects.len());
+    }
+    if !cfg.path_rewrites.is_empty() {
+        println!("{} path rewrite rule(s) loaded", cfg.path_rewrites.len());
+    }
+    for rule in &cfg.faults {
+        println!("Injecting fault {:?} with probability {}", rule.kind, rule.probability);
+    }
 
-    for h in handles {
-        if let Err(e) = h.join() {
-            eprintln!("[error] thread panic: {e:?}");
+    if shutdown.is_err() && reload.is_none() {
+        return server.join();
+    }
+    while !signal::SHUTDOWN.load(Ordering::Relaxed) {
+        match &mut watch {
+            Some(watch) => watch.wait(None),
+            None => thread::sleep(STOP_POLL),
+        }
+        if let Some(path) = reload.filter(|_| signal::take_reload()) {
+            reload_listeners(path, shared.listen);
+        }
+    }
+    println!("Shutting down; pushing the last stats interval");
+    for pusher in pushers {
+        if pusher.join().is_err() {
+            eprintln!("[error] stats pusher panicked");
         }
     }
 }
@@ -0,0 +1,90 @@
+use std::path::Path;
+
+pub const DEFAULT_CHARSET: &str = "utf-8";
+
+/// Used for files without a known extension, matching the built-in bodies.
+const FALLBACK: &str = "text/plain";
+
+const BUILTIN: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("xml", "application/xml"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("json", "application/json"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("bin", "application/octet-stream"),
+];
+
+/// Extension → media type table with a charset appended to `text/*` types.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
+pub struct MimeMap {
+    /// Entries added with `set` come first and so override the built-ins.
+    custom: Vec<(String, String)>,
+    charset: Option<String>,
+}
+
+impl Default for MimeMap {
+    fn default() -> Self {
+        Self { custom: Vec::new(), charset: Some(DEFAULT_CHARSET.to_string()) }
+    }
+}
+
+impl MimeMap {
+    /// Parses `ext=type/subtype` and adds it, replacing any earlier entry for `ext`.
+    pub fn set(&mut self, spec: &str) -> Result<(), String> {
+        let (ext, ty) = spec.split_once('=').ok_or_else(|| format!("expected 'ext=type/subtype', got '{spec}'"))?;
+        let ext = ext.trim().trim_start_matches('.').to_ascii_lowercase();
+        let ty = ty.trim();
+        if ext.is_empty() || !ty.contains('/') || ty.bytes().any(|b| b.is_ascii_control()) {
+            return Err(format!("invalid MIME mapping '{spec}'"));
+        }
+        self.custom.retain(|(e, _)| *e != ext);
+        self.custom.insert(0, (ext, ty.to_string()));
+        Ok(())
+    }
+
+    /// Charset appended to `text/*` types; `None` appends nothing.
+    pub fn set_charset(&mut self, charset: Option<String>) {
+        self.charset = charset;
+    }
+
+    /// `Content-Type` value for a file, chosen by its extension.
+    pub fn for_path(&self, path: &Path) -> String {
+        let ext = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+        let ty = ext.as_deref().and_then(|ext| self.lookup(ext)).unwrap_or(FALLBACK);
+        self.with_charset(ty)
+    }
+
+    /// `Content-Type` value of the built-in plain-text bodies.
+    pub fn text_plain(&self) -> String {
+        self.with_charset(FALLBACK)
+    }
+
+    fn lookup(&self, ext: &str) -> Option<&str> {
+        let custom = self.custom.iter().map(|(e, t)| (e.as_str(), t.as_str()));
+        custom.chain(BUILTIN.iter().copied()).find(|(e, _)| *e == ext).map(|(_, t)| t)
+    }
+
+    fn with_charset(&self, ty: &str) -> String {
+        match &self.charset {
+            Some(cs) if ty.starts_with("text/") && !ty.contains("charset=") => format!("{ty}; charset={cs}"),
+            _ => ty.to_string(),
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Request mirroring (`--mirror`): a sampled share of the requests relayed upstream — to a
+//! split group, or by the forward proxy in absolute form — is sent to a shadow upstream as
+//! well, so a new backend can be tried on production traffic. A mirrored request goes out
+//! on a connection of its own once the relay to the real upstream has been started; its
+//! response is read and thrown away, and nothing the shadow does can hold up or change what
+//! the client gets.
+//!
+//! Only a request whose body has all been read along with its head is mirrored, since the
+//! rest of the body is tunnelled to the real upstream as it arrives; `CONNECT` tunnels
+//! never are.
+
+use crate::config::{MIRROR_MAX_IN_FLIGHT, MIRROR_TIMEOUT};
+use crate::outbound::{Connect, Outbound};
+use crate::pool::TokenPool;
+use crate::tunnel::ProxyTarget;
+use mio::{Interest, Poll, Token};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// The shadow upstream and the share of relayed requests it is sent.
+#[derive(Clone, Debug)]
+pub struct Mirror {
+    /// Percentage of relayed requests mirrored, above 0 and up to 100.
+    pub percent: f64,
+    pub target: ProxyTarget,
+}
+
+impl Mirror {
+    /// Parses and resolves `percent@host:port`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (percent, upstream) = spec.split_once('@').ok_or_else(|| format!("expected 'percent@host:port', got '{spec}'"))?;
+        let percent = percent
+            .parse::<f64>()
+            .ok()
+            .filter(|p| *p > 0.0 && *p <= 100.0)
+            .ok_or_else(|| format!("percentage must be above 0 and up to 100, got '{percent}'"))?;
+        Ok(Self { percent, target: ProxyTarget::parse(upstream)? })
+    }
+}
+
+/// What became of a request sampled for mirroring.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MirrorOutcome {
+    /// A connection to the shadow upstream was started for its copy, which may still fail.
+    Sent,
+    /// Connecting or writing to the shadow failed, or it had not answered within `MIRROR_TIMEOUT`.
+    Failed,
+    /// The worker already had `MIRROR_MAX_IN_FLIGHT` mirrored requests outstanding.
+    Dropped,
+    /// Its body had not all been read with the head.
+    Skipped,
+}
+
+impl MirrorOutcome {
+    pub const ALL: [MirrorOutcome; 4] = [Self::Sent, Self::Failed, Self::Dropped, Self::Skipped];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sent => "sent",
+            Self::Failed => "failed",
+            Self::Dropped => "dropped",
+            Self::Skipped => "skipped",
+        }
+    }
+}
+
+/// Mirrored requests per `MirrorOutcome`, reported by the stats pusher.
+#[derive(Default)]
+pub struct MirrorStats {
+    outcomes: [AtomicU64; MirrorOutcome::ALL.len()],
+}
+
+impl MirrorStats {
+    pub fn new() -> &'static Self {
+        Box::leak(Box::default())
+    }
+
+    #[inline]
+    pub fn add(&self, outcome: MirrorOutcome) {
+        self.add_many(outcome, 1);
+    }
+
+    pub fn add_many(&self, outcome: MirrorOutcome, n: u64) {
+        self.outcomes[outcome as usize].fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Requests with `outcome` since the previous call.
+    pub fn take(&self, outcome: MirrorOutcome) -> u64 {
+        self.outcomes[outcome as usize].swap(0, Ordering::Relaxed)
+    }
+}
+
+/// One mirrored request: connecting, being written, or waiting for its response to end.
+struct Shadow {
+    upstream: Outbound,
+    request: Vec<u8>,
+    written: usize,
+    deadline: Instant,
+}
+
+impl Shadow {
+    /// Writes what is left of the request, then reads and discards the response: true once
+    /// the shadow has closed its end.
+    fn pump(&mut self, now: Instant) -> io::Result<bool> {
+        match self.upstream.poll(now) {
+            Connect::Pending => return Ok(false),
+            Connect::Failed(e) => return Err(e),
+            Connect::Ready => {}
+        }
+        while self.written < self.request.len() {
+            match self.upstream.stream.write(&self.request[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        let mut discard = [0u8; 4096];
+        loop {
+            match self.upstream.stream.read(&mut discard) {
+                Ok(0) => return Ok(true),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A worker's mirrored requests in flight, each on its own connection and token.
+#[derive(Default)]
+pub(crate) struct Shadows {
+    live: HashMap<Token, Shadow>,
+}
+
+impl Shadows {
+    pub(crate) fn owns(&self, token: Token) -> bool {
+        self.live.contains_key(&token)
+    }
+
+    /// Starts sending `request` to `addr`. The connect has `connect_timeout`, and the whole
+    /// exchange `MIRROR_TIMEOUT`.
+    pub(crate) fn open(
+        &mut self,
+        addr: SocketAddr,
+        request: Vec<u8>,
+        connect_timeout: Duration,
+        poll: &Poll,
+        tokens: &mut TokenPool,
+        now: Instant,
+    ) -> MirrorOutcome {
+        if self.live.len() >= MIRROR_MAX_IN_FLIGHT {
+            return MirrorOutcome::Dropped;
+        }
+        let Some(token) = tokens.acquire() else { return MirrorOutcome::Dropped };
+        let opened = Outbound::connect_until(addr, now + connect_timeout).and_then(|mut upstream| {
+            poll.registry().register(&mut upstream.stream, token, Interest::READABLE | Interest::WRITABLE)?;
+            Ok(upstream)
+        });
+        match opened {
+            Ok(upstream) => {
+                self.live.insert(token, Shadow { upstream, request, written: 0, deadline: now + MIRROR_TIMEOUT });
+                MirrorOutcome::Sent
+            }
+            Err(e) => {
+                eprintln!("[warn] mirror: cannot connect to {addr}: {e}");
+                tokens.release(token);
+                MirrorOutcome::Failed
+            }
+        }
+    }
+
+    /// Moves the shadow under `token` along after an event on it; `Some(Failed)` if it failed.
+    pub(crate) fn ready(&mut self, token: Token, poll: &Poll, tokens: &mut TokenPool, now: Instant) -> Option<MirrorOutcome> {
+        let shadow = self.live.get_mut(&token)?;
+        match shadow.pump(now) {
+            Ok(false) => None,
+            Ok(true) => {
+                self.close(token, poll, tokens);
+                None
+            }
+            Err(e) => {
+                eprintln!("[warn] mirror: {}: {e}", shadow.upstream.addr);
+                self.close(token, poll, tokens);
+                Some(MirrorOutcome::Failed)
+            }
+        }
+    }
+
+    /// Gives up on shadows past their connect deadline or `MIRROR_TIMEOUT`, returning how many.
+    pub(crate) fn expire(&mut self, poll: &Poll, tokens: &mut TokenPool, now: Instant) -> u64 {
+        let expired: Vec<Token> = self
+            .live
+            .iter()
+            .filter(|(_, s)| now >= s.deadline || s.upstream.deadline().is_some_and(|d| now >= d))
+            .map(|(&token, _)| token)
+            .collect();
+        for &token in &expired {
+            self.close(token, poll, tokens);
+        }
+        expired.len() as u64
+    }
+
+    fn close(&mut self, token: Token, poll: &Poll, tokens: &mut TokenPool) {
+        if let Some(mut shadow) = self.live.remove(&token) {
+            let _ = poll.registry().deregister(&mut shadow.upstream.stream);
+            tokens.release(token);
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> u64 {
+        self.live.values().map(|s| s.request.capacity() as u64).sum()
+    }
+}
@@ -0,0 +1,69 @@
+//! Outbound connections made from the event loop, such as forward-proxy upstreams. The
+//! connect is started without blocking — `EINPROGRESS` is the normal outcome — and is
+//! then polled when the socket turns writable, or given up on once its deadline passes.
+
+use mio::net::TcpStream;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Outcome of polling a connect in progress.
+#[derive(Debug)]
+pub enum Connect {
+    /// Still in progress; poll again on the next writable event or at the deadline.
+    Pending,
+    Ready,
+    Failed(io::Error),
+}
+
+/// A non-blocking TCP connection to `addr`, established or being established.
+pub struct Outbound {
+    pub stream: TcpStream,
+    pub addr: SocketAddr,
+    deadline: Instant,
+    connected: bool,
+}
+
+impl Outbound {
+    /// Starts connecting to `addr`; the connect fails with `TimedOut` if it is not up within `timeout`.
+    pub fn connect(addr: SocketAddr, timeout: Duration) -> io::Result<Self> {
+        Self::connect_until(addr, Instant::now() + timeout)
+    }
+
+    /// Starts connecting to `addr`; the connect fails with `TimedOut` if it is not up by `deadline`.
+    pub fn connect_until(addr: SocketAddr, deadline: Instant) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self { stream, addr, deadline, connected: false })
+    }
+
+    /// When the connect times out; `None` once the connection is up.
+    pub fn deadline(&self) -> Option<Instant> {
+        (!self.connected).then_some(self.deadline)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Checks on the connect. A connected socket has a peer address; until then
+    /// `getpeername` fails with `ENOTCONN`, and a failed connect leaves its error in `SO_ERROR`.
+    pub fn poll(&mut self, now: Instant) -> Connect {
+        if self.connected {
+            return Connect::Ready;
+        }
+        match (self.stream.take_error(), self.stream.peer_addr()) {
+            (Ok(None), Ok(_)) => {
+                self.connected = true;
+                Connect::Ready
+            }
+            (Ok(None), Err(e)) if e.kind() == io::ErrorKind::NotConnected => {
+                if now >= self.deadline {
+                    Connect::Failed(io::Error::new(io::ErrorKind::TimedOut, format!("connect to {} timed out", self.addr)))
+                } else {
+                    Connect::Pending
+                }
+            }
+            (Ok(Some(e)) | Err(e), _) | (_, Err(e)) => Connect::Failed(e),
+        }
+    }
+}
@@ -1,89 +1,239 @@
-use crate::config::{BUF_SIZE, MAX_CONNS};
+use crate::config::{BUF_SIZE, CONN_TOKEN_MIN, MAX_CONNS, TOKEN_INDEX_BITS};
+use crate::counter::PoolStat;
+use crate::region::{BufBacking, PoolBuf, Region};
 use mio::Token;
+use std::time::Instant;
 
+/// Smallest page size buffers can be backed with; `prefill` writes one byte per page.
+const PAGE_SIZE: usize = 4096;
+
+/// Read buffers lent to connections. Returned buffers are recycled as they are, without
+/// clearing them: a connection only ever looks at the bytes it read itself
+/// (`read_buf[..read_len]`, with `read_len` starting at 0), so what an earlier connection
+/// left behind is overwritten before it could be seen.
 pub struct BufPool {
-    free: Vec<Box<[u8; BUF_SIZE]>>,
+    free: Vec<PoolBuf>,
+    /// Mapping buffers are carved from, unless they come from the heap.
+    region: Option<Region>,
+    /// Region buffers whose pages were given back, used again before carving new ones.
+    cold: Vec<&'static mut [u8; BUF_SIZE]>,
     active: usize,
     max_active: usize,
     max_recycled: usize,
+    /// Totals per `PoolStat`; the worker publishes them with its stats sample.
+    stats: [u64; PoolStat::ALL.len()],
 }
 
 impl BufPool {
-    pub fn new(max_active: usize, max_recycled: usize) -> Self {
+    pub fn new(max_active: usize, max_recycled: usize, backing: BufBacking) -> Self {
+        let region = match backing {
+            BufBacking::Heap => None,
+            _ => Region::map(backing, max_active)
+                .inspect_err(|e| eprintln!("[warn] cannot map a {} buffer pool: {e}, using the heap", backing.name()))
+                .ok(),
+        };
         Self {
             free: Vec::with_capacity(max_recycled),
+            region,
+            cold: Vec::new(),
             active: 0,
             max_active,
             max_recycled,
+            stats: [0; PoolStat::ALL.len()],
         }
     }
 
     #[inline]
-    pub fn acquire(&mut self) -> Option<Box<[u8; BUF_SIZE]>> {
+    pub fn acquire(&mut self) -> Option<PoolBuf> {
         if self.active >= self.max_active {
             return None;
         }
         self.active += 1;
-        Some(self.free.pop().unwrap_or_else(|| Box::new([0u8; BUF_SIZE])))
+        self.stats[PoolStat::Acquires as usize] += 1;
+        if let Some(buf) = self.free.pop() {
+            self.stats[PoolStat::Recycled as usize] += 1;
+            return Some(buf);
+        }
+        let start = Instant::now();
+        let buf = self.fresh();
+        self.stats[PoolStat::ZeroingNs as usize] += start.elapsed().as_nanos() as u64;
+        self.stats[PoolStat::Fresh as usize] += 1;
+        Some(buf)
+    }
+
+    /// Fills the recycled list up to its cap with buffers whose pages have all been
+    /// written, so the first connections of a benchmark do not pay for page faults.
+    pub fn prefill(&mut self) {
+        while self.free.len() < self.max_recycled {
+            let mut buf = self.fresh();
+            for page in buf.chunks_mut(PAGE_SIZE) {
+                // Volatile, as a plain store of 0 to calloc'd memory may be optimised out.
+                // SAFETY: `page` is a live, non-empty slice of the buffer.
+                unsafe { std::ptr::write_volatile(page.as_mut_ptr(), 0) };
+            }
+            self.free.push(buf);
+        }
+    }
+
+    /// A buffer nobody has used yet: given back region pages first, then a new piece of
+    /// the region, then the heap.
+    fn fresh(&mut self) -> PoolBuf {
+        match self.cold.pop().or_else(|| self.region.as_mut()?.carve()) {
+            Some(buf) => PoolBuf::Region(buf),
+            None => PoolBuf::Heap(zeroed()),
+        }
     }
 
     #[inline]
-    pub fn release(&mut self, buf: Box<[u8; BUF_SIZE]>) {
+    pub fn release(&mut self, buf: PoolBuf) {
         if self.active == 0 {
             eprintln!("[bug] BufPool::release called with active == 0 (double-release?)");
             return;
         }
         self.active -= 1;
+        self.stats[PoolStat::Releases as usize] += 1;
         if self.free.len() < self.max_recycled {
             self.free.push(buf);
+        } else {
+            self.stats[PoolStat::Dropped as usize] += 1;
+            self.discard(buf);
+        }
+    }
+
+    /// Frees a heap buffer; a region one has its pages given back and is kept for reuse.
+    fn discard(&mut self, buf: PoolBuf) {
+        if let (PoolBuf::Region(buf), Some(region)) = (buf, &self.region) {
+            region.discard(buf);
+            self.cold.push(buf);
+        }
+    }
+
+    pub fn stats(&self) -> [u64; PoolStat::ALL.len()] {
+        self.stats
+    }
+
+    /// Bytes held in buffers, both lent out and recycled.
+    pub fn bytes(&self) -> u64 {
+        ((self.active + self.free.len()) * BUF_SIZE) as u64
+    }
+
+    /// Frees all recycled buffers; returns the bytes released.
+    pub fn trim(&mut self) -> u64 {
+        let freed = (self.free.len() * BUF_SIZE) as u64;
+        while let Some(buf) = self.free.pop() {
+            self.discard(buf);
         }
+        freed
     }
 }
 
+/// A buffer from `alloc_zeroed`, which can skip the memset for memory the allocator knows
+/// to be clear, rather than `Box::new([0; N])`, which may zero a temporary and copy it.
+fn zeroed() -> Box<[u8; BUF_SIZE]> {
+    vec![0u8; BUF_SIZE].into_boxed_slice().try_into().expect("buffer of BUF_SIZE bytes")
+}
+
+/// Hands out connection tokens for slot indexes `1..MAX_CONNS`. Each index carries a
+/// generation, bumped when its token is released, that is folded into the token: a token
+/// kept past its connection's close (a timer entry, a late event) never matches the next
+/// connection in the same slot. Indexes never handed out start at `next`, so the tables
+/// indexed by slot only grow as far as `next`.
 pub struct TokenPool {
     next: usize,
     free: Vec<usize>,
     in_use: Vec<bool>,
+    generations: Vec<usize>,
+    /// Generation for indexes handed out fresh: past every generation of a retired index.
+    fresh_generation: usize,
 }
 
+/// Generations wrap within the bits above the slot index, skipping 0.
+const MAX_GENERATION: usize = usize::MAX >> TOKEN_INDEX_BITS;
+
 impl TokenPool {
     pub fn new() -> Self {
         Self {
             next: 1,
-            free: Vec::with_capacity(MAX_CONNS),
-            in_use: vec![false; MAX_CONNS],
+            free: Vec::new(),
+            in_use: vec![false],
+            generations: vec![0],
+            fresh_generation: 1,
         }
     }
 
     #[inline]
     pub fn acquire(&mut self) -> Option<Token> {
-        if let Some(t) = self.free.pop() {
-            debug_assert!(!self.in_use[t], "TokenPool: acquired a token that was marked in-use");
-            self.in_use[t] = true;
-            return Some(Token(t));
-        }
-        if self.next < MAX_CONNS {
-            let t = self.next;
-            self.next += 1;
-            self.in_use[t] = true;
-            Some(Token(t))
-        } else {
-            None
-        }
+        let index = match self.free.pop() {
+            Some(i) => {
+                debug_assert!(!self.in_use[i], "TokenPool: acquired a token that was marked in-use");
+                i
+            }
+            None if self.next < MAX_CONNS => {
+                self.next += 1;
+                self.in_use.push(false);
+                self.generations.push(self.fresh_generation);
+                self.next - 1
+            }
+            None => return None,
+        };
+        self.in_use[index] = true;
+        Some(Token(self.generations[index] << TOKEN_INDEX_BITS | index))
     }
 
     #[inline]
     pub fn release(&mut self, tok: Token) {
-        let t = tok.0;
-        if t == 0 || t >= MAX_CONNS {
-            eprintln!("[bug] TokenPool::release: token {t} out of valid range");
+        let i = slot_index(tok);
+        if i == 0 || i >= self.next {
+            eprintln!("[bug] TokenPool::release: token {:#x} out of valid range", tok.0);
             return;
         }
-        if !self.in_use[t] {
-            eprintln!("[bug] TokenPool::release: token {t} double-released");
+        if !self.in_use[i] || self.generations[i] != tok.0 >> TOKEN_INDEX_BITS {
+            eprintln!("[bug] TokenPool::release: token {:#x} double-released or stale", tok.0);
             return;
         }
-        self.in_use[t] = false;
-        self.free.push(t);
+        self.in_use[i] = false;
+        self.generations[i] = next_generation(self.generations[i]);
+        self.free.push(i);
     }
+
+    pub fn bytes(&self) -> u64 {
+        let per_index = size_of::<bool>() + size_of::<usize>();
+        (self.in_use.capacity() * per_index + self.free.capacity() * size_of::<usize>()) as u64
+    }
+
+    /// One past the highest slot index handed out since the last `shrink`.
+    #[inline]
+    pub fn high_water(&self) -> usize {
+        self.next
+    }
+
+    /// Takes free indexes at the top of the range back into the never-used pool and orders
+    /// the rest so the lowest are handed out first, letting the top drain over time.
+    /// Releases capacity beyond what is left, keeping at least `floor`.
+    pub fn shrink(&mut self, floor: usize) {
+        while self.next > 1 && !self.in_use[self.next - 1] {
+            self.next -= 1;
+            let retired = self.generations[self.next];
+            self.fresh_generation = self.fresh_generation.max(retired);
+        }
+        let next = self.next;
+        self.free.retain(|&t| t < next);
+        self.free.sort_unstable_by(|a, b| b.cmp(a));
+        self.in_use.truncate(next);
+        self.generations.truncate(next);
+        self.in_use.shrink_to(floor);
+        self.generations.shrink_to(floor);
+        self.free.shrink_to(floor);
+    }
+}
+
+/// Slot index of a connection token.
+#[inline]
+pub fn slot_index(tok: Token) -> usize {
+    tok.0 & (CONN_TOKEN_MIN - 1)
+}
+
+#[inline]
+fn next_generation(g: usize) -> usize {
+    if g >= MAX_GENERATION { 1 } else { g + 1 }
 }
@@ -0,0 +1,93 @@
+//! Serving privileged ports without staying root (`--user`, `--group`): the listeners are
+//! bound first, as root or with `CAP_NET_BIND_SERVICE`, then the process switches to the
+//! given user and group and drops every capability.
+
+use std::ffi::CString;
+use std::io;
+
+/// `_LINUX_CAPABILITY_VERSION_3`, whose sets span two `CapData` words.
+const CAPABILITY_VERSION: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Switches to `user` and `group` (names or numeric ids; the user's primary group when
+/// only a user is given) and drops every capability. Capabilities are per thread, so this
+/// runs before the workers are spawned: they inherit the empty sets.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user.map(|(_, gid)| gid),
+    };
+    if let Some(gid) = gid {
+        // Supplementary groups first: root's would otherwise outlive the switch.
+        if unsafe { libc::setgroups(1, &gid) } != 0 || unsafe { libc::setgid(gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if let Some((uid, _)) = user {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "root could be regained after switching user"));
+        }
+    }
+    let header = CapHeader { version: CAPABILITY_VERSION, pid: 0 };
+    let data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The uid and primary gid of `name`, a user name or a uid in the user database.
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; 16 * 1024];
+    let mut found = std::ptr::null_mut();
+    let rc = match name.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) },
+        Err(_) => {
+            let c = CString::new(name).map_err(|_| not_found("user", name))?;
+            unsafe { libc::getpwnam_r(c.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) }
+        }
+    };
+    match (rc, found.is_null()) {
+        (0, false) => Ok((pwd.pw_uid, pwd.pw_gid)),
+        (0, true) => Err(not_found("user", name)),
+        (rc, _) => Err(io::Error::from_raw_os_error(rc)),
+    }
+}
+
+/// The gid of `name`, a group name or any numeric gid.
+fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let c = CString::new(name).map_err(|_| not_found("group", name))?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; 16 * 1024];
+    let mut found = std::ptr::null_mut();
+    match unsafe { libc::getgrnam_r(c.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut found) } {
+        0 if !found.is_null() => Ok(grp.gr_gid),
+        0 => Err(not_found("group", name)),
+        rc => Err(io::Error::from_raw_os_error(rc)),
+    }
+}
+
+fn not_found(kind: &str, name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no {kind} '{name}'"))
+}
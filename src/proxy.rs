@@ -0,0 +1,87 @@
+//! PROXY protocol (v1 text and v2 binary) preamble parsing, for `--proxy-protocol`.
+
+use crate::config::BUF_SIZE;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 line the spec allows, CRLF included.
+const V1_MAX: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+pub enum Preamble {
+    /// Not a PROXY preamble; the connection speaks HTTP (or TLS) directly.
+    Absent,
+    /// The buffer is a prefix of a preamble; wait for more bytes.
+    Incomplete,
+    /// A complete preamble of `len` bytes. `source` is the original client, unless the
+    /// proxy sent `UNKNOWN` / `LOCAL` (its own health checks).
+    Present { len: usize, source: Option<SocketAddr> },
+}
+
+/// Sniffs the start of a connection for a PROXY protocol preamble.
+pub fn parse(buf: &[u8]) -> Result<Preamble, &'static str> {
+    if is_prefix(buf, V1_PREFIX) {
+        return if buf.len() < V1_PREFIX.len() { Ok(Preamble::Incomplete) } else { parse_v1(buf) };
+    }
+    if is_prefix(buf, V2_SIGNATURE) {
+        return if buf.len() < V2_SIGNATURE.len() { Ok(Preamble::Incomplete) } else { parse_v2(buf) };
+    }
+    Ok(Preamble::Absent)
+}
+
+/// True if `buf` and `sig` agree on their common length.
+fn is_prefix(buf: &[u8], sig: &[u8]) -> bool {
+    let n = buf.len().min(sig.len());
+    buf[..n] == sig[..n]
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Preamble, &'static str> {
+    let window = &buf[..buf.len().min(V1_MAX)];
+    let Some(end) = window.windows(2).position(|w| w == b"\r\n") else {
+        return if buf.len() >= V1_MAX { Err("PROXY v1 line too long") } else { Ok(Preamble::Incomplete) };
+    };
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end]).map_err(|_| "PROXY v1 line is not text")?;
+    let mut parts = line.split(' ');
+    let source = match parts.next() {
+        Some("UNKNOWN") => None,
+        Some("TCP4" | "TCP6") => {
+            let ip = parts.next().and_then(|s| s.parse().ok()).ok_or("bad PROXY v1 source address")?;
+            let _dst = parts.next().ok_or("missing PROXY v1 destination address")?;
+            let port = parts.next().and_then(|s| s.parse().ok()).ok_or("bad PROXY v1 source port")?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err("unsupported PROXY v1 protocol"),
+    };
+    Ok(Preamble::Present { len: end + 2, source })
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Preamble, &'static str> {
+    let Some(&[ver_cmd, family, hi, lo]) = buf.get(12..16) else {
+        return Ok(Preamble::Incomplete);
+    };
+    if ver_cmd >> 4 != 2 {
+        return Err("unsupported PROXY v2 version");
+    }
+    let len = 16 + u16::from_be_bytes([hi, lo]) as usize;
+    if len > BUF_SIZE {
+        return Err("PROXY v2 header does not fit the read buffer");
+    }
+    let Some(body) = buf.get(16..len) else {
+        return Ok(Preamble::Incomplete);
+    };
+    let source = match (ver_cmd & 0x0f, family) {
+        // LOCAL: the proxy's own connection; keep the socket's peer address.
+        (0x0, _) => None,
+        (0x1, 0x11) if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            Some(SocketAddr::new(ip.into(), u16::from_be_bytes([body[8], body[9]])))
+        }
+        (0x1, 0x21) if body.len() >= 36 => {
+            let octets: [u8; 16] = body[..16].try_into().expect("16 bytes");
+            Some(SocketAddr::new(Ipv6Addr::from(octets).into(), u16::from_be_bytes([body[32], body[33]])))
+        }
+        (0x1, _) => None,
+        _ => return Err("unsupported PROXY v2 command"),
+    };
+    Ok(Preamble::Present { len, source })
+}
@@ -0,0 +1,76 @@
+//! Prometheus Pushgateway output for the stats pusher (`--stats-target http://…`), for
+//! networks where StatsD's UDP cannot get out. Each interval is rendered in the text
+//! exposition format and `PUT` by a thread of its own, so a slow gateway never holds up
+//! sampling.
+
+use crate::config::STATS_IO_TIMEOUT;
+use crate::statsd::{self, Retry, StatsTarget};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+
+/// Appends a `# TYPE` line and the sample for one gauge. Characters Prometheus does not
+/// allow in metric names become `_`, so `vrypt.worker.0.rps` is pushed as `vrypt_worker_0_rps`.
+pub fn write_gauge(buf: &mut Vec<u8>, name: fmt::Arguments, value: u64) {
+    buf.extend_from_slice(b"# TYPE ");
+    let start = buf.len();
+    write!(buf, "{name}").expect("writing to a Vec cannot fail");
+    for b in &mut buf[start..] {
+        if !(b.is_ascii_alphanumeric() || *b == b'_' || *b == b':') {
+            *b = b'_';
+        }
+    }
+    let end = buf.len();
+    buf.extend_from_slice(b" gauge\n");
+    buf.extend_from_within(start..end);
+    writeln!(buf, " {value}").expect("writing to a Vec cannot fail");
+}
+
+/// Starts the push thread. At most one interval waits behind the one being pushed; the
+/// thread exits once the sender is dropped and what is queued has been pushed.
+pub fn spawn(target: StatsTarget) -> io::Result<(SyncSender<Vec<u8>>, JoinHandle<()>)> {
+    let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(1);
+    let thread = thread::Builder::new().name("vrypt-pushgateway".to_string()).spawn(move || {
+        let mut retry = Retry::default();
+        for body in rx {
+            let metrics = statsd::metric_count(target.transport, &body);
+            if !retry.ready(metrics) {
+                continue;
+            }
+            match put(&target, &body) {
+                Ok(()) => retry.succeeded(&target),
+                Err(e) => retry.failed(&target, &e, metrics),
+            }
+        }
+    })?;
+    Ok((tx, thread))
+}
+
+/// Replaces the metrics of the target's group with `body`, on a connection of its own.
+fn put(target: &StatsTarget, body: &[u8]) -> io::Result<()> {
+    let mut stream = TcpStream::connect_timeout(&target.resolve()?, STATS_IO_TIMEOUT)?;
+    stream.set_write_timeout(Some(STATS_IO_TIMEOUT))?;
+    stream.set_read_timeout(Some(STATS_IO_TIMEOUT))?;
+    let mut req = Vec::with_capacity(body.len() + 256);
+    write!(
+        req,
+        "PUT {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        target.path,
+        target.authority(),
+        body.len()
+    )?;
+    req.extend_from_slice(body);
+    stream.write_all(&req)?;
+
+    // "HTTP/1.1 200" is all that is needed of the response.
+    let mut status_line = [0u8; 12];
+    stream.read_exact(&mut status_line)?;
+    let status = std::str::from_utf8(&status_line[9..]).ok().and_then(|s| s.parse::<u16>().ok());
+    match status {
+        Some(200..=299) if status_line.starts_with(b"HTTP/1.") => Ok(()),
+        Some(status) => Err(io::Error::other(format!("gateway answered {status}"))),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "malformed gateway response")),
+    }
+}
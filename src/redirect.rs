@@ -0,0 +1,148 @@
+enum PatPart {
+    Lit(Vec<u8>),
+    /// `(.*)`: captures any run of bytes, possibly empty.
+    Capture,
+}
+
+enum TargetPart {
+    Lit(Vec<u8>),
+    /// `$N`: the N-th capture, 1-based.
+    Group(usize),
+}
+
+/// A redirect rule: paths fully matching `pattern` are answered with `status`
+/// and a `Location` built from `target`.
+pub struct RedirectRule {
+    pub status: u16,
+    pattern: Vec<PatPart>,
+    target: Vec<TargetPart>,
+}
+
+impl RedirectRule {
+    /// Builds a rule from a status code, a path pattern (literal text with
+    /// `(.*)` capture groups, anchored at both ends) and a target using `$1`..`$9`.
+    pub fn parse(status: &str, pattern: &str, target: &str) -> Result<Self, String> {
+        let status = match status.parse::<u16>() {
+            Ok(s @ (301 | 302 | 303 | 307 | 308)) => s,
+            _ => return Err(format!("unsupported redirect status '{status}'")),
+        };
+
+        let mut pat = Vec::new();
+        let mut lit = Vec::new();
+        let mut rest = pattern.as_bytes();
+        let mut groups = 0;
+        while !rest.is_empty() {
+            if let Some(r) = rest.strip_prefix(b"(.*)") {
+                if !lit.is_empty() {
+                    pat.push(PatPart::Lit(std::mem::take(&mut lit)));
+                }
+                pat.push(PatPart::Capture);
+                groups += 1;
+                rest = r;
+            } else {
+                lit.push(rest[0]);
+                rest = &rest[1..];
+            }
+        }
+        if !lit.is_empty() {
+            pat.push(PatPart::Lit(lit));
+        }
+
+        let mut tgt = Vec::new();
+        let mut lit = Vec::new();
+        let mut bytes = target.bytes().peekable();
+        while let Some(b) = bytes.next() {
+            match (b, bytes.peek()) {
+                (b'$', Some(d @ b'1'..=b'9')) => {
+                    let n = (d - b'0') as usize;
+                    if n > groups {
+                        return Err(format!("target refers to ${n} but pattern has {groups} group(s)"));
+                    }
+                    bytes.next();
+                    if !lit.is_empty() {
+                        tgt.push(TargetPart::Lit(std::mem::take(&mut lit)));
+                    }
+                    tgt.push(TargetPart::Group(n));
+                }
+                _ => lit.push(b),
+            }
+        }
+        if !lit.is_empty() {
+            tgt.push(TargetPart::Lit(lit));
+        }
+        Ok(Self { status, pattern: pat, target: tgt })
+    }
+
+    /// The pattern and target the rule was parsed from.
+    pub fn source(&self) -> (String, String) {
+        let mut pattern = Vec::new();
+        for part in &self.pattern {
+            match part {
+                PatPart::Lit(lit) => pattern.extend_from_slice(lit),
+                PatPart::Capture => pattern.extend_from_slice(b"(.*)"),
+            }
+        }
+        let mut target = Vec::new();
+        for part in &self.target {
+            match part {
+                TargetPart::Lit(lit) => target.extend_from_slice(lit),
+                TargetPart::Group(n) => target.extend_from_slice(format!("${n}").as_bytes()),
+            }
+        }
+        (String::from_utf8_lossy(&pattern).into_owned(), String::from_utf8_lossy(&target).into_owned())
+    }
+
+    /// Appends the redirect location for `path` to `out`, or returns `false` if the rule does not match.
+    pub fn apply(&self, path: &[u8], query: Option<&[u8]>, out: &mut Vec<u8>) -> bool {
+        let mut caps: Vec<(usize, usize)> = Vec::new();
+        if !match_parts(&self.pattern, path, 0, &mut caps) {
+            return false;
+        }
+        let start = out.len();
+        for part in &self.target {
+            match part {
+                TargetPart::Lit(l) => out.extend_from_slice(l),
+                TargetPart::Group(n) => {
+                    let (s, e) = caps[n - 1];
+                    out.extend_from_slice(&path[s..e]);
+                }
+            }
+        }
+        if let Some(q) = query {
+            if !out[start..].contains(&b'?') {
+                out.push(b'?');
+                out.extend_from_slice(q);
+            }
+        }
+        true
+    }
+}
+
+/// Anchored backtracking match; captures are recorded as byte ranges of `input`.
+fn match_parts(parts: &[PatPart], input: &[u8], pos: usize, caps: &mut Vec<(usize, usize)>) -> bool {
+    match parts.split_first() {
+        None => pos == input.len(),
+        Some((PatPart::Lit(l), rest)) => input[pos..].starts_with(l) && match_parts(rest, input, pos + l.len(), caps),
+        Some((PatPart::Capture, rest)) => {
+            for end in (pos..=input.len()).rev() {
+                caps.push((pos, end));
+                if match_parts(rest, input, end, caps) {
+                    return true;
+                }
+                caps.pop();
+            }
+            false
+        }
+    }
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        _ => "Redirect",
+    }
+}
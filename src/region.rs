@@ -0,0 +1,147 @@
+//! Read buffers carved from one anonymous mapping per worker (`--buf-pool mmap|thp|hugetlb`)
+//! instead of one heap allocation each. At tens of thousands of connections per worker the
+//! buffers then sit side by side in memory the kernel can back with transparent (`thp`) or
+//! reserved (`hugetlb`) huge pages, so they take fewer TLB entries and no allocator
+//! metadata. The mapping covers `MAX_CONNS` buffers; untouched parts of it cost nothing but
+//! address space, except with `hugetlb`, whose pages are reserved when it is mapped.
+
+use crate::config::BUF_SIZE;
+use std::io;
+use std::ops::{Deref, DerefMut};
+
+/// Where the read buffer pool gets its buffers from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufBacking {
+    /// A heap allocation per buffer.
+    Heap,
+    /// An anonymous mapping per worker, in base pages.
+    Mmap,
+    /// An anonymous mapping per worker the kernel is asked to back with transparent huge pages.
+    Thp,
+    /// An anonymous mapping per worker in reserved huge pages (`vm.nr_hugepages`).
+    HugeTlb,
+}
+
+impl BufBacking {
+    pub const ALL: [BufBacking; 4] = [BufBacking::Heap, BufBacking::Mmap, BufBacking::Thp, BufBacking::HugeTlb];
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        BufBacking::ALL.into_iter().find(|b| b.name() == name).ok_or_else(|| {
+            format!("unrecognised buffer pool '{name}' (expected heap, mmap, thp or hugetlb)")
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BufBacking::Heap => "heap",
+            BufBacking::Mmap => "mmap",
+            BufBacking::Thp => "thp",
+            BufBacking::HugeTlb => "hugetlb",
+        }
+    }
+}
+
+/// A read buffer lent out by the pool.
+pub enum PoolBuf {
+    Heap(Box<[u8; BUF_SIZE]>),
+    Region(&'static mut [u8; BUF_SIZE]),
+}
+
+impl From<Box<[u8; BUF_SIZE]>> for PoolBuf {
+    fn from(buf: Box<[u8; BUF_SIZE]>) -> Self {
+        PoolBuf::Heap(buf)
+    }
+}
+
+impl Deref for PoolBuf {
+    type Target = [u8; BUF_SIZE];
+
+    #[inline]
+    fn deref(&self) -> &[u8; BUF_SIZE] {
+        match self {
+            PoolBuf::Heap(b) => b,
+            PoolBuf::Region(b) => b,
+        }
+    }
+}
+
+impl DerefMut for PoolBuf {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [u8; BUF_SIZE] {
+        match self {
+            PoolBuf::Heap(b) => b,
+            PoolBuf::Region(b) => b,
+        }
+    }
+}
+
+/// The not yet handed out part of a worker's mapping. The mapping is never unmapped, so
+/// the buffers carved from it can be `'static`; the pool owning the region lives as long
+/// as the worker thread, across event loop restarts.
+pub struct Region {
+    rest: &'static mut [u8],
+    backing: BufBacking,
+}
+
+impl Region {
+    /// Maps room for `bufs` buffers. Fails with `Heap`, which has no mapping.
+    pub fn map(backing: BufBacking, bufs: usize) -> io::Result<Self> {
+        let len = bufs * BUF_SIZE;
+        let ptr = map_anonymous(backing, len)?;
+        // SAFETY: the mapping is `len` bytes, readable and writable, zero-filled, never
+        // unmapped, and referenced from nowhere else.
+        let rest = unsafe { std::slice::from_raw_parts_mut(ptr, len) };
+        Ok(Self { rest, backing })
+    }
+
+    /// Takes the next untouched buffer, if any are left.
+    pub fn carve(&mut self) -> Option<&'static mut [u8; BUF_SIZE]> {
+        if self.rest.len() < BUF_SIZE {
+            return None;
+        }
+        let (buf, rest) = std::mem::take(&mut self.rest).split_at_mut(BUF_SIZE);
+        self.rest = rest;
+        buf.try_into().ok()
+    }
+
+    /// Gives a buffer's pages back to the kernel; it reads as zeros the next time it is used.
+    /// Returns whether it did: pages of a `hugetlb` mapping stay reserved.
+    pub fn discard(&self, buf: &mut [u8; BUF_SIZE]) -> bool {
+        if self.backing == BufBacking::HugeTlb {
+            return false;
+        }
+        // SAFETY: `buf` is a whole buffer of this private anonymous mapping, which nothing
+        // else refers to while it sits unused in the pool.
+        unsafe { libc::madvise(buf.as_mut_ptr().cast(), BUF_SIZE, libc::MADV_DONTNEED) == 0 }
+    }
+}
+
+/// Checks that a mapping for `bufs` buffers can be made, without keeping it.
+pub fn probe(backing: BufBacking, bufs: usize) -> io::Result<()> {
+    let len = bufs * BUF_SIZE;
+    let ptr = map_anonymous(backing, len)?;
+    // SAFETY: `ptr` is the start of the `len`-byte mapping just made, used by nothing.
+    unsafe { libc::munmap(ptr.cast(), len) };
+    Ok(())
+}
+
+fn map_anonymous(backing: BufBacking, len: usize) -> io::Result<*mut u8> {
+    let flags = libc::MAP_PRIVATE
+        | libc::MAP_ANONYMOUS
+        | match backing {
+            BufBacking::Heap => return Err(io::Error::new(io::ErrorKind::InvalidInput, "heap buffers are not mapped")),
+            BufBacking::Mmap | BufBacking::Thp => libc::MAP_NORESERVE,
+            // Reserved up front, so a shortage of huge pages fails here rather than as SIGBUS later.
+            BufBacking::HugeTlb => libc::MAP_HUGETLB,
+        };
+    // SAFETY: an anonymous mapping at an address of the kernel's choosing; no existing memory is affected.
+    let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, flags, -1, 0) };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    if backing == BufBacking::Thp {
+        // SAFETY: advice on the mapping just made. Without THP support it fails and base pages are used.
+        unsafe { libc::madvise(ptr, len, libc::MADV_HUGEPAGE) };
+    }
+    Ok(ptr.cast())
+}
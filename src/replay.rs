@@ -0,0 +1,134 @@
+//! `vrypt-server replay`: sends the requests of a capture file (`--capture`) to a server
+//! again, one connection per captured connection, at the captured pace or a multiple of
+//! it, so a client's traffic can be reproduced against a debug build. Responses are read
+//! and counted, not compared with the captured ones.
+
+use crate::capture::{Direction, Record};
+use crate::config::{REPLAY_IO_TIMEOUT, REPLAY_LINGER};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What a replay sent and got back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub conns: u64,
+    /// Request records written, each as one write.
+    pub writes: u64,
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+    /// Connections that could not be opened or were cut off while writing.
+    pub failed: u64,
+}
+
+impl ReplayReport {
+    fn add(&mut self, other: ReplayReport) {
+        self.conns += other.conns;
+        self.writes += other.writes;
+        self.sent_bytes += other.sent_bytes;
+        self.received_bytes += other.received_bytes;
+        self.failed += other.failed;
+    }
+}
+
+/// Replays the request records of `records` against `target`. `speed` scales the captured
+/// timing: 1 keeps it, 2 replays twice as fast, 0 sends everything without waiting.
+/// Connections start when their first request was captured and run concurrently.
+pub fn replay(records: &[Record], target: SocketAddr, speed: f64) -> ReplayReport {
+    let mut conns: BTreeMap<u64, Vec<&Record>> = BTreeMap::new();
+    for rec in records.iter().filter(|r| r.dir == Direction::Request) {
+        conns.entry(rec.conn).or_default().push(rec);
+    }
+    let Some(first) = conns.values().map(|reqs| reqs[0].micros).min() else {
+        return ReplayReport::default();
+    };
+    let clock = Pace { start: Instant::now(), first, speed };
+    let mut report = ReplayReport::default();
+    thread::scope(|s| {
+        let handles: Vec<_> = conns.iter().map(|(&id, reqs)| s.spawn(move || replay_conn(id, reqs, target, clock))).collect();
+        for h in handles {
+            report.add(h.join().unwrap_or(ReplayReport { conns: 1, failed: 1, ..ReplayReport::default() }));
+        }
+    });
+    report
+}
+
+/// Maps captured timestamps onto the replay's own timeline.
+#[derive(Clone, Copy)]
+struct Pace {
+    start: Instant,
+    /// Timestamp of the earliest request replayed, which goes out at `start`.
+    first: u64,
+    speed: f64,
+}
+
+impl Pace {
+    fn wait_for(&self, micros: u64) {
+        if self.speed <= 0.0 {
+            return;
+        }
+        let offset = Duration::from_secs_f64(micros.saturating_sub(self.first) as f64 / 1e6 / self.speed);
+        let left = (self.start + offset).saturating_duration_since(Instant::now());
+        if !left.is_zero() {
+            thread::sleep(left);
+        }
+    }
+}
+
+fn replay_conn(id: u64, reqs: &[&Record], target: SocketAddr, pace: Pace) -> ReplayReport {
+    let mut report = ReplayReport { conns: 1, ..ReplayReport::default() };
+    pace.wait_for(reqs[0].micros);
+    let res = (|| -> io::Result<()> {
+        let mut stream = TcpStream::connect_timeout(&target, REPLAY_IO_TIMEOUT)?;
+        stream.set_read_timeout(Some(REPLAY_LINGER))?;
+        stream.set_write_timeout(Some(REPLAY_IO_TIMEOUT))?;
+        let mut reader = stream.try_clone()?;
+        let written = AtomicBool::new(false);
+        thread::scope(|s| {
+            // Responses are drained as they come, so a server blocked on writing them keeps
+            // reading, until the server closes or goes quiet after the last request. The
+            // write side is not shut down: a server may drop requests it has yet to answer
+            // when it sees the client's end of stream.
+            let drain = s.spawn(|| {
+                let mut buf = [0u8; 16 * 1024];
+                let mut total = 0u64;
+                loop {
+                    match reader.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => total += n as u64,
+                        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                            if written.load(Ordering::Acquire) {
+                                break;
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                }
+                total
+            });
+            let sent = (|| {
+                for rec in reqs {
+                    pace.wait_for(rec.micros);
+                    stream.write_all(&rec.data)?;
+                    report.writes += 1;
+                    report.sent_bytes += rec.data.len() as u64;
+                }
+                Ok::<_, io::Error>(())
+            })();
+            written.store(true, Ordering::Release);
+            if sent.is_err() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            report.received_bytes = drain.join().unwrap_or(0);
+            sent
+        })
+    })();
+    if let Err(e) = res {
+        eprintln!("[replay] connection {id}: {e}");
+        report.failed = 1;
+    }
+    report
+}
@@ -0,0 +1,245 @@
+use crate::alloc;
+use crate::config::{
+    Config, BAD_GATEWAY_BODY, BAD_REQUEST_BODY, FORBIDDEN_BODY, HEAD_TOO_LARGE_BODY, HEALTHY_BODY, KEY_REUSED_BODY,
+    OVERLOADED_BODY, REQUEST_TIMEOUT_BODY, THROTTLED_BODY, UPDATED_BODY, UPLOAD_TOO_LARGE_BODY,
+};
+use crate::encoding::Encoding;
+use crate::error_page::{self, ErrorPage};
+use crate::headers;
+use crate::http::find;
+use crate::redirect;
+use crate::sha256::{self, Sha256};
+use crate::template::Template;
+use std::io::Write;
+use std::path::Path;
+
+/// Trailer field carrying the hex SHA-256 of a chunked response body.
+pub const DIGEST_TRAILER: &str = "Vrypt-Body-Sha256";
+
+pub fn write_head(out: &mut Vec<u8>, status: &str, content_type: &str, content_length: usize) {
+    let _ = write!(
+        out,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {content_length}\r\nConnection: keep-alive\r\n\r\n"
+    );
+}
+
+/// Appends a chunked response whose trailer section carries the body's SHA-256.
+pub fn write_chunked_with_digest(out: &mut Vec<u8>, status: &str, content_type: &str, body: &[u8]) {
+    let _ = write!(
+        out,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nTransfer-Encoding: chunked\r\nTrailer: {DIGEST_TRAILER}\r\nConnection: keep-alive\r\n\r\n"
+    );
+    if !body.is_empty() {
+        let _ = write!(out, "{:x}\r\n", body.len());
+        out.extend_from_slice(body);
+        out.extend_from_slice(b"\r\n");
+    }
+    let mut h = Sha256::new();
+    h.update(body);
+    let _ = write!(out, "0\r\n{DIGEST_TRAILER}: ");
+    sha256::write_hex(out, &h.finish());
+    out.extend_from_slice(b"\r\n\r\n");
+}
+
+pub fn write_response(out: &mut Vec<u8>, status: &str, content_type: &str, body: &[u8], trailers: bool) {
+    if trailers {
+        write_chunked_with_digest(out, status, content_type, body);
+    } else {
+        write_head(out, status, content_type, body.len());
+        out.extend_from_slice(body);
+    }
+}
+
+pub fn build_response(status: &str, content_type: &str, body: &[u8], trailers: bool) -> Vec<u8> {
+    let mut res = Vec::with_capacity(160 + body.len());
+    write_response(&mut res, status, content_type, body, trailers);
+    res
+}
+
+/// Builds a response that announces the connection will be closed after it.
+pub fn build_error(status: &str, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(128 + body.len());
+    let _ = write!(
+        res,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    res.extend_from_slice(body);
+    res
+}
+
+pub fn write_redirect(out: &mut Vec<u8>, status: u16, location: &[u8]) {
+    let _ = write!(out, "HTTP/1.1 {status} {}\r\nLocation: ", redirect::reason(status));
+    out.extend_from_slice(location);
+    out.extend_from_slice(b"\r\nContent-Length: 0\r\nConnection: keep-alive\r\n\r\n");
+}
+
+/// Appends `response` to `out` with `line` (a complete `Name: value\r\n`) added as the last header.
+pub fn insert_header(out: &mut Vec<u8>, response: &[u8], line: &[u8]) {
+    let head_end = find(response, b"\r\n\r\n").map_or(response.len(), |p| p + 2);
+    out.extend_from_slice(&response[..head_end]);
+    out.extend_from_slice(line);
+    out.extend_from_slice(&response[head_end..]);
+}
+
+/// Applies the configured header rules to a serialized response.
+pub fn with_header_rules(cfg: &Config, response: Vec<u8>) -> Vec<u8> {
+    if cfg.header_rules.is_empty() {
+        return response;
+    }
+    let mut edited = Vec::with_capacity(response.len() + 64);
+    headers::rewrite(&mut edited, &response, &cfg.header_rules);
+    edited
+}
+
+/// Version, git revision, compiler and enabled cargo features of this binary.
+pub fn build_info() -> Vec<u8> {
+    let features: &[&str] = &[
+        #[cfg(feature = "bench")]
+        "bench",
+        #[cfg(feature = "serde")]
+        "serde",
+        #[cfg(feature = "tls")]
+        "tls",
+        #[cfg(feature = "jemalloc")]
+        "jemalloc",
+        #[cfg(feature = "mimalloc")]
+        "mimalloc",
+    ];
+    format!(
+        "version: {}\ngit_sha: {}\nrustc: {}\nfeatures: {}\nallocator: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        env!("VRYPT_GIT_SHA"),
+        env!("VRYPT_RUSTC"),
+        features.join(" "),
+        alloc::NAME,
+    )
+    .into_bytes()
+}
+
+/// Pre-serialized responses shared by all workers.
+pub struct Responses {
+    pub ok: &'static [u8],
+    pub maintenance: &'static [u8],
+    /// Maintenance page precompressed siblings, in `Encoding` preference order.
+    pub maintenance_encoded: Vec<(Encoding, &'static [u8])>,
+    /// Fast rejection when the in-flight request limit is reached.
+    pub overloaded: &'static [u8],
+    /// Rejection of a request over its tenant's rate; the connection stays open.
+    pub throttled: &'static [u8],
+    /// Sent before closing on malformed framing.
+    pub bad_request: &'static [u8],
+    pub head_too_large: &'static [u8],
+    /// Sent when the header timeout fires on a request that has not been answered.
+    pub request_timeout: &'static [u8],
+    /// Build information served on `VERSION_PATH`.
+    pub version: &'static [u8],
+    /// Acknowledges an admin body upload.
+    pub updated: &'static [u8],
+    /// Served on `Config::health_path`.
+    pub healthy: &'static [u8],
+    pub upload_too_large: &'static [u8],
+    /// Forward-proxy request for a destination not on the allowlist.
+    pub forbidden: &'static [u8],
+    /// Forward-proxy upstream that could not be connected to.
+    pub bad_gateway: &'static [u8],
+    /// An `Idempotency-Key` reused for a different request; the connection stays open.
+    pub key_reused: &'static [u8],
+    /// `--error-page` responses for one virtual host: host name, status and response.
+    /// Pages for every host replace the fields above instead.
+    pub host_errors: Vec<(String, u16, &'static [u8])>,
+    /// When set, 200 responses are rendered per request from this template instead of `ok`.
+    pub template: Option<Template>,
+    /// `Content-Type` of template renders, chosen by the template file's extension.
+    pub template_type: String,
+    /// `Content-Type` of bodies generated per request (hashes, statistics) and of `ok`.
+    pub text_plain: String,
+    /// `Content-Type` of the JSON endpoints.
+    pub json: String,
+    pub maintenance_type: String,
+}
+
+impl Responses {
+    pub fn new(
+        cfg: &Config,
+        body: &[u8],
+        maintenance_body: &[u8],
+        maintenance_type: &str,
+        maintenance_encoded: &[(Encoding, Vec<u8>)],
+        template: Option<Template>,
+        error_pages: &[(&ErrorPage, Vec<u8>, String)],
+    ) -> &'static Self {
+        let trailers = cfg.trailers;
+        let text_plain = cfg.mime.text_plain();
+        let text = text_plain.as_str();
+        let leak = |v: Vec<u8>| -> &'static [u8] { Box::leak(with_header_rules(cfg, v).into_boxed_slice()) };
+        let maintenance = build_response("503 Service Unavailable", maintenance_type, maintenance_body, trailers);
+        let maintenance = if maintenance_encoded.is_empty() {
+            maintenance
+        } else {
+            let mut varied = Vec::with_capacity(maintenance.len() + 32);
+            insert_header(&mut varied, &maintenance, b"Vary: Accept-Encoding\r\n");
+            varied
+        };
+        let maintenance_encoded = maintenance_encoded
+            .iter()
+            .map(|(enc, body)| {
+                let plain = build_response("503 Service Unavailable", maintenance_type, body, trailers);
+                let mut encoded = Vec::with_capacity(plain.len() + 64);
+                let line = format!("Content-Encoding: {}\r\nVary: Accept-Encoding\r\n", enc.token());
+                insert_header(&mut encoded, &plain, line.as_bytes());
+                (*enc, leak(encoded))
+            })
+            .collect();
+        // The 503 shed and 429 throttled responses keep the connection open; the others close it.
+        let error = |status: u16, body: &[u8], ty: &str| {
+            let line = error_page::status_line(status).expect("error page status");
+            let keep_open = status == 503 || status == 429;
+            leak(if keep_open { build_response(line, ty, body, trailers) } else { build_error(line, ty, body) })
+        };
+        let default_error = |status: u16, body: &[u8]| {
+            let page = error_pages.iter().rfind(|(page, _, _)| page.host.is_none() && page.status == status);
+            match page {
+                Some((_, body, ty)) => error(status, body, ty),
+                None => error(status, body, text),
+            }
+        };
+        let host_errors = error_pages
+            .iter()
+            .filter_map(|(page, body, ty)| Some((page.host.clone()?, page.status, error(page.status, body, ty))))
+            .collect();
+        Box::leak(Box::new(Self {
+            ok: leak(build_response("200 OK", text, body, trailers)),
+            maintenance: leak(maintenance),
+            maintenance_encoded,
+            overloaded: default_error(503, OVERLOADED_BODY),
+            throttled: default_error(429, THROTTLED_BODY),
+            bad_request: default_error(400, BAD_REQUEST_BODY),
+            head_too_large: default_error(431, HEAD_TOO_LARGE_BODY),
+            request_timeout: default_error(408, REQUEST_TIMEOUT_BODY),
+            version: leak(build_response("200 OK", text, &build_info(), trailers)),
+            updated: leak(build_response("200 OK", text, UPDATED_BODY, trailers)),
+            healthy: leak(build_response("200 OK", text, HEALTHY_BODY, trailers)),
+            upload_too_large: default_error(413, UPLOAD_TOO_LARGE_BODY),
+            forbidden: default_error(403, FORBIDDEN_BODY),
+            bad_gateway: default_error(502, BAD_GATEWAY_BODY),
+            key_reused: leak(build_response("422 Unprocessable Content", text, KEY_REUSED_BODY, trailers)),
+            host_errors,
+            template_type: cfg.template.as_deref().map_or_else(|| text_plain.clone(), |p| cfg.mime.for_path(p)),
+            template,
+            text_plain,
+            json: cfg.mime.for_path(Path::new("response.json")),
+            maintenance_type: maintenance_type.to_string(),
+        }))
+    }
+
+    /// The response for error `status` to a request with `Host` header `host`: that host's
+    /// `--error-page` if it has one, else `default`.
+    pub fn error_for(&self, host: Option<&[u8]>, status: u16, default: &'static [u8]) -> &'static [u8] {
+        let Some(host) = host.filter(|_| !self.host_errors.is_empty()) else {
+            return default;
+        };
+        let mut pages = self.host_errors.iter().rev();
+        pages.find(|(name, s, _)| *s == status && error_page::host_matches(name, host)).map_or(default, |(_, _, r)| r)
+    }
+}
@@ -0,0 +1,52 @@
+//! Path rewriting (`--rewrite-prefix FROM=TO`): a request whose path starts with `FROM` has
+//! it replaced by `TO` before it is dispatched, so static routes, route backends, the split
+//! proxy and templates all see the new path. `/api=` strips a prefix and `/=/v1/` adds
+//! one. The first matching rule applies and the query string is kept.
+//!
+//! A prefix not ending in `/` only matches whole segments: `/api` matches `/api` and
+//! `/api/users` but not `/apis`. The server's own endpoints (`/__vrypt/...`) and the
+//! `--health-path` are never rewritten.
+
+/// One `--rewrite-prefix` rule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathRewrite {
+    pub from: String,
+    pub to: String,
+}
+
+impl PathRewrite {
+    /// Parses `FROM=TO`; `FROM` starts with `/`, `TO` is empty or does.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (from, to) = spec.split_once('=').ok_or_else(|| format!("expected 'FROM=TO', got '{spec}'"))?;
+        if !from.starts_with('/') || !(to.is_empty() || to.starts_with('/')) {
+            return Err(format!("prefixes must start with '/', got '{spec}'"));
+        }
+        if spec.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control() || b == b'?' || b == b'#') {
+            return Err(format!("prefixes cannot contain spaces, a query or a fragment, got '{spec}'"));
+        }
+        Ok(Self { from: from.to_string(), to: to.to_string() })
+    }
+
+    /// The rest of `path` after the rule's prefix, if it matches.
+    fn rest<'a>(&self, path: &'a [u8]) -> Option<&'a [u8]> {
+        let rest = path.strip_prefix(self.from.as_bytes())?;
+        (self.from.ends_with('/') || rest.is_empty() || rest.starts_with(b"/")).then_some(rest)
+    }
+}
+
+/// Writes the target for `path` and `query` as the first rule of `rules` matching `path`
+/// rewrites it into `out`; false, leaving `out` alone, if none does.
+pub fn apply(rules: &[PathRewrite], path: &[u8], query: Option<&[u8]>, out: &mut Vec<u8>) -> bool {
+    let Some((rule, rest)) = rules.iter().find_map(|r| Some((r, r.rest(path)?))) else { return false };
+    out.clear();
+    out.extend_from_slice(rule.to.as_bytes());
+    out.extend_from_slice(rest);
+    if out.is_empty() {
+        out.push(b'/');
+    }
+    if let Some(query) = query {
+        out.push(b'?');
+        out.extend_from_slice(query);
+    }
+    true
+}
@@ -0,0 +1,393 @@
+//! Static routes (`--static-route PATH=FILE`): a file served with `200 OK` on a fixed path.
+//! Its responses — status line, headers and body, one per precompressed sibling
+//! (`FILE.br`, `FILE.gz`) besides the identity one — are rendered once, so answering a
+//! request for it is a single write of shared bytes. `PUT`ting a new body to
+//! `ADMIN_ROUTES_PATH` plus the path re-renders it and drops the precompressed variants,
+//! which no longer match.
+//!
+//! A route can also serve a pre-serialized protobuf message for benchmarking clients that
+//! decode them: `protobuf:` sends the file as `application/x-protobuf`, and `grpc:` wraps
+//! it in a gRPC length-prefixed message, sent chunked as `application/grpc` with a
+//! `grpc-status: 0` trailer, the framing of a unary gRPC response.
+//!
+//! A route can belong to a tenant (`accounts/users=FILE`): it is then served only on
+//! requests that tenant claims, its path matched against the path asked of the tenant.
+//!
+//! A route can list the methods it answers (`GET,POST /api=FILE`). Any other method the
+//! server knows of, a standard one or one some route lists, gets `405` with the route's
+//! `Allow` header, and an unknown one `501`; both are rendered with the route.
+//!
+//! A route can have header rules of its own (`--route-header`): they edit its rendered
+//! responses, and a FastCGI or SCGI backend's heads, after the global ones. Those of
+//! `--route-request-header` edit the request headers passed on to its backend.
+//!
+//! An `exec:` route runs FILE, a command line, for each request and streams its output
+//! (see `exec`); only its response head is rendered. A `fastcgi:` or `scgi:` route relays
+//! each request to the backend listening on the unix socket FILE names, optionally
+//! followed by the script to run (see `fastcgi`); nothing is rendered.
+
+use crate::config::{Config, METHOD_NOT_ALLOWED_BODY, NOT_IMPLEMENTED_BODY};
+use crate::encoding::{self, Encoding};
+use crate::exec;
+use crate::headers::{self, HeaderRule, RouteHeader};
+use crate::http::{self, RequestHead};
+use crate::response;
+use crate::tenant::{self, Tenant};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const PROTOBUF_TYPE: &str = "application/x-protobuf";
+pub const GRPC_TYPE: &str = "application/grpc";
+/// The methods of RFC 9110 and `PATCH`, known whether or not a route lists them.
+pub const STANDARD_METHODS: [&str; 9] = ["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+
+/// How a route's file is sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Payload {
+    /// As is, typed by its extension.
+    File,
+    /// A serialized protobuf message.
+    Protobuf,
+    /// A serialized protobuf message framed as a unary gRPC response.
+    Grpc,
+    /// A command line, run per request; its output is the body.
+    Exec,
+    /// A FastCGI backend's socket, and the script it runs; it answers each request.
+    FastCgi,
+    /// An SCGI backend's socket; it answers each request.
+    Scgi,
+}
+
+impl Payload {
+    /// Spec prefix selecting the payload; empty for `File`.
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Payload::File => "",
+            Payload::Protobuf => "protobuf:",
+            Payload::Grpc => "grpc:",
+            Payload::Exec => "exec:",
+            Payload::FastCgi => "fastcgi:",
+            Payload::Scgi => "scgi:",
+        }
+    }
+}
+
+/// One `--static-route` rule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StaticRoute {
+    /// Request path, compared exactly; a query string does not take part.
+    pub path: String,
+    pub file: PathBuf,
+    pub payload: Payload,
+    /// Tenant the route is served for; `None` for requests no tenant claims.
+    pub tenant: Option<String>,
+    /// Methods the route answers, as listed; empty for any method.
+    pub methods: Vec<String>,
+}
+
+impl StaticRoute {
+    /// Parses `[protobuf:|grpc:|exec:|fastcgi:|scgi:][METHOD,... ][TENANT]PATH=FILE`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (payload, rest) = [Payload::Protobuf, Payload::Grpc, Payload::Exec, Payload::FastCgi, Payload::Scgi]
+            .into_iter()
+            .find_map(|p| Some((p, spec.strip_prefix(p.prefix())?)))
+            .unwrap_or((Payload::File, spec));
+        // Paths hold no spaces, so a space before the `=` ends the method list.
+        let (methods, rest) = match rest.find([' ', '=']) {
+            Some(space) if rest.as_bytes()[space] == b' ' => (&rest[..space], &rest[space + 1..]),
+            _ => ("", rest),
+        };
+        let methods: Vec<String> = methods.split(',').filter(|m| !m.is_empty()).map(str::to_string).collect();
+        if let Some(m) = methods.iter().find(|m| !http::is_token(m.as_bytes())) {
+            return Err(format!("route method must be a token, got '{m}'"));
+        }
+        let bad = || format!("expected '[protobuf:|grpc:|exec:|fastcgi:|scgi:][METHOD,... ][TENANT]PATH=FILE', got '{spec}'");
+        let (path, file) = rest.split_once('=').ok_or_else(bad)?;
+        let (tenant, path) = match path.find('/') {
+            Some(0) | None => (None, path),
+            Some(slash) if tenant::valid_name(&path[..slash]) => (Some(path[..slash].to_string()), &path[slash..]),
+            Some(_) => return Err(format!("route tenant must be letters, digits, '-' or '_', got '{path}'")),
+        };
+        if !path.starts_with('/') || path.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control()) {
+            return Err(format!("route path must start with '/' and contain no spaces, got '{path}'"));
+        }
+        if path.contains(['?', '#']) {
+            return Err(format!("route path cannot have a query or fragment, got '{path}'"));
+        }
+        if file.is_empty() {
+            return Err(format!("missing file name in '{spec}'"));
+        }
+        Ok(Self { path: path.to_string(), file: PathBuf::from(file), payload, tenant, methods })
+    }
+
+    /// Index of the route's tenant among `tenants`.
+    pub fn tenant_index(&self, tenants: &[Tenant]) -> Result<Option<usize>, String> {
+        let Some(name) = &self.tenant else { return Ok(None) };
+        match tenants.iter().position(|t| t.name == *name) {
+            Some(i) => Ok(Some(i)),
+            None => Err(format!("no tenant '{name}' for static route {}", self.path)),
+        }
+    }
+}
+
+/// The rendered responses of one static route.
+pub struct Route {
+    pub path: String,
+    /// Index of the route's tenant in `Config::tenants`.
+    pub tenant: Option<usize>,
+    payload: Payload,
+    content_type: String,
+    identity: Arc<[u8]>,
+    /// Precompressed variants, in `Encoding` preference order.
+    encoded: Vec<(Encoding, Arc<[u8]>)>,
+    /// Methods answered, with `HEAD` added for `GET`; empty for any.
+    allowed: Vec<String>,
+    /// Answers to the other methods; only for routes that list their methods.
+    refusals: Option<Refusals>,
+    /// Program and arguments of an `exec:` route, or socket and script of a `fastcgi:` or
+    /// `scgi:` one; empty for the others.
+    backend: Vec<String>,
+    /// The route's `--route-header` rules.
+    headers: Vec<HeaderRule>,
+    /// The route's `--route-request-header` rules.
+    request_headers: Vec<HeaderRule>,
+}
+
+#[derive(Clone)]
+struct Refusals {
+    /// `405` with the `Allow` header.
+    not_allowed: Arc<[u8]>,
+    /// `501`, for methods the server does not know of.
+    not_implemented: Arc<[u8]>,
+}
+
+impl Route {
+    /// Reads the route's file and, unless it is gRPC framed, its precompressed siblings and
+    /// renders them. The command line of a route whose requests run a backend is only split.
+    pub fn load(cfg: &Config, route: &StaticRoute, tenant: Option<usize>) -> io::Result<Self> {
+        let (body, backend) = match route.payload {
+            payload if exec::runs(payload) => (Vec::new(), command_line(payload, &route.file)?),
+            _ => (std::fs::read(&route.file)?, Vec::new()),
+        };
+        let content_type = match route.payload {
+            Payload::File => cfg.mime.for_path(&route.file),
+            Payload::Protobuf => PROTOBUF_TYPE.to_string(),
+            Payload::Grpc => GRPC_TYPE.to_string(),
+            // Typed by the extension of the path, if it has one.
+            Payload::Exec | Payload::FastCgi | Payload::Scgi => cfg.mime.for_path(Path::new(&route.path)),
+        };
+        let framed = route.payload == Payload::Grpc || exec::runs(route.payload);
+        let encodings = if framed { &[][..] } else { &Encoding::ALL[..] };
+        let encoded: Vec<_> = encodings
+            .iter()
+            .filter_map(|&enc| {
+                let mut sibling = route.file.clone().into_os_string();
+                sibling.push(enc.suffix());
+                std::fs::read(sibling).ok().map(|body| (enc, body))
+            })
+            .collect();
+        let mut allowed = route.methods.clone();
+        if allowed.iter().any(|m| m == "GET") && !allowed.iter().any(|m| m == "HEAD") {
+            allowed.push("HEAD".to_string());
+        }
+        let refusals = (!allowed.is_empty()).then(|| Refusals::render(cfg, &allowed));
+        let rendered = Self::render(cfg, route.path.clone(), tenant, route.payload, content_type, &body, &encoded);
+        let rules = |list: &[RouteHeader]| list.iter().filter(|h| h.applies(route)).map(|h| h.rule.clone()).collect();
+        let (headers, request_headers) = (rules(&cfg.route_headers), rules(&cfg.route_request_headers));
+        Ok(Self { allowed, refusals, backend, headers, request_headers, ..rendered }.with_route_headers())
+    }
+
+    fn render(
+        cfg: &Config,
+        path: String,
+        tenant: Option<usize>,
+        payload: Payload,
+        content_type: String,
+        body: &[u8],
+        encoded: &[(Encoding, Vec<u8>)],
+    ) -> Self {
+        let build = |body: &[u8], line: &str| {
+            let plain = match payload {
+                Payload::Grpc => grpc_response(&content_type, body),
+                Payload::Exec | Payload::FastCgi | Payload::Scgi => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nTransfer-Encoding: chunked\r\nConnection: keep-alive\r\n\r\n"
+                )
+                .into_bytes(),
+                _ => response::build_response("200 OK", &content_type, body, cfg.trailers),
+            };
+            let mut out = Vec::with_capacity(plain.len() + line.len());
+            response::insert_header(&mut out, &plain, line.as_bytes());
+            Arc::from(response::with_header_rules(cfg, out))
+        };
+        let vary = if encoded.is_empty() { "" } else { "Vary: Accept-Encoding\r\n" };
+        let identity = build(body, vary);
+        let encoded = encoded
+            .iter()
+            .map(|(enc, body)| (*enc, build(body, &format!("Content-Encoding: {}\r\n{vary}", enc.token()))))
+            .collect();
+        Self {
+            path,
+            tenant,
+            payload,
+            content_type,
+            identity,
+            encoded,
+            allowed: Vec::new(),
+            refusals: None,
+            backend: Vec::new(),
+            headers: Vec::new(),
+            request_headers: Vec::new(),
+        }
+    }
+
+    /// The route with its own header rules applied to its rendered responses.
+    fn with_route_headers(mut self) -> Self {
+        if self.headers.is_empty() {
+            return self;
+        }
+        let edit = |response: &Arc<[u8]>| {
+            let mut out = Vec::with_capacity(response.len() + 64);
+            headers::rewrite(&mut out, response, &self.headers);
+            Arc::from(out)
+        };
+        self.identity = edit(&self.identity);
+        for (_, response) in &mut self.encoded {
+            *response = edit(response);
+        }
+        if let Some(refusals) = &mut self.refusals {
+            refusals.not_allowed = edit(&refusals.not_allowed);
+            refusals.not_implemented = edit(&refusals.not_implemented);
+        }
+        self
+    }
+
+    /// The route with its body (the message, for protobuf payloads) replaced by `body`,
+    /// served to every client as is.
+    pub fn replaced(&self, cfg: &Config, body: &[u8]) -> Self {
+        let rendered =
+            Self::render(cfg, self.path.clone(), self.tenant, self.payload, self.content_type.clone(), body, &[]);
+        let (headers, request_headers) = (self.headers.clone(), self.request_headers.clone());
+        let replaced = Self { allowed: self.allowed.clone(), backend: self.backend.clone(), headers, request_headers, ..rendered };
+        // The refusals are unchanged, and already edited.
+        Self { refusals: self.refusals.clone(), ..replaced.with_route_headers() }
+    }
+
+    /// The response for `head`: a refusal if the route does not answer its method, else `select`'s.
+    pub fn respond(&self, cfg: &Config, head: &RequestHead) -> &Arc<[u8]> {
+        match &self.refusals {
+            Some(refusals) if !self.answers(head.method) => {
+                if is_known_method(cfg, head.method) {
+                    &refusals.not_allowed
+                } else {
+                    &refusals.not_implemented
+                }
+            }
+            _ => self.select(head),
+        }
+    }
+
+    /// Whether the route answers `method` rather than refusing it.
+    pub fn answers(&self, method: &[u8]) -> bool {
+        self.refusals.is_none() || self.allowed.iter().any(|m| m.as_bytes() == method)
+    }
+
+    pub fn payload(&self) -> Payload {
+        self.payload
+    }
+
+    /// What answers each request, for an `exec:`, `fastcgi:` or `scgi:` route: the program
+    /// and its arguments, or the backend's socket and the script it runs.
+    pub fn backend(&self) -> Option<&[String]> {
+        (!self.backend.is_empty()).then_some(&self.backend[..])
+    }
+
+    /// The route's `--route-header` rules, for responses rendered per request.
+    pub fn headers(&self) -> &[HeaderRule] {
+        &self.headers
+    }
+
+    /// The route's `--route-request-header` rules, for the requests relayed to its backend.
+    pub fn request_headers(&self) -> &[HeaderRule] {
+        &self.request_headers
+    }
+
+    /// The first precompressed variant the client accepts, else the identity one.
+    pub fn select(&self, head: &RequestHead) -> &Arc<[u8]> {
+        let accepted = self.encoded.iter().find(|(enc, _)| encoding::accepts(head, *enc));
+        accepted.map_or(&self.identity, |(_, r)| r)
+    }
+
+    /// Bytes held by the rendered responses.
+    pub fn bytes(&self) -> usize {
+        self.identity.len() + self.encoded.iter().map(|(_, r)| r.len()).sum::<usize>()
+    }
+}
+
+impl Refusals {
+    fn render(cfg: &Config, allowed: &[String]) -> Self {
+        let text = cfg.mime.text_plain();
+        let plain = response::build_response("405 Method Not Allowed", &text, METHOD_NOT_ALLOWED_BODY, cfg.trailers);
+        let mut not_allowed = Vec::with_capacity(plain.len() + 64);
+        response::insert_header(&mut not_allowed, &plain, format!("Allow: {}\r\n", allowed.join(", ")).as_bytes());
+        let not_implemented = response::build_response("501 Not Implemented", &text, NOT_IMPLEMENTED_BODY, cfg.trailers);
+        Self {
+            not_allowed: Arc::from(response::with_header_rules(cfg, not_allowed)),
+            not_implemented: Arc::from(response::with_header_rules(cfg, not_implemented)),
+        }
+    }
+}
+
+/// Whether `method` is a standard method or one some route lists.
+pub fn is_known_method(cfg: &Config, method: &[u8]) -> bool {
+    let listed = cfg.static_routes.iter().flat_map(|r| &r.methods);
+    STANDARD_METHODS.iter().copied().chain(listed.map(String::as_str)).any(|m| m.as_bytes() == method)
+}
+
+/// Loads every configured route, skipping (with a warning) those whose file cannot be read
+/// or whose tenant is not configured.
+pub fn load_all(cfg: &Config) -> Vec<Route> {
+    cfg.static_routes
+        .iter()
+        .filter_map(|route| {
+            let tenant = route.tenant_index(&cfg.tenants).map_err(|e| eprintln!("{e}, not serving it")).ok()?;
+            match Route::load(cfg, route, tenant) {
+                Ok(loaded) => Some(loaded),
+                Err(e) => {
+                    let file = route.file.display();
+                    eprintln!("Cannot read static route file {file}: {e}, not serving {}", route.path);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Splits the command line of a route running a backend on whitespace; no shell is
+/// involved. A program given by path must exist; a backend's socket may come up later.
+fn command_line(payload: Payload, file: &Path) -> io::Result<Vec<String>> {
+    let command: Vec<String> = file.to_string_lossy().split_whitespace().map(str::to_string).collect();
+    match command.first() {
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, "empty command")),
+        Some(program) if payload == Payload::Exec && program.contains('/') => {
+            std::fs::metadata(program).map(|_| command)
+        }
+        Some(_) => Ok(command),
+    }
+}
+
+/// A unary gRPC response carrying `message` uncompressed: the length-prefixed message as
+/// the chunked body, then `grpc-status: 0` in the trailer section.
+fn grpc_response(content_type: &str, message: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(192 + message.len());
+    let _ = write!(
+        out,
+        "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nTransfer-Encoding: chunked\r\nTrailer: grpc-status\r\nConnection: keep-alive\r\n\r\n"
+    );
+    let _ = write!(out, "{:x}\r\n", 5 + message.len());
+    out.push(0);
+    out.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    out.extend_from_slice(message);
+    out.extend_from_slice(b"\r\n0\r\ngrpc-status: 0\r\n\r\n");
+    out
+}
@@ -0,0 +1,47 @@
+//! Prometheus scrape endpoint for the stats pusher (`--stats-sink scrape://host:port`), for
+//! setups where Prometheus pulls rather than being pushed to. A thread of its own answers
+//! every request on the address, whatever its path, with the text exposition of the last
+//! interval the pusher rendered.
+
+use crate::config::STATS_IO_TIMEOUT;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Binds `addr` and starts answering scrapes. The pusher replaces what is served by
+/// swapping the returned buffer's contents.
+pub fn serve(addr: SocketAddr) -> io::Result<Arc<Mutex<Vec<u8>>>> {
+    let listener = TcpListener::bind(addr)?;
+    let latest = Arc::new(Mutex::new(Vec::new()));
+    let served = Arc::clone(&latest);
+    thread::Builder::new().name("vrypt-scrape".to_string()).spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = answer(stream, &served);
+        }
+    })?;
+    Ok(latest)
+}
+
+fn answer(mut stream: TcpStream, latest: &Mutex<Vec<u8>>) -> io::Result<()> {
+    stream.set_read_timeout(Some(STATS_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(STATS_IO_TIMEOUT))?;
+    // Only the end of the head is looked for; a scrape has no body.
+    let mut head = [0u8; 4096];
+    let mut len = 0;
+    while !head[..len].windows(4).any(|w| w == b"\r\n\r\n") && len < head.len() {
+        match stream.read(&mut head[len..])? {
+            0 => return Ok(()),
+            n => len += n,
+        }
+    }
+    let body = latest.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let mut resp = Vec::with_capacity(body.len() + 128);
+    write!(
+        resp,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    resp.extend_from_slice(&body);
+    stream.write_all(&resp)
+}
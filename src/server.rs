@@ -0,0 +1,236 @@
+use crate::bodies::BodyStore;
+use crate::bus::Bus;
+use crate::capture::Capture;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{Config, MAINTENANCE_BODY, RESPONSE_BODY};
+use crate::connlist::ConnList;
+use crate::counter::RpsCounter;
+use crate::encoding::Encoding;
+use crate::exec;
+use crate::idempotency::IdempotencyStore;
+use crate::limit::{InflightLimit, ScopedLimits};
+use crate::mirror::MirrorStats;
+use crate::response::Responses;
+use crate::routes;
+use crate::sizes::SizeStats;
+use crate::split::SplitStats;
+use crate::tcpinfo::TcpStats;
+use crate::template::Template;
+use crate::tenant::Tenants;
+use crate::toggle::Toggle;
+use crate::upstream::{self, UpstreamStats};
+use crate::watch;
+use crate::acceptor::{acceptor, Handoff};
+use crate::error::VryptError;
+use crate::listen::{self, AcceptMode, ListenSet, Listeners};
+use crate::longpoll::LongPoll;
+use crate::privilege;
+use crate::worker::{worker, Shared};
+use crate::xdp::{self, AbuseTracker, XdpMap};
+use std::net::SocketAddr;
+use std::thread::{self, JoinHandle};
+
+/// A running set of workers sharing one listen address.
+pub struct Server {
+    /// First bound address; differs from the listed one when that asked for port 0.
+    pub addr: SocketAddr,
+    pub shared: &'static Shared,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Loads the response material named in `cfg`, binds one listener per worker for each
+    /// listen address and starts the workers. With port 0 the first listener picks an ephemeral port and the
+    /// others join it.
+    pub fn start(cfg: &'static Config, threads: usize) -> Result<Self, VryptError> {
+        Self::start_with_clock(cfg, threads, &SystemClock)
+    }
+
+    /// Like `start`, with the workers' timeouts running on `clock`; see `clock`.
+    pub fn start_with_clock(cfg: &'static Config, threads: usize, clock: &'static dyn Clock) -> Result<Self, VryptError> {
+        let (maintenance_body, maintenance_type) = match &cfg.maintenance_page {
+            Some(path) => match std::fs::read(path) {
+                Ok(body) => (body, cfg.mime.for_path(path)),
+                Err(e) => {
+                    eprintln!("Cannot read maintenance page {}: {e}, using built-in page", path.display());
+                    (MAINTENANCE_BODY.to_vec(), cfg.mime.text_plain())
+                }
+            },
+            None => (MAINTENANCE_BODY.to_vec(), cfg.mime.text_plain()),
+        };
+        // Precompressed siblings (`page.html.br`, `page.html.gz`) are served to clients accepting them.
+        let maintenance_encoded: Vec<_> = match &cfg.maintenance_page {
+            Some(path) if maintenance_body != MAINTENANCE_BODY => Encoding::ALL
+                .into_iter()
+                .filter_map(|enc| {
+                    let mut sibling = path.clone().into_os_string();
+                    sibling.push(enc.suffix());
+                    std::fs::read(sibling).ok().map(|body| (enc, body))
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let template = cfg.template.as_ref().and_then(|path| {
+            let src = std::fs::read(path)
+                .map_err(|e| eprintln!("Cannot read template {}: {e}, serving the static body", path.display()))
+                .ok()?;
+            Template::parse(&src)
+                .map_err(|e| eprintln!("Invalid template {}: {e}, serving the static body", path.display()))
+                .ok()
+        });
+        let error_pages: Vec<_> = cfg
+            .error_pages
+            .iter()
+            .filter_map(|page| match page.load(&cfg.mime) {
+                Ok((body, ty)) => Some((page, body, ty)),
+                Err(e) => {
+                    eprintln!("Cannot read error page for {}: {e}, using the built-in body", page.status);
+                    None
+                }
+            })
+            .collect();
+        let responses = Responses::new(
+            cfg,
+            RESPONSE_BODY,
+            &maintenance_body,
+            &maintenance_type,
+            &maintenance_encoded,
+            template,
+            &error_pages,
+        );
+        let counter = RpsCounter::new(threads);
+        let capture = cfg.capture_path.as_ref().and_then(|path| {
+            Capture::open(path.clone(), cfg.capture_sample, cfg.capture_max_bytes)
+                .map_err(|e| eprintln!("[capture] cannot open {}: {e}", path.display()))
+                .ok()
+        });
+        if capture.is_some() {
+            Toggle::Capture.set(true);
+        }
+        let limit = cfg.max_inflight.map(InflightLimit::new);
+        let exec = cfg.static_routes.iter().any(|r| exec::runs(r.payload)).then(|| InflightLimit::new(cfg.exec_max));
+        let drop_map = cfg.xdp_drop_map.as_ref().and_then(|path| {
+            XdpMap::open(path)
+                .map_err(|e| eprintln!("[xdp] cannot open drop map {}: {e}, not banning", path.display()))
+                .ok()
+        });
+        let idempotency = cfg.idempotency_ttl.map(IdempotencyStore::new);
+        let tcp = cfg.tcp_stats.then(|| TcpStats::new(threads));
+        let sizes = cfg.size_stats.then(|| SizeStats::new(&cfg.size_routes));
+        let split = (!cfg.split.groups.is_empty()).then(|| SplitStats::new(cfg.split.groups.len()));
+        let mirror = cfg.mirror.as_ref().map(|_| MirrorStats::new());
+        let tenants = (!cfg.tenants.is_empty()).then(|| Tenants::new(&cfg.tenants));
+        let (sockets, relays) = (upstream::sockets(cfg), upstream::relays(cfg));
+        let backends = (!sockets.is_empty() || !relays.is_empty()).then(|| UpstreamStats::new(sockets, relays));
+        let bus = Bus::new(threads);
+        let long_poll = cfg.long_poll_path.as_ref().map(|_| LongPoll::new(bus));
+        let scoped = ScopedLimits::new(cfg, bus);
+        #[cfg(feature = "tls")]
+        let tls = match (&cfg.tls_cert, &cfg.tls_key) {
+            (Some(cert), Some(key)) => Some(crate::tls::server_config(cert, key).map_err(VryptError::Tls)?),
+            _ => None,
+        };
+        let conns = cfg.admin.then(|| ConnList::new(threads));
+        let addrs = match &cfg.listen_file {
+            Some(path) => listen::read_file(path)
+                .map_err(|source| VryptError::ListenFile { path: path.clone(), source })?,
+            None => listen::fixed_addrs(cfg),
+        };
+        let mode = cfg.accept_mode;
+        let (listen, bound) = ListenSet::bind(&addrs, mode.owners(threads), mode, cfg.bind_device.clone(), bus)?;
+        let addr = bound[0][0].listener.local_addr().map_err(|source| VryptError::Bind { addr: addrs[0], source })?;
+        // Taken over before any thread runs, so a listen change in between is not missed.
+        let mut listeners: Vec<_> =
+            bound.into_iter().enumerate().map(|(i, b)| Listeners::new(listen, i, b)).collect();
+        let bodies = BodyStore::new(routes::load_all(cfg), bus);
+        let handoffs = match mode {
+            AcceptMode::Thread => (0..threads).map(|_| Handoff::default()).collect(),
+            _ => Vec::new(),
+        };
+        // Everything needing root is bound or opened by now; no thread has started yet, so
+        // the ban thread and the workers below all run without it.
+        if cfg.user.is_some() || cfg.group.is_some() {
+            privilege::drop_privileges(cfg.user.as_deref(), cfg.group.as_deref()).map_err(VryptError::Privileges)?;
+        }
+        let abuse = drop_map.and_then(|map| {
+            let (tracker, rx) = AbuseTracker::new(cfg.ban_threshold, cfg.ban_suspicious);
+            xdp::spawn(tracker, rx, map, cfg.ban_duration)
+                .map_err(|e| eprintln!("[xdp] cannot start the ban thread: {e}, not banning"))
+                .ok()?;
+            Some(tracker)
+        });
+        if cfg.watch_routes && !cfg.static_routes.is_empty() {
+            if let Err(e) = watch::spawn(cfg, bodies) {
+                eprintln!("[warn] cannot watch static route files: {e}, not reloading them");
+            }
+        }
+        let shared: &'static Shared = Box::leak(Box::new(Shared {
+            cfg,
+            clock,
+            responses,
+            bodies,
+            counter,
+            capture,
+            limit,
+            scoped,
+            exec,
+            abuse,
+            idempotency,
+            tcp,
+            sizes,
+            split,
+            mirror,
+            tenants,
+            backends,
+            long_poll,
+            conns,
+            listen,
+            bus,
+            #[cfg(feature = "tls")]
+            tls,
+            handoffs,
+        }));
+
+        let spawn = |name: String, f: Box<dyn FnOnce() + Send>| {
+            thread::Builder::new().name(name).spawn(f).map_err(VryptError::Spawn)
+        };
+        if let Some(warmup) = cfg.warmup {
+            counter.begin_warmup();
+            spawn(
+                "warmup".into(),
+                Box::new(move || {
+                    thread::sleep(warmup);
+                    counter.end_warmup();
+                    eprintln!("[info] warm-up over; stats reset");
+                }),
+            )?;
+        }
+        let mut handles = Vec::with_capacity(threads + 1);
+        if mode == AcceptMode::Thread {
+            let own = listeners.pop().expect("accept thread listeners");
+            for i in 0..threads {
+                handles.push(spawn(format!("worker-{i}"), Box::new(move || worker(shared, i, None)))?);
+            }
+            handles.push(spawn("acceptor".into(), Box::new(move || acceptor(shared, own)))?);
+        } else {
+            for (i, own) in listeners.into_iter().enumerate() {
+                handles.push(spawn(format!("worker-{i}"), Box::new(move || worker(shared, i, Some(own))))?);
+            }
+        }
+        Ok(Self { addr, shared, handles })
+    }
+
+    /// Number of workers, not counting an accept thread.
+    pub fn threads(&self) -> usize {
+        self.shared.counter.slots().len()
+    }
+
+    /// Blocks until every worker has exited.
+    pub fn join(self) {
+        for h in self.handles {
+            if let Err(e) = h.join() {
+                eprintln!("[error] thread panic: {e:?}");
+            }
+        }
+    }
+}
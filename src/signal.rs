@@ -0,0 +1,114 @@
+use crate::config::STOP_POLL;
+use crate::toggle::Toggle;
+use mio::{Events, Poll, Token, Waker};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+/// Woken by the reload and shutdown handlers, so the thread in `Watch::wait` acts on them
+/// at once. Reading a set `OnceLock` and writing the waker's eventfd are both safe in a
+/// signal handler.
+static WAKER: OnceLock<Waker> = OnceLock::new();
+
+fn wake() {
+    if let Some(waker) = WAKER.get() {
+        let _ = waker.wake();
+    }
+}
+
+/// Set while the server answers every request with the maintenance response.
+/// Flipped by `SIGUSR1` and the `maintenance` toggle; read by workers when arming a response.
+pub static MAINTENANCE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigusr1(_: libc::c_int) {
+    MAINTENANCE.fetch_xor(true, Ordering::Relaxed);
+}
+
+extern "C" fn on_sigusr2(_: libc::c_int) {
+    Toggle::DebugLog.flip();
+}
+
+/// Set by `SIGHUP` (when a listen file is in use); cleared by whoever performs the reload.
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD.store(true, Ordering::Relaxed);
+    wake();
+}
+
+/// Set by `SIGTERM` and `SIGINT`; the main thread then stops the stats pusher and exits.
+pub static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_shutdown(_: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+    wake();
+}
+
+#[inline]
+pub fn maintenance() -> bool {
+    MAINTENANCE.load(Ordering::Relaxed)
+}
+
+/// Whether a reload was requested since the last call.
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::Relaxed)
+}
+
+fn install(sig: libc::c_int, handler: extern "C" fn(libc::c_int)) -> io::Result<()> {
+    unsafe {
+        let mut sa: libc::sigaction = std::mem::zeroed();
+        sa.sa_sigaction = handler as usize;
+        sa.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut sa.sa_mask);
+        if libc::sigaction(sig, &sa, std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub fn install_handlers() -> io::Result<()> {
+    install(libc::SIGUSR1, on_sigusr1)?;
+    install(libc::SIGUSR2, on_sigusr2)
+}
+
+/// Turns `SIGTERM` and `SIGINT` into a shutdown request, so the last stats interval is
+/// pushed before the process exits.
+pub fn install_shutdown_handler() -> io::Result<()> {
+    install(libc::SIGTERM, on_shutdown)?;
+    install(libc::SIGINT, on_shutdown)
+}
+
+/// Turns `SIGHUP` into a reload request instead of terminating the process.
+pub fn install_reload_handler() -> io::Result<()> {
+    install(libc::SIGHUP, on_sighup)
+}
+
+/// What the main thread blocks on between reload and shutdown requests.
+pub struct Watch {
+    poll: Poll,
+    events: Events,
+}
+
+/// Sets up the waker the reload and shutdown handlers wake. Only one watch can exist.
+pub fn watch() -> io::Result<Watch> {
+    let poll = Poll::new()?;
+    let waker = Waker::new(poll.registry(), Token(0))?;
+    WAKER.set(waker).map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "signals are already watched"))?;
+    Ok(Watch { poll, events: Events::with_capacity(1) })
+}
+
+impl Watch {
+    /// Blocks until a reload or shutdown is requested, or `timeout` passes. A request made
+    /// before the call returns at once; the flags say which it was.
+    pub fn wait(&mut self, timeout: Option<Duration>) {
+        if let Err(e) = self.poll.poll(&mut self.events, timeout) {
+            if e.kind() != io::ErrorKind::Interrupted {
+                eprintln!("[warn] waiting for signals failed: {e}");
+                thread::sleep(STOP_POLL);
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use crate::config::MAX_UPLOAD_SIZE;
+use crate::sha256::Sha256;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// How request bodies are consumed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SinkMode {
+    /// Drop body bytes as they arrive.
+    Discard,
+    /// Hash the body and answer with its hex SHA-256 instead of the normal body.
+    Hash,
+    /// Write each body to its own file in the given directory.
+    Store(PathBuf),
+}
+
+impl SinkMode {
+    /// Parses `discard`, `hash` or `store:<dir>`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec {
+            "discard" => Ok(SinkMode::Discard),
+            "hash" => Ok(SinkMode::Hash),
+            _ => match spec.strip_prefix("store:") {
+                Some(dir) if !dir.is_empty() => Ok(SinkMode::Store(PathBuf::from(dir))),
+                _ => Err(format!("unrecognised body sink '{spec}' (expected discard, hash or store:<dir>)")),
+            },
+        }
+    }
+}
+
+/// Per-request body consumer created from a `SinkMode`.
+pub enum BodySink {
+    Discard,
+    Hash(Box<Sha256>),
+    Store(File),
+    /// Admin upload kept in memory, up to `MAX_UPLOAD_SIZE`.
+    Collect(Vec<u8>),
+}
+
+impl BodySink {
+    pub fn open(mode: &SinkMode, file_name: impl FnOnce() -> String) -> io::Result<Self> {
+        Ok(match mode {
+            SinkMode::Discard => BodySink::Discard,
+            SinkMode::Hash => BodySink::Hash(Box::new(Sha256::new())),
+            SinkMode::Store(dir) => BodySink::Store(File::create(Path::new(dir).join(file_name()))?),
+        })
+    }
+
+    #[inline]
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            BodySink::Discard => Ok(()),
+            BodySink::Hash(h) => {
+                h.update(data);
+                Ok(())
+            }
+            BodySink::Store(f) => f.write_all(data),
+            BodySink::Collect(v) => {
+                if v.len() + data.len() > MAX_UPLOAD_SIZE {
+                    return Err(io::Error::other(format!("upload larger than {MAX_UPLOAD_SIZE} bytes")));
+                }
+                v.extend_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Request and response size histograms (`--size-stats`), per route, for sizing `BUF_SIZE`
+//! from real traffic. A route is the longest `--size-route` path prefix a request matches;
+//! without any, every request falls under `all`.
+
+use crate::config::BUF_SIZE;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Upper bounds (bytes) of the size buckets; a final bucket catches everything above.
+/// `le_8k` is a request that fits the read buffer.
+pub const SIZE_BUCKETS: [u64; 6] = [256, 1024, 4096, BUF_SIZE as u64, 64 * 1024, 1024 * 1024];
+pub const SIZE_BUCKET_NAMES: [&str; 7] = ["le_256", "le_1k", "le_4k", "le_8k", "le_64k", "le_1m", "gt_1m"];
+
+/// Index of the route for `path` among `routes`: the longest matching prefix, or
+/// `routes.len()` (`other`, or `all` without routes) when none matches.
+pub fn route(routes: &[String], path: &[u8]) -> usize {
+    routes
+        .iter()
+        .enumerate()
+        .filter(|(_, prefix)| path.starts_with(prefix.as_bytes()))
+        .max_by_key(|(_, prefix)| prefix.len())
+        .map_or(routes.len(), |(i, _)| i)
+}
+
+#[derive(Default)]
+struct Histogram([AtomicU64; SIZE_BUCKET_NAMES.len()]);
+
+impl Histogram {
+    fn record(&self, bytes: u64) {
+        let bucket = SIZE_BUCKETS.iter().position(|&b| bytes <= b).unwrap_or(SIZE_BUCKETS.len());
+        self.0[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> [u64; SIZE_BUCKET_NAMES.len()] {
+        std::array::from_fn(|i| self.0[i].swap(0, Ordering::Relaxed))
+    }
+}
+
+/// Per-route histograms recorded by workers as responses complete and reported by the
+/// stats pusher.
+pub struct SizeStats {
+    names: Vec<String>,
+    requests: Box<[Histogram]>,
+    responses: Box<[Histogram]>,
+}
+
+impl SizeStats {
+    pub fn new(routes: &[String]) -> &'static Self {
+        let mut names: Vec<String> = routes.iter().map(|r| metric_name(r)).collect();
+        names.push(if routes.is_empty() { "all" } else { "other" }.to_string());
+        let histograms = || (0..names.len()).map(|_| Histogram::default()).collect();
+        Box::leak(Box::new(Self { requests: histograms(), responses: histograms(), names }))
+    }
+
+    /// Records one exchange on `route`: request head and body bytes, and the response as written.
+    #[inline]
+    pub fn record(&self, route: usize, request: u64, response: u64) {
+        let route = route.min(self.names.len() - 1);
+        self.requests[route].record(request);
+        self.responses[route].record(response);
+    }
+
+    /// Route names as they appear in metric names.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Per-bucket request and response counts for `route` recorded since the previous call.
+    pub fn take(&self, route: usize) -> ([u64; SIZE_BUCKET_NAMES.len()], [u64; SIZE_BUCKET_NAMES.len()]) {
+        (self.requests[route].take(), self.responses[route].take())
+    }
+}
+
+/// `/api/v1` becomes `api_v1` and `/` becomes `root`.
+fn metric_name(prefix: &str) -> String {
+    let name: String = prefix
+        .trim_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    if name.is_empty() { "root".to_string() } else { name }
+}
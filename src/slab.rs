@@ -1,34 +1,100 @@
 use crate::conn::Conn;
+use crate::pool::slot_index;
 use mio::Token;
 
+struct Entry {
+    /// Full token the connection was inserted under, generation included.
+    token: Token,
+    conn: Box<Conn>,
+}
+
+/// Marks a slot index with no connection in `index`.
+const EMPTY: u32 = u32::MAX;
+
+/// Connections indexed by the slot index of their token. Lookups with a token from an
+/// earlier generation of the slot find nothing. The connections are kept packed, with a
+/// table from slot index to position, so iterating costs the number of connections
+/// rather than the highest index ever handed out. The table grows to the highest index
+/// inserted and can be shrunk back once the top of it has emptied.
 pub struct Slab {
-    slots: Vec<Option<Box<Conn>>>,
+    index: Vec<u32>,
+    entries: Vec<Entry>,
 }
 
 impl Slab {
+    /// A table with room for `cap` slots before it has to grow.
     pub fn new(cap: usize) -> Self {
-        Self {
-            slots: (0..cap).map(|_| None).collect(),
-        }
+        Self { index: vec![EMPTY; cap], entries: Vec::new() }
     }
 
     #[inline]
     pub fn insert(&mut self, tok: Token, conn: Conn) {
-        self.slots[tok.0] = Some(Box::new(conn));
+        let i = slot_index(tok);
+        if i >= self.index.len() {
+            self.index.resize(i + 1, EMPTY);
+        }
+        let entry = Entry { token: tok, conn: Box::new(conn) };
+        match self.index[i] {
+            EMPTY => {
+                self.index[i] = self.entries.len() as u32;
+                self.entries.push(entry);
+            }
+            pos => self.entries[pos as usize] = entry,
+        }
+    }
+
+    #[inline]
+    fn position(&self, tok: Token) -> Option<usize> {
+        let pos = *self.index.get(slot_index(tok))?;
+        (pos != EMPTY && self.entries[pos as usize].token == tok).then_some(pos as usize)
     }
 
     #[inline]
     pub fn get(&self, tok: Token) -> Option<&Conn> {
-        self.slots[tok.0].as_deref()
+        self.position(tok).map(|pos| &*self.entries[pos].conn)
     }
 
     #[inline]
     pub fn get_mut(&mut self, tok: Token) -> Option<&mut Conn> {
-        self.slots[tok.0].as_deref_mut()
+        self.position(tok).map(|pos| &mut *self.entries[pos].conn)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Conn> {
+        self.entries.iter().map(|e| &*e.conn)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (Token, &Conn)> {
+        self.entries.iter().map(|e| (e.token, &*e.conn))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Token, &mut Conn)> {
+        self.entries.iter_mut().map(|e| (e.token, &mut *e.conn))
     }
 
+    /// Bytes of the tables and the boxed connections, not counting buffers they own.
+    pub fn bytes(&self) -> u64 {
+        let tables = self.index.capacity() * size_of::<u32>() + self.entries.capacity() * size_of::<Entry>();
+        (tables + self.entries.len() * size_of::<Conn>()) as u64
+    }
+
+    /// Takes the connection out, moving the last one into its place.
     #[inline]
     pub fn remove(&mut self, tok: Token) -> Option<Conn> {
-        self.slots[tok.0].take().map(|b| *b)
+        let pos = self.position(tok)?;
+        self.index[slot_index(tok)] = EMPTY;
+        let entry = self.entries.swap_remove(pos);
+        if let Some(moved) = self.entries.get(pos) {
+            self.index[slot_index(moved.token)] = pos as u32;
+        }
+        Some(*entry.conn)
+    }
+
+    /// Drops empty slots at the end of the table, keeping at least `floor`, and releases
+    /// the capacity they and the packed connections' spare room held.
+    pub fn shrink(&mut self, floor: usize) {
+        let used = self.index.iter().rposition(|&pos| pos != EMPTY).map_or(0, |i| i + 1);
+        self.index.truncate(used.max(floor));
+        self.index.shrink_to_fit();
+        self.entries.shrink_to_fit();
     }
 }
@@ -0,0 +1,86 @@
+use socket2::SockRef;
+use std::io;
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+use std::time::Duration;
+
+/// Runs `f` with a `SockRef` for a socket that only exposes `AsRawFd` (mio 0.8 types).
+pub fn with_sock<T: AsRawFd, R>(sock: &T, f: impl FnOnce(SockRef<'_>) -> R) -> R {
+    // SAFETY: the fd is owned by `sock`, which outlives this call.
+    let fd = unsafe { BorrowedFd::borrow_raw(sock.as_raw_fd()) };
+    f(SockRef::from(&fd))
+}
+
+/// Makes the next close send RST instead of FIN (SO_LINGER with a zero timeout).
+pub fn set_abortive_close<T: AsRawFd>(sock: &T) -> io::Result<()> {
+    with_sock(sock, |s| s.set_linger(Some(Duration::ZERO)))
+}
+
+/// DSCP class names accepted by `--dscp`, with their code points.
+const DSCP_NAMES: [(&str, u8); 21] = [
+    ("cs0", 0),
+    ("cs1", 8),
+    ("af11", 10),
+    ("af12", 12),
+    ("af13", 14),
+    ("cs2", 16),
+    ("af21", 18),
+    ("af22", 20),
+    ("af23", 22),
+    ("cs3", 24),
+    ("af31", 26),
+    ("af32", 28),
+    ("af33", 30),
+    ("cs4", 32),
+    ("af41", 34),
+    ("af42", 36),
+    ("af43", 38),
+    ("cs5", 40),
+    ("ef", 46),
+    ("cs6", 48),
+    ("cs7", 56),
+];
+
+/// Parses a DSCP code point: `0`..`63` or a class name such as `ef`, `af41` or `cs1`.
+pub fn parse_dscp(spec: &str) -> Result<u8, String> {
+    let lower = spec.to_ascii_lowercase();
+    match DSCP_NAMES.iter().find(|(name, _)| *name == lower) {
+        Some(&(_, dscp)) => Ok(dscp),
+        None => spec
+            .parse::<u8>()
+            .ok()
+            .filter(|&d| d < 64)
+            .ok_or_else(|| format!("expected a DSCP value 0-63 or a class name (ef, af11-af43, cs0-cs7), got '{spec}'")),
+    }
+}
+
+/// Marks a connection's traffic: `dscp` goes into the IPv4 TOS and, on an IPv6 socket, the
+/// traffic class byte (ECN bits left clear); `priority` into `SO_PRIORITY` for the local qdisc.
+/// Both bytes are set on IPv6 sockets so IPv4-mapped peers are marked as well.
+pub fn set_qos<T: AsRawFd>(sock: &T, v6: bool, dscp: Option<u8>, priority: Option<u32>) -> io::Result<()> {
+    if let Some(dscp) = dscp {
+        let tos = u32::from(dscp) << 2;
+        with_sock(sock, |s| {
+            if v6 {
+                s.set_tclass_v6(tos)?;
+            }
+            s.set_tos(tos)
+        })?;
+    }
+    if let Some(priority) = priority {
+        let value = priority as libc::c_int;
+        // SAFETY: `value` outlives the call and its size is passed along.
+        let rc = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PRIORITY,
+                &value as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,304 @@
+//! Spec strings: the one-line form each configurable rule takes on the command line, e.g.
+//! `reset:0:0.1` for a fault. With the `serde` feature these types are serialized as their
+//! spec string, so a config file reads like the flags it replaces.
+
+use crate::close::CloseRule;
+use crate::error_page::{ErrorPage, PageBody};
+use crate::fault::{FaultKind, FaultRule};
+use crate::headers::{HeaderRule, RouteHeader};
+use crate::limit::{ListenerLimit, RouteLimit};
+use crate::listen::AcceptMode;
+use crate::mirror::Mirror;
+use crate::redirect::RedirectRule;
+use crate::region::BufBacking;
+use crate::rewrite::PathRewrite;
+use crate::routes::StaticRoute;
+use crate::sink::SinkMode;
+use crate::split::{SplitGroup, Sticky};
+use crate::statsd::{StatsSink, StatsTarget};
+use crate::tenant::Tenant;
+use crate::tunnel::ProxyTarget;
+
+/// A value that round-trips through its command-line spec string.
+pub trait Spec: Sized {
+    fn from_spec(spec: &str) -> Result<Self, String>;
+    fn to_spec(&self) -> String;
+}
+
+impl Spec for FaultRule {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        FaultRule::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        match self.kind {
+            FaultKind::Stall => format!("stall:{}", self.probability),
+            FaultKind::Reset(n) => format!("reset:{n}:{}", self.probability),
+            FaultKind::CloseHeaders => format!("close-headers:{}", self.probability),
+        }
+    }
+}
+
+/// `Name: value` sets a header, as `--header` does; `-Name` removes it.
+impl Spec for HeaderRule {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        HeaderRule::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        match self {
+            HeaderRule::Set(name, value) => {
+                format!("{}: {}", String::from_utf8_lossy(name), String::from_utf8_lossy(value))
+            }
+            HeaderRule::Remove(name) => format!("-{}", String::from_utf8_lossy(name)),
+        }
+    }
+}
+
+/// `[TENANT]PATH=` and a header rule, as `--route-header` takes it.
+impl Spec for RouteHeader {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        RouteHeader::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        format!("{}{}={}", self.tenant.as_deref().unwrap_or_default(), self.path, self.rule.to_spec())
+    }
+}
+
+impl Spec for ListenerLimit {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        ListenerLimit::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        format!("{}={}", self.addr, self.max)
+    }
+}
+
+impl Spec for RouteLimit {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        RouteLimit::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        format!("{}{}={}", self.tenant.as_deref().unwrap_or_default(), self.path, self.max)
+    }
+}
+
+impl Spec for PathRewrite {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        PathRewrite::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        format!("{}={}", self.from, self.to)
+    }
+}
+
+/// `STATUS PATTERN TARGET`, the three `--redirect` arguments separated by spaces.
+impl Spec for RedirectRule {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        match spec.split_whitespace().collect::<Vec<_>>()[..] {
+            [status, pattern, target] => RedirectRule::parse(status, pattern, target),
+            _ => Err(format!("expected 'STATUS PATTERN TARGET', got '{spec}'")),
+        }
+    }
+
+    fn to_spec(&self) -> String {
+        let (pattern, target) = self.source();
+        format!("{} {pattern} {target}", self.status)
+    }
+}
+
+impl Spec for SinkMode {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        SinkMode::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        match self {
+            SinkMode::Discard => "discard".to_string(),
+            SinkMode::Hash => "hash".to_string(),
+            SinkMode::Store(dir) => format!("store:{}", dir.display()),
+        }
+    }
+}
+
+impl Spec for AcceptMode {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        AcceptMode::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        self.name().to_string()
+    }
+}
+
+impl Spec for BufBacking {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        BufBacking::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        self.name().to_string()
+    }
+}
+
+impl Spec for StatsTarget {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        StatsTarget::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// `TARGET[,every=SECS][,prefix=NAME][,off]`, leaving out options at their defaults.
+impl Spec for StatsSink {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        StatsSink::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// `host:port`; the host is resolved again when the spec is read back.
+impl Spec for ProxyTarget {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        ProxyTarget::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl Spec for SplitGroup {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        SplitGroup::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        format!("{}={}@{}", self.name, self.weight, self.target.to_spec())
+    }
+}
+
+impl Spec for Mirror {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        Mirror::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        format!("{}@{}", self.percent, self.target.to_spec())
+    }
+}
+
+impl Spec for Sticky {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        Sticky::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        match self {
+            Sticky::Ip => "ip".to_string(),
+            Sticky::Header(name) => format!("header:{name}"),
+            Sticky::Cookie(name) => format!("cookie:{name}"),
+        }
+    }
+}
+
+impl Spec for ErrorPage {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        ErrorPage::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        let host = self.host.as_deref().map(|h| format!("{h}/")).unwrap_or_default();
+        match &self.body {
+            PageBody::Inline(text) => format!("{host}{}={text}", self.status),
+            PageBody::File(path) => format!("{host}{}=@{}", self.status, path.display()),
+        }
+    }
+}
+
+impl Spec for Tenant {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        Tenant::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl Spec for StaticRoute {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        StaticRoute::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        let tenant = self.tenant.as_deref().unwrap_or_default();
+        let methods = if self.methods.is_empty() { String::new() } else { format!("{} ", self.methods.join(",")) };
+        format!("{}{methods}{tenant}{}={}", self.payload.prefix(), self.path, self.file.display())
+    }
+}
+
+impl Spec for CloseRule {
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        CloseRule::parse(spec)
+    }
+
+    fn to_spec(&self) -> String {
+        format!("{}={}", self.reason.name(), self.mode.name())
+    }
+}
+
+#[cfg(feature = "serde")]
+macro_rules! serde_as_spec {
+    ($($ty:ty),* $(,)?) => {$(
+        impl serde::Serialize for $ty {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(&self.to_spec())
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $ty {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let spec = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+                <$ty>::from_spec(&spec).map_err(serde::de::Error::custom)
+            }
+        }
+    )*};
+}
+
+#[cfg(feature = "serde")]
+serde_as_spec!(
+    FaultRule,
+    HeaderRule,
+    RouteHeader,
+    PathRewrite,
+    ListenerLimit,
+    RouteLimit,
+    RedirectRule,
+    SinkMode,
+    AcceptMode,
+    BufBacking,
+    StatsTarget,
+    StatsSink,
+    ProxyTarget,
+    SplitGroup,
+    Mirror,
+    Sticky,
+    ErrorPage,
+    StaticRoute,
+    Tenant,
+    CloseRule,
+);
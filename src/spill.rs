@@ -0,0 +1,124 @@
+//! Buffering route backend output ahead of a slow client (`--spill-threshold`). Without it
+//! a backend's output is only read as fast as the client takes it, so a slow client keeps
+//! the command running or the FastCGI connection busy. With it, output is read as soon as
+//! the backend sends it: up to the threshold is held in memory, the rest is appended to an
+//! unlinked temporary file in `--spill-dir` and sent from there with `sendfile`.
+//!
+//! Output goes out in the order it came: once a file has been started, new output is
+//! appended to it until it has been sent whole, and the backend's last bytes are kept
+//! apart to be written after everything else.
+
+use crate::config::MAX_SPILL_SIZE;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// What `Spill::take` found to send next.
+pub enum Next {
+    /// Bytes swapped into the caller's buffer.
+    Memory,
+    /// Bytes in the file, for `send`.
+    File,
+    /// Nothing until the backend sends more.
+    Nothing,
+}
+
+/// One response's output read ahead of the client.
+pub struct Spill {
+    mem: Vec<u8>,
+    file: Option<File>,
+    /// Bytes appended to `file`, and how many of them have been sent.
+    end: u64,
+    sent: u64,
+    /// The backend's final bytes, sent once everything before them is out.
+    last: Option<Vec<u8>>,
+    /// `last` has been pushed.
+    ended: bool,
+    threshold: usize,
+    dir: &'static Path,
+    /// Where the backend's output is read into before `push_chunk` or `push_last`.
+    pub chunk: Vec<u8>,
+}
+
+impl Spill {
+    pub fn new(threshold: usize, dir: &'static Path) -> Self {
+        Self { mem: Vec::new(), file: None, end: 0, sent: 0, last: None, ended: false, threshold, dir, chunk: Vec::new() }
+    }
+
+    /// The response has ended and nothing is left to send.
+    pub fn finished(&self) -> bool {
+        self.ended && self.mem.is_empty() && self.sent == self.end && self.last.is_none()
+    }
+
+    /// The file has reached `MAX_SPILL_SIZE`: the backend is read no further until it drains.
+    pub fn full(&self) -> bool {
+        self.end - self.sent >= MAX_SPILL_SIZE
+    }
+
+    /// Queues `chunk`: in memory while it fits under the threshold and no file is pending.
+    pub fn push_chunk(&mut self) -> io::Result<()> {
+        if self.file.is_none() && self.mem.len() + self.chunk.len() <= self.threshold {
+            self.mem.extend_from_slice(&self.chunk);
+            return Ok(());
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(temp_file(self.dir)?),
+        };
+        file.write_all(&self.chunk)?;
+        self.end += self.chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Keeps `chunk` as the response's final bytes.
+    pub fn push_last(&mut self) {
+        self.last = Some(std::mem::take(&mut self.chunk));
+        self.ended = true;
+    }
+
+    /// Moves the next bytes held in memory into `out`, or says where they are.
+    pub fn take(&mut self, out: &mut Vec<u8>) -> Next {
+        if !self.mem.is_empty() {
+            out.clear();
+            std::mem::swap(out, &mut self.mem);
+            return Next::Memory;
+        }
+        if self.sent < self.end {
+            return Next::File;
+        }
+        match self.last.take() {
+            Some(last) => {
+                *out = last;
+                Next::Memory
+            }
+            None => Next::Nothing,
+        }
+    }
+
+    /// Sends up to `max` bytes of the file to `socket`; the file is dropped once sent whole.
+    pub fn send(&mut self, socket: &impl AsRawFd, max: usize) -> io::Result<usize> {
+        let Some(file) = &self.file else { return Ok(0) };
+        let mut offset = self.sent as libc::off_t;
+        let count = ((self.end - self.sent) as usize).min(max);
+        let n = unsafe { libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut offset, count) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.sent += n as u64;
+        if self.sent == self.end {
+            (self.file, self.end, self.sent) = (None, 0, 0);
+        }
+        Ok(n as usize)
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.mem.capacity() + self.chunk.capacity() + self.last.as_ref().map_or(0, Vec::capacity)
+    }
+}
+
+/// An unlinked file in `dir`, gone with its last descriptor.
+fn temp_file(dir: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).mode(0o600).custom_flags(libc::O_TMPFILE).open(dir)
+}
@@ -0,0 +1,204 @@
+//! Weighted traffic splitting (`--split-group`): origin-form requests are relayed to one of
+//! several upstream groups in proportion to their weights, e.g. 95/5 between a stable and
+//! a canary build. Assignment is random per connection unless `--split-sticky` names a
+//! key: equal keys are then hashed onto the same group, or with `--split-affinity` drawn
+//! once and remembered in the worker's `AffinityTable`.
+
+use crate::affinity::{AffinityCounts, AffinityTable};
+use crate::http::RequestHead;
+use crate::rng::Rng;
+use crate::tunnel::ProxyTarget;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Paths under this prefix are always served locally, so the admin API stays reachable.
+const LOCAL_PREFIX: &[u8] = b"/__vrypt/";
+
+/// One upstream group and its share of the split traffic.
+#[derive(Clone, Debug)]
+pub struct SplitGroup {
+    pub name: String,
+    pub weight: u32,
+    pub target: ProxyTarget,
+}
+
+impl SplitGroup {
+    /// Parses and resolves `name=weight@host:port`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let bad = || format!("expected 'name=weight@host:port', got '{spec}'");
+        let (name, rest) = spec.split_once('=').ok_or_else(bad)?;
+        let (weight, upstream) = rest.split_once('@').ok_or_else(bad)?;
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return Err(format!("group name '{name}' must be letters, digits, '-' or '_'"));
+        }
+        let weight = weight.parse::<u32>().ok().filter(|&w| w > 0).ok_or_else(bad)?;
+        Ok(Self { name: name.to_string(), weight, target: ProxyTarget::parse(upstream)? })
+    }
+}
+
+/// Where a sticky assignment is keyed from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Sticky {
+    /// The client address (the PROXY protocol source when there is one).
+    Ip,
+    Header(String),
+    Cookie(String),
+}
+
+impl Sticky {
+    /// Parses `ip`, `header:NAME` or `cookie:NAME`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        if spec == "ip" {
+            return Ok(Sticky::Ip);
+        }
+        match spec.split_once(':') {
+            Some(("header", name)) if !name.is_empty() => Ok(Sticky::Header(name.to_string())),
+            Some(("cookie", name)) if !name.is_empty() => Ok(Sticky::Cookie(name.to_string())),
+            _ => Err(format!("expected 'ip', 'header:NAME' or 'cookie:NAME', got '{spec}'")),
+        }
+    }
+
+    /// Hash of the value the assignment is keyed on, if the request carries one.
+    fn key(&self, head: &RequestHead, peer: SocketAddr) -> Option<u64> {
+        let value = match self {
+            Sticky::Ip => {
+                return Some(match peer.ip() {
+                    IpAddr::V4(ip) => fnv1a(&ip.octets()),
+                    IpAddr::V6(ip) => fnv1a(&ip.octets()),
+                })
+            }
+            Sticky::Header(name) => head.header(name.as_bytes()),
+            Sticky::Cookie(name) => {
+                let mut cookies = head.headers().filter(|(n, _)| n.eq_ignore_ascii_case(b"cookie"));
+                cookies.find_map(|(_, v)| {
+                    let mut pairs = v.split(|&b| b == b';').map(<[u8]>::trim_ascii);
+                    pairs.find_map(|pair| pair.strip_prefix(name.as_bytes())?.strip_prefix(b"="))
+                })
+            }
+        };
+        value.map(fnv1a)
+    }
+}
+
+impl fmt::Display for Sticky {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sticky::Ip => write!(f, "client address"),
+            Sticky::Header(name) => write!(f, "header {name}"),
+            Sticky::Cookie(name) => write!(f, "cookie {name}"),
+        }
+    }
+}
+
+/// The configured split; inactive without groups.
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
+pub struct Split {
+    pub groups: Vec<SplitGroup>,
+    /// Path prefixes that are split; empty means every path.
+    pub paths: Vec<String>,
+    pub sticky: Option<Sticky>,
+    /// Remember each sticky key's group for this long since its last request, instead of
+    /// hashing keys onto the weights.
+    pub affinity: Option<Duration>,
+}
+
+impl Split {
+    /// Whether a request for `path` goes to an upstream group.
+    pub fn applies(&self, path: &[u8]) -> bool {
+        !self.groups.is_empty()
+            && !path.starts_with(LOCAL_PREFIX)
+            && (self.paths.is_empty() || self.paths.iter().any(|p| path.starts_with(p.as_bytes())))
+    }
+
+    /// The group for a request with sticky value `key`, or for the random draw `random` when
+    /// there is none.
+    pub fn group_for(&self, key: Option<&[u8]>, random: u64) -> usize {
+        self.weighted(key.map_or(random, fnv1a))
+    }
+
+    /// The group `point` falls into when the weights are laid end to end.
+    fn weighted(&self, point: u64) -> usize {
+        let total: u64 = self.groups.iter().map(|g| u64::from(g.weight)).sum();
+        let mut point = point % total.max(1);
+        for (i, group) in self.groups.iter().enumerate() {
+            match point.checked_sub(u64::from(group.weight)) {
+                Some(rest) => point = rest,
+                None => return i,
+            }
+        }
+        self.groups.len().saturating_sub(1)
+    }
+
+    /// The group for a request from `peer`. With an affinity table, a known key gets its
+    /// remembered group and a new one a random draw that is then remembered.
+    pub(crate) fn choose(
+        &self,
+        head: &RequestHead,
+        peer: SocketAddr,
+        affinity: Option<&mut AffinityTable>,
+        rng: &mut Rng,
+    ) -> usize {
+        let key = self.sticky.as_ref().and_then(|s| s.key(head, peer));
+        match (key, affinity) {
+            (Some(key), Some(table)) => {
+                let now = Instant::now();
+                match table.get(key, now) {
+                    Some(group) if (group as usize) < self.groups.len() => group as usize,
+                    _ => {
+                        let group = self.weighted(rng.next_u64());
+                        table.insert(key, group as u32, now);
+                        group
+                    }
+                }
+            }
+            (Some(key), None) => self.weighted(key),
+            (None, _) => self.weighted(rng.next_u64()),
+        }
+    }
+}
+
+/// 64-bit FNV-1a: stable across runs and builds, so sticky keys keep their group over restarts.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Requests relayed to each group and the workers' affinity table counts, reported by the
+/// stats pusher.
+pub struct SplitStats {
+    requests: Box<[AtomicU64]>,
+    /// Hits, misses, evictions and expiries, added by each worker at its stats sample.
+    affinity: [AtomicU64; 4],
+}
+
+impl SplitStats {
+    pub fn new(groups: usize) -> &'static Self {
+        let requests = (0..groups).map(|_| AtomicU64::new(0)).collect();
+        Box::leak(Box::new(Self { requests, affinity: Default::default() }))
+    }
+
+    pub fn add_affinity(&self, counts: AffinityCounts) {
+        let counts = [counts.hits, counts.misses, counts.evictions, counts.expired];
+        for (total, n) in self.affinity.iter().zip(counts) {
+            total.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    /// Affinity counts added since the previous call.
+    pub fn take_affinity(&self) -> AffinityCounts {
+        let [hits, misses, evictions, expired] = std::array::from_fn(|i| self.affinity[i].swap(0, Ordering::Relaxed));
+        AffinityCounts { hits, misses, evictions, expired }
+    }
+
+    #[inline]
+    pub fn routed(&self, group: usize) {
+        self.requests[group].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Requests relayed to `group` since the previous call.
+    pub fn take(&self, group: usize) -> u64 {
+        self.requests[group].swap(0, Ordering::Relaxed)
+    }
+}
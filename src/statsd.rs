@@ -0,0 +1,417 @@
+//! StatsD client for the stats pusher: gauges batched into as few packets as they fit in,
+//! sent over UDP or TCP (or handed to a Pushgateway, see `pushgateway`), with the collector
+//! re-resolved and reconnected under backoff while pushes fail. The same client renders an
+//! interval for a Prometheus scrape endpoint (`scrape`) or as a JSON line on stdout.
+
+use crate::config::{
+    STATS_INTERVAL, STATS_IO_TIMEOUT, STATS_MAX_PACKET, STATS_PREFIX, STATS_PUSHGATEWAY_PATH, STATS_PUSHGATEWAY_PORT,
+};
+use crate::error;
+use crate::json::Json;
+use crate::pushgateway;
+use crate::scrape;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    /// Newline-delimited lines on one connection, as StatsD's TCP server reads them.
+    Tcp,
+    /// A Prometheus Pushgateway, sent the whole interval with one HTTP `PUT`.
+    Pushgateway,
+    /// A Prometheus scrape endpoint served on `host:port`, answering with the last interval.
+    Scrape,
+    /// One JSON object per interval on standard output.
+    Stdout,
+}
+
+/// Where gauges are pushed. The host is resolved on every (re)connect, so a collector
+/// that moves to another address is followed.
+#[derive(Clone, Debug)]
+pub struct StatsTarget {
+    pub transport: Transport,
+    pub host: String,
+    pub port: u16,
+    /// Request path on a Pushgateway; empty otherwise.
+    pub path: String,
+}
+
+impl StatsTarget {
+    /// Parses `[udp://|tcp://]host:port`, `http://host[:port][/path]`, `scrape://host:port`
+    /// or `stdout`; without a scheme the target is UDP.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (transport, rest) = match spec.split_once("://") {
+            None if spec == "stdout" => {
+                return Ok(Self { transport: Transport::Stdout, host: String::new(), port: 0, path: String::new() });
+            }
+            None => (Transport::Udp, spec),
+            Some(("udp", rest)) => (Transport::Udp, rest),
+            Some(("tcp", rest)) => (Transport::Tcp, rest),
+            Some(("http", rest)) => (Transport::Pushgateway, rest),
+            Some(("scrape", rest)) => (Transport::Scrape, rest),
+            Some((scheme, _)) => return Err(format!("unknown transport '{scheme}', expected udp, tcp, http or scrape")),
+        };
+        let bad = || {
+            let forms = "'[udp://|tcp://]host:port', 'http://host[:port][/path]', 'scrape://host:port' or 'stdout'";
+            format!("expected {forms}, got '{spec}'")
+        };
+        let (authority, path) = match transport {
+            Transport::Pushgateway => match rest.find('/') {
+                Some(slash) => (&rest[..slash], &rest[slash..]),
+                None => (rest, STATS_PUSHGATEWAY_PATH),
+            },
+            _ => (rest, ""),
+        };
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(v6) => match v6.split_once(']').ok_or_else(bad)? {
+                (host, "") => (host, None),
+                (host, rest) => (host, Some(rest.strip_prefix(':').ok_or_else(bad)?)),
+            },
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match (port, transport) {
+            (Some(port), _) => port.parse::<u16>().ok().filter(|&p| p > 0).ok_or_else(bad)?,
+            (None, Transport::Pushgateway) => STATS_PUSHGATEWAY_PORT,
+            (None, _) => return Err(bad()),
+        };
+        if host.is_empty() {
+            return Err(bad());
+        }
+        Ok(Self { transport, host: host.to_string(), port, path: path.to_string() })
+    }
+
+    pub fn resolve(&self) -> io::Result<SocketAddr> {
+        (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} resolves to no address", self.host)))
+    }
+
+    /// `host:port`, with an IPv6 host in brackets.
+    pub fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl fmt::Display for StatsTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.transport {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Pushgateway => "http",
+            Transport::Scrape => "scrape",
+            Transport::Stdout => return f.write_str("stdout"),
+        };
+        write!(f, "{scheme}://{}{}", self.authority(), self.path)
+    }
+}
+
+/// One place the stats pusher sends to, on its own interval and under its own metric prefix.
+#[derive(Clone, Debug)]
+pub struct StatsSink {
+    pub target: StatsTarget,
+    pub interval: Duration,
+    /// Replaces the leading `vrypt` of every metric name.
+    pub prefix: String,
+    /// A disabled sink stays in the config but is not pushed to.
+    pub enabled: bool,
+}
+
+impl StatsSink {
+    /// `target` every `STATS_INTERVAL` under the default prefix.
+    pub fn new(target: StatsTarget) -> Self {
+        Self { target, interval: STATS_INTERVAL, prefix: STATS_PREFIX.to_string(), enabled: true }
+    }
+
+    /// Parses `TARGET[,every=SECS][,prefix=NAME][,off]`, e.g. `stdout,every=10,prefix=edge`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut parts = spec.split(',');
+        let mut sink = StatsSink::new(StatsTarget::parse(parts.next().unwrap_or_default())?);
+        for opt in parts {
+            match opt.split_once('=') {
+                Some(("every", secs)) => match secs.parse::<u64>() {
+                    Ok(secs) if secs > 0 => sink.interval = Duration::from_secs(secs),
+                    _ => return Err(format!("'every' takes a positive number of seconds, got '{secs}'")),
+                },
+                Some(("prefix", prefix)) => {
+                    let valid = prefix.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'.');
+                    if !valid || prefix.is_empty() || prefix.starts_with('.') || prefix.ends_with('.') {
+                        return Err(format!("invalid prefix '{prefix}' (letters, digits, '_' and inner '.')"));
+                    }
+                    sink.prefix = prefix.to_string();
+                }
+                None if opt == "off" => sink.enabled = false,
+                _ => return Err(format!("unrecognised sink option '{opt}' (expected every=SECS, prefix=NAME or off)")),
+            }
+        }
+        Ok(sink)
+    }
+}
+
+impl fmt::Display for StatsSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.target)?;
+        if self.interval != STATS_INTERVAL {
+            write!(f, ",every={}", self.interval.as_secs())?;
+        }
+        if self.prefix != STATS_PREFIX {
+            write!(f, ",prefix={}", self.prefix)?;
+        }
+        if !self.enabled {
+            f.write_str(",off")?;
+        }
+        Ok(())
+    }
+}
+
+/// Backoff state shared by the StatsD client and the Pushgateway thread.
+///
+/// After a failed send, nothing is sent until the pause from `error::backoff` has passed,
+/// so an unreachable collector costs one error line in the log rather than one per push.
+#[derive(Default)]
+pub(crate) struct Retry {
+    failures: u32,
+    retry_at: Option<Instant>,
+    /// Metrics dropped since the collector became unreachable.
+    dropped: u64,
+}
+
+impl Retry {
+    /// Whether a send may be tried now; if not, `metrics` are counted as dropped.
+    pub(crate) fn ready(&mut self, metrics: u64) -> bool {
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            self.dropped += metrics;
+            return false;
+        }
+        true
+    }
+
+    pub(crate) fn failed(&mut self, target: &StatsTarget, e: &io::Error, metrics: u64) {
+        if self.failures == 0 {
+            eprintln!("[stats] cannot push to {target}: {e}; retrying with backoff");
+        }
+        self.dropped += metrics;
+        self.retry_at = Some(Instant::now() + error::backoff(self.failures));
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    pub(crate) fn succeeded(&mut self, target: &StatsTarget) {
+        if self.failures > 0 {
+            eprintln!("[stats] pushing to {target} again; {} metric(s) dropped meanwhile", self.dropped);
+        }
+        *self = Retry::default();
+    }
+}
+
+enum Link {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    /// Queue to the thread that `PUT`s to a Pushgateway.
+    Push(SyncSender<Vec<u8>>, JoinHandle<()>),
+    /// What the scrape endpoint serves.
+    Scrape(Arc<Mutex<Vec<u8>>>),
+    Stdout,
+}
+
+/// Buffers gauges and sends them `STATS_MAX_PACKET` bytes at a time, or for a Pushgateway,
+/// a scrape endpoint or stdout an interval at a time.
+///
+/// A failed send drops the connection and the lines in it; until `Retry` lets it try
+/// again, further lines are dropped without touching the network.
+pub struct StatsClient {
+    target: StatsTarget,
+    link: Option<Link>,
+    buf: Vec<u8>,
+    retry: Retry,
+    /// Replaces `STATS_PREFIX` at the start of metric names.
+    prefix: String,
+    /// The name being queued, with the prefix replaced.
+    name: String,
+    /// Gauges are dropped rather than queued (`mute`).
+    muted: bool,
+    /// Gauges are queued as 0 whatever their value (`zero_gauges`).
+    zero: bool,
+    /// A full Pushgateway queue is waited on rather than the interval skipped (`finish`).
+    wait: bool,
+}
+
+impl StatsClient {
+    pub fn new(target: StatsTarget) -> Self {
+        let buf = Vec::with_capacity(STATS_MAX_PACKET);
+        let prefix = STATS_PREFIX.to_string();
+        let name = String::new();
+        Self { target, link: None, buf, retry: Retry::default(), prefix, name, muted: false, zero: false, wait: false }
+    }
+
+    /// Pushes metric names under `prefix` instead of `STATS_PREFIX`.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Queues `name:value|g`, sending the lines queued so far first if it would not fit.
+    pub fn gauge(&mut self, name: fmt::Arguments, value: u64) {
+        if self.muted {
+            return;
+        }
+        let value = if self.zero { 0 } else { value };
+        self.name.clear();
+        write!(self.name, "{name}").expect("writing to a String cannot fail");
+        if self.prefix != STATS_PREFIX && self.name.starts_with(STATS_PREFIX) {
+            self.name.replace_range(..STATS_PREFIX.len(), &self.prefix);
+        }
+        let name = self.name.as_str();
+        match self.target.transport {
+            Transport::Pushgateway | Transport::Scrape => {
+                return pushgateway::write_gauge(&mut self.buf, format_args!("{name}"), value);
+            }
+            Transport::Stdout => {
+                // One member per line; the lines are joined with commas when the interval is written.
+                Json::new(&mut self.buf).key(name).u64(value);
+                return self.buf.push(b'\n');
+            }
+            Transport::Udp | Transport::Tcp => {}
+        }
+        let start = self.buf.len();
+        writeln!(self.buf, "{name}:{value}|g").expect("writing to a Vec cannot fail");
+        if self.buf.len() - start > STATS_MAX_PACKET {
+            self.buf.truncate(start);
+            eprintln!("[stats] metric '{name}' does not fit in a packet; skipping");
+        } else if self.buf.len() > STATS_MAX_PACKET {
+            self.send(start);
+        }
+    }
+
+    /// Sends everything queued.
+    pub fn flush(&mut self) {
+        self.send(self.buf.len());
+    }
+
+    /// Drops gauges instead of queuing them while `muted`, e.g. while the server warms up.
+    pub fn mute(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// Queues every gauge from here on as 0, for a last push on exit that leaves dashboards
+    /// showing an idle server rather than its final values.
+    pub fn zero_gauges(&mut self) {
+        self.zero = true;
+    }
+
+    /// Sends everything queued and, for a Pushgateway, waits until it has been pushed.
+    pub fn finish(mut self) {
+        self.wait = true;
+        self.flush();
+        if let Some(Link::Push(queue, thread)) = self.link.take() {
+            drop(queue);
+            let _ = thread.join();
+        }
+    }
+
+    /// Whether pushes are currently failing. Pushgateway requests fail on their own thread
+    /// and are not reflected here, nor are scrapes.
+    pub fn failing(&self) -> bool {
+        self.retry.failures > 0
+    }
+
+    /// Sends the first `len` bytes of the buffer as one packet and drops them from it.
+    fn send(&mut self, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let metrics = metric_count(self.target.transport, &self.buf[..len]);
+        if self.retry.ready(metrics) {
+            match self.try_send(len) {
+                Ok(()) => self.retry.succeeded(&self.target),
+                Err(e) => {
+                    self.link = None;
+                    self.retry.failed(&self.target, &e, metrics);
+                }
+            }
+        }
+        self.buf.drain(..len);
+    }
+
+    fn try_send(&mut self, len: usize) -> io::Result<()> {
+        let packet = &self.buf[..len];
+        let link = match &mut self.link {
+            Some(link) => link,
+            link => link.insert(connect(&self.target)?),
+        };
+        match link {
+            Link::Udp(sock) => match sock.send(packet) {
+                // An ICMP port unreachable from an earlier packet: nobody is listening yet,
+                // which plain fire-and-forget StatsD does not treat as an error.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+                res => res.map(drop),
+            },
+            Link::Tcp(stream) => stream.write_all(packet),
+            Link::Push(queue, _) if self.wait => {
+                queue.send(packet.to_vec()).map_err(|_| io::Error::other("pushgateway thread exited"))
+            }
+            Link::Push(queue, _) => match queue.try_send(packet.to_vec()) {
+                // The previous interval is still being pushed; this one is skipped.
+                Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+                Err(TrySendError::Disconnected(_)) => Err(io::Error::other("pushgateway thread exited")),
+            },
+            Link::Scrape(latest) => {
+                *latest.lock().unwrap_or_else(|e| e.into_inner()) = packet.to_vec();
+                Ok(())
+            }
+            Link::Stdout => {
+                let mut line = Vec::with_capacity(packet.len() + 2);
+                line.push(b'{');
+                let members = packet.strip_suffix(b"\n").unwrap_or(packet);
+                line.extend(members.iter().map(|&b| if b == b'\n' { b',' } else { b }));
+                line.extend_from_slice(b"}\n");
+                io::stdout().lock().write_all(&line)
+            }
+        }
+    }
+}
+
+/// Metrics in a batch: one per line for StatsD and stdout, one per sample line in the
+/// Prometheus text format.
+pub(crate) fn metric_count(transport: Transport, batch: &[u8]) -> u64 {
+    let lines = batch.split(|&b| b == b'\n').filter(|l| !l.is_empty());
+    match transport {
+        Transport::Pushgateway | Transport::Scrape => lines.filter(|l| !l.starts_with(b"#")).count() as u64,
+        _ => lines.count() as u64,
+    }
+}
+
+/// Resolves `target` and opens a fresh socket to it.
+fn connect(target: &StatsTarget) -> io::Result<Link> {
+    match target.transport {
+        Transport::Udp => {
+            let addr = target.resolve()?;
+            let local: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+            let sock = UdpSocket::bind(local)?;
+            sock.connect(addr)?;
+            Ok(Link::Udp(sock))
+        }
+        Transport::Tcp => {
+            let stream = TcpStream::connect_timeout(&target.resolve()?, STATS_IO_TIMEOUT)?;
+            stream.set_write_timeout(Some(STATS_IO_TIMEOUT))?;
+            stream.set_nodelay(true)?;
+            Ok(Link::Tcp(stream))
+        }
+        // The push thread resolves and connects for every request itself.
+        Transport::Pushgateway => pushgateway::spawn(target.clone()).map(|(queue, thread)| Link::Push(queue, thread)),
+        Transport::Scrape => scrape::serve(target.resolve()?).map(Link::Scrape),
+        Transport::Stdout => Ok(Link::Stdout),
+    }
+}
@@ -0,0 +1,84 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Upper bounds (µs) of the RTT buckets; a final bucket catches everything above.
+pub const RTT_BUCKETS_US: [u32; 4] = [100, 1_000, 10_000, 100_000];
+pub const RTT_BUCKET_NAMES: [&str; 5] = ["le_100us", "le_1ms", "le_10ms", "le_100ms", "gt_100ms"];
+
+pub fn tcp_info<T: AsRawFd>(sock: &T) -> io::Result<libc::tcp_info> {
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info)
+}
+
+/// System-wide `TcpExt: ListenOverflows` from `/proc/net/netstat`.
+pub fn listen_overflows() -> Option<u64> {
+    let text = std::fs::read_to_string("/proc/net/netstat").ok()?;
+    let mut lines = text.lines();
+    while let (Some(names), Some(values)) = (lines.next(), lines.next()) {
+        if !names.starts_with("TcpExt:") {
+            continue;
+        }
+        let idx = names.split_whitespace().position(|n| n == "ListenOverflows")?;
+        return values.split_whitespace().nth(idx)?.parse().ok();
+    }
+    None
+}
+
+/// Kernel-side TCP health sampled by workers and reported by the stats pusher.
+pub struct TcpStats {
+    listen_queue: Box<[AtomicU64]>,
+    retrans: AtomicU64,
+    rtt: [AtomicU64; 5],
+}
+
+impl TcpStats {
+    pub fn new(num_threads: usize) -> &'static Self {
+        Box::leak(Box::new(Self {
+            listen_queue: (0..num_threads).map(|_| AtomicU64::new(0)).collect(),
+            retrans: AtomicU64::new(0),
+            rtt: Default::default(),
+        }))
+    }
+
+    /// Records the accept queue length of a worker's listeners (`tcpi_unacked` on a listening socket).
+    pub fn sample_listeners<'a, T: AsRawFd + 'a>(&self, thread_id: usize, listeners: impl Iterator<Item = &'a T>) {
+        let queued = listeners.filter_map(|l| tcp_info(l).ok()).map(|info| u64::from(info.tcpi_unacked)).sum();
+        self.listen_queue[thread_id].store(queued, Ordering::Relaxed);
+    }
+
+    /// Records retransmits and smoothed RTT of a connection about to be closed.
+    pub fn sample_conn<T: AsRawFd>(&self, stream: &T) {
+        let Ok(info) = tcp_info(stream) else { return };
+        self.retrans.fetch_add(u64::from(info.tcpi_total_retrans), Ordering::Relaxed);
+        let bucket = RTT_BUCKETS_US.iter().position(|&b| info.tcpi_rtt <= b).unwrap_or(RTT_BUCKETS_US.len());
+        self.rtt[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn listen_queue(&self) -> u64 {
+        self.listen_queue.iter().map(|q| q.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Retransmits recorded since the previous call.
+    pub fn take_retrans(&self) -> u64 {
+        self.retrans.swap(0, Ordering::Relaxed)
+    }
+
+    /// Per-bucket connection counts recorded since the previous call.
+    pub fn take_rtt(&self) -> [u64; 5] {
+        std::array::from_fn(|i| self.rtt[i].swap(0, Ordering::Relaxed))
+    }
+}
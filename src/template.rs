@@ -0,0 +1,75 @@
+use crate::date;
+use crate::http::RequestHead;
+use std::io::Write;
+use std::net::SocketAddr;
+
+enum Part {
+    Text(Vec<u8>),
+    RemoteAddr,
+    RemotePort,
+    Method,
+    Path,
+    Now,
+    Header(Vec<u8>),
+}
+
+/// Response body template with `{{variable}}` placeholders.
+///
+/// Supported variables: `remote_addr`, `remote_port`, `method`, `path`, `now` (RFC 3339 UTC) and
+/// `header.<name>` (empty when the header is absent).
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(src: &[u8]) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut text = Vec::new();
+        let mut rest = src;
+        while let Some(open) = rest.windows(2).position(|w| w == b"{{") {
+            let Some(close) = rest[open + 2..].windows(2).position(|w| w == b"}}") else { break };
+            text.extend_from_slice(&rest[..open]);
+            let name = &rest[open + 2..open + 2 + close];
+            let name = std::str::from_utf8(name).map_err(|_| "template variable is not UTF-8".to_string())?.trim();
+            let part = match name {
+                "remote_addr" => Part::RemoteAddr,
+                "remote_port" => Part::RemotePort,
+                "method" => Part::Method,
+                "path" => Part::Path,
+                "now" => Part::Now,
+                _ => match name.strip_prefix("header.") {
+                    Some(h) if !h.is_empty() => Part::Header(h.as_bytes().to_vec()),
+                    _ => return Err(format!("unknown template variable '{{{{{name}}}}}'")),
+                },
+            };
+            if !text.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut text)));
+            }
+            parts.push(part);
+            rest = &rest[open + 2 + close + 2..];
+        }
+        text.extend_from_slice(rest);
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self { parts })
+    }
+
+    pub fn render(&self, req: &RequestHead, peer: SocketAddr, out: &mut Vec<u8>) {
+        for part in &self.parts {
+            match part {
+                Part::Text(t) => out.extend_from_slice(t),
+                Part::RemoteAddr => {
+                    let _ = write!(out, "{}", peer.ip());
+                }
+                Part::RemotePort => {
+                    let _ = write!(out, "{}", peer.port());
+                }
+                Part::Method => out.extend_from_slice(req.method),
+                Part::Path => out.extend_from_slice(req.path()),
+                Part::Now => date::write_rfc3339(out, date::unix_now()),
+                Part::Header(name) => out.extend_from_slice(req.header(name).unwrap_or(&[])),
+            }
+        }
+    }
+}
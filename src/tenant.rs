@@ -0,0 +1,164 @@
+//! Tenants (`--tenant`): named slices of the request space, claimed by path prefix or by
+//! `Host`, so one instance can stand in for several backend services in a load test. Each
+//! tenant serves its own static routes and has its own in-flight limit, request rate and
+//! metrics; requests no tenant claims are served as without tenants.
+
+use crate::error_page;
+use crate::http::RequestHead;
+use crate::limit::InflightLimit;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How a tenant's requests are recognised.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TenantMatch {
+    /// Paths under this prefix (without a trailing `/`), which is stripped before routing.
+    Prefix(String),
+    /// Requests whose `Host` is this name, lowercase and without a port.
+    Host(String),
+}
+
+/// One `--tenant` rule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tenant {
+    pub name: String,
+    pub matcher: TenantMatch,
+    /// Requests in flight for this tenant, on top of `--max-inflight`.
+    pub max_inflight: Option<usize>,
+    /// Requests each worker takes on per second for this tenant; the rest get a `429`.
+    pub rate: Option<u32>,
+}
+
+impl Tenant {
+    /// Parses `NAME=prefix:/PATH` or `NAME=host:HOST`, followed by any of
+    /// `,max-inflight=N` and `,rate=N`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let bad = || format!("expected 'NAME=prefix:/PATH' or 'NAME=host:HOST', got '{spec}'");
+        let mut parts = spec.split(',');
+        let (name, matcher) = parts.next().and_then(|p| p.split_once('=')).ok_or_else(bad)?;
+        if !valid_name(name) {
+            return Err(format!("tenant name '{name}' must be letters, digits, '-' or '_'"));
+        }
+        let matcher = match matcher.split_once(':').ok_or_else(bad)? {
+            ("prefix", path) => {
+                let prefix = path.trim_end_matches('/');
+                if !prefix.starts_with('/') || prefix.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control()) {
+                    return Err(format!("tenant prefix must be a path below '/' without spaces, got '{path}'"));
+                }
+                TenantMatch::Prefix(prefix.to_string())
+            }
+            ("host", host) if !host.is_empty() && !host.contains(['/', ' ']) => {
+                TenantMatch::Host(host.to_ascii_lowercase())
+            }
+            _ => return Err(bad()),
+        };
+        let mut tenant = Self { name: name.to_string(), matcher, max_inflight: None, rate: None };
+        for option in parts {
+            let positive = |v: &str| v.parse::<u32>().ok().filter(|&n| n > 0);
+            match option.split_once('=') {
+                Some(("max-inflight", v)) => tenant.max_inflight = Some(positive(v).ok_or_else(bad)? as usize),
+                Some(("rate", v)) => tenant.rate = Some(positive(v).ok_or_else(bad)?),
+                _ => return Err(format!("unknown tenant option '{option}', expected max-inflight=N or rate=N")),
+            }
+        }
+        Ok(tenant)
+    }
+
+    /// The path `head` asks this tenant for, if the tenant claims the request.
+    fn claims<'a>(&self, head: &RequestHead<'a>) -> Option<&'a [u8]> {
+        let path = head.path();
+        match &self.matcher {
+            TenantMatch::Prefix(prefix) => match path.strip_prefix(prefix.as_bytes())? {
+                [] => Some(b"/"),
+                rest if rest[0] == b'/' => Some(rest),
+                _ => None,
+            },
+            TenantMatch::Host(name) => head.header(b"host").filter(|h| error_page::host_matches(name, h)).map(|_| path),
+        }
+    }
+}
+
+impl fmt::Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.matcher {
+            TenantMatch::Prefix(path) => write!(f, "{}=prefix:{path}", self.name)?,
+            TenantMatch::Host(host) => write!(f, "{}=host:{host}", self.name)?,
+        }
+        if let Some(n) = self.max_inflight {
+            write!(f, ",max-inflight={n}")?;
+        }
+        if let Some(n) = self.rate {
+            write!(f, ",rate={n}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `name` can name a tenant, and so a metric path segment.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The first of `tenants` claiming `head`, and the path it asks that tenant for.
+pub fn find<'a>(tenants: &[Tenant], head: &RequestHead<'a>) -> Option<(usize, &'a [u8])> {
+    tenants.iter().enumerate().find_map(|(i, t)| Some((i, t.claims(head)?)))
+}
+
+/// What the stats pusher reports per tenant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TenantStat {
+    Requests,
+    /// Answered `503` for the tenant's in-flight limit.
+    Shed,
+    /// Answered `429` for the tenant's request rate.
+    Throttled,
+}
+
+impl TenantStat {
+    pub const ALL: [TenantStat; 3] = [TenantStat::Requests, TenantStat::Shed, TenantStat::Throttled];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TenantStat::Requests => "requests",
+            TenantStat::Shed => "shed",
+            TenantStat::Throttled => "throttled",
+        }
+    }
+}
+
+/// Per-tenant in-flight limits and counters, shared by all workers.
+pub struct Tenants {
+    limits: Vec<Option<&'static InflightLimit>>,
+    counts: Box<[[AtomicU64; TenantStat::ALL.len()]]>,
+}
+
+impl Tenants {
+    pub fn new(tenants: &[Tenant]) -> &'static Self {
+        let limits = tenants.iter().map(|t| t.max_inflight.map(InflightLimit::new)).collect();
+        let counts = tenants.iter().map(|_| Default::default()).collect();
+        Box::leak(Box::new(Self { limits, counts }))
+    }
+
+    #[inline]
+    pub fn count(&self, tenant: usize, stat: TenantStat) {
+        self.counts[tenant][stat as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `stat` for `tenant` since the previous call.
+    pub fn take(&self, tenant: usize, stat: TenantStat) -> u64 {
+        self.counts[tenant][stat as usize].swap(0, Ordering::Relaxed)
+    }
+
+    /// Takes a slot of `tenant`'s in-flight limit; always succeeds without one.
+    #[inline]
+    pub fn try_acquire(&self, tenant: usize) -> bool {
+        self.limits[tenant].is_none_or(|l| l.try_acquire())
+    }
+
+    #[inline]
+    pub fn release(&self, tenant: usize) {
+        if let Some(l) = self.limits[tenant] {
+            l.release();
+        }
+    }
+}
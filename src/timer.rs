@@ -1,32 +1,53 @@
 use mio::Token;
 use std::time::{Duration, Instant};
 
-const WHEEL_SIZE: usize = 64;
+const WHEEL_SIZE: usize = 128;
 const WHEEL_MASK: usize = WHEEL_SIZE - 1;
 const SLOT_DURATION: Duration = Duration::from_secs(1);
+/// Entries each slot keeps room for when shrunk.
+const SLOT_FLOOR: usize = 64;
+/// Longest timeout the wheel can represent.
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(WHEEL_MASK as u64 - 1);
 
-pub struct TimerWheel {
+pub(crate) struct TimerWheel {
     slots: Vec<Vec<(Token, u64)>>,
     cursor: usize,
     last_tick: Instant,
-    timeout_slots: usize,
 }
 
 impl TimerWheel {
-    pub fn new(timeout: Duration) -> Self {
-        let timeout_slots = (timeout.as_secs() as usize + 1).min(WHEEL_MASK);
+    pub fn new(now: Instant) -> Self {
         Self {
             slots: vec![Vec::new(); WHEEL_SIZE],
             cursor: 0,
-            last_tick: Instant::now(),
-            timeout_slots,
+            last_tick: now,
         }
     }
 
     #[inline]
-    pub fn add(&mut self, token: Token, generation: u64) {
-        let slot = (self.cursor + self.timeout_slots) & WHEEL_MASK;
-        self.slots[slot].push((token, generation));
+    pub fn add(&mut self, token: Token, epoch: u64, timeout: Duration) {
+        let timeout_slots = (timeout.as_secs() as usize + 1).min(WHEEL_MASK);
+        let slot = (self.cursor + timeout_slots) & WHEEL_MASK;
+        self.slots[slot].push((token, epoch));
+    }
+
+    /// Drops entries `live` rejects (connections closed before their timeout came up) and
+    /// releases slot capacity left over from earlier bursts.
+    pub fn shrink(&mut self, live: impl Fn(Token, u64) -> bool) {
+        for slot in &mut self.slots {
+            slot.retain(|&(token, epoch)| live(token, epoch));
+            slot.shrink_to(SLOT_FLOOR);
+        }
+    }
+
+    /// Pending entries, stale ones included until their slot comes up or `shrink` purges them.
+    pub fn len(&self) -> usize {
+        self.slots.iter().map(Vec::len).sum()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        let entries: usize = self.slots.iter().map(Vec::capacity).sum();
+        (entries * size_of::<(Token, u64)>()) as u64
     }
 
     pub fn advance(&mut self, now: Instant, out: &mut Vec<(Token, u64)>) {
@@ -37,7 +58,7 @@ impl TimerWheel {
         }
         for _ in 0..ticks {
             self.cursor = (self.cursor + 1) & WHEEL_MASK;
-            out.extend(self.slots[self.cursor].drain(..));
+            out.append(&mut self.slots[self.cursor]);
         }
         self.last_tick += SLOT_DURATION * ticks as u32;
     }
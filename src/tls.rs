@@ -0,0 +1,140 @@
+//! TLS termination (`tls` feature, `--tls-cert` and `--tls-key`). Listeners keep serving
+//! plaintext HTTP: a connection whose first bytes are a TLS handshake record — after its
+//! PROXY preamble, if one is expected — is handed to rustls, and the request state machine
+//! goes on reading and writing through it as on any other.
+//!
+//! Until the handshake is over the connection is `Handshaking`: it has
+//! `--tls-handshake-timeout` to finish, counted from the ClientHello rather than reset by
+//! each byte, and a worker runs at most `--tls-max-handshakes` at once. Failures are
+//! counted per `HandshakeFailure`.
+
+use mio::net::TcpStream;
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{Error, ServerConfig, ServerConnection};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::counter::HandshakeFailure;
+use crate::http;
+use crate::transport::Stream;
+
+/// Reads the certificate chain and private key, both PEM, into a rustls config offering
+/// HTTP/1.1 and h2 over ALPN.
+pub fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("cannot read certificates from {}: {e}", cert.display()))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", cert.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("cannot read private key from {}: {e}", key.display()))?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|b| b.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| format!("cannot use {}: {e}", cert.display()))?;
+    // HTTP/1.1 first: a client offering both gets it. One offering only h2 is told to
+    // fall back by the handler, rather than failing the handshake.
+    config.alpn_protocols = vec![b"http/1.1".to_vec(), http::ALPN_H2.to_vec()];
+    Ok(Arc::new(config))
+}
+
+impl Stream {
+    /// Hands the connection to rustls; `hello` is what the client has sent so far.
+    pub fn start_tls(&mut self, config: &Arc<ServerConfig>, mut hello: &[u8]) -> io::Result<()> {
+        let mut tls = Box::new(ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?);
+        while !hello.is_empty() {
+            tls.read_tls(&mut hello)?;
+            process(&mut tls, &mut self.tcp)?;
+        }
+        self.tls = Some(tls);
+        Ok(())
+    }
+
+    /// Takes the handshake as far as the client's records allow: true once it is complete.
+    pub fn handshake(&mut self) -> io::Result<bool> {
+        let Some(tls) = &mut self.tls else { return Ok(true) };
+        while tls.is_handshaking() {
+            match tls.read_tls(&mut self.tcp) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => process(tls, &mut self.tcp)?,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    /// A TLS handshake has been started on the connection and is not over yet.
+    pub fn handshaking(&self) -> bool {
+        self.tls.as_ref().is_some_and(|tls| tls.is_handshaking())
+    }
+
+    /// Sends a `close_notify` if the connection is encrypted, as far as the socket takes it.
+    pub fn close_notify(&mut self) {
+        if let Some(tls) = &mut self.tls {
+            tls.send_close_notify();
+            let _ = send(tls, &mut self.tcp);
+        }
+    }
+}
+
+/// What a handshake that failed with `e` is counted as.
+pub fn failure(e: &io::Error) -> HandshakeFailure {
+    match e.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+        Some(Error::AlertReceived(_)) => HandshakeFailure::Alert,
+        Some(Error::PeerIncompatible(_)) => HandshakeFailure::Incompatible,
+        Some(_) => HandshakeFailure::Malformed,
+        None => HandshakeFailure::Closed,
+    }
+}
+
+/// Reads decrypted bytes, taking in records from `tcp` until there are some.
+pub(crate) fn read(tls: &mut ServerConnection, tcp: &mut TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match tls.reader().read(buf) {
+            Ok(n) => return Ok(n),
+            // The client went away without a `close_notify`; what it sent is complete
+            // records, so this is no worse than a plain EOF.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        if tls.read_tls(tcp)? == 0 {
+            return Ok(0);
+        }
+        process(tls, tcp)?;
+    }
+}
+
+/// Encrypts what of `buf` rustls takes. Records left from an earlier write go out first;
+/// until they have, nothing new is taken.
+pub(crate) fn write(tls: &mut ServerConnection, tcp: &mut TcpStream, buf: &[u8]) -> io::Result<usize> {
+    send(tls, tcp)?;
+    let n = tls.writer().write(buf)?;
+    match send(tls, tcp) {
+        Err(e) if e.kind() != io::ErrorKind::WouldBlock => Err(e),
+        _ => Ok(n),
+    }
+}
+
+/// Writes out the records rustls has ready; `WouldBlock` if the socket cannot take them all.
+pub(crate) fn send(tls: &mut ServerConnection, tcp: &mut TcpStream) -> io::Result<()> {
+    while tls.wants_write() {
+        tls.write_tls(tcp)?;
+    }
+    Ok(())
+}
+
+/// Processes the records read, sending what they call for: the handshake's replies, or
+/// the alert a bad record earns before the connection is given up.
+fn process(tls: &mut ServerConnection, tcp: &mut TcpStream) -> io::Result<()> {
+    let processed = tls.process_new_packets();
+    match send(tls, tcp) {
+        Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+        _ => {}
+    }
+    processed.map(|_| ()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,86 @@
+//! Diagnostics and maintenance mode switched on and off at runtime, by `SIGUSR1`/`SIGUSR2`
+//! or through the admin API.
+
+use crate::json::Json;
+use crate::signal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(rename_all = "kebab-case"))]
+pub enum Toggle {
+    /// `[debug]` lines from the event loop: events per wakeup, connection state transitions,
+    /// accepts and closes. Flipped by `SIGUSR2`.
+    DebugLog,
+    /// Per-worker event loop gauges (`wakeups`, `events`) in the stats push.
+    ExtraMetrics,
+    /// Sampling new connections into the `--capture` file; on from startup when there is
+    /// one. Connections already sampled stay captured until they close.
+    Capture,
+    /// Answering every request but health checks and admin requests with the maintenance
+    /// `503`; the same switch `SIGUSR1` and `--maintenance` set.
+    Maintenance,
+}
+
+/// Every toggle's switch but `Maintenance`, which is `signal::MAINTENANCE`.
+static STATES: [AtomicBool; Toggle::ALL.len() - 1] = [AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false)];
+
+impl Toggle {
+    pub const ALL: [Toggle; 4] = [Toggle::DebugLog, Toggle::ExtraMetrics, Toggle::Capture, Toggle::Maintenance];
+
+    pub fn parse(name: &[u8]) -> Option<Self> {
+        Toggle::ALL.into_iter().find(|t| t.name().as_bytes() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Toggle::DebugLog => "debug-log",
+            Toggle::ExtraMetrics => "extra-metrics",
+            Toggle::Capture => "capture",
+            Toggle::Maintenance => "maintenance",
+        }
+    }
+
+    fn state(self) -> &'static AtomicBool {
+        match self {
+            Toggle::Maintenance => &signal::MAINTENANCE,
+            t => &STATES[t as usize],
+        }
+    }
+
+    #[inline]
+    pub fn enabled(self) -> bool {
+        self.state().load(Ordering::Relaxed)
+    }
+
+    pub fn set(self, on: bool) {
+        self.state().store(on, Ordering::Relaxed);
+    }
+
+    /// Async-signal-safe, for the `SIGUSR2` handler.
+    pub fn flip(self) {
+        self.state().fetch_xor(true, Ordering::Relaxed);
+    }
+}
+
+/// Every toggle with whether it is on.
+pub fn states() -> [(Toggle, bool); Toggle::ALL.len()] {
+    Toggle::ALL.map(|t| (t, t.enabled()))
+}
+
+/// `name: on|off` per line, for the admin endpoint.
+pub fn write_states(out: &mut Vec<u8>) {
+    for t in Toggle::ALL {
+        out.extend_from_slice(t.name().as_bytes());
+        out.extend_from_slice(if t.enabled() { b": on\n" } else { b": off\n" });
+    }
+}
+
+/// `{"name": true|false, ...}`, for the admin endpoint.
+pub fn write_states_json(out: &mut Vec<u8>) {
+    let mut json = Json::new(out);
+    json.begin_object();
+    for t in Toggle::ALL {
+        json.key(t.name()).bool(t.enabled());
+    }
+    json.end_object();
+}
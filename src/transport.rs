@@ -0,0 +1,344 @@
+//! The byte stream under a connection. Workers only ever drive a `Stream`, a `mio` TCP
+//! stream that rustls may have taken over, but `Conn`, the handler and the loop that drives
+//! a connection (`drive`) are generic over `Transport`, so the request state machine can also be
+//! run against a scripted in-memory stream (`ScriptedStream`, with the `mock-transport`
+//! feature) that hands out partial reads, `WouldBlock`s and short writes in a chosen order.
+
+use mio::event::Source;
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, RawFd};
+
+pub trait Transport: Read + Write {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    /// Whether bytes are encrypted on their way through, so the socket must not be
+    /// written to directly (no `sendfile`).
+    fn encrypted(&self) -> bool {
+        false
+    }
+
+    /// The application protocol the client picked with ALPN, if any.
+    fn alpn(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    #[inline]
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+/// A client connection's socket: read and written as is, or through rustls once the
+/// client has sent a ClientHello (`tls` feature, see `tls.rs`). A write through rustls may
+/// leave records behind; `flush` sends them, or fails with `WouldBlock`.
+pub struct Stream {
+    pub(crate) tcp: TcpStream,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<Box<rustls::ServerConnection>>,
+}
+
+impl Stream {
+    pub fn new(tcp: TcpStream) -> Self {
+        Self {
+            tcp,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+impl Read for Stream {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            return crate::tls::read(tls, &mut self.tcp, buf);
+        }
+        self.tcp.read(buf)
+    }
+}
+
+impl Write for Stream {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            return crate::tls::write(tls, &mut self.tcp, buf);
+        }
+        self.tcp.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            return crate::tls::send(tls, &mut self.tcp);
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Stream {
+    #[inline]
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.tcp.shutdown(how)
+    }
+
+    #[inline]
+    fn encrypted(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        false
+    }
+
+    #[inline]
+    fn alpn(&self) -> Option<&[u8]> {
+        #[cfg(feature = "tls")]
+        return self.tls.as_ref().and_then(|tls| tls.alpn_protocol());
+        #[cfg(not(feature = "tls"))]
+        None
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.tcp.as_raw_fd()
+    }
+}
+
+impl Source for Stream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.tcp.register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.tcp.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.tcp.deregister(registry)
+    }
+}
+
+#[cfg(feature = "mock-transport")]
+pub use scripted::{Drive, Machine, ScriptedStream};
+
+#[cfg(feature = "mock-transport")]
+mod scripted {
+    use super::Transport;
+    use crate::bodies::BodyStore;
+    use crate::bus::Bus;
+    use crate::config::{Config, BUF_SIZE, MAINTENANCE_BODY, RESPONSE_BODY};
+    use crate::conn::Conn;
+    use crate::counter::RpsCounter;
+    use crate::drive::{self, Host};
+    use crate::handler::Handler;
+    use crate::response::Responses;
+    use mio::Token;
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+    use std::net::{Shutdown, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    enum ReadStep {
+        Data(Vec<u8>),
+        WouldBlock,
+        Eof,
+        Error(io::ErrorKind),
+    }
+
+    /// An in-memory stream that plays back a script. Each read returns the next scripted
+    /// step (data no larger than the caller's buffer is returned in one piece, the rest
+    /// on the next read); once the script is used up, reads would block. Each write takes
+    /// up to the next scripted allowance, or would block where the script says so; with no
+    /// allowances left, writes take everything.
+    #[derive(Default)]
+    pub struct ScriptedStream {
+        reads: VecDeque<ReadStep>,
+        writes: VecDeque<Option<usize>>,
+        written: Vec<u8>,
+        shutdown: Cell<Option<Shutdown>>,
+    }
+
+    impl ScriptedStream {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// A read returning `data`.
+        pub fn read(mut self, data: &[u8]) -> Self {
+            self.reads.push_back(ReadStep::Data(data.to_vec()));
+            self
+        }
+
+        /// A read failing with `WouldBlock`, ending the worker's read loop for this event.
+        pub fn read_blocks(mut self) -> Self {
+            self.reads.push_back(ReadStep::WouldBlock);
+            self
+        }
+
+        /// The client closing its side.
+        pub fn eof(mut self) -> Self {
+            self.reads.push_back(ReadStep::Eof);
+            self
+        }
+
+        pub fn read_error(mut self, kind: io::ErrorKind) -> Self {
+            self.reads.push_back(ReadStep::Error(kind));
+            self
+        }
+
+        /// A write taking at most `n` bytes.
+        pub fn write_up_to(mut self, n: usize) -> Self {
+            self.writes.push_back(Some(n));
+            self
+        }
+
+        /// A write failing with `WouldBlock`.
+        pub fn write_blocks(mut self) -> Self {
+            self.writes.push_back(None);
+            self
+        }
+
+        /// Everything written so far.
+        pub fn written(&self) -> &[u8] {
+            &self.written
+        }
+
+        /// How the stream was shut down, if it was.
+        pub fn shut_down(&self) -> Option<Shutdown> {
+            self.shutdown.get()
+        }
+    }
+
+    impl Read for ScriptedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.reads.pop_front() {
+                Some(ReadStep::Data(mut data)) => {
+                    let n = data.len().min(buf.len());
+                    buf[..n].copy_from_slice(&data[..n]);
+                    if n < data.len() {
+                        self.reads.push_front(ReadStep::Data(data.split_off(n)));
+                    }
+                    Ok(n)
+                }
+                Some(ReadStep::Eof) => Ok(0),
+                Some(ReadStep::Error(kind)) => Err(kind.into()),
+                Some(ReadStep::WouldBlock) | None => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    impl Write for ScriptedStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = match self.writes.pop_front() {
+                Some(Some(limit)) => buf.len().min(limit),
+                Some(None) => return Err(io::ErrorKind::WouldBlock.into()),
+                None => buf.len(),
+            };
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for ScriptedStream {
+        fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            self.shutdown.set(Some(how));
+            Ok(())
+        }
+    }
+
+    /// How a `Machine::drive` round ended.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Drive {
+        /// Waiting for the stream to become readable or writable again.
+        Blocked,
+        /// The round budget was used up; the worker would drive it again next round.
+        Yielded,
+        /// The connection would be closed.
+        Closed,
+    }
+
+    /// What a round asked of the worker around it.
+    #[derive(Default)]
+    struct Round {
+        closed: bool,
+        yielded: bool,
+        timer: Option<Duration>,
+    }
+
+    impl Host<ScriptedStream> for Round {
+        fn close_later(&mut self, conn: &mut Conn<ScriptedStream>, _token: Token) {
+            conn.mark_closing();
+            self.closed = true;
+        }
+
+        fn yield_later(&mut self, conn: &mut Conn<ScriptedStream>, _token: Token) {
+            conn.yielded = true;
+            self.yielded = true;
+        }
+
+        fn set_writable(&mut self, _conn: &mut Conn<ScriptedStream>, _token: Token, _writable: bool) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn arm_timer(&mut self, _token: Token, _epoch: u64, timeout: Duration) {
+            self.timer = Some(timeout);
+        }
+    }
+
+    /// One connection on a scripted stream, driven by the worker's own `drive` on each
+    /// readiness event. There is no poll, so tunnels, TLS and route backends fail the way
+    /// they would on a socket that cannot take them.
+    pub struct Machine {
+        handler: Handler,
+        pub conn: Conn<ScriptedStream>,
+        /// The timeout the last round armed.
+        pub timer: Option<Duration>,
+    }
+
+    impl Machine {
+        pub fn new(cfg: Config, stream: ScriptedStream) -> Self {
+            let cfg: &'static Config = Box::leak(Box::new(cfg));
+            let responses = Responses::new(cfg, RESPONSE_BODY, MAINTENANCE_BODY, "text/plain", &[], None, &[]);
+            let bodies = BodyStore::new(Vec::new(), Bus::new(1));
+            let handler = Handler::new(0, cfg, responses, None, RpsCounter::new(1), bodies, None);
+            let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+            let conn = Conn::new(stream, peer, Box::new([0u8; BUF_SIZE]).into(), Instant::now());
+            Self { handler, conn, timer: None }
+        }
+
+        pub fn stream(&self) -> &ScriptedStream {
+            &self.conn.stream
+        }
+
+        /// Requests answered on this machine, as the worker counts them for `vrypt.rps`.
+        pub fn requests(&self) -> u64 {
+            self.handler.counter.total()
+        }
+
+        /// Handles one readiness event.
+        pub fn drive(&mut self) -> Drive {
+            let mut round = Round::default();
+            self.conn.yielded = false;
+            drive::drive(&mut self.conn, Token(0), &mut self.handler, &mut round, Instant::now());
+            self.timer = round.timer;
+            match round {
+                Round { closed: true, .. } => Drive::Closed,
+                Round { yielded: true, .. } => Drive::Yielded,
+                _ => Drive::Blocked,
+            }
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Forward-proxy mode (`--forward-proxy`): `CONNECT` tunnels and absolute-form requests
+//! relayed to allowlisted destinations, both copied byte for byte through the event loop.
+
+use crate::config::BUF_SIZE;
+use crate::outbound::{Connect, Outbound};
+use crate::transport::Transport;
+use crate::upstream::Relay;
+use mio::Token;
+use std::io;
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::time::Instant;
+
+/// Sent to a `CONNECT` client once the upstream connection is up.
+pub const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+
+/// Hop-by-hop headers dropped from relayed requests; `Connection: close` is sent instead.
+const HOP_BY_HOP: [&[u8]; 4] = [b"connection", b"proxy-connection", b"keep-alive", b"proxy-authorization"];
+
+/// A destination the forward proxy may connect to, resolved at startup so the event loop
+/// never waits on DNS.
+#[derive(Clone, Debug)]
+pub struct ProxyTarget {
+    pub host: String,
+    pub port: u16,
+    pub addr: SocketAddr,
+}
+
+impl ProxyTarget {
+    /// Parses and resolves `host:port`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (host, port) = split_authority(spec.as_bytes()).ok_or_else(|| format!("expected 'host:port', got '{spec}'"))?;
+        let (Ok(host), Some(port)) = (std::str::from_utf8(host), port.filter(|&p| p > 0)) else {
+            return Err(format!("expected 'host:port', got '{spec}'"));
+        };
+        let addr = (host, port)
+            .to_socket_addrs()
+            .map_err(|e| format!("cannot resolve '{spec}': {e}"))?
+            .next()
+            .ok_or_else(|| format!("'{spec}' resolves to no address"))?;
+        Ok(Self { host: host.to_ascii_lowercase(), port, addr })
+    }
+}
+
+/// The address of the allowlisted destination `authority` names; a missing port means `default_port`.
+pub fn lookup(allow: &[ProxyTarget], authority: &[u8], default_port: Option<u16>) -> Option<SocketAddr> {
+    let (host, port) = split_authority(authority)?;
+    let port = port.or(default_port)?;
+    allow.iter().find(|t| t.port == port && t.host.as_bytes().eq_ignore_ascii_case(host)).map(|t| t.addr)
+}
+
+/// `host[:port]`, with IPv6 hosts in brackets; the brackets are not part of the host.
+/// `None` if the port is present but not a number.
+fn split_authority(authority: &[u8]) -> Option<(&[u8], Option<u16>)> {
+    let (host, port) = match authority.strip_prefix(b"[") {
+        Some(v6) => {
+            let end = v6.iter().position(|&b| b == b']')?;
+            (&v6[..end], v6[end + 1..].strip_prefix(b":"))
+        }
+        None => match authority.iter().rposition(|&b| b == b':') {
+            Some(colon) => (&authority[..colon], Some(&authority[colon + 1..])),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(p) => Some(std::str::from_utf8(p).ok()?.parse().ok()?),
+        None => None,
+    };
+    (!host.is_empty()).then_some((host, port))
+}
+
+/// Writes the head of a request relayed in origin-form: `target` is the path and query of
+/// the absolute-form target, and the upstream is asked to close after its response.
+pub fn write_relayed_head<'a>(
+    out: &mut Vec<u8>,
+    method: &[u8],
+    target: &[u8],
+    authority: &[u8],
+    headers: impl Iterator<Item = (&'a [u8], &'a [u8])>,
+) {
+    out.extend_from_slice(method);
+    out.push(b' ');
+    if target.first() != Some(&b'/') {
+        out.push(b'/');
+    }
+    out.extend_from_slice(target);
+    out.extend_from_slice(b" HTTP/1.1\r\n");
+    let mut host = false;
+    for (name, value) in headers {
+        if HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h)) {
+            continue;
+        }
+        host |= name.eq_ignore_ascii_case(b"host");
+        for part in [name, b": ", value, b"\r\n"] {
+            out.extend_from_slice(part);
+        }
+    }
+    if !host {
+        for part in [b"Host: ", authority, b"\r\n"] {
+            out.extend_from_slice(part);
+        }
+    }
+    out.extend_from_slice(b"Connection: close\r\n\r\n");
+}
+
+/// Bytes read from one side of a tunnel and not yet written to the other.
+struct Pipe {
+    buf: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    /// The source side has reached EOF (or is gone).
+    eof: bool,
+    /// EOF has been passed on by shutting down the destination's write side.
+    shut: bool,
+}
+
+impl Pipe {
+    fn new(initial: &[u8]) -> Self {
+        let mut buf = vec![0u8; BUF_SIZE.max(initial.len())].into_boxed_slice();
+        buf[..initial.len()].copy_from_slice(initial);
+        Self { buf, pos: 0, len: initial.len(), eof: false, shut: false }
+    }
+
+    /// Copies from `src` to `dst` until neither can make progress. Reads stop while the
+    /// buffer is full and resume at the next call, which the destination becoming
+    /// writable again triggers.
+    fn pump(&mut self, src: &mut impl Transport, dst: &mut impl Transport) -> io::Result<()> {
+        loop {
+            let mut progress = false;
+            if self.pos < self.len {
+                match dst.write(&self.buf[self.pos..self.len]) {
+                    Ok(n) => {
+                        self.pos += n;
+                        progress = n > 0;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+                if self.pos == self.len {
+                    (self.pos, self.len) = (0, 0);
+                }
+            }
+            // A TLS client may still hold records of what was written.
+            let flushed = match dst.flush() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => false,
+                Err(e) => return Err(e),
+                Ok(()) => true,
+            };
+            if !self.eof && self.len < self.buf.len() {
+                match src.read(&mut self.buf[self.len..]) {
+                    Ok(0) => self.eof = true,
+                    Ok(n) => {
+                        self.len += n;
+                        progress = true;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            if self.eof && self.len == 0 && flushed && !self.shut {
+                let _ = dst.shutdown(Shutdown::Write);
+                self.shut = true;
+            }
+            if !progress {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The upstream half of a tunnelled client connection, registered under its own token.
+pub struct Tunnel {
+    pub upstream: Outbound,
+    pub token: Token,
+    /// The connect has succeeded or failed; either way the pipes are set up for it.
+    connected: bool,
+    /// Sent to the client once the upstream connection is up.
+    established: &'static [u8],
+    /// Sent to the client instead if connecting fails.
+    bad_gateway: &'static [u8],
+    /// Client to upstream.
+    up: Pipe,
+    /// Upstream to client.
+    down: Pipe,
+    /// Counts the tunnel as a busy connection to its upstream.
+    pub relay: Option<Relay>,
+}
+
+impl Tunnel {
+    /// Starts connecting to `addr`; `initial` is sent upstream ahead of anything the client sends.
+    pub fn open(
+        addr: SocketAddr,
+        token: Token,
+        initial: &[u8],
+        established: &'static [u8],
+        bad_gateway: &'static [u8],
+        connect_deadline: Instant,
+    ) -> io::Result<Self> {
+        let upstream = Outbound::connect_until(addr, connect_deadline)?;
+        let (up, down) = (Pipe::new(initial), Pipe::new(&[]));
+        Ok(Self { upstream, token, connected: false, established, bad_gateway, up, down, relay: None })
+    }
+
+    /// Moves bytes both ways. Returns true once both directions have been closed and
+    /// everything read has been delivered.
+    pub fn pump(&mut self, client: &mut impl Transport, now: Instant) -> io::Result<bool> {
+        if !self.connected && !self.finish_connect(now) {
+            return Ok(false);
+        }
+        if !self.up.shut {
+            self.up.pump(client, &mut self.upstream.stream)?;
+        }
+        if !self.down.shut {
+            self.down.pump(&mut self.upstream.stream, client)?;
+        }
+        Ok(self.up.shut && self.down.shut)
+    }
+
+    /// Whether the connect is over. A failed (or timed out) connect turns the tunnel into
+    /// one that delivers `bad_gateway` to the client and closes.
+    fn finish_connect(&mut self, now: Instant) -> bool {
+        let failed = match self.upstream.poll(now) {
+            Connect::Pending => return false,
+            Connect::Ready => None,
+            Connect::Failed(e) => Some(e),
+        };
+        self.connected = true;
+        match failed {
+            None => self.down = Pipe::new(self.established),
+            Some(e) => {
+                eprintln!("[warn] forward proxy: cannot connect upstream: {e}");
+                self.down = Pipe { eof: true, ..Pipe::new(self.bad_gateway) };
+                self.up = Pipe { eof: true, shut: true, ..Pipe::new(&[]) };
+            }
+        }
+        true
+    }
+
+    /// Still waiting for the upstream connect to succeed or fail.
+    pub fn connecting(&self) -> bool {
+        !self.connected
+    }
+
+    pub fn bytes(&self) -> u64 {
+        (self.up.buf.len() + self.down.buf.len()) as u64
+    }
+}
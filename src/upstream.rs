@@ -0,0 +1,281 @@
+//! Connections to upstreams: route backends (`fastcgi:` and `scgi:` static routes) and the
+//! TCP upstreams requests are relayed to (split groups and forward-proxy destinations).
+//!
+//! Each worker keeps up to `--upstream-pool` idle connections per backend socket; a request
+//! takes the most recently used one that is still open before connecting anew. Idle
+//! connections are closed after `--upstream-idle-timeout`, and any connection past
+//! `--upstream-max-lifetime` is closed rather than kept.
+//!
+//! Only FastCGI connections are ever kept: the request asks the backend to keep the
+//! connection (`FCGI_KEEP_CONN`) and it is pooled once the response has ended cleanly. SCGI
+//! ends every response by closing, so its backends are only counted. So are TCP upstreams:
+//! a relayed request asks for `Connection: close` and the rest of the client connection is
+//! tunnelled byte for byte, with no response framing to tell when a connection could be
+//! handed back.
+
+use crate::config::Config;
+use crate::routes::Payload;
+use mio::net::UnixStream;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// What the stats pusher reports per backend; `Busy` and `Idle` are gauges, the rest
+/// counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamStat {
+    /// New connections made; for a TCP upstream, relays started.
+    Opened,
+    /// Requests sent on a pooled connection.
+    Reused,
+    /// Pooled connections found closed by the backend when taken.
+    Stale,
+    /// Connections closed for `--upstream-idle-timeout` or `--upstream-max-lifetime`.
+    Expired,
+    /// Connections handed back while the pool was full, and closed.
+    Overflow,
+    /// Connections carrying a request, or a relay to a TCP upstream.
+    Busy,
+    /// Connections waiting in a pool.
+    Idle,
+}
+
+impl UpstreamStat {
+    pub const ALL: [UpstreamStat; 7] = [
+        UpstreamStat::Opened,
+        UpstreamStat::Reused,
+        UpstreamStat::Stale,
+        UpstreamStat::Expired,
+        UpstreamStat::Overflow,
+        UpstreamStat::Busy,
+        UpstreamStat::Idle,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            UpstreamStat::Opened => "opened",
+            UpstreamStat::Reused => "reused",
+            UpstreamStat::Stale => "stale",
+            UpstreamStat::Expired => "expired",
+            UpstreamStat::Overflow => "overflow",
+            UpstreamStat::Busy => "busy",
+            UpstreamStat::Idle => "idle",
+        }
+    }
+
+    pub fn is_gauge(self) -> bool {
+        matches!(self, UpstreamStat::Busy | UpstreamStat::Idle)
+    }
+}
+
+/// Per-upstream counters and gauges, shared by all workers.
+pub struct UpstreamStats {
+    sockets: Vec<String>,
+    names: Vec<String>,
+    /// The address of each TCP upstream; `None` for a backend socket.
+    addrs: Vec<Option<SocketAddr>>,
+    counts: Box<[[AtomicU64; UpstreamStat::ALL.len()]]>,
+}
+
+impl UpstreamStats {
+    /// Stats for the backends listening on `sockets`, then for the TCP upstreams `relays`.
+    pub fn new(mut sockets: Vec<String>, relays: Vec<(String, SocketAddr)>) -> &'static Self {
+        let mut addrs = vec![None; sockets.len()];
+        for (name, addr) in relays {
+            sockets.push(name);
+            addrs.push(Some(addr));
+        }
+        let names = sockets.iter().map(|s| metric_name(s)).collect();
+        let counts = sockets.iter().map(|_| Default::default()).collect();
+        Box::leak(Box::new(Self { sockets, names, addrs, counts }))
+    }
+
+    /// Backend sockets, then TCP upstreams as `host:port`, in the order of their index.
+    pub fn sockets(&self) -> &[String] {
+        &self.sockets
+    }
+
+    /// Backends as they appear in metric names.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    fn index(&self, socket: &str) -> Option<usize> {
+        self.sockets.iter().position(|s| s == socket)
+    }
+
+    #[inline]
+    fn add(&self, upstream: usize, stat: UpstreamStat) {
+        self.counts[upstream][stat as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn sub(&self, upstream: usize, stat: UpstreamStat) {
+        self.counts[upstream][stat as usize].fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Counts a relay to the TCP upstream at `addr` as opened and busy until the returned
+    /// guard is dropped; `None` if `addr` is not one of the configured upstreams.
+    pub fn relay(&'static self, addr: SocketAddr) -> Option<Relay> {
+        let upstream = self.addrs.iter().position(|a| *a == Some(addr))?;
+        self.add(upstream, UpstreamStat::Opened);
+        self.add(upstream, UpstreamStat::Busy);
+        Some(Relay { upstream, stats: self })
+    }
+
+    /// `stat` for `upstream`: since the previous call for a count, as of now for a gauge.
+    pub fn take(&self, upstream: usize, stat: UpstreamStat) -> u64 {
+        let n = &self.counts[upstream][stat as usize];
+        if stat.is_gauge() { n.load(Ordering::Relaxed) } else { n.swap(0, Ordering::Relaxed) }
+    }
+}
+
+/// The backend sockets of `cfg`'s FastCGI and SCGI routes, each once, in route order.
+pub fn sockets(cfg: &Config) -> Vec<String> {
+    let mut sockets: Vec<String> = Vec::new();
+    let backends = cfg.static_routes.iter().filter(|r| matches!(r.payload, Payload::FastCgi | Payload::Scgi));
+    for socket in backends.filter_map(|r| r.file.to_string_lossy().split_whitespace().next().map(str::to_string)) {
+        if !sockets.contains(&socket) {
+            sockets.push(socket);
+        }
+    }
+    sockets
+}
+
+/// The TCP upstreams of `cfg`'s split groups and forward-proxy destinations, each address
+/// once, as `host:port` and the address it resolved to.
+pub fn relays(cfg: &Config) -> Vec<(String, SocketAddr)> {
+    let mut relays: Vec<(String, SocketAddr)> = Vec::new();
+    let targets = cfg.split.groups.iter().map(|g| &g.target).chain(&cfg.proxy_allow);
+    for target in targets {
+        if !relays.iter().any(|(_, addr)| *addr == target.addr) {
+            relays.push((format!("{}:{}", target.host, target.port), target.addr));
+        }
+    }
+    relays
+}
+
+/// A relay to a TCP upstream, counted as busy for as long as it is kept.
+pub struct Relay {
+    upstream: usize,
+    stats: &'static UpstreamStats,
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.stats.sub(self.upstream, UpstreamStat::Busy);
+    }
+}
+
+/// A connection to a backend, busy with a request or idle in a pool.
+pub struct Upstream {
+    pub stream: UnixStream,
+    upstream: usize,
+    opened: Instant,
+    idle: bool,
+    stats: &'static UpstreamStats,
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        let stat = if self.idle { UpstreamStat::Idle } else { UpstreamStat::Busy };
+        self.stats.sub(self.upstream, stat);
+    }
+}
+
+/// One worker's idle connections, per backend.
+pub struct UpstreamPool {
+    /// Idle connections with when each was handed back, most recent last.
+    idle: Vec<Vec<(Upstream, Instant)>>,
+    stats: Option<&'static UpstreamStats>,
+    cfg: &'static Config,
+}
+
+impl UpstreamPool {
+    pub fn new(cfg: &'static Config, stats: Option<&'static UpstreamStats>) -> Self {
+        let backends = stats.map_or(0, |s| s.sockets().len());
+        Self { idle: (0..backends).map(|_| Vec::new()).collect(), stats, cfg }
+    }
+
+    /// Whether requests may ask backends to keep their connection for the pool.
+    pub fn keeps(&self) -> bool {
+        self.cfg.upstream_pool > 0
+    }
+
+    /// A connection to the backend listening on `socket`: a pooled one still open, else a new one.
+    pub fn connect(&mut self, socket: &str, now: Instant) -> io::Result<Upstream> {
+        let (Some(stats), Some(upstream)) = (self.stats, self.stats.and_then(|s| s.index(socket))) else {
+            return Err(io::Error::other(format!("no backend stats for {socket}")));
+        };
+        while let Some((mut conn, since)) = self.idle[upstream].pop() {
+            if self.expired(&conn, since, now) {
+                stats.add(upstream, UpstreamStat::Expired);
+                continue;
+            }
+            // A backend that closed the connection meanwhile has left it readable.
+            match conn.stream.read(&mut [0]) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    stats.sub(upstream, UpstreamStat::Idle);
+                    stats.add(upstream, UpstreamStat::Busy);
+                    stats.add(upstream, UpstreamStat::Reused);
+                    conn.idle = false;
+                    return Ok(conn);
+                }
+                _ => stats.add(upstream, UpstreamStat::Stale),
+            }
+        }
+        let stream = UnixStream::connect(socket)?;
+        stats.add(upstream, UpstreamStat::Opened);
+        stats.add(upstream, UpstreamStat::Busy);
+        Ok(Upstream { stream, upstream, opened: now, idle: false, stats })
+    }
+
+    /// Keeps `conn`, whose last response has ended cleanly, for the next request to its backend.
+    pub fn release(&mut self, mut conn: Upstream, now: Instant) {
+        let stats = conn.stats;
+        if self.expired(&conn, now, now) {
+            stats.add(conn.upstream, UpstreamStat::Expired);
+            return;
+        }
+        let idle = &mut self.idle[conn.upstream];
+        if idle.len() >= self.cfg.upstream_pool {
+            stats.add(conn.upstream, UpstreamStat::Overflow);
+            return;
+        }
+        stats.sub(conn.upstream, UpstreamStat::Busy);
+        stats.add(conn.upstream, UpstreamStat::Idle);
+        conn.idle = true;
+        idle.push((conn, now));
+    }
+
+    /// Closes the idle connections past their idle timeout or lifetime.
+    pub fn expire(&mut self, now: Instant) {
+        for idle in 0..self.idle.len() {
+            let before = self.idle[idle].len();
+            let mut kept = std::mem::take(&mut self.idle[idle]);
+            kept.retain(|(conn, since)| !self.expired(conn, *since, now));
+            if let Some(stats) = self.stats {
+                for _ in kept.len()..before {
+                    stats.add(idle, UpstreamStat::Expired);
+                }
+            }
+            self.idle[idle] = kept;
+        }
+    }
+
+    /// Whether `conn`, idle since `since`, is past `--upstream-idle-timeout` or `--upstream-max-lifetime`.
+    fn expired(&self, conn: &Upstream, since: Instant, now: Instant) -> bool {
+        now.saturating_duration_since(since) >= self.cfg.upstream_idle_timeout
+            || self.cfg.upstream_max_lifetime.is_some_and(|max| now.saturating_duration_since(conn.opened) >= max)
+    }
+}
+
+/// `/run/php/fpm.sock` becomes `run_php_fpm_sock`, and `app.internal:8080` `app_internal_8080`.
+fn metric_name(socket: &str) -> String {
+    socket
+        .trim_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
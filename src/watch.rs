@@ -0,0 +1,117 @@
+//! Static route reloading (`--watch-routes`): an inotify watch on the directory of every
+//! route file re-renders a route once its file, or a precompressed sibling of it, has been
+//! written or renamed into place, and swaps it in with `BodyStore::replace_route`. Routes
+//! stay rendered in memory, so requests never open or stat anything; the watch is what
+//! keeps them current. Directories are watched rather than the files themselves, so a
+//! deploy that renames a new file over the old one is seen as well.
+
+use crate::bodies::BodyStore;
+use crate::config::Config;
+use crate::encoding::Encoding;
+use crate::exec;
+use crate::routes::{Route, StaticRoute};
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// Events that mean a file in a watched directory has new contents.
+const CHANGED: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+
+/// Watches the directories of `cfg`'s rendered static routes and reloads them on changes,
+/// on a thread of its own.
+pub fn spawn(cfg: &'static Config, bodies: &'static BodyStore) -> io::Result<()> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut dirs: HashMap<libc::c_int, PathBuf> = HashMap::new();
+    for route in cfg.static_routes.iter().filter(|r| !exec::runs(r.payload)) {
+        let dir = match route.file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        if dirs.values().any(|d| *d == dir) {
+            continue;
+        }
+        let path = CString::new(dir.as_os_str().as_bytes()).map_err(io::Error::other)?;
+        let wd = unsafe { libc::inotify_add_watch(fd, path.as_ptr(), CHANGED) };
+        if wd < 0 {
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(io::Error::new(e.kind(), format!("{}: {e}", dir.display())));
+        }
+        dirs.insert(wd, dir);
+    }
+    thread::Builder::new().name("vrypt-watch".to_string()).spawn(move || {
+        // Aligned for the `inotify_event` headers read out of it.
+        let mut buf = [0u64; 512];
+        loop {
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), std::mem::size_of_val(&buf)) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                eprintln!("[warn] static route watch failed: {e}, no longer reloading");
+                return;
+            }
+            let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr().cast::<u8>(), n as usize) };
+            let mut changed = Vec::new();
+            for (wd, name) in events(bytes) {
+                if let Some(dir) = dirs.get(&wd) {
+                    changed.push(dir.join(OsStr::from_bytes(name)));
+                }
+            }
+            for route in cfg.static_routes.iter().filter(|r| changed.iter().any(|p| renders_from(r, p))) {
+                reload(cfg, bodies, route);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// The watch descriptor and file name of each event in `bytes`.
+fn events(mut bytes: &[u8]) -> impl Iterator<Item = (libc::c_int, &[u8])> {
+    const HEADER: usize = std::mem::size_of::<libc::inotify_event>();
+    std::iter::from_fn(move || {
+        if bytes.len() < HEADER {
+            return None;
+        }
+        let event = unsafe { std::ptr::read_unaligned(bytes.as_ptr().cast::<libc::inotify_event>()) };
+        let end = (HEADER + event.len as usize).min(bytes.len());
+        // The name is NUL-padded to the event's length.
+        let name = &bytes[HEADER..end];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        bytes = &bytes[end..];
+        Some((event.wd, name))
+    })
+}
+
+/// Whether `route` is rendered from `path`: its file or one of the precompressed siblings.
+fn renders_from(route: &StaticRoute, path: &Path) -> bool {
+    if exec::runs(route.payload) {
+        return false;
+    }
+    let file = route.file.as_os_str().as_bytes();
+    let path = path.as_os_str().as_bytes();
+    let path = path.strip_prefix(b"./").filter(|_| !file.starts_with(b"./")).unwrap_or(path);
+    path == file || Encoding::ALL.iter().any(|enc| path.strip_prefix(file) == Some(enc.suffix().as_bytes()))
+}
+
+/// Renders `route` again from its files and swaps it in for the loaded route it replaces.
+fn reload(cfg: &Config, bodies: &BodyStore, route: &StaticRoute) {
+    let Ok(tenant) = route.tenant_index(&cfg.tenants) else { return };
+    let Some(index) = bodies.routes().iter().position(|r| r.path == route.path && r.tenant == tenant) else {
+        return;
+    };
+    match Route::load(cfg, route, tenant) {
+        Ok(loaded) => {
+            eprintln!("[info] reloaded static route {} from {}", route.path, route.file.display());
+            bodies.replace_route(index, loaded);
+        }
+        Err(e) => eprintln!("[warn] cannot reload static route {} from {}: {e}", route.path, route.file.display()),
+    }
+}
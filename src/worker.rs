@@ -1,249 +1,1064 @@
-use crate::config::{BUF_SIZE, CONN_TIMEOUT, MAX_CONNS, MAX_RECYCLED_BUFS, MAX_REQUEST_SIZE, POLL_TIMEOUT, SERVER_TOKEN};
-use crate::conn::Conn;
-use crate::counter::RpsCounter;
+use crate::acceptor::Handoff;
+use crate::bodies::BodyStore;
+use crate::bus::{Bus, Event};
+use crate::capture::Capture;
+use crate::clock::Clock;
+use crate::collapse::{End, Fan};
+use crate::close::{CloseMode, CloseReason};
+use crate::config::{
+    Config, ACCEPT_RETRY_LIMIT, BUF_SIZE, CONN_TOKEN_MIN, LINGER_TIMEOUT, MAX_CONNS, MAX_RECYCLED_BUFS,
+    POLL_TIMEOUT, LISTENER_TOKEN_BASE, RESTART_RESET, SHRINK_FLOOR, STATS_INTERVAL,
+    WAKE_TOKEN,
+};
+use crate::conn::{Conn, ConnState, IdleClass};
+use crate::drive::{self, Budget, Flushed, Host};
+use crate::connlist::ConnList;
+use crate::counter::{AcceptError, HandshakeFailure, RpsCounter};
+use crate::error::{self, VryptError};
+use crate::exec::Exec;
+use crate::handler::{Admin, Handler};
+use crate::idempotency::IdempotencyStore;
+use crate::limit::{AcceptRate, InflightLimit, ScopedLimits};
+use crate::listen::{AcceptMode, ListenSet, Listeners};
+use crate::longpoll::LongPoll;
+use crate::mirror::{MirrorOutcome, MirrorStats, Shadows};
 use crate::pool::{BufPool, TokenPool};
+use crate::response::Responses;
+use crate::sizes::SizeStats;
+use crate::split::SplitStats;
 use crate::slab::Slab;
+use crate::sockopt;
+use crate::tcpinfo::TcpStats;
+use crate::tenant::Tenants;
 use crate::timer::TimerWheel;
-use mio::net::TcpListener;
-use mio::{Events, Interest, Poll, Token};
+use crate::toggle::Toggle;
+use crate::transport::{Stream, Transport};
+use crate::tunnel::{self, Tunnel};
+use crate::upstream::{UpstreamPool, UpstreamStats};
+use crate::xdp::AbuseTracker;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
 use socket2::{Domain, Protocol, Socket, Type};
-use std::io::{self, Read, Write};
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr};
 use std::os::unix::io::{FromRawFd, IntoRawFd};
-use std::time::Instant;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub fn worker(addr: SocketAddr, response: &'static [u8], counter: &'static RpsCounter, thread_id: usize) {
-    let sock = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).expect("socket::new");
-    sock.set_reuse_address(true).expect("set_reuse_address");
-    sock.set_reuse_port(true).expect("set_reuse_port");
-    sock.set_nonblocking(true).expect("set_nonblocking");
-    sock.bind(&addr.into()).expect("bind");
-    sock.listen(4096).expect("listen");
+/// Process-wide state handed to every worker.
+pub struct Shared {
+    pub cfg: &'static Config,
+    /// What workers time out by; `SystemClock` outside tests.
+    pub clock: &'static dyn Clock,
+    pub responses: &'static Responses,
+    /// Bodies uploaded through the admin API.
+    pub bodies: &'static BodyStore,
+    pub counter: &'static RpsCounter,
+    pub capture: Option<&'static Capture>,
+    pub limit: Option<&'static InflightLimit>,
+    /// Per-listener and per-route in-flight limits; only kept with `--listener-max-inflight`
+    /// or `--route-max-inflight`.
+    pub scoped: Option<&'static ScopedLimits>,
+    /// Slots of `--exec-max`; only kept with `exec:` static routes.
+    pub exec: Option<&'static InflightLimit>,
+    /// Per-address connection rates; only kept with `--xdp-drop-map`.
+    pub abuse: Option<&'static AbuseTracker>,
+    /// Responses remembered by `Idempotency-Key`; only kept with `--idempotency-ttl`.
+    pub idempotency: Option<&'static IdempotencyStore>,
+    pub tcp: Option<&'static TcpStats>,
+    pub sizes: Option<&'static SizeStats>,
+    /// Requests per split group; only kept with `--split-group`.
+    pub split: Option<&'static SplitStats>,
+    /// Requests sampled for mirroring; only kept with `--mirror`.
+    pub mirror: Option<&'static MirrorStats>,
+    /// Per-tenant limits and counts; only kept with `--tenant`.
+    pub tenants: Option<&'static Tenants>,
+    /// Per-upstream connection counts; only kept with `fastcgi:` or `scgi:` static routes,
+    /// split groups or forward-proxy destinations.
+    pub backends: Option<&'static UpstreamStats>,
+    /// The latest long-poll event; only kept with `--long-poll`.
+    pub long_poll: Option<&'static LongPoll>,
+    /// Connection snapshots for the admin dump; only kept with `--admin`.
+    pub conns: Option<&'static ConnList>,
+    pub listen: &'static ListenSet,
+    /// What other threads tell workers about changes to the state above.
+    pub bus: &'static Bus,
+    /// What ClientHellos are answered with; only kept with `--tls-cert` and `--tls-key`.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    /// Per-worker queues the accept thread fills; empty unless `AcceptMode::Thread`.
+    pub handoffs: Vec<Handoff>,
+}
 
-    let std_listener = unsafe { std::net::TcpListener::from_raw_fd(sock.into_raw_fd()) };
-    let mut listener = TcpListener::from_std(std_listener);
+/// A `WorkerHost` borrowing `$w`'s fields, leaving its slab and handler free for the
+/// connection being driven.
+macro_rules! host {
+    ($w:ident) => {
+        WorkerHost {
+            shared: $w.shared,
+            poll: &$w.poll,
+            wheel: &mut $w.wheel,
+            to_close: &mut $w.to_close,
+            yielded: &mut $w.yielded,
+            upstreams: &mut $w.upstreams,
+            token_pool: &mut $w.token_pool,
+            shadows: &mut $w.shadows,
+            #[cfg(feature = "tls")]
+            handshakes: &mut $w.handshakes,
+        }
+    };
+}
+
+struct Worker {
+    thread_id: usize,
+    shared: &'static Shared,
+    poll: Poll,
+    /// `None` when the accept thread owns the listeners.
+    listeners: Option<Listeners>,
+    /// Connections taken from the accept thread's handoff queue, reused between wakeups.
+    handed: Vec<(TcpStream, SocketAddr, SocketAddr)>,
+    /// `ListenSet` generation last acted on; see `drain_connections`.
+    listen_generation: u64,
+    /// `LongPoll` generation parked polls were last answered up to; see `release_parked`.
+    long_poll_seen: u64,
+    /// Events taken from the bus, reused between wakeups.
+    posted: Vec<Event>,
+    accept_rate: Option<AcceptRate>,
+    /// Set when `accept_rate` ran out with connections possibly still queued; the
+    /// listeners are edge-triggered, so they are retried once tokens refill. Also set when
+    /// `conn_cap` resumes them.
+    accept_deferred: bool,
+    /// `Config::conn_watermarks`: the listeners are suspended at the first count and resumed
+    /// at the second, leaving connections over the cap in the kernel's backlog.
+    conn_cap: Option<(u64, u64)>,
+    /// `Config::priority_reserve`: connections taken on past the cap before pausing.
+    reserve: u64,
+    slab: Slab,
+    buf_pool: BufPool,
+    token_pool: TokenPool,
+    wheel: TimerWheel,
+    to_close: Vec<Token>,
+    /// Connections that used up their round budget with work left; driven again next round
+    /// since no new edge will report what is already buffered.
+    yielded: Vec<Token>,
+    /// Forward-proxy upstream tokens, each mapped to its client connection's token.
+    upstreams: HashMap<Token, Token>,
+    /// Mirrored requests on their way to the `--mirror` upstream.
+    shadows: Shadows,
+    expired: Vec<(Token, u64)>,
+    handler: Handler,
+    accepted: u64,
+    active: u64,
+    /// TLS handshakes in progress, up to `Config::tls_max_handshakes`.
+    #[cfg(feature = "tls")]
+    handshakes: usize,
+    last_sample: Instant,
+    /// A failed `--dscp` / `--socket-priority` setting has been logged; it would fail for
+    /// every connection alike.
+    qos_warned: bool,
+}
 
-    let mut poll = Poll::new().expect("poll::new");
-    let mut events = Events::with_capacity(1024);
-    let mut slab = Slab::new(MAX_CONNS);
-    let mut buf_pool = BufPool::new(MAX_CONNS, MAX_RECYCLED_BUFS);
-    let mut token_pool = TokenPool::new();
-    let mut to_close: Vec<Token> = Vec::with_capacity(64);
-    let mut wheel = TimerWheel::new(CONN_TIMEOUT);
-    let mut expired: Vec<(Token, u64)> = Vec::with_capacity(64);
+/// Binds a non-blocking `SO_REUSEPORT` listener; every worker gets its own on the same address.
+pub fn bind_listener(addr: SocketAddr, device: Option<&str>) -> io::Result<TcpListener> {
+    let sock = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    sock.set_reuse_address(true)?;
+    sock.set_reuse_port(true)?;
+    if let Some(device) = device {
+        sock.bind_device(Some(device.as_bytes()))?;
+    }
+    sock.set_nonblocking(true)?;
+    sock.bind(&addr.into())?;
+    sock.listen(4096)?;
+    let std_listener = unsafe { std::net::TcpListener::from_raw_fd(sock.into_raw_fd()) };
+    Ok(TcpListener::from_std(std_listener))
+}
 
-    poll.registry()
-        .register(&mut listener, SERVER_TOKEN, Interest::READABLE)
-        .expect("register listener");
+/// Another fd for the same listening socket, for workers sharing one listener.
+pub fn clone_listener(listener: &TcpListener) -> io::Result<TcpListener> {
+    let sock = sockopt::with_sock(listener, |s| s.try_clone())?;
+    let std_listener = unsafe { std::net::TcpListener::from_raw_fd(sock.into_raw_fd()) };
+    Ok(TcpListener::from_std(std_listener))
+}
 
+/// Runs worker `thread_id` on `listeners` (`None` when the accept thread owns them). When
+/// its event loop fails, the connections it held are closed and a new loop is started after
+/// a backoff that grows with consecutive failures, keeping the listeners and buffer pool.
+pub fn worker(shared: &'static Shared, thread_id: usize, mut listeners: Option<Listeners>) {
+    let mut failures = 0;
+    let mut buf_pool = BufPool::new(MAX_CONNS, MAX_RECYCLED_BUFS, shared.cfg.buf_pool);
+    if shared.cfg.warmup.is_some() {
+        buf_pool.prefill();
+    }
+    let mut buf_pool = Some(buf_pool);
     loop {
+        let started = Instant::now();
+        let err = match Poll::new() {
+            Ok(poll) => {
+                let pool = buf_pool.take().expect("buffer pool handed back by the previous loop");
+                let mut w = Worker::new(shared, thread_id, poll, listeners.take(), pool);
+                let err = w.run();
+                let (own, pool) = w.shut_down();
+                (listeners, buf_pool) = (own, Some(pool));
+                err
+            }
+            Err(e) => VryptError::Poll(e),
+        };
+        if started.elapsed() >= RESTART_RESET {
+            failures = 0;
+        }
+        let pause = error::backoff(failures);
+        failures += 1;
+        eprintln!("[error] worker {thread_id}: {err}, restarting in {}ms", pause.as_millis());
+        thread::sleep(pause);
+    }
+}
+
+impl Worker {
+    fn new(shared: &'static Shared, thread_id: usize, poll: Poll, listeners: Option<Listeners>, buf_pool: BufPool) -> Self {
+        let cfg = shared.cfg;
+        Self {
+            thread_id,
+            shared,
+            poll,
+            listeners,
+            handed: Vec::new(),
+            listen_generation: shared.listen.generation(),
+            long_poll_seen: shared.long_poll.map_or(0, LongPoll::generation),
+            posted: Vec::new(),
+            accept_rate: cfg.accept_rate.map(|n| AcceptRate::new(n, shared.clock.now())),
+            accept_deferred: false,
+            conn_cap: cfg.conn_watermarks(),
+            reserve: cfg.priority_reserve.map_or(0, u64::from),
+            slab: Slab::new(SHRINK_FLOOR),
+            buf_pool,
+            token_pool: TokenPool::new(),
+            wheel: TimerWheel::new(shared.clock.now()),
+            to_close: Vec::with_capacity(64),
+            yielded: Vec::new(),
+            upstreams: HashMap::new(),
+            shadows: Shadows::default(),
+            expired: Vec::with_capacity(64),
+            handler: Handler::new(
+                thread_id,
+                cfg,
+                shared.responses,
+                shared.limit,
+                shared.counter,
+                shared.bodies,
+                shared.conns.map(|conns| Admin { conns, listen: shared.listen }),
+            )
+            .with_idempotency(shared.idempotency)
+            .with_clock(shared.clock)
+            .with_tenants(shared.tenants)
+            .with_long_poll(shared.long_poll)
+            .with_abuse(shared.abuse)
+            .with_mirror(shared.mirror)
+            .with_exec(shared.exec, shared.backends)
+            .with_scoped_limits(shared.scoped),
+            accepted: 0,
+            active: 0,
+            #[cfg(feature = "tls")]
+            handshakes: 0,
+            last_sample: shared.clock.now(),
+            qos_warned: false,
+        }
+    }
+
+    /// Serves until the event loop fails, returning why.
+    fn run(&mut self) -> VryptError {
+        if let Some(own) = &mut self.listeners {
+            own.register(&self.poll);
+        }
+        // A poll takes one waker; the accept thread and the bus share it.
+        let waker = match Waker::new(self.poll.registry(), Token(WAKE_TOKEN)) {
+            Ok(waker) => Arc::new(waker),
+            Err(e) => return VryptError::Poll(e),
+        };
+        if let Some(handoff) = self.shared.handoffs.get(self.thread_id) {
+            handoff.set_waker(waker.clone());
+        }
+        self.shared.bus.set_waker(self.thread_id, waker);
+        let mut events = Events::with_capacity(1024);
+
         loop {
-            match poll.poll(&mut events, Some(POLL_TIMEOUT)) {
-                Ok(_) => break,
-                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                Err(e) => panic!("poll: {e}"),
+            let timeout = match &self.accept_rate {
+                _ if !self.yielded.is_empty() => Duration::ZERO,
+                Some(rate) if self.accept_deferred => rate.wait().min(POLL_TIMEOUT),
+                _ => POLL_TIMEOUT,
+            };
+            loop {
+                match self.poll.poll(&mut events, Some(timeout)) {
+                    Ok(_) => break,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return VryptError::Poll(e),
+                }
+            }
+            let (debug, metrics) = (Toggle::DebugLog.enabled(), Toggle::ExtraMetrics.enabled());
+            if debug || metrics {
+                let n = events.iter().count();
+                if metrics {
+                    self.shared.counter.wakeup(self.thread_id, n);
+                }
+                if debug {
+                    eprintln!("[debug] worker {}: woke with {n} events, {} connections", self.thread_id, self.active);
+                }
+            }
+
+            self.to_close.clear();
+            let now = self.shared.clock.now();
+            if self.shared.cfg.date {
+                self.handler.date.refresh();
+            }
+
+            if self.accept_deferred {
+                self.accept_deferred = false;
+                for slot in 0..self.listeners.as_ref().map_or(0, Listeners::slots) {
+                    self.accept_connections(slot);
+                }
+            }
+
+            if now.duration_since(self.last_sample) >= STATS_INTERVAL {
+                self.sample_states();
+                if let Some(list) = self.shared.conns {
+                    list.publish(self.thread_id, self.slab.entries(), now);
+                }
+                if let (Some(split), Some(counts)) = (self.shared.split, self.handler.take_affinity_counts()) {
+                    split.add_affinity(counts);
+                }
+                self.shrink_tables();
+                self.handler.upstream_pool.expire(now);
+                let timed_out = self.shadows.expire(&self.poll, &mut self.token_pool, now);
+                if let Some(mirror) = self.shared.mirror {
+                    mirror.add_many(MirrorOutcome::Failed, timed_out);
+                }
+                self.sample_memory();
+                if let (Some(tcp), Some(listeners)) = (self.shared.tcp, &self.listeners) {
+                    // A shared listener is the same socket in every worker; count its queue once.
+                    if self.shared.listen.mode() == AcceptMode::ReusePort || self.thread_id == 0 {
+                        tcp.sample_listeners(self.thread_id, listeners.iter());
+                    }
+                }
+                self.last_sample = now;
+            }
+
+            self.expired.clear();
+            self.wheel.advance(now, &mut self.expired);
+            for (tok, epoch) in self.expired.drain(..) {
+                if let Some(conn) = self.slab.get_mut(tok) {
+                    if conn.epoch == epoch && conn.state() == ConnState::Parked {
+                        self.handler.unpark(conn);
+                        yield_later(&mut self.yielded, conn, tok);
+                    } else if conn.epoch == epoch && conn.state() == ConnState::Queued {
+                        // Tried once more; past its deadline it is refused.
+                        conn.dequeue();
+                        yield_later(&mut self.yielded, conn, tok);
+                    } else if conn.epoch == epoch && conn.state() != ConnState::Closing {
+                        let class = conn.idle_class();
+                        eprintln!("[info] {:?} timeout, closing {:?}", class, tok);
+                        // A stalled response would keep the socket around however it was
+                        // closed, so a write timeout always resets.
+                        let stalled = class == IdleClass::Write && conn.write_started.is_some();
+                        if stalled {
+                            self.shared.counter.write_timeout(self.thread_id);
+                        }
+                        // A request that never finished its head gets a 408; a connection
+                        // whose last request was answered is just idle and closes quietly.
+                        if conn.state() == ConnState::ReadingHeaders && (conn.read_len > 0 || conn.requests == 0) {
+                            let _ = conn.stream.write(self.shared.responses.request_timeout);
+                            let _ = conn.stream.shutdown(Shutdown::Write);
+                            self.shared.counter.header_timeout(self.thread_id);
+                        }
+                        if class == IdleClass::Connect {
+                            let _ = conn.stream.write(self.shared.responses.bad_gateway);
+                            let _ = conn.stream.shutdown(Shutdown::Write);
+                        }
+                        if class == IdleClass::Linger {
+                            close_later(&mut self.to_close, conn, tok);
+                            continue;
+                        }
+                        if class == IdleClass::Handshake {
+                            self.shared.counter.handshake_failure(self.thread_id, HandshakeFailure::Timeout);
+                        }
+                        let reason = CloseReason::Timeout;
+                        let mode = match self.shared.cfg.close_mode(reason) {
+                            _ if stalled => CloseMode::Rst,
+                            // A half-done handshake has nothing worth draining.
+                            CloseMode::Drain if class == IdleClass::Handshake => CloseMode::Fin,
+                            mode => mode,
+                        };
+                        self.shared.counter.closed(self.thread_id, reason, mode);
+                        drive::close_as(&mut host!(self), conn, tok, reason, mode, now);
+                        if conn.state() == ConnState::Draining {
+                            self.wheel.add(tok, conn.epoch, LINGER_TIMEOUT);
+                        }
+                    }
+                }
+            }
+
+            let yielded = std::mem::take(&mut self.yielded);
+            for event in events.iter() {
+                match event.token() {
+                    Token(WAKE_TOKEN) => {
+                        self.adopt_handed();
+                        self.take_events();
+                    }
+                    Token(t) if t < CONN_TOKEN_MIN => self.accept_connections(t - LISTENER_TOKEN_BASE),
+                    token if self.upstreams.contains_key(&token) => self.handle_connection(self.upstreams[&token], false),
+                    token if self.shadows.owns(token) => {
+                        let now = self.shared.clock.now();
+                        let failed = self.shadows.ready(token, &self.poll, &mut self.token_pool, now);
+                        if let (Some(mirror), Some(outcome)) = (self.shared.mirror, failed) {
+                            mirror.add(outcome);
+                        }
+                    }
+                    token => self.handle_connection(token, event.is_error() || event.is_write_closed()),
+                }
+            }
+            for tok in yielded {
+                if let Some(conn) = self.slab.get_mut(tok).filter(|c| c.yielded) {
+                    conn.yielded = false;
+                    self.handle_connection(tok, false);
+                }
+            }
+
+            while let Some(tok) = self.to_close.pop() {
+                self.close_conn(tok);
             }
         }
+    }
 
-        to_close.clear();
-        let now = Instant::now();
+    /// Closes every connection and takes the listeners off this poll so they can be
+    /// registered with the next one; the buffer pool, all buffers returned, goes with them.
+    fn shut_down(mut self) -> (Option<Listeners>, BufPool) {
+        let tokens: Vec<Token> = self.slab.iter_mut().map(|(tok, _)| tok).collect();
+        for tok in tokens {
+            self.close_conn(tok);
+        }
+        let mut listeners = self.listeners.take();
+        if let Some(own) = &mut listeners {
+            own.deregister(&self.poll);
+        }
+        (listeners, self.buf_pool)
+    }
+
+    fn sample_states(&self) {
+        let mut counts = [0u64; ConnState::ALL.len()];
+        for conn in self.slab.iter() {
+            counts[conn.state() as usize] += 1;
+        }
+        self.shared.counter.set_states(self.thread_id, counts);
+        self.shared.counter.set_timers(self.thread_id, self.wheel.len() as u64);
+        self.shared.counter.set_buf_pool(self.thread_id, self.buf_pool.stats());
+    }
+
+    /// Publishes this worker's memory estimate and, over the ceiling, gives back its share of the excess.
+    fn sample_memory(&mut self) {
+        let bytes = self.memory_bytes();
+        self.shared.counter.set_memory(self.thread_id, bytes);
+        let Some(limit) = self.shared.cfg.memory_limit else { return };
+        let total = self.shared.counter.memory();
+        if total > limit {
+            let share = (total - limit).div_ceil(self.shared.counter.slots().len() as u64);
+            let freed = self.relieve_memory(share);
+            eprintln!("[warn] memory {total} bytes over the {limit} byte limit, freed {freed} bytes");
+            self.shared.counter.set_memory(self.thread_id, bytes.saturating_sub(freed));
+        }
+    }
+
+    fn memory_bytes(&self) -> u64 {
+        let out: usize = self
+            .slab
+            .iter()
+            .map(|c| c.out.capacity() + c.read_buf.spilled() + c.tunnel.as_ref().map_or(0, |t| t.bytes() as usize) + c.exec.as_ref().map_or(0, |e| e.bytes()) + c.spill.as_ref().map_or(0, |s| s.bytes()))
+            .sum();
+        self.slab.bytes() + self.buf_pool.bytes() + self.token_pool.bytes() + self.wheel.bytes() + self.handler.bytes()
+            + self.shadows.bytes()
+            + out as u64
+    }
+
+    /// Once a burst is over — fewer live connections than a quarter of the tokens handed
+    /// out — gives back what the token pool, slab, timer wheel and scratch lists grew to.
+    fn shrink_tables(&mut self) {
+        let high = self.token_pool.high_water();
+        if high <= SHRINK_FLOOR || self.active as usize * 4 > high {
+            return;
+        }
+        self.token_pool.shrink(SHRINK_FLOOR);
+        self.slab.shrink(SHRINK_FLOOR);
+        let slab = &self.slab;
+        self.wheel.shrink(|tok, epoch| slab.get(tok).is_some_and(|c| c.epoch == epoch));
+        self.expired.shrink_to(SHRINK_FLOOR);
+        self.to_close.shrink_to(SHRINK_FLOOR);
+    }
 
-        expired.clear();
-        wheel.advance(now, &mut expired);
-        for (tok, gen) in expired.drain(..) {
-            if let Some(conn) = slab.get(tok) {
-                if conn.generation == gen {
-                    eprintln!("[info] timeout, closing {:?}", tok);
-                    to_close.push(tok);
+    /// Frees at least `target` bytes if it can: recycled buffers, response buffers of
+    /// connections not writing, then idle keep-alive connections (least recently active first).
+    fn relieve_memory(&mut self, target: u64) -> u64 {
+        let mut freed = self.buf_pool.trim();
+        let mut idle = Vec::new();
+        for (tok, conn) in self.slab.iter_mut() {
+            if conn.state() == ConnState::Idle {
+                idle.push((conn.last_active, tok));
+            }
+            let out_in_use = conn.owned && matches!(conn.state(), ConnState::ReadingBody | ConnState::Writing);
+            if !out_in_use {
+                freed += conn.out.capacity() as u64;
+                conn.out = Vec::new();
+            }
+        }
+        idle.sort_unstable();
+        // Draining would hold on to what is being reclaimed; those connections get a FIN.
+        let mode = match self.shared.cfg.close_mode(CloseReason::Policy) {
+            CloseMode::Drain => CloseMode::Fin,
+            mode => mode,
+        };
+        for (_, tok) in idle {
+            if freed >= target {
+                break;
+            }
+            if let Some(conn) = self.slab.get_mut(tok) {
+                conn.close_reason = Some(CloseReason::Policy);
+                if mode == CloseMode::Rst {
+                    let _ = sockopt::set_abortive_close(&conn.stream);
                 }
             }
+            self.shared.counter.closed(self.thread_id, CloseReason::Policy, mode);
+            self.close_conn(tok);
+            freed += (size_of::<Conn>() + BUF_SIZE) as u64;
+        }
+        // Reaped connections handed their buffers back to the pool.
+        self.buf_pool.trim();
+        freed
+    }
+
+    /// Picks up listeners bound for this worker and closes those no longer listed, after
+    /// accepting what is already queued on them.
+    fn sync_listeners(&mut self) {
+        let Some(listeners) = &mut self.listeners else { return };
+        for bound in listeners.sync(&self.poll) {
+            while let Ok((stream, peer)) = bound.listener.accept() {
+                self.adopt(stream, peer, bound.addr);
+            }
         }
+    }
 
-        for event in events.iter() {
-            match event.token() {
-                SERVER_TOKEN => {
-                    accept_connections(
-                        &mut listener, &mut slab, &mut token_pool,
-                        &mut buf_pool, &poll, response, &mut wheel,
-                    );
+    /// Winds down connections accepted on a draining listener: idle ones are closed now,
+    /// the others once their next response has been written.
+    fn drain_connections(&mut self) {
+        let draining = self.shared.listen.draining();
+        if draining.is_empty() {
+            return;
+        }
+        let (reason, now) = (CloseReason::Policy, self.shared.clock.now());
+        for (tok, conn) in self.slab.iter_mut() {
+            if !conn.listener.is_some_and(|addr| draining.contains(&addr)) {
+                continue;
+            }
+            match conn.state() {
+                ConnState::Idle => {
+                    let mode = self.shared.cfg.close_mode(reason);
+                    self.shared.counter.closed(self.thread_id, reason, mode);
+                    drive::close_as(&mut host!(self), conn, tok, reason, mode, now);
+                    if conn.state() == ConnState::Draining {
+                        self.wheel.add(tok, conn.epoch, LINGER_TIMEOUT);
+                    }
                 }
-                token => {
-                    handle_connection(token, &mut slab, &poll, &mut to_close, counter, thread_id, &mut wheel);
+                _ => {
+                    conn.close_after_write = true;
+                    conn.close_reason = Some(reason);
                 }
             }
         }
+    }
 
-        for tok in to_close.drain(..) {
-            close_conn(&mut slab, &mut token_pool, &mut buf_pool, &poll, tok);
+    /// Answers the parked long polls waiting on an event older than `generation`; they are
+    /// written next round.
+    fn release_parked(&mut self, generation: u64) {
+        for (tok, conn) in self.slab.iter_mut() {
+            if conn.state() == ConnState::Parked && conn.poll_since < generation {
+                self.handler.unpark(conn);
+                yield_later(&mut self.yielded, conn, tok);
+            }
         }
     }
-}
 
-fn accept_connections(
-    listener: &mut TcpListener,
-    slab: &mut Slab,
-    token_pool: &mut TokenPool,
-    buf_pool: &mut BufPool,
-    poll: &Poll,
-    response: &'static [u8],
-    wheel: &mut TimerWheel,
-) {
-    loop {
-        match listener.accept() {
-            Ok((stream, _peer)) => {
-                let _ = stream.set_nodelay(true);
-
-                let tok = match token_pool.acquire() {
-                    Some(t) => t,
-                    None => {
-                        eprintln!("[warn] token pool exhausted, dropping connection");
-                        continue;
+    /// Tries the requests waiting for a per-listener or per-route in-flight slot again next
+    /// round; those that find none wait on.
+    fn retry_queued(&mut self) {
+        for (tok, conn) in self.slab.iter_mut() {
+            if conn.state() == ConnState::Queued {
+                conn.dequeue();
+                yield_later(&mut self.yielded, conn, tok);
+            }
+        }
+    }
+
+    fn accept_connections(&mut self, slot: usize) {
+        let mut retries = 0;
+        loop {
+            let Some(bound) = self.listeners.as_ref().and_then(|l| l.get(slot)) else { return };
+            if let Some(rate) = &mut self.accept_rate {
+                if !rate.try_take(self.shared.clock.now()) {
+                    self.accept_deferred = true;
+                    return;
+                }
+            }
+            let listed = bound.addr;
+            match bound.listener.accept() {
+                Ok((stream, peer)) => {
+                    retries = 0;
+                    self.adopt(stream, peer, listed)
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if let Some(rate) = &mut self.accept_rate {
+                        rate.refund();
                     }
-                };
-                let buf = match buf_pool.acquire() {
-                    Some(b) => b,
-                    None => {
-                        eprintln!("[warn] buffer pool exhausted, dropping connection");
-                        token_pool.release(tok);
+                    break;
+                }
+                Err(e) => {
+                    let kind = AcceptError::classify(&e);
+                    self.shared.counter.accept_error(self.thread_id, kind);
+                    if kind.is_transient() && retries < ACCEPT_RETRY_LIMIT {
+                        retries += 1;
                         continue;
                     }
-                };
+                    eprintln!("[warn] accept error: {e}");
+                    break;
+                }
+            }
+        }
+    }
 
-                let mut conn = Conn::new(stream, response, buf);
+    /// Takes the connections the accept thread queued for this worker.
+    fn adopt_handed(&mut self) {
+        let Some(handoff) = self.shared.handoffs.get(self.thread_id) else { return };
+        let mut handed = std::mem::take(&mut self.handed);
+        handoff.take(&mut handed);
+        for (stream, peer, listener) in handed.drain(..) {
+            self.adopt(stream, peer, listener);
+        }
+        self.handed = handed;
+    }
 
-                if let Err(e) = poll.registry().register(&mut conn.stream, tok, Interest::READABLE) {
-                    eprintln!("[warn] register failed: {e}");
-                    buf_pool.release(conn.read_buf);
-                    token_pool.release(tok);
-                    continue;
+    /// Acts on the events other threads posted for this worker since it last looked.
+    fn take_events(&mut self) {
+        let mut posted = std::mem::take(&mut self.posted);
+        self.shared.bus.take(self.thread_id, &mut posted);
+        for event in posted.drain(..) {
+            match event {
+                Event::Listeners => {
+                    if self.listeners.as_ref().is_some_and(Listeners::stale) {
+                        self.sync_listeners();
+                    }
+                    // Checked even without listeners of its own: a drain concerns connections
+                    // the accept thread handed over too.
+                    let generation = self.shared.listen.generation();
+                    if generation != self.listen_generation {
+                        self.listen_generation = generation;
+                        self.drain_connections();
+                    }
                 }
-
-                let generation = conn.generation;
-                slab.insert(tok, conn);
-                wheel.add(tok, generation);
-            }
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
-            Err(e) => {
-                eprintln!("[warn] accept error: {e}");
-                break;
+                Event::LongPoll => {
+                    let generation = self.shared.long_poll.map_or(0, LongPoll::generation);
+                    if generation != self.long_poll_seen {
+                        self.long_poll_seen = generation;
+                        self.release_parked(generation);
+                    }
+                }
+                Event::Bodies => self.handler.refresh_bodies(),
+                Event::Slots => self.retry_queued(),
             }
         }
+        self.posted = posted;
     }
-}
 
-fn handle_connection(
-    token: Token,
-    slab: &mut Slab,
-    poll: &Poll,
-    to_close: &mut Vec<Token>,
-    counter: &'static RpsCounter,
-    thread_id: usize,
-    wheel: &mut TimerWheel,
-) {
-    let Some(conn) = slab.get_mut(token) else { return };
+    /// Sets up a connection freshly accepted on `listener` (as listed) and starts its header timer.
+    fn adopt(&mut self, stream: TcpStream, peer: SocketAddr, listener: SocketAddr) {
+        if let Some(abuse) = self.shared.abuse {
+            abuse.record(peer.ip(), self.shared.clock.now());
+        }
+        let _ = stream.set_nodelay(true);
+        let cfg = self.shared.cfg;
+        if cfg.dscp.is_some() || cfg.socket_priority.is_some() {
+            if let Err(e) = sockopt::set_qos(&stream, listener.is_ipv6(), cfg.dscp, cfg.socket_priority) {
+                if !self.qos_warned {
+                    eprintln!("[warn] cannot mark connections for QoS: {e}");
+                    self.qos_warned = true;
+                }
+            }
+        }
 
-    conn.touch();
-    wheel.add(token, conn.generation);
+        let Some(tok) = self.token_pool.acquire() else {
+            eprintln!("[warn] token pool exhausted, dropping connection");
+            return;
+        };
+        let Some(buf) = self.buf_pool.acquire() else {
+            eprintln!("[warn] buffer pool exhausted, dropping connection");
+            self.token_pool.release(tok);
+            return;
+        };
 
-    if !conn.has_pending_write() {
-        if !do_read(conn, token, to_close) {
+        let mut conn = Conn::new(Stream::new(stream), peer, buf, self.shared.clock.now());
+        conn.listener = Some(listener);
+        conn.reserved = self.conn_cap.is_some_and(|(high, _)| self.active >= high);
+        self.accepted += 1;
+        self.shared.counter.accepted(self.thread_id);
+        if let Some(cap) = self.shared.capture.filter(|_| Toggle::Capture.enabled()) {
+            if self.handler.rng.chance(cap.sample()) {
+                let seq = self.accepted & 0xFFFF_FFFF_FFFF;
+                conn.capture_id = Some(((self.thread_id as u64) << 48) | seq);
+            }
+        }
+
+        if let Err(e) = self.poll.registry().register(&mut conn.stream, tok, Interest::READABLE) {
+            eprintln!("[warn] register failed: {e}");
+            self.buf_pool.release(conn.read_buf.into_pooled());
+            self.token_pool.release(tok);
             return;
         }
 
-        if conn.request_complete() {
-            conn.arm_write();
-            let _ = poll.registry().reregister(
-                &mut conn.stream, token,
-                Interest::READABLE | Interest::WRITABLE,
-            );
+        if Toggle::DebugLog.enabled() {
+            eprintln!("[debug] worker {}: accepted {peer} as {tok:?}", self.thread_id);
+        }
+        let epoch = conn.epoch;
+        self.slab.insert(tok, conn);
+        self.wheel.add(tok, epoch, self.shared.cfg.header_timeout);
+        self.active += 1;
+        self.shared.counter.set_active(self.thread_id, self.active);
+        if let (Some((high, _)), Some(listeners)) = (self.conn_cap, &mut self.listeners) {
+            if self.active >= high + self.reserve && !listeners.suspended() {
+                eprintln!("[info] worker {}: {} connections, pausing accepts", self.thread_id, self.active);
+                listeners.suspend(&self.poll);
+            }
         }
     }
 
-    if conn.has_pending_write() {
-        do_write(conn, token, poll, to_close, counter, thread_id);
+    /// `hung_up` is set when the peer reset or fully closed the connection.
+    fn handle_connection(&mut self, token: Token, hung_up: bool) {
+        if hung_up && self.abandon_response(token) {
+            return;
+        }
+        let before = self.slab.get(token).map(Conn::state);
+        self.drive(token);
+        self.fan_out(token);
+        if Toggle::DebugLog.enabled() {
+            if let (Some(before), Some(conn)) = (before, self.slab.get(token)) {
+                if conn.state() != before {
+                    eprintln!("[debug] {token:?}: {} -> {}", before.name(), conn.state().name());
+                }
+            }
+        }
     }
-}
 
-fn do_read(conn: &mut Conn, token: Token, to_close: &mut Vec<Token>) -> bool {
-    loop {
-        if conn.read_len >= BUF_SIZE {
-            eprintln!("[warn] buffer full, closing {:?}", token);
-            to_close.push(token);
+    /// Closes a connection whose client went away mid-response. Nothing written from here
+    /// on could arrive, so this saves the rest of the response instead of finding out at
+    /// the next write, or never when the response is stalled.
+    fn abandon_response(&mut self, token: Token) -> bool {
+        let Some(conn) = self.slab.get_mut(token) else { return false };
+        if !matches!(conn.state(), ConnState::Writing | ConnState::Streaming) {
             return false;
         }
-        let dst = &mut conn.read_buf[conn.read_len..];
-        match conn.stream.read(dst) {
-            Ok(0) => {
-                to_close.push(token);
-                return false;
+        eprintln!("[info] client went away after {} of {} bytes, closing {:?}", conn.write_pos, conn.outgoing().len(), token);
+        conn.mark_closing();
+        self.to_close.push(token);
+        true
+    }
+
+    /// Copies what the backend request of `token` has sent since the last call to the
+    /// requests waiting on it.
+    fn fan_out(&mut self, token: Token) {
+        let Some(conn) = self.slab.get_mut(token) else { return };
+        let Some(fan) = conn.flight.and_then(|id| self.handler.collapse.take(id)) else { return };
+        if fan.end != End::Open {
+            conn.flight = None;
+        }
+        self.deliver(fan);
+    }
+
+    /// Queues `fan` on each waiting connection still open and drives them next round. A
+    /// failed or abandoned response closes them once sent; one abandoned before any of
+    /// it came gets `502` where the backend would have sent the head.
+    fn deliver(&mut self, fan: Fan) {
+        for &waiter in &fan.waiters {
+            let Some(conn) = self.slab.get_mut(waiter) else { continue };
+            let Some(spill) = conn.spill.as_mut() else { continue };
+            spill.chunk.clear();
+            match fan.end {
+                End::Abandoned { started: false } if fan.relays_head => {
+                    spill.chunk.extend_from_slice(self.shared.responses.bad_gateway)
+                }
+                _ => spill.chunk.extend_from_slice(&fan.bytes),
             }
-            Ok(n) => {
-                conn.read_len += n;
-                if conn.read_len > MAX_REQUEST_SIZE {
-                    eprintln!("[warn] request too large (>{} bytes), closing {:?}", MAX_REQUEST_SIZE, token);
-                    to_close.push(token);
-                    return false;
+            match fan.end {
+                End::Open => {
+                    if let Err(e) = spill.push_chunk() {
+                        eprintln!("[warn] cannot queue collapsed response on {:?}: {e}", waiter);
+                        close_later(&mut self.to_close, conn, waiter);
+                        continue;
+                    }
+                }
+                End::Done => spill.push_last(),
+                End::Failed | End::Abandoned { .. } => {
+                    spill.push_last();
+                    conn.close_after_write = true;
+                    conn.close_reason = Some(CloseReason::Error);
                 }
             }
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
-            Err(e) => {
-                eprintln!("[warn] read error on {:?}: {e}", token);
-                to_close.push(token);
-                return false;
+            yield_later(&mut self.yielded, conn, waiter);
+        }
+    }
+
+    /// Drives `token` through `drive::drive` on this worker's poll, wheel and queues.
+    fn drive(&mut self, token: Token) {
+        let now = self.shared.clock.now();
+        let Some(conn) = self.slab.get_mut(token) else { return };
+        let mut host = host!(self);
+        drive::drive(conn, token, &mut self.handler, &mut host, now);
+    }
+
+    fn close_conn(&mut self, tok: Token) {
+        if let Some(mut c) = self.slab.remove(tok) {
+            if Toggle::DebugLog.enabled() {
+                eprintln!("[debug] {tok:?}: closed after {} requests", c.requests);
+            }
+            self.handler.release_slot(&mut c);
+            if let Some(tcp) = self.shared.tcp {
+                tcp.sample_conn(&c.stream);
+            }
+            if self.shared.cfg.abortive_close && c.close_reason.is_none() {
+                let _ = sockopt::set_abortive_close(&c.stream);
+            }
+            #[cfg(feature = "tls")]
+            if c.stream.handshaking() {
+                self.handshakes -= 1;
+            }
+            #[cfg(feature = "tls")]
+            c.stream.close_notify();
+            let _ = self.poll.registry().deregister(&mut c.stream);
+            if let Some(fan) = c.flight.take().and_then(|id| self.handler.collapse.abandon(id)) {
+                self.deliver(fan);
+            }
+            if let Some(mut t) = c.tunnel.take() {
+                let _ = self.poll.registry().deregister(&mut t.upstream.stream);
+                self.upstreams.remove(&t.token);
+                self.token_pool.release(t.token);
+            }
+            if let Some(exec) = c.exec.take() {
+                let (pool, now) = (&mut self.handler.upstream_pool, self.shared.clock.now());
+                end_exec(*exec, &self.poll, &mut self.upstreams, &mut self.token_pool, pool, now);
+            }
+            self.buf_pool.release(c.read_buf.into_pooled());
+            self.token_pool.release(tok);
+            self.active -= 1;
+            self.shared.counter.set_active(self.thread_id, self.active);
+            if let (Some((_, low)), Some(listeners)) = (self.conn_cap, &mut self.listeners) {
+                if self.active <= low && listeners.suspended() {
+                    eprintln!("[info] worker {}: {} connections, resuming accepts", self.thread_id, self.active);
+                    listeners.resume(&self.poll);
+                    self.accept_deferred = true;
+                }
             }
         }
     }
 }
 
-fn do_write(
-    conn: &mut Conn,
-    token: Token,
+/// The parts of a `Worker` that driving one of its connections touches, borrowed apart
+/// from the connection and the handler.
+struct WorkerHost<'a> {
+    shared: &'static Shared,
+    poll: &'a Poll,
+    wheel: &'a mut TimerWheel,
+    to_close: &'a mut Vec<Token>,
+    yielded: &'a mut Vec<Token>,
+    upstreams: &'a mut HashMap<Token, Token>,
+    token_pool: &'a mut TokenPool,
+    shadows: &'a mut Shadows,
+    #[cfg(feature = "tls")]
+    handshakes: &'a mut usize,
+}
+
+impl Host<Stream> for WorkerHost<'_> {
+    fn capture(&self) -> Option<&'static Capture> {
+        self.shared.capture
+    }
+
+    fn sizes(&self) -> Option<&'static SizeStats> {
+        self.shared.sizes
+    }
+
+    fn close_later(&mut self, conn: &mut Conn, token: Token) {
+        close_later(self.to_close, conn, token);
+    }
+
+    fn yield_later(&mut self, conn: &mut Conn, token: Token) {
+        yield_later(self.yielded, conn, token);
+    }
+
+    fn set_writable(&mut self, conn: &mut Conn, token: Token, writable: bool) -> io::Result<()> {
+        let interest = if writable { Interest::READABLE | Interest::WRITABLE } else { Interest::READABLE };
+        self.poll.registry().reregister(&mut conn.stream, token, interest)
+    }
+
+    fn arm_timer(&mut self, token: Token, epoch: u64, timeout: Duration) {
+        self.wheel.add(token, epoch, timeout);
+    }
+
+    fn abort(&mut self, conn: &Conn) {
+        let _ = sockopt::set_abortive_close(&conn.stream);
+    }
+
+    #[cfg(feature = "tls")]
+    fn start_tls(&mut self, conn: &mut Conn) -> Result<(), HandshakeFailure> {
+        if *self.handshakes >= self.shared.cfg.tls_max_handshakes {
+            return Err(HandshakeFailure::Busy);
+        }
+        let tls = self.shared.tls.as_ref().expect("ClientHello handed over without a TLS config");
+        if let Err(e) = conn.stream.start_tls(tls, &conn.read_buf[..conn.read_len]) {
+            let failure = crate::tls::failure(&e);
+            eprintln!("[info] TLS handshake with {} failed ({}): {e}, closing", conn.peer, failure.name());
+            return Err(failure);
+        }
+        *self.handshakes += 1;
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    fn handshake(&mut self, conn: &mut Conn) -> io::Result<bool> {
+        let done = conn.stream.handshake()?;
+        if done {
+            *self.handshakes -= 1;
+        }
+        Ok(done)
+    }
+
+    fn open_tunnel(
+        &mut self,
+        conn: &mut Conn,
+        token: Token,
+        addr: SocketAddr,
+        established: bool,
+        group: Option<usize>,
+        mirror: Option<usize>,
+    ) -> io::Result<()> {
+        // Copied before the tunnel takes the body bytes over.
+        let copy = mirror.map(|body| [&conn.out[..], &conn.read_buf[..body]].concat());
+        let up = self.token_pool.acquire().ok_or_else(|| io::Error::other("token pool exhausted"))?;
+        if let Err(e) = open_tunnel(conn, token, up, addr, established, self.shared, self.poll) {
+            self.token_pool.release(up);
+            return Err(e);
+        }
+        self.upstreams.insert(up, token);
+        if let (Some(split), Some(group)) = (self.shared.split, group) {
+            split.routed(group);
+        }
+        if let (Some(request), Some(mirror), Some(stats)) = (copy, &self.shared.cfg.mirror, self.shared.mirror) {
+            let (timeout, now) = (self.shared.cfg.connect_timeout, self.shared.clock.now());
+            stats.add(self.shadows.open(mirror.target.addr, request, timeout, self.poll, self.token_pool, now));
+        }
+        Ok(())
+    }
+
+    fn watch_backend(&mut self, exec: &mut Exec, token: Token) -> io::Result<()> {
+        let up = self.token_pool.acquire().ok_or_else(|| io::Error::other("token pool exhausted"))?;
+        // Mapped first, so `close_conn` gives the token back whatever happens.
+        self.upstreams.insert(up, token);
+        exec.register(self.poll.registry(), up)
+    }
+
+    fn end_backend(&mut self, exec: Exec, pool: &mut UpstreamPool, now: Instant) {
+        end_exec(exec, self.poll, self.upstreams, self.token_pool, pool, now);
+    }
+
+    fn send_spilled(&mut self, conn: &mut Conn, token: Token, budget: &mut Budget) -> io::Result<Flushed> {
+        send_spilled(conn, token, self.poll, budget)
+    }
+}
+
+/// Stops watching a route backend and drops it, which reaps or kills a command; a
+/// backend connection fit for another request goes back to `pool`.
+fn end_exec(
+    mut exec: Exec,
     poll: &Poll,
-    to_close: &mut Vec<Token>,
-    counter: &'static RpsCounter,
-    thread_id: usize,
+    upstreams: &mut HashMap<Token, Token>,
+    token_pool: &mut TokenPool,
+    pool: &mut UpstreamPool,
+    now: Instant,
 ) {
-    let mut current_pos = match conn.write_pos {
-        Some(p) => p,
-        None => return,
-    };
+    if let Some(up) = exec.deregister(poll.registry()) {
+        upstreams.remove(&up);
+        token_pool.release(up);
+    }
+    if let Some(conn) = exec.into_upstream() {
+        pool.release(conn, now);
+    }
+}
 
-    loop {
-        let slice = &conn.write_buf[current_pos..];
-        match conn.stream.write(slice) {
-            Ok(n) => {
-                current_pos += n;
-                conn.write_pos = Some(current_pos);
-                if !conn.has_pending_write() {
-                    counter.increment(thread_id);
-                    conn.reset_for_read();
-                    let _ = poll.registry().reregister(
-                        &mut conn.stream, token,
-                        Interest::READABLE,
-                    );
-                    break;
-                }
-            }
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
-            Err(e) => {
-                eprintln!("[warn] write error on {:?}: {e}", token);
-                to_close.push(token);
-                break;
-            }
+/// Sends the spill file of `conn` as `do_write` sends a response.
+fn send_spilled(conn: &mut Conn, token: Token, poll: &Poll, budget: &mut Budget) -> io::Result<Flushed> {
+    let spill = conn.spill.as_mut().expect("sending a spill that is not there");
+    let flushed = loop {
+        if budget.bytes == 0 {
+            break Flushed::Paused;
+        }
+        match spill.send(&conn.stream, budget.bytes) {
+            Ok(0) => break Flushed::Done,
+            Ok(n) => budget.bytes = budget.bytes.saturating_sub(n),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Flushed::Blocked,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    };
+    match flushed {
+        Flushed::Done if conn.write_interest => {
+            conn.write_interest = false;
+            let _ = poll.registry().reregister(&mut conn.stream, token, Interest::READABLE);
         }
+        Flushed::Blocked if !conn.write_interest => {
+            conn.write_interest = true;
+            poll.registry().reregister(&mut conn.stream, token, Interest::READABLE | Interest::WRITABLE)?;
+        }
+        _ => {}
     }
+    Ok(flushed)
 }
 
-fn close_conn(
-    slab: &mut Slab,
-    token_pool: &mut TokenPool,
-    buf_pool: &mut BufPool,
+/// Starts connecting to a forward-proxy destination and switches `conn` to relaying. The
+/// upstream is sent the relayed request head in `conn.out`, then any client bytes already read.
+fn open_tunnel(
+    conn: &mut Conn,
+    token: Token,
+    up: Token,
+    addr: SocketAddr,
+    established: bool,
+    shared: &Shared,
     poll: &Poll,
-    tok: Token,
-) {
-    if let Some(mut c) = slab.remove(tok) {
-        let _ = poll.registry().deregister(&mut c.stream);
-        buf_pool.release(c.read_buf);
-        token_pool.release(tok);
+) -> io::Result<()> {
+    conn.out.extend_from_slice(&conn.read_buf[..conn.read_len]);
+    conn.read_len = 0;
+    let reply = if established { tunnel::ESTABLISHED } else { &[] };
+    let bad_gateway = shared.responses.bad_gateway;
+    let deadline = shared.clock.now() + shared.cfg.connect_timeout;
+    let mut t = Tunnel::open(addr, up, &conn.out, reply, bad_gateway, deadline)?;
+    t.relay = shared.backends.and_then(|stats| stats.relay(addr));
+    conn.out.clear();
+    poll.registry().register(&mut t.upstream.stream, up, Interest::READABLE | Interest::WRITABLE)?;
+    poll.registry().reregister(&mut conn.stream, token, Interest::READABLE | Interest::WRITABLE)?;
+    conn.write_interest = true;
+    conn.begin_tunnel(Box::new(t));
+    Ok(())
+}
+
+fn yield_later(yielded: &mut Vec<Token>, conn: &mut Conn, token: Token) {
+    if !conn.yielded {
+        conn.yielded = true;
+        yielded.push(token);
     }
 }
+
+#[inline]
+fn close_later(to_close: &mut Vec<Token>, conn: &mut Conn, token: Token) {
+    conn.mark_closing();
+    to_close.push(token);
+}
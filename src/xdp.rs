@@ -0,0 +1,342 @@
+//! Abuse drop list for `--xdp-drop-map`: addresses opening connections faster than
+//! `--ban-threshold` per second, or sending more than `--ban-suspicious` suspicious
+//! requests per second, are written into a pinned BPF hash map, which an XDP
+//! program attached to the interface consults to drop their packets before they reach
+//! the TCP stack. vrypt does not load the program; it only fills the map.
+//!
+//! Map layout: 16-byte keys holding the address as IPv6 (IPv4 as `::ffff:a.b.c.d`) and
+//! 8-byte values holding the ban's expiry in `CLOCK_MONOTONIC` nanoseconds, the clock
+//! `bpf_ktime_get_ns` reads, so the program can also ignore entries left behind by an
+//! instance that has exited. A maintenance thread deletes entries as their bans run out.
+
+use crate::config::{XDP_SWEEP_INTERVAL, XDP_TRACK_CAPACITY};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SHARDS: usize = 16;
+/// Connections are counted per address over windows of this length.
+const WINDOW: Duration = Duration::from_secs(1);
+
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_MAP_DELETE_ELEM: libc::c_int = 3;
+const BPF_OBJ_GET: libc::c_int = 7;
+const BPF_OBJ_GET_INFO_BY_FD: libc::c_int = 15;
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_MAP_TYPE_LRU_HASH: u32 = 9;
+
+struct Window {
+    start: Instant,
+    count: u32,
+    suspicious: u32,
+    /// Whether the address was already reported in this window.
+    reported: bool,
+}
+
+/// Why an address was reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Offence {
+    /// Over `--ban-threshold` connections per second.
+    Connections,
+    /// Over `--ban-suspicious` suspicious requests per second.
+    Suspicious,
+}
+
+/// Per-address connection and suspicious request rates, shared by all workers. Each accept
+/// and each suspicious request is counted against its peer address; the first one over its
+/// threshold within a window reports the address to the maintenance thread. At most `XDP_TRACK_CAPACITY` addresses are tracked at once;
+/// further ones go uncounted until idle entries are pruned.
+pub struct AbuseTracker {
+    shards: [Mutex<HashMap<IpAddr, Window>>; SHARDS],
+    hasher: RandomState,
+    threshold: u32,
+    suspicious: Option<u32>,
+    report: Sender<(IpAddr, Offence)>,
+}
+
+impl AbuseTracker {
+    /// A tracker reporting addresses over `threshold` connections or, if given, `suspicious`
+    /// suspicious requests per second on the returned receiver.
+    pub fn new(threshold: u32, suspicious: Option<u32>) -> (&'static Self, Receiver<(IpAddr, Offence)>) {
+        let (report, rx) = mpsc::channel();
+        let tracker = Box::leak(Box::new(Self {
+            shards: std::array::from_fn(|_| Mutex::new(HashMap::new())),
+            hasher: RandomState::new(),
+            threshold,
+            suspicious,
+            report,
+        }));
+        (tracker, rx)
+    }
+
+    /// Counts a connection accepted from `ip`.
+    pub fn record(&self, ip: IpAddr, now: Instant) {
+        self.count(ip, now, Offence::Connections, self.threshold);
+    }
+
+    /// Counts a suspicious request from `ip`; does nothing without a `suspicious` threshold.
+    pub fn record_suspicious(&self, ip: IpAddr, now: Instant) {
+        if let Some(threshold) = self.suspicious {
+            self.count(ip, now, Offence::Suspicious, threshold);
+        }
+    }
+
+    fn count(&self, ip: IpAddr, now: Instant, offence: Offence, threshold: u32) {
+        let shard = &self.shards[self.hasher.hash_one(ip) as usize % SHARDS];
+        let mut shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+        if shard.len() >= XDP_TRACK_CAPACITY / SHARDS && !shard.contains_key(&ip) {
+            return;
+        }
+        let window = shard.entry(ip).or_insert(Window { start: now, count: 0, suspicious: 0, reported: false });
+        if now.saturating_duration_since(window.start) >= WINDOW {
+            *window = Window { start: now, count: 0, suspicious: 0, reported: false };
+        }
+        let count = match offence {
+            Offence::Connections => &mut window.count,
+            Offence::Suspicious => &mut window.suspicious,
+        };
+        *count += 1;
+        if *count > threshold && !window.reported {
+            window.reported = true;
+            let _ = self.report.send((ip, offence));
+        }
+    }
+
+    /// Drops addresses whose last window has ended.
+    pub fn prune(&self, now: Instant) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            shard.retain(|_, w| now.saturating_duration_since(w.start) < WINDOW);
+        }
+    }
+
+    /// Addresses currently tracked.
+    pub fn tracked(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap_or_else(PoisonError::into_inner).len()).sum()
+    }
+}
+
+/// Where banned addresses go; `XdpMap` in production.
+pub trait DropList {
+    fn insert(&mut self, ip: IpAddr, ttl: Duration) -> io::Result<()>;
+    fn remove(&mut self, ip: IpAddr) -> io::Result<()>;
+}
+
+/// Active bans and the order they run out in.
+pub struct Bans<L> {
+    list: L,
+    ttl: Duration,
+    until: HashMap<IpAddr, Instant>,
+    /// Expiries in ban order; with one TTL for all bans that is also expiry order.
+    order: VecDeque<(Instant, IpAddr)>,
+}
+
+impl<L: DropList> Bans<L> {
+    pub fn new(list: L, ttl: Duration) -> Self {
+        Self { list, ttl, until: HashMap::new(), order: VecDeque::new() }
+    }
+
+    /// Adds `ip` to the drop list. Returns `false` when it is already banned.
+    pub fn ban(&mut self, ip: IpAddr, now: Instant) -> io::Result<bool> {
+        if self.until.contains_key(&ip) {
+            return Ok(false);
+        }
+        self.list.insert(ip, self.ttl)?;
+        let until = now + self.ttl;
+        self.until.insert(ip, until);
+        self.order.push_back((until, ip));
+        Ok(true)
+    }
+
+    /// Lifts the bans that have run out by `now`, returning how many were lifted.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let mut lifted = 0;
+        while let Some(&(until, ip)) = self.order.front() {
+            if until > now {
+                break;
+            }
+            self.order.pop_front();
+            self.until.remove(&ip);
+            if let Err(e) = self.list.remove(ip) {
+                eprintln!("[warn] cannot remove {ip} from the XDP drop map: {e}");
+            }
+            lifted += 1;
+        }
+        lifted
+    }
+
+    pub fn len(&self) -> usize {
+        self.until.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.until.is_empty()
+    }
+
+    pub fn list(&self) -> &L {
+        &self.list
+    }
+}
+
+/// Starts the maintenance thread: bans what `tracker` reports on `rx`, and every
+/// `XDP_SWEEP_INTERVAL` lifts expired bans and prunes idle addresses from the tracker.
+pub fn spawn<L: DropList + Send + 'static>(
+    tracker: &'static AbuseTracker,
+    rx: Receiver<(IpAddr, Offence)>,
+    list: L,
+    ttl: Duration,
+) -> io::Result<()> {
+    thread::Builder::new().name("vrypt-xdp".to_string()).spawn(move || {
+        let mut bans = Bans::new(list, ttl);
+        let mut swept = Instant::now();
+        loop {
+            match rx.recv_timeout(XDP_SWEEP_INTERVAL) {
+                Ok((ip, offence)) => match bans.ban(ip, Instant::now()) {
+                    Ok(true) => {
+                        let over = match offence {
+                            Offence::Connections => format!("{} connections/s", tracker.threshold),
+                            Offence::Suspicious => format!("{} suspicious requests/s", tracker.suspicious.unwrap_or(0)),
+                        };
+                        eprintln!("[xdp] dropping {ip} for {}s: over {over}", ttl.as_secs());
+                    }
+                    Ok(false) => {}
+                    Err(e) => eprintln!("[warn] cannot add {ip} to the XDP drop map: {e}"),
+                },
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            let now = Instant::now();
+            if now.duration_since(swept) >= XDP_SWEEP_INTERVAL {
+                bans.expire(now);
+                tracker.prune(now);
+                swept = now;
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// A pinned BPF hash map with the layout described in the module docs.
+pub struct XdpMap {
+    fd: OwnedFd,
+}
+
+#[repr(C, align(8))]
+struct ObjGetAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+#[repr(C, align(8))]
+struct InfoAttr {
+    bpf_fd: u32,
+    info_len: u32,
+    info: u64,
+}
+
+/// Leading fields of `struct bpf_map_info`; the kernel fills in as much as is asked for.
+#[repr(C)]
+#[derive(Default)]
+struct MapInfo {
+    map_type: u32,
+    id: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C, align(8))]
+struct ElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+impl XdpMap {
+    /// Opens the map pinned at `path` and checks it is a hash map with 16-byte keys and 8-byte values.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| io::ErrorKind::InvalidInput)?;
+        let fd = bpf(BPF_OBJ_GET, &ObjGetAttr { pathname: c_path.as_ptr() as u64, bpf_fd: 0, file_flags: 0 })?;
+        // SAFETY: BPF_OBJ_GET returned a new descriptor that nothing else owns.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut info = MapInfo::default();
+        let attr = InfoAttr {
+            bpf_fd: fd.as_raw_fd() as u32,
+            info_len: size_of::<MapInfo>() as u32,
+            info: &mut info as *mut MapInfo as u64,
+        };
+        bpf(BPF_OBJ_GET_INFO_BY_FD, &attr)?;
+        let hash = matches!(info.map_type, BPF_MAP_TYPE_HASH | BPF_MAP_TYPE_LRU_HASH);
+        if !hash || info.key_size != 16 || info.value_size != 8 {
+            let found =
+                format!("type {} with {}-byte keys, {}-byte values", info.map_type, info.key_size, info.value_size);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected a hash map with 16-byte keys and 8-byte values, found {found}"),
+            ));
+        }
+        Ok(Self { fd })
+    }
+
+    fn elem(&self, key: &[u8; 16], value: Option<&u64>) -> ElemAttr {
+        ElemAttr {
+            map_fd: self.fd.as_raw_fd() as u32,
+            _pad: 0,
+            key: key.as_ptr() as u64,
+            value: value.map_or(0, |v| v as *const u64 as u64),
+            flags: 0, // BPF_ANY
+        }
+    }
+}
+
+impl DropList for XdpMap {
+    fn insert(&mut self, ip: IpAddr, ttl: Duration) -> io::Result<()> {
+        let expires = monotonic_ns().saturating_add(ttl.as_nanos() as u64);
+        bpf(BPF_MAP_UPDATE_ELEM, &self.elem(&key(ip), Some(&expires))).map(drop)
+    }
+
+    fn remove(&mut self, ip: IpAddr) -> io::Result<()> {
+        match bpf(BPF_MAP_DELETE_ELEM, &self.elem(&key(ip), None)) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            res => res.map(drop),
+        }
+    }
+}
+
+/// The map key for `ip`.
+pub fn key(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    }
+}
+
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `ts` is a valid, writable timespec.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &T) -> io::Result<libc::c_int> {
+    // SAFETY: `attr` is a `#[repr(C)]` prefix of `union bpf_attr` for `cmd`, and the
+    // pointers it carries outlive the call.
+    let rc = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *const T, size_of::<T>() as libc::c_uint) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(rc as libc::c_int)
+}
@@ -0,0 +1,55 @@
+use std::time::{Duration, Instant};
+use vrypt_server::affinity::{AffinityCounts, AffinityTable};
+
+const TTL: Duration = Duration::from_secs(60);
+
+#[test]
+fn least_recently_used_key_is_evicted_when_full() {
+    let now = Instant::now();
+    let mut table = AffinityTable::new(3, TTL);
+    for key in 1..=3 {
+        table.insert(key, key as u32 * 10, now);
+    }
+    assert_eq!(table.get(1, now), Some(10));
+    table.insert(4, 40, now);
+    assert_eq!(table.get(2, now), None);
+    assert_eq!(table.get(1, now), Some(10));
+    assert_eq!(table.get(3, now), Some(30));
+    assert_eq!(table.get(4, now), Some(40));
+    assert_eq!(table.take_counts(), AffinityCounts { hits: 4, misses: 1, evictions: 1, expired: 0 });
+    assert_eq!(table.take_counts(), AffinityCounts::default());
+}
+
+#[test]
+fn entries_expire_after_the_ttl_since_their_last_use() {
+    let now = Instant::now();
+    let mut table = AffinityTable::new(8, TTL);
+    table.insert(1, 1, now);
+    table.insert(2, 2, now);
+    assert_eq!(table.get(1, now + TTL / 2), Some(1));
+    assert_eq!(table.get(1, now + TTL), Some(1));
+    assert_eq!(table.get(2, now + TTL), None);
+    assert_eq!(table.take_counts(), AffinityCounts { hits: 2, misses: 1, evictions: 0, expired: 1 });
+
+    // The freed entry is reused, and overwriting a key keeps a single entry for it.
+    table.insert(2, 5, now + TTL);
+    table.insert(2, 6, now + TTL);
+    assert_eq!(table.get(2, now + TTL), Some(6));
+}
+
+#[test]
+fn colliding_keys_are_kept_apart() {
+    let now = Instant::now();
+    let mut table = AffinityTable::new(4, TTL);
+    // Same low bits, so the same bucket in a four-bucket table.
+    let keys = [0x10, 0x20, 0x30, 0x40];
+    for (i, &key) in keys.iter().enumerate() {
+        table.insert(key, i as u32, now);
+    }
+    table.insert(0x50, 9, now);
+    assert_eq!(table.get(0x10, now), None);
+    for (i, &key) in keys.iter().enumerate().skip(1) {
+        assert_eq!(table.get(key, now), Some(i as u32));
+    }
+    assert_eq!(table.get(0x50, now), Some(9));
+}
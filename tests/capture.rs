@@ -0,0 +1,29 @@
+//! The capture toggle is process-wide, so it is flipped in a test binary of its own.
+
+mod support;
+
+use std::fs::File;
+use std::time::Duration;
+use support::Client;
+use vrypt_server::capture::{self, Direction};
+use vrypt_server::config::Config;
+
+#[test]
+fn admin_toggle_pauses_capture_of_new_connections() {
+    let path = std::env::temp_dir().join(format!("vrypt-test-{}-toggle.cap", std::process::id()));
+    let server = support::start_server(Config { admin: true, capture_path: Some(path.clone()), ..Config::default() });
+    let mut admin = Client::connect(server.addr);
+    admin.send(b"PUT /__vrypt/toggles/capture?off HTTP/1.1\r\n\r\n");
+    assert_eq!(admin.read_response().status, 200);
+    assert_eq!(Client::connect(server.addr).get("/skipped").status, 200);
+    admin.send(b"PUT /__vrypt/toggles/capture?on HTTP/1.1\r\n\r\n");
+    assert_eq!(admin.read_response().status, 200);
+    assert_eq!(Client::connect(server.addr).get("/kept").status, 200);
+    std::thread::sleep(Duration::from_millis(100));
+
+    let records = capture::read_records(File::open(&path).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let requests: Vec<_> = records.iter().filter(|r| r.dir == Direction::Request).map(|r| &r.data).collect();
+    assert!(requests.iter().any(|r| r.starts_with(b"GET /kept ")), "{requests:?}");
+    assert!(!requests.iter().any(|r| r.starts_with(b"GET /skipped ")), "{requests:?}");
+}
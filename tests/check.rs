@@ -0,0 +1,54 @@
+//! The `check` subcommand against route backends and upstreams.
+
+mod support;
+
+use std::os::unix::net::UnixListener;
+use vrypt_server::check;
+use vrypt_server::config::Config;
+use vrypt_server::routes::StaticRoute;
+use vrypt_server::split::{Split, SplitGroup};
+
+fn checks(routes: &[String]) -> bool {
+    let static_routes = routes.iter().map(|r| StaticRoute::parse(r).unwrap()).collect();
+    check::run(&Config { addr: support::free_addr(), static_routes, ..Config::default() }, 0)
+}
+
+#[test]
+fn backend_routes_are_checked_by_their_socket() {
+    let dir = std::env::temp_dir().join(format!("vrypt-check-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let sock = dir.join("fcgi.sock");
+    let _ = std::fs::remove_file(&sock);
+    let _listener = UnixListener::bind(&sock).unwrap();
+
+    assert!(checks(&[
+        format!("fastcgi:/app.php={} /srv/app.php", sock.display()),
+        format!("scgi:/app={}", sock.display()),
+    ]));
+    // Missing, and a regular file where the socket should be.
+    assert!(!checks(&[format!("fastcgi:/down={}", dir.join("missing.sock").display())]));
+    let plain = dir.join("plain");
+    std::fs::write(&plain, b"x").unwrap();
+    assert!(!checks(&[format!("scgi:/plain={}", plain.display())]));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn command_routes_need_an_executable_program() {
+    assert!(checks(&["exec:/date=date -u".to_string(), "exec:/sh=/bin/sh -c true".to_string()]));
+    assert!(!checks(&["exec:/none=vrypt-no-such-program".to_string()]));
+    let script = std::env::temp_dir().join(format!("vrypt-check-{}-script", std::process::id()));
+    std::fs::write(&script, b"#!/bin/sh\n").unwrap();
+    assert!(!checks(&[format!("exec:/script={}", script.display())]));
+    let _ = std::fs::remove_file(&script);
+}
+
+#[test]
+fn split_groups_need_a_listening_upstream() {
+    let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let group = |addr| SplitGroup::parse(&format!("canary=5@{addr}")).unwrap();
+    let split = |addr| Split { groups: vec![group(addr)], ..Split::default() };
+    let cfg = |split| Config { addr: support::free_addr(), split, ..Config::default() };
+    assert!(check::run(&cfg(split(upstream.local_addr().unwrap())), 0));
+    assert!(!check::run(&cfg(split(support::free_addr())), 0));
+}
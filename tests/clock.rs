@@ -0,0 +1,54 @@
+//! Worker timeouts driven by a simulated clock instead of real sleeps. Built only with the
+//! `sim-clock` feature:
+//!
+//! ```text
+//! cargo test --features sim-clock --test clock
+//! ```
+
+mod support;
+
+use std::time::{Duration, Instant};
+use support::Client;
+use vrypt_server::clock::SimClock;
+use vrypt_server::config::Config;
+
+#[test]
+fn idle_keep_alive_connection_closes_once_the_clock_passes_its_timeout() {
+    let clock = SimClock::new();
+    let keepalive_timeout = Duration::from_secs(60);
+    let addr = support::start_with_clock(Config { keepalive_timeout, ..Config::default() }, clock);
+    let mut c = Client::connect(addr);
+    assert_eq!(c.get("/").status, 200);
+
+    let started = Instant::now();
+    clock.advance(keepalive_timeout + Duration::from_secs(2));
+    assert!(c.is_closed());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn unfinished_head_gets_408_after_the_header_timeout_of_simulated_time() {
+    let clock = SimClock::new();
+    let header_timeout = Duration::from_secs(30);
+    let addr = support::start_with_clock(Config { header_timeout, ..Config::default() }, clock);
+    let mut c = Client::connect(addr);
+    // Once the first response is back, the worker has read the partial head behind it too.
+    c.send(b"GET / HTTP/1.1\r\nHost: test\r\n\r\nGET / HTTP/1.1\r\nHost: te");
+    assert_eq!(c.read_response().status, 200);
+
+    clock.advance(header_timeout + Duration::from_secs(2));
+    assert_eq!(c.read_response().status, 408);
+}
+
+#[test]
+fn keep_alive_connection_survives_while_the_clock_stays_within_its_timeout() {
+    let clock = SimClock::new();
+    let keepalive_timeout = Duration::from_secs(10);
+    let addr = support::start_with_clock(Config { keepalive_timeout, ..Config::default() }, clock);
+    let mut c = Client::connect(addr);
+    for _ in 0..5 {
+        assert_eq!(c.get("/").status, 200);
+        clock.advance(Duration::from_secs(5));
+    }
+    assert_eq!(c.get("/").status, 200);
+}
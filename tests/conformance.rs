@@ -0,0 +1,97 @@
+//! HTTP/1.1 message-framing corpus: request heads and bodies on the edges of RFC 9112,
+//! each with the response the server must give. Locks in what the parser accepts and
+//! what it refuses.
+
+mod support;
+
+use support::Client;
+
+enum Expect {
+    Status(u16),
+    /// 200 with a body starting with these bytes; tells routed requests apart.
+    Body(&'static [u8]),
+}
+
+use Expect::{Body, Status};
+
+const CASES: &[(&str, &[u8], Expect)] = &[
+    // Accepted.
+    ("minimal", b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", Status(200)),
+    ("http/1.0", b"GET / HTTP/1.0\r\n\r\n", Status(200)),
+    ("no headers", b"GET / HTTP/1.1\r\n\r\n", Status(200)),
+    ("lf-only line endings", b"GET / HTTP/1.1\nHost: a\n\n", Status(200)),
+    ("mixed line endings", b"GET / HTTP/1.1\r\nHost: a\n\r\n", Status(200)),
+    ("leading empty line", b"\r\nGET / HTTP/1.1\r\nHost: a\r\n\r\n", Status(200)),
+    ("repeated spaces in request line", b"GET  /  HTTP/1.1\r\n\r\n", Status(200)),
+    ("whitespace around value", b"GET / HTTP/1.1\r\nHost: \t a \t\r\n\r\n", Status(200)),
+    ("empty value", b"GET / HTTP/1.1\r\nX-Empty:\r\n\r\n", Status(200)),
+    ("no space after colon", b"GET / HTTP/1.1\r\nHost:a\r\n\r\n", Status(200)),
+    ("header name case", b"GET / HTTP/1.1\r\nhOsT: a\r\n\r\n", Status(200)),
+    ("tchar header name", b"GET / HTTP/1.1\r\nX-a.b_c!~: 1\r\n\r\n", Status(200)),
+    ("absolute-form", b"GET http://example.com/__vrypt/version HTTP/1.1\r\n\r\n", Body(b"version:")),
+    ("absolute-form with port and query", b"GET HTTPS://example.com:8443/__vrypt/version?x HTTP/1.1\r\n\r\n", Body(b"version:")),
+    ("absolute-form without path", b"GET http://example.com HTTP/1.1\r\n\r\n", Body(b"Vrypt")),
+    ("content-length body", b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello", Status(200)),
+    ("repeated equal content-length", b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nhi", Status(200)),
+    ("content-length list", b"POST / HTTP/1.1\r\nContent-Length: 2, 2\r\n\r\nhi", Status(400)),
+    ("chunked body", b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhi\r\n0\r\n\r\n", Status(200)),
+    ("chunked case", b"POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n0\r\n\r\n", Status(200)),
+    ("chunk extension", b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2;x=y\r\nhi\r\n0\r\n\r\n", Status(200)),
+    (
+        "h2c upgrade is answered over HTTP/1.1",
+        b"GET / HTTP/1.1\r\nHost: a\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n",
+        Body(b"Vrypt"),
+    ),
+    // Rejected.
+    ("obs-fold", b"GET / HTTP/1.1\r\nX-Folded: a\r\n b\r\n\r\n", Status(400)),
+    ("obs-fold with tab", b"GET / HTTP/1.1\r\nX-Folded: a\r\n\tb\r\n\r\n", Status(400)),
+    ("space before colon", b"GET / HTTP/1.1\r\nHost : a\r\n\r\n", Status(400)),
+    ("space in name", b"GET / HTTP/1.1\r\nX Y: a\r\n\r\n", Status(400)),
+    ("line without colon", b"GET / HTTP/1.1\r\nNoColon\r\n\r\n", Status(400)),
+    ("empty name", b"GET / HTTP/1.1\r\n: a\r\n\r\n", Status(400)),
+    ("bare CR in value", b"GET / HTTP/1.1\r\nX: a\rb\r\n\r\n", Status(400)),
+    ("NUL in value", b"GET / HTTP/1.1\r\nX: a\0b\r\n\r\n", Status(400)),
+    ("tab in request line", b"GET\t/ HTTP/1.1\r\n\r\n", Status(400)),
+    ("missing version", b"GET /\r\n\r\n", Status(400)),
+    ("misspelled version", b"GET / HTTX/1.1\r\n\r\n", Status(400)),
+    ("version without minor", b"GET / HTTP/1\r\n\r\n", Status(400)),
+    ("lowercase version", b"GET / http/1.1\r\n\r\n", Status(400)),
+    ("extra request-line token", b"GET / HTTP/1.1 x\r\n\r\n", Status(400)),
+    ("non-token method", b"G(T / HTTP/1.1\r\n\r\n", Status(400)),
+    ("control byte in target", b"GET /a\x7fb HTTP/1.1\r\n\r\n", Status(400)),
+    ("conflicting content-length", b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\nhi", Status(400)),
+    ("signed content-length", b"POST / HTTP/1.1\r\nContent-Length: +2\r\n\r\nhi", Status(400)),
+    ("both framings", b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n0\r\n\r\n", Status(400)),
+    ("unsupported coding", b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n", Status(400)),
+    ("chunked not last", b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n", Status(400)),
+    ("bad chunk size", b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nhi\r\n0\r\n\r\n", Status(400)),
+    ("not a method", b"\x01GET / HTTP/1.1\r\n\r\n", Status(400)),
+];
+
+#[test]
+fn request_framing_corpus() {
+    let addr = support::start_default();
+    let mut failures = Vec::new();
+    for (name, request, expect) in CASES {
+        let mut c = Client::connect(addr);
+        c.send(request);
+        let res = c.read_response();
+        let ok = match expect {
+            Status(status) => res.status == *status,
+            Body(prefix) => res.status == 200 && res.body.starts_with(prefix),
+        };
+        if !ok {
+            failures.push(format!("{name}: got {} {:?}", res.status, String::from_utf8_lossy(&res.body)));
+        }
+    }
+    assert!(failures.is_empty(), "non-conforming responses:\n{}", failures.join("\n"));
+}
+
+#[test]
+fn pipelined_lf_only_requests_are_framed_separately() {
+    let addr = support::start_default();
+    let mut c = Client::connect(addr);
+    c.send(b"GET / HTTP/1.1\n\nGET /__vrypt/version HTTP/1.1\n\n");
+    assert_eq!(c.read_response().body, b"Vrypt");
+    assert!(c.read_response().body.starts_with(b"version:"));
+}
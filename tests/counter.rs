@@ -0,0 +1,56 @@
+use std::io;
+use vrypt_server::counter::{AcceptError, RpsCounter};
+
+fn classify(errno: i32) -> AcceptError {
+    AcceptError::classify(&io::Error::from_raw_os_error(errno))
+}
+
+#[test]
+fn accept_errors_are_classified_by_errno() {
+    assert_eq!(classify(libc::EMFILE), AcceptError::FdLimit);
+    assert_eq!(classify(libc::ENFILE), AcceptError::FdLimit);
+    assert_eq!(classify(libc::ENOBUFS), AcceptError::NoMemory);
+    assert_eq!(classify(libc::ECONNABORTED), AcceptError::Aborted);
+    assert_eq!(classify(libc::EPROTO), AcceptError::Protocol);
+    assert_eq!(classify(libc::EINTR), AcceptError::Interrupted);
+    assert_eq!(classify(libc::EINVAL), AcceptError::Other);
+}
+
+#[test]
+fn only_errors_about_one_connection_keep_the_accept_loop_going() {
+    let transient: Vec<_> = AcceptError::ALL.into_iter().filter(|k| k.is_transient()).collect();
+    assert_eq!(
+        transient,
+        [AcceptError::NoMemory, AcceptError::Aborted, AcceptError::Protocol, AcceptError::Interrupted]
+    );
+}
+
+#[test]
+fn each_accept_error_kind_has_its_own_counter() {
+    let counter = RpsCounter::new(2);
+    counter.accept_error(0, AcceptError::Aborted);
+    counter.accept_error(1, AcceptError::Aborted);
+    counter.accept_error(1, AcceptError::Interrupted);
+    assert_eq!(counter.accept_errors(AcceptError::Aborted), 2);
+    assert_eq!(counter.accept_errors(AcceptError::Interrupted), 1);
+    assert_eq!(counter.accept_errors(AcceptError::Protocol), 0);
+    assert_eq!(counter.snapshot().accept_errors["aborted"], 2);
+}
+
+#[test]
+fn reset_starts_the_counters_over_and_hands_back_the_old_ones() {
+    let counter = RpsCounter::new(2);
+    counter.increment(0);
+    counter.increment(1);
+    counter.accept_error(1, AcceptError::Aborted);
+    counter.set_memory(0, 4096);
+    let before = counter.reset();
+    assert_eq!((before.requests, before.workers[1].requests, before.accept_errors["aborted"]), (2, 1, 1));
+
+    counter.increment(1);
+    let after = counter.snapshot();
+    assert_eq!((after.requests, after.workers[0].requests, after.workers[1].requests), (1, 0, 1));
+    assert_eq!(after.accept_errors["aborted"], 0);
+    assert_eq!(after.memory_bytes, 4096, "gauges are not reset");
+    assert_eq!(counter.total(), 3, "raw totals, which the stats push takes differences of, keep counting");
+}
@@ -0,0 +1,1711 @@
+mod support;
+
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use support::Client;
+use vrypt_server::close::{CloseMode, CloseReason, CloseRule};
+use vrypt_server::config::{Config, BUF_SIZE, MAX_RECYCLED_BUFS, POLL_TIMEOUT};
+use vrypt_server::conn::ConnState;
+use vrypt_server::counter::PoolStat;
+use vrypt_server::error::VryptError;
+use vrypt_server::error_page::ErrorPage;
+use vrypt_server::fault::FaultRule;
+use vrypt_server::headers::{HeaderRule, RouteHeader};
+use vrypt_server::limit::RouteLimit;
+use vrypt_server::listen::AcceptMode;
+use vrypt_server::mime::MimeMap;
+use vrypt_server::mirror::{Mirror, MirrorOutcome};
+use vrypt_server::redirect::RedirectRule;
+use vrypt_server::region::BufBacking;
+use vrypt_server::rewrite::PathRewrite;
+use vrypt_server::routes::StaticRoute;
+use vrypt_server::server::Server;
+use vrypt_server::sink::SinkMode;
+use vrypt_server::tenant::{Tenant, TenantStat};
+use vrypt_server::split::{Split, SplitGroup, Sticky};
+use vrypt_server::tunnel::{ProxyTarget, ESTABLISHED};
+use vrypt_server::upstream::UpstreamStat;
+
+#[test]
+fn keep_alive_serves_several_requests() {
+    let addr = support::start_default();
+    let mut c = Client::connect(addr);
+    for _ in 0..3 {
+        let res = c.get("/");
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body, b"Vrypt");
+        assert_eq!(res.header("connection"), Some("keep-alive"));
+    }
+}
+
+#[test]
+fn pipelined_requests_are_answered_in_order() {
+    let addr = support::start_default();
+    let mut c = Client::connect(addr);
+    c.send(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n");
+    for _ in 0..3 {
+        assert_eq!(c.read_response().status, 200);
+    }
+}
+
+#[test]
+fn pipelined_batches_longer_than_a_round_are_all_answered() {
+    let addr = support::start_default();
+    let mut c = Client::connect(addr);
+    c.send(&b"GET / HTTP/1.1\r\n\r\n".repeat(300));
+    for _ in 0..300 {
+        assert_eq!(c.read_response().status, 200);
+    }
+    assert_eq!(c.get("/").status, 200);
+}
+
+#[test]
+fn request_heads_larger_than_the_read_buffer_spill_up_to_the_cap() {
+    let server = support::start_server(Config { max_header_size: 32 * 1024, ..Config::default() });
+    let mut c = Client::connect(server.addr);
+    let cookie = "c".repeat(20 * 1024);
+    c.send(format!("GET / HTTP/1.1\r\nCookie: {cookie}\r\n\r\nGET /next HTTP/1.1\r\n\r\n").as_bytes());
+    assert_eq!(c.read_response().status, 200);
+    assert_eq!(c.read_response().status, 200);
+    assert_eq!(server.shared.counter.header_spills(), 1);
+
+    let cookie = "c".repeat(40 * 1024);
+    c.send(format!("GET / HTTP/1.1\r\nCookie: {cookie}\r\n\r\n").as_bytes());
+    assert_eq!(c.read_response().status, 431);
+}
+
+#[test]
+fn idle_connection_times_out() {
+    let addr = support::start(Config { header_timeout: Duration::from_secs(1), ..Config::default() });
+    let mut c = Client::connect(addr);
+    let start = Instant::now();
+    c.send(b"GET / HT");
+    assert!(c.is_closed());
+    assert!(start.elapsed() >= Duration::from_secs(1));
+}
+
+#[test]
+fn oversized_request_head_is_rejected() {
+    let addr = support::start_default();
+    let mut c = Client::connect(addr);
+    c.send(b"GET / HTTP/1.1\r\n");
+    for _ in 0..70 {
+        c.send(&[b'a'; 1024]);
+    }
+    let res = c.read_response();
+    assert_eq!(res.status, 431);
+    assert_eq!(res.header("connection"), Some("close"));
+    assert!(c.is_closed());
+}
+
+#[test]
+fn responses_carry_an_imf_fixdate() {
+    let addr = support::start_default();
+    let mut c = Client::connect(addr);
+    let res = c.get("/");
+    let date = res.header("date").expect("Date header");
+    assert_eq!(date.len(), 29, "{date}");
+    assert!(date.ends_with(" GMT") && date.as_bytes()[3] == b',', "{date}");
+}
+
+#[test]
+fn date_header_can_be_disabled() {
+    let addr = support::start(Config { date: false, ..Config::default() });
+    let mut c = Client::connect(addr);
+    assert_eq!(c.get("/").header("date"), None);
+}
+
+#[test]
+fn server_header_names_the_version() {
+    let addr = support::start_default();
+    let mut c = Client::connect(addr);
+    let expected = concat!("vrypt/", env!("CARGO_PKG_VERSION"));
+    assert_eq!(c.get("/").header("server"), Some(expected));
+}
+
+#[test]
+fn version_endpoint_bypasses_redirects() {
+    let rule = RedirectRule::parse("302", "/(.*)", "https://example.com/$1").unwrap();
+    let addr = support::start(Config { redirects: vec![rule], ..Config::default() });
+    let mut c = Client::connect(addr);
+    let res = c.get("/__vrypt/version");
+    assert_eq!(res.status, 200);
+    let body = String::from_utf8(res.body).unwrap();
+    assert!(body.starts_with(concat!("version: ", env!("CARGO_PKG_VERSION"), "\n")), "{body}");
+    assert_eq!(c.get("/").status, 302);
+}
+
+#[test]
+fn tls_client_hello_gets_an_alert() {
+    let addr = support::start_default();
+    let mut c = Client::connect(addr);
+    c.send(&[0x16, 0x03, 0x01, 0x00, 0xf4, 0x01, 0x00, 0x00, 0xf0, 0x03, 0x03]);
+    assert_eq!(c.read_to_close(), [0x15, 0x03, 0x01, 0x00, 0x02, 0x02, 0x28]);
+}
+
+#[test]
+fn h2_prior_knowledge_gets_a_goaway() {
+    let addr = support::start_default();
+    let mut c = Client::connect(addr);
+    c.send(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n");
+    let frames = c.read_to_close();
+    assert_eq!(frames[..9], [0, 0, 0, 0x04, 0, 0, 0, 0, 0]);
+    assert_eq!(frames[9..13], [0, 0, 8, 0x07]);
+    assert_eq!(frames[frames.len() - 4..], [0, 0, 0, 0x0d]);
+}
+
+#[test]
+fn garbage_preface_is_rejected_at_once() {
+    let addr = support::start_default();
+    let mut c = Client::connect(addr);
+    c.send(b"\x00\x01garbage");
+    let res = c.read_response();
+    assert_eq!(res.status, 400);
+    assert!(c.is_closed());
+}
+
+#[test]
+fn close_rules_pick_how_timeouts_and_errors_are_closed_and_count_them() {
+    let server = support::start_server(Config {
+        close_rules: vec![CloseRule::parse("timeout=rst").unwrap(), CloseRule::parse("error=fin").unwrap()],
+        keepalive_timeout: Duration::from_secs(1),
+        ..Config::default()
+    });
+    let counter = server.shared.counter;
+    let mut bad = Client::connect(server.addr);
+    bad.send(b"\x00\x01garbage");
+    assert_eq!(bad.read_response().status, 400);
+    assert!(bad.is_closed());
+    assert_eq!(counter.closes(CloseReason::Error, CloseMode::Fin), 1);
+    assert_eq!(counter.closes(CloseReason::Error, CloseMode::Drain), 0);
+
+    let mut idle = TcpStream::connect(server.addr).unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    std::io::Write::write_all(&mut idle, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+    let mut buf = [0u8; 1024];
+    assert!(std::io::Read::read(&mut idle, &mut buf).unwrap() > 0);
+    let err = std::io::Read::read(&mut idle, &mut buf).expect_err("keep-alive timeout should reset");
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    assert_eq!(counter.closes(CloseReason::Timeout, CloseMode::Rst), 1);
+    assert_eq!(counter.snapshot().closes["timeout.rst"], 1);
+}
+
+#[test]
+fn suspicious_request_heads_are_counted_by_kind() {
+    let server = support::start_server(Config { max_header_size: 32 * 1024, ..Config::default() });
+    let rejected: [&[u8]; 4] = [
+        b"G\x01T / HTTP/1.1\r\n\r\n",
+        b"GET / HTTP/1.1\r\nX-Junk: a\x00b\r\n\r\n",
+        b"POST / HTTP/1.1\r\nContent-Length: 2\r\nTransfer-Encoding: chunked\r\n\r\n",
+        b"GET / HTTP/1.1\r\nno colon here\r\n\r\n",
+    ];
+    for head in rejected {
+        let mut c = Client::connect(server.addr);
+        c.send(head);
+        assert_eq!(c.read_response().status, 400, "{}", String::from_utf8_lossy(head));
+    }
+    let long = "a".repeat(40 * 1024);
+    for head in [format!("GET /{long} HTTP/1.1\r\n\r\n"), format!("GET / HTTP/1.1\r\nX-Long: {long}\r\n\r\n")] {
+        let mut c = Client::connect(server.addr);
+        c.send(head.as_bytes());
+        assert_eq!(c.read_response().status, 431);
+    }
+    let mut c = Client::connect(server.addr);
+    c.send(b"GET / HTTP/1.1\r\nX-Name: caf\xe9\r\n\r\n");
+    assert_eq!(c.read_response().status, 200, "not UTF-8 but still served");
+
+    let counts = server.shared.counter.snapshot().suspicious;
+    let expected = [
+        ("bad_method", 1),
+        ("bad_utf8", 1),
+        ("long_head", 1),
+        ("long_uri", 1),
+        ("malformed", 1),
+        ("nul", 1),
+        ("smuggling", 1),
+    ];
+    assert_eq!(counts.into_iter().collect::<Vec<_>>(), expected.map(|(k, n)| (k.to_string(), n)));
+}
+
+fn proxy_protocol_server() -> std::net::SocketAddr {
+    let template = std::env::temp_dir().join(format!("vrypt-test-{}-remote.tpl", std::process::id()));
+    std::fs::write(&template, "{{remote_addr}}").unwrap();
+    support::start(Config { proxy_protocol: true, template: Some(template), ..Config::default() })
+}
+
+#[test]
+fn proxy_protocol_v1_sets_the_client_address() {
+    let addr = proxy_protocol_server();
+    let mut c = Client::connect(addr);
+    c.send(b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 80\r\n");
+    assert_eq!(c.get("/").body, b"203.0.113.7");
+}
+
+#[test]
+fn proxy_protocol_v2_sets_the_client_address() {
+    let addr = proxy_protocol_server();
+    let mut c = Client::connect(addr);
+    let mut preamble = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+    preamble.extend_from_slice(&[198, 51, 100, 9, 10, 0, 0, 1, 0xc7, 0x38, 0, 80]);
+    c.send(&preamble);
+    assert_eq!(c.get("/").body, b"198.51.100.9");
+}
+
+#[test]
+fn proxy_protocol_preamble_is_optional() {
+    let addr = proxy_protocol_server();
+    let mut c = Client::connect(addr);
+    assert_eq!(c.get("/").body, b"127.0.0.1");
+}
+
+#[test]
+fn malformed_proxy_preamble_closes() {
+    let addr = proxy_protocol_server();
+    let mut c = Client::connect(addr);
+    c.send(b"PROXY TCP4 nonsense\r\nGET / HTTP/1.1\r\n\r\n");
+    assert!(c.read_to_close().is_empty());
+}
+
+#[test]
+fn admin_upload_replaces_the_default_body() {
+    let addr = support::start(Config { admin: true, ..Config::default() });
+    let mut c = Client::connect(addr);
+    c.send(b"PUT /__vrypt/bodies/default HTTP/1.1\r\nContent-Length: 8\r\n\r\n{\"a\":1}\n");
+    assert_eq!(c.read_response().status, 200);
+    let res = c.get("/");
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, b"{\"a\":1}\n");
+    assert_eq!(Client::connect(addr).get("/").body, b"{\"a\":1}\n");
+}
+
+#[test]
+fn admin_upload_accepts_chunked_bodies() {
+    let addr = support::start(Config { admin: true, ..Config::default() });
+    let mut c = Client::connect(addr);
+    c.send(b"PUT /__vrypt/bodies/default HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nnew\r\n5\r\n body\r\n0\r\n\r\n");
+    assert_eq!(c.read_response().status, 200);
+    assert_eq!(c.get("/").body, b"new body");
+}
+
+#[test]
+fn responses_that_outgrow_the_socket_buffer_finish_once_the_client_reads() {
+    let addr = support::start(Config { admin: true, ..Config::default() });
+    let body = vec![b'x'; 8 << 20];
+    let mut c = Client::connect(addr);
+    c.send(format!("PUT /__vrypt/bodies/default HTTP/1.1\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes());
+    c.send(&body);
+    assert_eq!(c.read_response().status, 200);
+    c.send(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n");
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(c.read_response().body.len(), body.len());
+    assert_eq!(c.get("/").body.len(), body.len());
+}
+
+#[test]
+fn admin_upload_over_the_limit_is_refused() {
+    let addr = support::start(Config { admin: true, ..Config::default() });
+    let mut c = Client::connect(addr);
+    c.send(b"PUT /__vrypt/bodies/default HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\n");
+    assert_eq!(c.read_response().status, 413);
+    assert_eq!(Client::connect(addr).get("/").body, b"Vrypt");
+}
+
+#[test]
+fn admin_endpoints_are_off_by_default() {
+    let addr = support::start_default();
+    let mut c = Client::connect(addr);
+    c.send(b"PUT /__vrypt/bodies/default HTTP/1.1\r\nContent-Length: 3\r\n\r\nnew");
+    assert_eq!(c.read_response().body, b"Vrypt");
+    assert_eq!(c.get("/").body, b"Vrypt");
+}
+
+#[test]
+fn idle_connections_are_reaped_over_the_memory_limit() {
+    let addr = support::start(Config { memory_limit: Some(1), ..Config::default() });
+    let mut c = Client::connect(addr);
+    assert_eq!(c.get("/").status, 200);
+    let start = Instant::now();
+    assert!(c.is_closed());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn admin_reports_allocator_stats() {
+    let addr = support::start(Config { admin: true, ..Config::default() });
+    let res = Client::connect(addr).get("/__vrypt/allocator");
+    assert_eq!(res.status, 200);
+    let (name, stat) = if cfg!(feature = "jemalloc") {
+        ("jemalloc", "\nallocated_bytes: ")
+    } else if cfg!(feature = "mimalloc") {
+        ("mimalloc", "\ncommitted_bytes: ")
+    } else {
+        ("system", if cfg!(target_env = "gnu") { "\narena_bytes: " } else { "\nheap: " })
+    };
+    let body = String::from_utf8(res.body).unwrap();
+    assert!(body.starts_with(&format!("allocator: {name}\n")), "{body}");
+    assert!(body.contains(stat), "{body}");
+}
+
+#[test]
+fn admin_lists_open_connections_with_their_peers() {
+    let addr = support::start(Config { admin: true, ..Config::default() });
+    let mut idle = Client::connect(addr);
+    assert_eq!(idle.get("/").status, 200);
+    let line = format!("peer={} state=idle requests=1 ", idle.local_addr());
+    let mut admin = Client::connect(addr);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let body = String::from_utf8(admin.get("/__vrypt/connections").body).unwrap();
+        if body.contains(&line) {
+            break;
+        }
+        assert!(Instant::now() < deadline, "'{line}' not in:\n{body}");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn draining_a_listener_keeps_the_others_serving() {
+    let server = support::start_server(Config { admin: true, ..Config::default() });
+    let second = support::free_addr();
+    server.shared.listen.update(vec![SocketAddr::from(([127, 0, 0, 1], 0)), second]).unwrap();
+    std::thread::sleep(Duration::from_millis(700));
+    let mut idle = Client::connect(second);
+    assert_eq!(idle.get("/").status, 200);
+
+    let mut admin = Client::connect(server.addr);
+    admin.send(format!("PUT /__vrypt/listeners/{}?drain HTTP/1.1\r\n\r\n", second.port()).as_bytes());
+    let res = admin.read_response();
+    assert_eq!(res.status, 200);
+    let body = String::from_utf8(res.body).unwrap();
+    assert!(body.contains(&format!("addr={second} local={second} state=draining")), "{body}");
+    assert!(idle.is_closed());
+
+    // Still bound, so a new connection waits in the accept queue until the listener resumes.
+    let mut queued = Client::connect(second);
+    queued.send(b"GET / HTTP/1.1\r\n\r\n");
+    let mut waiting = TcpStream::connect(second).unwrap();
+    waiting.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    assert!(std::io::Read::read(&mut waiting, &mut [0u8; 1]).is_err());
+    assert_eq!(Client::connect(server.addr).get("/").status, 200);
+
+    admin.send(b"PUT /__vrypt/listeners/127.0.0.1:1?drain HTTP/1.1\r\n\r\n");
+    assert_eq!(admin.read_response().status, 404);
+    admin.send(format!("PUT /__vrypt/listeners/{second}?resume HTTP/1.1\r\n\r\n").as_bytes());
+    assert_eq!(admin.read_response().status, 200);
+    assert_eq!(queued.read_response().status, 200);
+}
+
+#[test]
+fn listen_changes_reach_an_idle_worker_before_its_poll_timeout() {
+    let server = support::start_server(Config::default());
+    // Let the worker settle into a full-length poll.
+    std::thread::sleep(Duration::from_millis(100));
+    let second = support::free_addr();
+    let started = Instant::now();
+    server.shared.listen.update(vec![SocketAddr::from(([127, 0, 0, 1], 0)), second]).unwrap();
+    assert_eq!(Client::connect(second).get("/").status, 200);
+    assert!(started.elapsed() < POLL_TIMEOUT / 2, "took {:?}", started.elapsed());
+}
+
+#[test]
+fn admin_toggles_switch_extra_metrics_at_runtime() {
+    let server = support::start_server(Config { admin: true, ..Config::default() });
+    let mut c = Client::connect(server.addr);
+    c.send(b"PUT /__vrypt/toggles/extra-metrics?on HTTP/1.1\r\n\r\n");
+    let res = c.read_response();
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, b"debug-log: off\nextra-metrics: on\ncapture: off\nmaintenance: off\n");
+    assert_eq!(c.get("/").status, 200);
+    assert!(server.shared.counter.slots()[0].wakeups.load(Ordering::Relaxed) > 0);
+
+    c.send(b"PUT /__vrypt/toggles/extra-metrics?off HTTP/1.1\r\n\r\n");
+    assert_eq!(c.read_response().body, b"debug-log: off\nextra-metrics: off\ncapture: off\nmaintenance: off\n");
+    assert_eq!(c.get("/__vrypt/toggles").body, b"debug-log: off\nextra-metrics: off\ncapture: off\nmaintenance: off\n");
+}
+
+#[test]
+fn admin_reset_stats_starts_the_counters_over() {
+    let server = support::start_server(Config { admin: true, ..Config::default() });
+    let mut c = Client::connect(server.addr);
+    assert_eq!(c.get("/").status, 200);
+    assert_eq!(c.get("/").status, 200);
+    c.send(b"PUT /__vrypt/reset-stats HTTP/1.1\r\n\r\n");
+    let res = c.read_response();
+    assert_eq!(res.header("content-type"), Some("application/json"));
+    let body = String::from_utf8(res.body).unwrap();
+    assert!(body.starts_with(r#"{"requests":2,"accepted":1,"#), "{body}");
+
+    let body = String::from_utf8(c.get("/__vrypt/stats").body).unwrap();
+    assert!(body.starts_with(r#"{"requests":1,"accepted":0,"#), "{body}");
+    assert!(body.contains(r#","workers":[{"requests":1,"accepted":0,"#), "{body}");
+    assert_eq!(c.get("/__vrypt/reset-stats").status, 200, "GET serves the default response");
+}
+
+#[test]
+fn warmup_holds_stats_back_and_resets_them_when_over() {
+    let server = support::start_server(Config { warmup: Some(Duration::from_millis(300)), ..Config::default() });
+    assert!(server.shared.counter.warming());
+    let mut c = Client::connect(server.addr);
+    assert_eq!(c.get("/").status, 200);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.shared.counter.warming() {
+        assert!(Instant::now() < deadline, "warm-up never ended");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(server.shared.counter.snapshot().requests, 0);
+    assert_eq!(c.get("/").status, 200);
+    // Counted once the response is written, which may be just after the client has it.
+    while server.shared.counter.snapshot().requests == 0 {
+        assert!(Instant::now() < deadline, "request after the warm-up not counted");
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(server.shared.counter.snapshot().requests, 1);
+}
+
+#[test]
+fn admin_endpoints_answer_json_when_asked() {
+    let server = support::start_server(Config { admin: true, ..Config::default() });
+    let mut c = Client::connect(server.addr);
+    assert_eq!(c.get("/").status, 200);
+    c.send(b"GET /__vrypt/toggles HTTP/1.1\r\nAccept: application/json\r\n\r\n");
+    let res = c.read_response();
+    assert_eq!(res.header("content-type"), Some("application/json"));
+    assert_eq!(res.body, br#"{"debug-log":false,"extra-metrics":false,"capture":false,"maintenance":false}"#);
+
+    c.send(b"PUT /__vrypt/listeners/1?drain HTTP/1.1\r\nAccept: application/json\r\n\r\n");
+    let res = c.read_response();
+    assert_eq!(res.status, 404);
+    assert!(res.body.starts_with(br#"{"error":""#), "{}", String::from_utf8_lossy(&res.body));
+
+    let res = c.get("/__vrypt/stats");
+    assert_eq!(res.header("content-type"), Some("application/json"));
+    let body = String::from_utf8(res.body).unwrap();
+    assert!(body.starts_with(r#"{"requests":3,"accepted":1,"#), "{body}");
+    assert!(body.contains(r#""protocol_errors":{"tls":0,"garbage":0,"h2c":0,"h2":0}"#), "{body}");
+    assert!(body.contains(r#","workers":[{"requests":3,"accepted":1,"active":"#), "{body}");
+}
+
+#[test]
+fn headers_endpoint_echoes_the_request_as_json() {
+    let addr = support::start(Config { echo_headers: true, ..Config::default() });
+    let mut c = Client::connect(addr);
+    c.send(b"GET /headers?x=1 HTTP/1.1\r\nHost: example\r\nX-Quote: a \"b\"\r\nX-Quote: c\r\n\r\n");
+    let res = c.read_response();
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("content-type"), Some("application/json"));
+    let peer = c.local_addr();
+    let expected = format!(
+        r#"{{"method":"GET","target":"/headers?x=1","peer":"{peer}","headers":[["Host","example"],["X-Quote","a \"b\""],["X-Quote","c"]]}}"#
+    );
+    assert_eq!(String::from_utf8(res.body).unwrap(), expected);
+
+    let addr = support::start_default();
+    assert_eq!(Client::connect(addr).get("/headers").header("content-type"), Some("text/plain; charset=utf-8"));
+}
+
+#[test]
+fn text_bodies_carry_the_default_charset() {
+    let addr = support::start_default();
+    assert_eq!(Client::connect(addr).get("/").header("content-type"), Some("text/plain; charset=utf-8"));
+}
+
+#[test]
+fn template_content_type_follows_its_extension() {
+    let template = std::env::temp_dir().join(format!("vrypt-test-{}-method.json", std::process::id()));
+    std::fs::write(&template, "{\"method\":\"{{method}}\"}").unwrap();
+    let mut mime = MimeMap::default();
+    mime.set_charset(None);
+    let addr = support::start(Config { template: Some(template), mime, ..Config::default() });
+    let res = Client::connect(addr).get("/");
+    assert_eq!(res.header("content-type"), Some("application/json"));
+    assert_eq!(res.body, b"{\"method\":\"GET\"}");
+}
+
+#[test]
+fn configured_mime_types_override_the_builtins() {
+    let template = std::env::temp_dir().join(format!("vrypt-test-{}-page.html", std::process::id()));
+    std::fs::write(&template, "<p>{{path}}</p>").unwrap();
+    let mut mime = MimeMap::default();
+    mime.set(".html=application/xhtml+xml").unwrap();
+    let addr = support::start(Config { template: Some(template), mime, ..Config::default() });
+    assert_eq!(Client::connect(addr).get("/").header("content-type"), Some("application/xhtml+xml"));
+}
+
+#[test]
+fn listeners_can_be_added_and_removed_at_runtime() {
+    let server = support::start_server(Config::default());
+    let (first, listed_first) = (server.addr, SocketAddr::from(([127, 0, 0, 1], 0)));
+    let second = support::free_addr();
+    let mut kept = Client::connect(first);
+    assert_eq!(kept.get("/").status, 200);
+
+    server.shared.listen.update(vec![listed_first, second]).unwrap();
+    std::thread::sleep(Duration::from_millis(700));
+    assert_eq!(Client::connect(second).get("/").status, 200);
+
+    server.shared.listen.update(vec![second]).unwrap();
+    std::thread::sleep(Duration::from_millis(700));
+    assert!(TcpStream::connect(first).is_err());
+    assert_eq!(kept.get("/").status, 200);
+}
+
+#[test]
+fn logged_requests_keep_server_timing_on_every_response() {
+    let cfg = Config {
+        server_timing: true,
+        access_log_sample: 1.0,
+        slow_request: Some(Duration::from_millis(1)),
+        ..Config::default()
+    };
+    let mut c = Client::connect(support::start(cfg));
+    c.send(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n");
+    for _ in 0..2 {
+        let res = c.read_response();
+        assert_eq!(res.status, 200);
+        assert!(res.header("server-timing").is_some_and(|t| t.starts_with("parse;dur=")));
+    }
+}
+
+#[test]
+fn size_histograms_are_kept_per_route() {
+    let cfg = Config { size_stats: true, size_routes: vec!["/api".into(), "/api/upload".into()], ..Config::default() };
+    let server = support::start_server(cfg);
+    let mut c = Client::connect(server.addr);
+    assert_eq!(c.get("/api/items").status, 200);
+    let body = vec![b'x'; 5000];
+    c.send(format!("POST /api/upload HTTP/1.1\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes());
+    c.send(&body);
+    assert_eq!(c.read_response().status, 200);
+    assert_eq!(c.get("/").status, 200);
+    std::thread::sleep(Duration::from_millis(100));
+
+    let sizes = server.shared.sizes.unwrap();
+    assert_eq!(sizes.names(), ["api", "api_upload", "other"]);
+    let (api, _) = sizes.take(0);
+    let (upload, _) = sizes.take(1);
+    let (other, responses) = sizes.take(2);
+    assert_eq!(api, [1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(upload, [0, 0, 0, 1, 0, 0, 0]);
+    assert_eq!(other, [1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(responses.iter().sum::<u64>(), 1);
+}
+
+#[test]
+fn every_port_of_a_range_is_served() {
+    // The ports next to a free one may be taken by other tests' clients; try a few ranges.
+    let (first, server) = (0..10)
+        .find_map(|_| {
+            let first = support::free_addr().port();
+            let cfg = Config { addr: support::free_addr(), port_range: Some(first..=first + 2), ..Config::default() };
+            Server::start(Box::leak(Box::new(cfg)), 1).ok().map(|server| (first, server))
+        })
+        .expect("a free range of three ports");
+    assert_eq!(server.shared.listen.addrs().len(), 3);
+    for port in first..=first + 2 {
+        let res = Client::connect(SocketAddr::from(([127, 0, 0, 1], port))).get("/");
+        assert_eq!(res.body, b"Vrypt");
+    }
+}
+
+#[test]
+fn unfinished_request_head_gets_408_on_timeout() {
+    let addr = support::start(Config { header_timeout: Duration::from_secs(1), ..Config::default() });
+    let mut c = Client::connect(addr);
+    c.send(b"GET / HTTP/1.1\r\nHost: te");
+    let res = c.read_response();
+    assert_eq!(res.status, 408);
+    assert_eq!(res.header("connection"), Some("close"));
+    assert!(c.is_closed());
+}
+
+#[test]
+fn error_pages_replace_built_in_bodies_per_host() {
+    let dir = std::env::temp_dir().join(format!("vrypt-error-page-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let page = dir.join("bad.html");
+    std::fs::write(&page, "<h1>shop: bad request</h1>").unwrap();
+    let error_pages = vec![
+        ErrorPage::parse("400=nope\n").unwrap(),
+        ErrorPage::parse(&format!("shop.example/400=@{}", page.display())).unwrap(),
+        ErrorPage::parse("408=too slow\n").unwrap(),
+    ];
+    let addr = support::start(Config { error_pages, header_timeout: Duration::from_secs(1), ..Config::default() });
+    let conflicting = "Content-Length: 2\r\nContent-Length: 3\r\n\r\nhi";
+
+    let mut c = Client::connect(addr);
+    c.send(format!("POST / HTTP/1.1\r\nHost: Shop.Example:8080\r\n{conflicting}").as_bytes());
+    let res = c.read_response();
+    assert_eq!(res.status, 400);
+    assert_eq!(res.header("content-type"), Some("text/html; charset=utf-8"));
+    assert_eq!(res.header("connection"), Some("close"));
+    assert_eq!(res.body, b"<h1>shop: bad request</h1>");
+
+    let mut c = Client::connect(addr);
+    c.send(format!("POST / HTTP/1.1\r\nHost: other.example\r\n{conflicting}").as_bytes());
+    let res = c.read_response();
+    assert_eq!(res.status, 400);
+    assert_eq!(res.body, b"nope\n");
+
+    let mut c = Client::connect(addr);
+    c.send(b"GET / HTTP/1.1\r\nHost: shop.ex");
+    assert_eq!(c.read_response().body, b"too slow\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn static_routes_serve_the_precompressed_variant_the_client_accepts() {
+    let dir = std::env::temp_dir().join(format!("vrypt-static-route-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("app.js");
+    std::fs::write(&file, "let plain = 1;").unwrap();
+    std::fs::write(dir.join("app.js.gz"), "gzipped").unwrap();
+    let static_routes = vec![StaticRoute::parse(&format!("/app.js={}", file.display())).unwrap()];
+    let addr = support::start(Config { static_routes, ..Config::default() });
+    let mut c = Client::connect(addr);
+
+    let res = c.get("/app.js");
+    assert_eq!(res.status, 200);
+    assert_eq!(res.header("content-type"), Some("text/javascript; charset=utf-8"));
+    assert_eq!(res.header("content-encoding"), None);
+    assert_eq!(res.header("vary"), Some("Accept-Encoding"));
+    assert_eq!(res.body, b"let plain = 1;");
+
+    c.send(b"GET /app.js HTTP/1.1\r\nHost: test\r\nAccept-Encoding: br;q=0, gzip\r\n\r\n");
+    let res = c.read_response();
+    assert_eq!(res.header("content-encoding"), Some("gzip"));
+    assert_eq!(res.body, b"gzipped");
+
+    assert_eq!(c.get("/app.js?v=2").body, b"let plain = 1;");
+    assert_eq!(c.get("/").body, b"Vrypt");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn protobuf_routes_send_the_message_raw_or_grpc_framed() {
+    let dir = std::env::temp_dir().join(format!("vrypt-proto-route-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let message = dir.join("reply.bin");
+    std::fs::write(&message, b"\x08\x96\x01").unwrap();
+    let static_routes = vec![
+        StaticRoute::parse(&format!("protobuf:/reply={}", message.display())).unwrap(),
+        StaticRoute::parse(&format!("grpc:/echo.Echo/Reply={}", message.display())).unwrap(),
+    ];
+    let addr = support::start(Config { static_routes, ..Config::default() });
+
+    let res = Client::connect(addr).get("/reply");
+    assert_eq!(res.header("content-type"), Some("application/x-protobuf"));
+    assert_eq!(res.body, b"\x08\x96\x01");
+
+    let mut c = Client::connect(addr);
+    c.send(b"POST /echo.Echo/Reply HTTP/1.1\r\nHost: test\r\nContent-Length: 0\r\n\r\n");
+    let mut raw = Vec::new();
+    while !raw.ends_with(b"grpc-status: 0\r\n\r\n") {
+        raw.extend(c.read_exact(1));
+    }
+    let text = String::from_utf8_lossy(&raw);
+    assert!(text.contains("Content-Type: application/grpc\r\n"), "{text}");
+    assert!(text.contains("Trailer: grpc-status\r\n"), "{text}");
+    assert!(raw.ends_with(b"\r\n\r\n8\r\n\0\0\0\0\x03\x08\x96\x01\r\n0\r\ngrpc-status: 0\r\n\r\n"), "{text}");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn routes_listing_their_methods_refuse_the_others_with_405_or_501() {
+    let dir = std::env::temp_dir().join(format!("vrypt-route-methods-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("users.json");
+    std::fs::write(&file, "[]").unwrap();
+    let static_routes = vec![
+        StaticRoute::parse(&format!("GET,POST /users={}", file.display())).unwrap(),
+        StaticRoute::parse(&format!("PURGE /cache={}", file.display())).unwrap(),
+    ];
+    let addr = support::start(Config { static_routes, ..Config::default() });
+    let mut c = Client::connect(addr);
+
+    assert_eq!(c.get("/users").body, b"[]");
+    c.send(b"POST /users HTTP/1.1\r\nHost: test\r\nContent-Length: 2\r\n\r\n{}");
+    assert_eq!(c.read_response().body, b"[]");
+
+    c.send(b"DELETE /users HTTP/1.1\r\nHost: test\r\nContent-Length: 2\r\n\r\n{}");
+    let res = c.read_response();
+    assert_eq!(res.status, 405);
+    assert_eq!(res.header("allow"), Some("GET, POST, HEAD"));
+    // Listed by another route, so known here too.
+    c.send(b"PURGE /users HTTP/1.1\r\nHost: test\r\n\r\n");
+    assert_eq!(c.read_response().status, 405);
+    c.send(b"BREW /users HTTP/1.1\r\nHost: test\r\n\r\n");
+    let res = c.read_response();
+    assert_eq!(res.status, 501);
+    assert_eq!(res.header("allow"), None);
+
+    // The connection stays open, and routes without a list answer every method.
+    c.send(b"BREW / HTTP/1.1\r\nHost: test\r\n\r\n");
+    assert_eq!(c.read_response().body, b"Vrypt");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn exec_routes_stream_the_output_of_their_command() {
+    let static_routes = vec![
+        StaticRoute::parse("exec:/hello=/bin/echo hello").unwrap(),
+        StaticRoute::parse("exec:/env=/usr/bin/env").unwrap(),
+        StaticRoute::parse("exec:/slow=/bin/sleep 5").unwrap(),
+    ];
+    let addr = support::start(Config { static_routes, exec_max: 1, ..Config::default() });
+    let read_until = |c: &mut Client, end: &[u8]| {
+        let mut raw = Vec::new();
+        while !raw.ends_with(end) {
+            raw.extend(c.read_exact(1));
+        }
+        String::from_utf8(raw).unwrap()
+    };
+
+    let mut c = Client::connect(addr);
+    c.send(b"GET /hello HTTP/1.1\r\nHost: test\r\n\r\n");
+    let text = read_until(&mut c, b"\r\n0\r\n\r\n");
+    assert!(text.contains("Transfer-Encoding: chunked\r\n"), "{text}");
+    assert!(text.ends_with("\r\n\r\n6\r\nhello\n\r\n0\r\n\r\n"), "{text}");
+    c.send(b"GET /env?a=1 HTTP/1.1\r\nHost: test\r\nX-Test: yes\r\nProxy: evil\r\n\r\n");
+    let text = read_until(&mut c, b"\r\n0\r\n\r\n");
+    for var in ["REQUEST_METHOD=GET\n", "QUERY_STRING=a=1\n", "SCRIPT_NAME=/env\n", "HTTP_X_TEST=yes\n"] {
+        assert!(text.contains(var), "{var} missing from {text}");
+    }
+    assert!(!text.contains("HTTP_PROXY"), "{text}");
+    assert_eq!(c.get("/").body, b"Vrypt");
+
+    // With the one slot taken, another command is refused.
+    let mut slow = Client::connect(addr);
+    slow.send(b"GET /slow HTTP/1.1\r\nHost: test\r\n\r\n");
+    assert!(read_until(&mut slow, b"\r\n\r\n").starts_with("HTTP/1.1 200"));
+    assert_eq!(c.get("/hello").status, 503);
+    // Going away kills the command and gives its slot back.
+    drop(slow);
+    let started = Instant::now();
+    loop {
+        let mut c = Client::connect(addr);
+        c.send(b"GET /hello HTTP/1.1\r\nHost: test\r\n\r\n");
+        if read_until(&mut c, b"\r\n\r\n").starts_with("HTTP/1.1 200") {
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(2), "the slot was not given back");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn requests_over_a_route_limit_wait_in_its_queue_until_a_slot_frees_or_time_runs_out() {
+    let start = |route: &str, timeout| {
+        support::start(Config {
+            static_routes: vec![StaticRoute::parse(route).unwrap()],
+            route_limits: vec![RouteLimit::parse("/slow=1").unwrap()],
+            inflight_queue: 1,
+            inflight_queue_timeout: timeout,
+            ..Config::default()
+        })
+    };
+    let head = |c: &mut Client| {
+        let mut raw = Vec::new();
+        while !raw.ends_with(b"\r\n\r\n") {
+            raw.extend(c.read_exact(1));
+        }
+        String::from_utf8(raw).unwrap()
+    };
+
+    let addr = start("exec:/slow=/bin/sleep 0.5", Duration::from_secs(5));
+    let mut first = Client::connect(addr);
+    first.send(b"GET /slow HTTP/1.1\r\nHost: test\r\n\r\n");
+    assert!(head(&mut first).starts_with("HTTP/1.1 200"));
+    let mut waiting = Client::connect(addr);
+    waiting.send(b"GET /slow HTTP/1.1\r\nHost: test\r\n\r\n");
+    std::thread::sleep(Duration::from_millis(100));
+    // The queue is full, and other paths are not limited.
+    let mut c = Client::connect(addr);
+    assert_eq!(c.get("/slow").status, 503);
+    assert_eq!(c.get("/").status, 200);
+    let started = Instant::now();
+    assert!(head(&mut waiting).starts_with("HTTP/1.1 200"));
+    assert!(started.elapsed() < Duration::from_secs(2), "the waiting request was not woken");
+
+    let addr = start("exec:/slow=/bin/sleep 5", Duration::from_secs(1));
+    let mut first = Client::connect(addr);
+    first.send(b"GET /slow HTTP/1.1\r\nHost: test\r\n\r\n");
+    assert!(head(&mut first).starts_with("HTTP/1.1 200"));
+    let mut c = Client::connect(addr);
+    let started = Instant::now();
+    assert_eq!(c.get("/slow").status, 503);
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert!(started.elapsed() < Duration::from_secs(4), "the waiting request outstayed its deadline");
+}
+
+#[test]
+fn route_header_rules_and_prefix_rewrites_apply_before_dispatch() {
+    let dir = std::env::temp_dir().join(format!("vrypt-route-headers-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("users.json");
+    std::fs::write(&file, "[]").unwrap();
+    let static_routes = vec![
+        StaticRoute::parse(&format!("/users={}", file.display())).unwrap(),
+        StaticRoute::parse(&format!("/v1/users={}", file.display())).unwrap(),
+        StaticRoute::parse("exec:/env=/usr/bin/env").unwrap(),
+    ];
+    let route_headers = ["/users=Cache-Control: max-age=60", "/users=-Server"].map(|s| RouteHeader::parse(s).unwrap());
+    let route_request_headers = ["/env=X-Added: yes", "/env=-X-Test"].map(|s| RouteHeader::parse(s).unwrap());
+    let path_rewrites = ["/api=", "/legacy/=/v1/"].map(|s| PathRewrite::parse(s).unwrap());
+    let addr = support::start(Config {
+        static_routes,
+        route_headers: route_headers.into(),
+        route_request_headers: route_request_headers.into(),
+        path_rewrites: path_rewrites.into(),
+        ..Config::default()
+    });
+
+    let mut c = Client::connect(addr);
+    let res = c.get("/api/users?page=2");
+    assert_eq!(res.body, b"[]");
+    assert_eq!(res.header("Cache-Control"), Some("max-age=60"));
+    assert_eq!(res.header("Server"), None);
+    let res = c.get("/legacy/users");
+    assert_eq!(res.body, b"[]");
+    assert_eq!(res.header("Cache-Control"), None);
+    assert!(res.header("Server").is_some());
+    // A prefix matches whole segments only.
+    assert_eq!(c.get("/apis/users").body, b"Vrypt");
+
+    c.send(b"GET /api/env?a=1 HTTP/1.1\r\nHost: test\r\nX-Test: no\r\n\r\n");
+    let mut raw = Vec::new();
+    while !raw.ends_with(b"\r\n0\r\n\r\n") {
+        raw.extend(c.read_exact(1));
+    }
+    let text = String::from_utf8(raw).unwrap();
+    for var in ["REQUEST_URI=/env?a=1\n", "HTTP_X_ADDED=yes\n", "HTTP_HOST=test\n"] {
+        assert!(text.contains(var), "{var} missing from {text}");
+    }
+    assert!(!text.contains("HTTP_X_TEST"), "{text}");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn route_output_spills_past_the_threshold_while_the_client_is_slow() {
+    const SIZE: usize = 16 * 1024 * 1024;
+    let static_routes = vec![
+        StaticRoute::parse(&format!("exec:/big=/usr/bin/head -c {SIZE} /dev/zero")).unwrap(),
+        StaticRoute::parse("exec:/hello=/bin/echo hello").unwrap(),
+    ];
+    let cfg = Config { static_routes, exec_max: 1, spill_threshold: Some(64 * 1024), ..Config::default() };
+    let addr = support::start(cfg);
+    let read_line = |c: &mut Client| {
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            line.extend(c.read_exact(1));
+        }
+        String::from_utf8(line).unwrap()
+    };
+
+    // The command's output is taken before the client reads any of it, so its slot is
+    // given back while the response is still on its way.
+    let mut big = Client::connect(addr);
+    big.send(b"GET /big HTTP/1.1\r\nHost: test\r\n\r\n");
+    let started = Instant::now();
+    loop {
+        let mut c = Client::connect(addr);
+        c.send(b"GET /hello HTTP/1.1\r\nHost: test\r\n\r\n");
+        if read_line(&mut c).starts_with("HTTP/1.1 200") {
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(5), "the command was held up by the client");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    while read_line(&mut big) != "\r\n" {}
+    let mut body = 0;
+    loop {
+        let size = usize::from_str_radix(read_line(&mut big).trim_end(), 16).unwrap();
+        let chunk = big.read_exact(size + 2);
+        assert!(chunk[..size].iter().all(|&b| b == 0));
+        body += size;
+        if size == 0 {
+            break;
+        }
+    }
+    assert_eq!(body, SIZE);
+    // The connection goes on with the next request.
+    assert_eq!(big.get("/").body, b"Vrypt");
+}
+
+#[test]
+fn concurrent_gets_for_a_route_collapse_into_one_command_run() {
+    let dir = std::env::temp_dir().join(format!("vrypt-test-{}-collapse", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("pid.sh");
+    std::fs::write(&script, "#!/bin/sh\nsleep 0.5\necho run $$\n").unwrap();
+    std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+    let static_routes = vec![StaticRoute::parse(&format!("exec:/pid={}", script.display())).unwrap()];
+    let server = support::start_server(Config { static_routes, collapse: true, ..Config::default() });
+    let read_until = |c: &mut Client, end: &[u8]| {
+        let mut raw = Vec::new();
+        while !raw.ends_with(end) {
+            raw.extend(c.read_exact(1));
+        }
+        String::from_utf8(raw).unwrap()
+    };
+    let request = |extra: &str| {
+        let mut c = Client::connect(server.addr);
+        c.send(format!("GET /pid HTTP/1.1\r\nHost: test\r\n{extra}\r\n").as_bytes());
+        c
+    };
+
+    // The first request runs the command; the next two wait on it, but not one with a cookie.
+    let mut clients = [request(""), request(""), request("Accept: */*\r\n")];
+    let mut private = request("Cookie: id=1\r\n");
+    let bodies: Vec<String> = clients.iter_mut().map(|c| read_until(c, b"\r\n0\r\n\r\n")).collect();
+    assert!(bodies[0].starts_with("HTTP/1.1 200") && bodies[0].contains("run "), "{}", bodies[0]);
+    assert!(bodies.iter().all(|b| b == &bodies[0]), "{bodies:?}");
+    assert_ne!(read_until(&mut private, b"\r\n0\r\n\r\n"), bodies[0]);
+    assert_eq!(server.shared.counter.collapsed_requests(), 2);
+
+    // Once the response is over, the next request runs the command again.
+    let mut again = request("");
+    assert_ne!(read_until(&mut again, b"\r\n0\r\n\r\n"), bodies[0]);
+
+    // A leader going away cuts its waiters' responses short.
+    let leader = request("");
+    let mut waiter = request("");
+    read_until(&mut waiter, b"\r\n\r\n");
+    drop(leader);
+    assert!(!waiter.read_to_close().ends_with(b"0\r\n\r\n"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fastcgi_and_scgi_routes_relay_requests_to_their_backend() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+
+    let dir = std::env::temp_dir().join(format!("vrypt-fastcgi-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (fcgi, scgi) = (dir.join("fcgi.sock"), dir.join("scgi.sock"));
+    let _ = std::fs::remove_file(&fcgi);
+    let _ = std::fs::remove_file(&scgi);
+    // Answers each FastCGI request with its method, script, length and body, keeping the
+    // connection when asked to.
+    let listener = UnixListener::bind(&fcgi).unwrap();
+    std::thread::spawn(move || {
+        'conns: for mut s in listener.incoming().map(Result::unwrap) {
+            let mut keep = true;
+            while keep {
+                let (mut params, mut stdin) = (Vec::new(), Vec::new());
+                loop {
+                    let mut header = [0; 8];
+                    if s.read_exact(&mut header).is_err() {
+                        continue 'conns;
+                    }
+                    let mut content = vec![0; u16::from_be_bytes([header[4], header[5]]) as usize + header[6] as usize];
+                    s.read_exact(&mut content).unwrap();
+                    match header[1] {
+                        1 => keep = content[2] & 1 == 1,
+                        4 => params.extend_from_slice(&content),
+                        5 if content.is_empty() => break,
+                        5 => stdin.extend_from_slice(&content),
+                        _ => {}
+                    }
+                }
+                let mut vars = std::collections::HashMap::new();
+                let mut rest = &params[..];
+                while !rest.is_empty() {
+                    let (name, value) = (rest[0] as usize, rest[1] as usize);
+                    let name_end = 2 + name;
+                    vars.insert(rest[2..name_end].to_vec(), rest[name_end..name_end + value].to_vec());
+                    rest = &rest[name_end + value..];
+                }
+                let var = |name: &[u8]| String::from_utf8(vars.get(name).cloned().unwrap_or_default()).unwrap();
+                let body = format!(
+                    "Status: 201 Created\r\nX-Backend: fcgi\r\nContent-Length: 1\r\n\r\n{} {} {} {}",
+                    var(b"REQUEST_METHOD"),
+                    var(b"SCRIPT_FILENAME"),
+                    var(b"CONTENT_LENGTH"),
+                    String::from_utf8_lossy(&stdin)
+                );
+                let mut out = vec![1, 7, 0, 1, 0, 4, 0, 0];
+                out.extend_from_slice(b"oops");
+                out.extend_from_slice(&[1, 6, 0, 1, 0, body.len() as u8, 0, 0]);
+                out.extend_from_slice(body.as_bytes());
+                out.extend_from_slice(&[1, 3, 0, 1, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+                s.write_all(&out).unwrap();
+            }
+        }
+    });
+    // Answers each SCGI request with a redirect to its `REQUEST_URI`.
+    let listener = UnixListener::bind(&scgi).unwrap();
+    std::thread::spawn(move || {
+        for mut s in listener.incoming().map(Result::unwrap) {
+            let mut raw = Vec::new();
+            while !raw.ends_with(b",") {
+                let mut byte = [0];
+                s.read_exact(&mut byte).unwrap();
+                raw.push(byte[0]);
+            }
+            let headers: Vec<&[u8]> = raw.split(|&b| b == 0).collect();
+            assert!(headers[0].ends_with(b"CONTENT_LENGTH"), "{:?}", String::from_utf8_lossy(&raw));
+            let uri = headers.iter().position(|h| *h == b"REQUEST_URI").map(|i| headers[i + 1]).unwrap();
+            let _ = write!(s, "Location: {}\n\n", String::from_utf8_lossy(uri));
+        }
+    });
+    let static_routes = vec![
+        StaticRoute::parse(&format!("fastcgi:/app.php={} /srv/app.php", fcgi.display())).unwrap(),
+        StaticRoute::parse(&format!("scgi:/scgi={}", scgi.display())).unwrap(),
+        StaticRoute::parse(&format!("fastcgi:/down={}", dir.join("missing.sock").display())).unwrap(),
+    ];
+    let server = support::start_server(Config { static_routes, ..Config::default() });
+    let addr = server.addr;
+    let read_until = |c: &mut Client, end: &[u8]| {
+        let mut raw = Vec::new();
+        while !raw.ends_with(end) {
+            raw.extend(c.read_exact(1));
+        }
+        String::from_utf8(raw).unwrap()
+    };
+
+    let mut c = Client::connect(addr);
+    c.send(b"POST /app.php HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\n\r\nhello");
+    let text = read_until(&mut c, b"\r\n0\r\n\r\n");
+    assert!(text.starts_with("HTTP/1.1 201 Created\r\n"), "{text}");
+    assert!(text.contains("X-Backend: fcgi\r\n") && !text.contains("Content-Length"), "{text}");
+    assert!(text.ends_with("\r\n\r\n19\r\nPOST /srv/app.php 5 hello\r\n0\r\n\r\n"), "{text}");
+    // The next request goes over the same, pooled backend connection.
+    c.send(b"GET /app.php HTTP/1.1\r\nHost: test\r\n\r\n");
+    assert!(read_until(&mut c, b"\r\n0\r\n\r\n").ends_with("GET /srv/app.php 0 \r\n0\r\n\r\n"));
+    let backends = server.shared.backends.expect("backend stats");
+    assert_eq!(backends.take(0, UpstreamStat::Opened), 1);
+    assert_eq!(backends.take(0, UpstreamStat::Reused), 1);
+    assert_eq!(backends.take(0, UpstreamStat::Idle), 1);
+    assert_eq!(backends.take(0, UpstreamStat::Busy), 0);
+    c.send(b"GET /scgi?x=1 HTTP/1.1\r\nHost: test\r\n\r\n");
+    let text = read_until(&mut c, b"\r\n0\r\n\r\n");
+    assert!(text.starts_with("HTTP/1.1 302 Found\r\nLocation: /scgi?x=1\r\n"), "{text}");
+    assert_eq!(c.get("/").body, b"Vrypt");
+
+    // SCGI closes after each response: nothing is pooled.
+    assert_eq!(backends.take(1, UpstreamStat::Opened), 1);
+    assert_eq!(backends.take(1, UpstreamStat::Idle), 0);
+    let mut c = Client::connect(addr);
+    assert_eq!(c.get("/down").status, 502);
+    assert_eq!(backends.take(2, UpstreamStat::Busy), 0);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn admin_upload_re_renders_a_static_route() {
+    let dir = std::env::temp_dir().join(format!("vrypt-static-upload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("index.html");
+    std::fs::write(&file, "<p>old</p>").unwrap();
+    std::fs::write(dir.join("index.html.br"), "brotli").unwrap();
+    let static_routes = vec![StaticRoute::parse(&format!("/index.html={}", file.display())).unwrap()];
+    let addr = support::start(Config { static_routes, admin: true, ..Config::default() });
+    let mut c = Client::connect(addr);
+
+    c.send(b"PUT /__vrypt/routes/index.html HTTP/1.1\r\nContent-Length: 10\r\n\r\n<p>new</p>");
+    assert_eq!(c.read_response().status, 200);
+    let mut other = Client::connect(addr);
+    other.send(b"GET /index.html HTTP/1.1\r\nHost: test\r\nAccept-Encoding: br\r\n\r\n");
+    let res = other.read_response();
+    assert_eq!(res.header("content-type"), Some("text/html; charset=utf-8"));
+    assert_eq!(res.header("content-encoding"), None);
+    assert_eq!(res.body, b"<p>new</p>");
+    assert_eq!(c.get("/").body, b"Vrypt");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn watched_routes_reload_when_their_files_change() {
+    let dir = std::env::temp_dir().join(format!("vrypt-static-watch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("app.js");
+    std::fs::write(&file, "v1").unwrap();
+    let static_routes = vec![StaticRoute::parse(&format!("/app.js={}", file.display())).unwrap()];
+    let addr = support::start(Config { static_routes, watch_routes: true, ..Config::default() });
+    let mut c = Client::connect(addr);
+    assert_eq!(c.get("/app.js").body, b"v1");
+    let served = |c: &mut Client, body: &[u8]| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while c.get("/app.js").body != body && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        c.get("/app.js").body
+    };
+
+    std::fs::write(&file, "v2").unwrap();
+    assert_eq!(served(&mut c, b"v2"), b"v2");
+    // Renamed over the old file, as a deploy would.
+    std::fs::write(dir.join("app.js.tmp"), "v3").unwrap();
+    std::fs::rename(dir.join("app.js.tmp"), &file).unwrap();
+    assert_eq!(served(&mut c, b"v3"), b"v3");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn tenants_serve_their_own_routes_and_count_their_requests() {
+    let dir = std::env::temp_dir().join(format!("vrypt-tenant-routes-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let route = |spec: &str, name: &str| {
+        std::fs::write(dir.join(name), name).unwrap();
+        StaticRoute::parse(&format!("{spec}={}", dir.join(name).display())).unwrap()
+    };
+    let server = support::start_server(Config {
+        tenants: vec![
+            Tenant::parse("accounts=prefix:/accounts/").unwrap(),
+            Tenant::parse("billing=host:Billing.test").unwrap(),
+        ],
+        static_routes: vec![
+            route("accounts/users", "accounts.txt"),
+            route("billing/users", "billing.txt"),
+            route("/users", "own.txt"),
+        ],
+        admin: true,
+        ..Config::default()
+    });
+    let mut c = Client::connect(server.addr);
+    assert_eq!(c.get("/accounts/users").body, b"accounts.txt");
+    assert_eq!(c.get("/accounts/users?page=2").body, b"accounts.txt");
+    assert_eq!(c.get("/accounts").body, b"Vrypt");
+    assert_eq!(c.get("/accountsusers").body, b"Vrypt");
+    assert_eq!(c.get("/users").body, b"own.txt");
+    c.send(b"GET /users HTTP/1.1\r\nHost: billing.test:8080\r\n\r\n");
+    assert_eq!(c.read_response().body, b"billing.txt");
+
+    c.send(b"PUT /__vrypt/routes/accounts/users HTTP/1.1\r\nContent-Length: 7\r\n\r\nrenamed");
+    assert_eq!(c.read_response().status, 200);
+    assert_eq!(c.get("/accounts/users").body, b"renamed");
+    assert_eq!(c.get("/users").body, b"own.txt");
+
+    let tenants = server.shared.tenants.expect("tenant stats");
+    assert_eq!(tenants.take(0, TenantStat::Requests), 4);
+    assert_eq!(tenants.take(1, TenantStat::Requests), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn a_tenant_over_its_rate_is_answered_429_and_others_are_not() {
+    let server = support::start_server(Config {
+        tenants: vec![Tenant::parse("slow=prefix:/slow,rate=1").unwrap(), Tenant::parse("fast=prefix:/fast").unwrap()],
+        ..Config::default()
+    });
+    let mut c = Client::connect(server.addr);
+    assert_eq!(c.get("/slow/a").status, 200);
+    let res = c.get("/slow/b");
+    assert_eq!(res.status, 429);
+    assert_eq!(res.body, b"Request rate exceeded");
+    // Keep-alive: the same connection goes on to be served.
+    for _ in 0..3 {
+        assert_eq!(c.get("/fast/a").status, 200);
+    }
+    let tenants = server.shared.tenants.expect("tenant stats");
+    assert_eq!(tenants.take(0, TenantStat::Throttled), 1);
+    assert_eq!(tenants.take(1, TenantStat::Throttled), 0);
+}
+
+#[test]
+fn parked_long_polls_are_answered_by_the_next_published_event() {
+    let addr = support::start(Config { admin: true, long_poll_path: Some("/poll".into()), ..Config::default() });
+    let mut polls: Vec<Client> = (0..3).map(|_| Client::connect(addr)).collect();
+    for c in &mut polls {
+        c.send(b"GET /poll?since=0 HTTP/1.1\r\nHost: test\r\n\r\n");
+    }
+    std::thread::sleep(Duration::from_millis(100));
+    let mut admin = Client::connect(addr);
+    admin.send(b"PUT /__vrypt/events HTTP/1.1\r\nContent-Length: 5\r\n\r\nfirst");
+    assert_eq!(admin.read_response().status, 200);
+    for c in &mut polls {
+        let res = c.read_response();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body, b"first");
+        assert_eq!(res.header("vrypt-event"), Some("1"));
+    }
+
+    // Already past `since`: answered without waiting, on the same connection.
+    let res = polls[0].get("/poll?since=0");
+    assert_eq!((res.status, res.body.as_slice()), (200, &b"first"[..]));
+    assert_eq!(polls[0].get("/other").body, b"Vrypt");
+}
+
+#[test]
+fn a_long_poll_without_an_event_times_out_with_204() {
+    let addr = support::start(Config {
+        long_poll_path: Some("/poll".into()),
+        long_poll_timeout: Duration::from_secs(1),
+        ..Config::default()
+    });
+    let mut c = Client::connect(addr);
+    let start = Instant::now();
+    let res = c.get("/poll");
+    assert!(start.elapsed() >= Duration::from_millis(900), "answered after {:?}", start.elapsed());
+    assert_eq!(res.status, 204);
+    assert_eq!(res.header("vrypt-event"), Some("0"));
+    assert!(res.body.is_empty());
+    // Pipelined behind a poll, a request waits for it and is then served.
+    c.send(b"GET /poll HTTP/1.1\r\nHost: test\r\n\r\nGET /next HTTP/1.1\r\nHost: test\r\n\r\n");
+    assert_eq!(c.read_response().status, 204);
+    assert_eq!(c.read_response().body, b"Vrypt");
+}
+
+#[test]
+fn retries_with_an_idempotency_key_replay_the_first_response() {
+    let addr = support::start(Config {
+        idempotency_ttl: Some(Duration::from_secs(60)),
+        body_sink: SinkMode::Hash,
+        ..Config::default()
+    });
+    let post = |c: &mut Client, target: &str, key: &str, body: &str| {
+        let head = format!("POST {target} HTTP/1.1\r\nHost: test\r\nIdempotency-Key: {key}\r\n");
+        c.send(format!("{head}Content-Length: {}\r\n\r\n{body}", body.len()).as_bytes());
+        c.read_response()
+    };
+    let mut c = Client::connect(addr);
+    let first = post(&mut c, "/orders", "k-1", "first");
+    assert_eq!(first.status, 200);
+    assert_eq!(first.header("idempotent-replayed"), None);
+
+    let retry = post(&mut Client::connect(addr), "/orders", "k-1", "second");
+    assert_eq!(retry.status, 200);
+    assert_eq!(retry.header("idempotent-replayed"), Some("true"));
+    assert_eq!(retry.body, first.body);
+
+    let fresh = post(&mut c, "/orders", "k-2", "second");
+    assert_eq!(fresh.header("idempotent-replayed"), None);
+    assert_ne!(fresh.body, first.body);
+
+    let reused = post(&mut c, "/refunds", "k-1", "first");
+    assert_eq!(reused.status, 422);
+    assert_eq!(c.get("/").status, 200);
+}
+
+#[test]
+fn idle_keep_alive_connection_closes_without_408() {
+    let addr = support::start(Config { keepalive_timeout: Duration::from_secs(1), ..Config::default() });
+    let mut c = Client::connect(addr);
+    assert_eq!(c.get("/").status, 200);
+    assert!(c.read_to_close().is_empty());
+}
+
+#[test]
+fn stalled_response_is_dropped_when_the_client_resets() {
+    let stall = FaultRule::parse("stall:1").unwrap();
+    let server = support::start_server(Config { faults: vec![stall], ..Config::default() });
+    let mut c = Client::connect(server.addr);
+    c.send(b"GET / HTTP/1.1\r\n\r\n");
+    std::thread::sleep(Duration::from_millis(200));
+    c.reset();
+    std::thread::sleep(Duration::from_millis(1500));
+    assert_eq!(server.shared.counter.states(ConnState::Writing), 0);
+}
+
+#[test]
+fn shared_listener_serves_and_follows_listen_changes() {
+    let server = support::start_server(Config { accept_mode: AcceptMode::Shared, ..Config::default() });
+    assert_eq!(Client::connect(server.addr).get("/").status, 200);
+
+    let other = support::free_addr();
+    server.shared.listen.update(vec![other]).unwrap();
+    std::thread::sleep(Duration::from_millis(700));
+    assert_eq!(Client::connect(other).get("/").status, 200);
+    assert!(TcpStream::connect(server.addr).is_err());
+}
+
+#[test]
+fn accept_thread_deals_connections_round_robin() {
+    let cfg = Config { addr: SocketAddr::from(([127, 0, 0, 1], 0)), accept_mode: AcceptMode::Thread, ..Config::default() };
+    let server = Server::start(Box::leak(Box::new(cfg)), 3).expect("start server");
+    for _ in 0..6 {
+        assert_eq!(Client::connect(server.addr).get("/").status, 200);
+    }
+    let accepted: Vec<u64> = server.shared.counter.slots().iter().map(|s| s.accepted.load(Ordering::Relaxed)).collect();
+    assert_eq!(accepted, [2, 2, 2]);
+}
+
+#[test]
+fn tables_shrink_back_after_a_connection_burst() {
+    let server = support::start_server(Config::default());
+    let counter = server.shared.counter;
+    std::thread::sleep(Duration::from_millis(1200));
+    let baseline = counter.memory();
+
+    let burst: Vec<TcpStream> = (0..3000).map(|_| TcpStream::connect(server.addr).unwrap()).collect();
+    while counter.slots()[0].active.load(Ordering::Relaxed) < 3000 {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    drop(burst);
+    while counter.slots()[0].active.load(Ordering::Relaxed) > 0 {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    std::thread::sleep(Duration::from_millis(2200));
+
+    // Only the bounded set of recycled read buffers and the tables' floor may stay behind.
+    let recycled = (MAX_RECYCLED_BUFS * BUF_SIZE) as u64;
+    assert!(counter.memory() <= baseline + recycled + 32 * 1024, "{} vs {baseline}", counter.memory());
+
+    // Every buffer of the burst was allocated fresh; those beyond the recycle list were freed.
+    assert_eq!(counter.buf_pool(PoolStat::Acquires), 3000);
+    assert_eq!(counter.buf_pool(PoolStat::Fresh), 3000);
+    assert_eq!(counter.buf_pool(PoolStat::Releases), 3000);
+    assert_eq!(counter.buf_pool(PoolStat::Dropped), (3000 - MAX_RECYCLED_BUFS) as u64);
+    assert!(counter.buf_pool(PoolStat::ZeroingNs) > 0);
+}
+
+#[test]
+fn mapped_buffer_pool_reuses_given_back_buffers_across_bursts() {
+    let server = support::start_server(Config { buf_pool: BufBacking::Mmap, ..Config::default() });
+    let counter = server.shared.counter;
+    for _ in 0..2 {
+        let mut burst: Vec<Client> = (0..300).map(|_| Client::connect(server.addr)).collect();
+        for c in &mut burst {
+            assert_eq!(c.get("/").status, 200);
+        }
+        drop(burst);
+        while counter.slots()[0].active.load(Ordering::Relaxed) > 0 {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+    std::thread::sleep(Duration::from_millis(1200));
+
+    // The second burst takes the recycled buffers, then the ones whose pages were given back.
+    assert_eq!(counter.buf_pool(PoolStat::Acquires), 600);
+    assert_eq!(counter.buf_pool(PoolStat::Recycled), MAX_RECYCLED_BUFS as u64);
+    assert_eq!(counter.buf_pool(PoolStat::Dropped), 2 * (300 - MAX_RECYCLED_BUFS) as u64);
+}
+
+#[test]
+fn reused_token_is_not_timed_out_by_its_previous_connection() {
+    let cfg = Config {
+        header_timeout: Duration::from_secs(5),
+        keepalive_timeout: Duration::from_secs(1),
+        ..Config::default()
+    };
+    let addr = support::start(cfg);
+    let mut first = Client::connect(addr);
+    assert_eq!(first.get("/").status, 200);
+    drop(first);
+    std::thread::sleep(Duration::from_millis(100));
+
+    // Same worker, same token: the first connection's keep-alive timer is still pending.
+    let mut second = Client::connect(addr);
+    second.send(b"GET / HTTP/1.1\r\n");
+    std::thread::sleep(Duration::from_millis(2500));
+    second.send(b"\r\n");
+    assert_eq!(second.read_response().status, 200);
+}
+
+#[test]
+fn start_reports_a_taken_address_as_a_transient_bind_error() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = taken.local_addr().unwrap();
+    let cfg = Config { addr, ..Config::default() };
+    let err = Server::start(Box::leak(Box::new(cfg)), 1).err().expect("address is taken");
+    assert!(matches!(err, VryptError::Bind { addr: a, .. } if a == addr));
+    assert!(err.is_transient());
+    assert!(err.to_string().contains(&addr.to_string()));
+}
+
+#[test]
+fn listeners_bind_to_the_configured_device() {
+    let addr = support::start(Config { bind_device: Some("lo".to_string()), ..Config::default() });
+    assert_eq!(Client::connect(addr).get("/").status, 200);
+
+    let cfg = Config {
+        addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        bind_device: Some("vrypt-none0".to_string()),
+        ..Config::default()
+    };
+    let err = Server::start(Box::leak(Box::new(cfg)), 1).err().expect("no such device");
+    assert!(matches!(&err, VryptError::Bind { source, .. } if source.raw_os_error() == Some(libc::ENODEV)), "{err}");
+}
+
+#[test]
+fn start_fails_before_serving_when_the_user_to_switch_to_is_unknown() {
+    let cfg = Config {
+        addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        user: Some("vrypt-no-such-user".to_string()),
+        ..Config::default()
+    };
+    let err = Server::start(Box::leak(Box::new(cfg)), 1).err().expect("no such user");
+    assert!(matches!(&err, VryptError::Privileges(source) if source.kind() == std::io::ErrorKind::NotFound), "{err}");
+    assert!(!err.is_transient());
+    assert!(err.to_string().contains("vrypt-no-such-user"), "{err}");
+}
+
+#[test]
+fn accept_rate_paces_new_connections() {
+    let addr = support::start(Config { accept_rate: Some(5), ..Config::default() });
+    let start = Instant::now();
+    let mut clients: Vec<Client> = (0..8).map(|_| Client::connect(addr)).collect();
+    for c in &mut clients {
+        c.send(b"GET / HTTP/1.1\r\n\r\n");
+    }
+    for c in &mut clients {
+        assert_eq!(c.read_response().status, 200);
+    }
+    // One connection up front, then one every 200ms.
+    assert!(start.elapsed() >= Duration::from_millis(1200), "took {:?}", start.elapsed());
+}
+
+#[test]
+fn max_conns_leaves_connections_in_the_backlog_until_below_the_low_mark() {
+    let addr = support::start(Config { max_conns: Some(2), max_conns_low: Some(1), ..Config::default() });
+    let mut held: Vec<Client> = (0..2).map(|_| Client::connect(addr)).collect();
+    for c in &mut held {
+        assert_eq!(c.get("/").status, 200);
+    }
+    // Connected, but left in the accept backlog: the request goes unanswered.
+    let mut queued = TcpStream::connect(addr).unwrap();
+    std::io::Write::write_all(&mut queued, b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    queued.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    assert!(std::io::Read::read(&mut queued, &mut [0u8; 1]).is_err());
+
+    drop(held.pop());
+    queued.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut head = [0u8; 12];
+    std::io::Read::read_exact(&mut queued, &mut head).unwrap();
+    assert_eq!(&head, b"HTTP/1.1 200");
+}
+
+#[test]
+fn priority_reserve_admits_health_checks_past_the_connection_cap() {
+    let cfg = Config {
+        max_conns: Some(1),
+        priority_reserve: Some(2),
+        health_path: Some("/healthz".into()),
+        ..Config::default()
+    };
+    let addr = support::start(cfg);
+    let mut held = Client::connect(addr);
+    assert_eq!(held.get("/").status, 200);
+
+    // Both taken into the reserve, which only serves health checks and admin requests.
+    let mut other = Client::connect(addr);
+    let mut probe = Client::connect(addr);
+    assert_eq!(other.get("/").status, 503);
+    assert!(other.is_closed());
+    let res = probe.get("/healthz");
+    assert_eq!((res.status, res.body.as_slice()), (200, &b"OK"[..]));
+    assert_eq!(probe.get("/__vrypt/version").status, 200);
+    assert_eq!(held.get("/").status, 200);
+}
+
+#[test]
+fn health_checks_are_answered_while_requests_are_shed() {
+    let addr = support::start(Config { max_inflight: Some(1), health_path: Some("/healthz".into()), ..Config::default() });
+    // Holds the only in-flight slot while its body is outstanding.
+    let mut upload = Client::connect(addr);
+    upload.send(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc");
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(Client::connect(addr).get("/").status, 503);
+    assert_eq!(Client::connect(addr).get("/healthz").status, 200);
+}
+
+fn forward_proxy(allowed: SocketAddr) -> SocketAddr {
+    let target = ProxyTarget::parse(&allowed.to_string()).unwrap();
+    support::start(Config { proxy_allow: vec![target], ..Config::default() })
+}
+
+#[test]
+fn connect_tunnels_to_an_allowed_destination() {
+    let echo = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let dest = echo.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (mut s, _) = echo.accept().unwrap();
+        let mut r = s.try_clone().unwrap();
+        let _ = std::io::copy(&mut r, &mut s);
+    });
+    let mut c = Client::connect(forward_proxy(dest));
+    c.send(format!("CONNECT {dest} HTTP/1.1\r\nHost: {dest}\r\n\r\n").as_bytes());
+    assert_eq!(c.read_exact(ESTABLISHED.len()), ESTABLISHED);
+    c.send(b"ping");
+    assert_eq!(c.read_exact(4), b"ping");
+}
+
+#[test]
+fn absolute_form_requests_are_relayed() {
+    let origin = support::start_default();
+    let mut c = Client::connect(forward_proxy(origin));
+    c.send(format!("GET http://{origin}/ HTTP/1.1\r\nProxy-Connection: keep-alive\r\n\r\n").as_bytes());
+    let res = c.read_response();
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, b"Vrypt");
+}
+
+#[test]
+fn forward_proxy_refuses_destinations_not_allowed() {
+    let mut c = Client::connect(forward_proxy(support::free_addr()));
+    c.send(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n");
+    assert_eq!(c.read_response().status, 403);
+    c.send(b"GET http://example.com/ HTTP/1.1\r\n\r\n");
+    assert!(c.is_closed());
+}
+
+#[test]
+fn unreachable_upstream_gets_bad_gateway() {
+    let dest = support::free_addr();
+    let mut c = Client::connect(forward_proxy(dest));
+    c.send(format!("CONNECT {dest} HTTP/1.1\r\n\r\n").as_bytes());
+    assert_eq!(c.read_response().status, 502);
+    assert!(c.is_closed());
+}
+
+#[test]
+fn upstream_connect_timeout_gets_bad_gateway() {
+    // With its accept queue full, the destination drops further SYNs and the connect hangs.
+    let dest = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+    dest.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+    dest.listen(0).unwrap();
+    let dest = dest.local_addr().unwrap().as_socket().unwrap();
+    let _queued: Vec<_> = (0..2).filter_map(|_| TcpStream::connect_timeout(&dest, Duration::from_millis(200)).ok()).collect();
+    let target = ProxyTarget::parse(&dest.to_string()).unwrap();
+    let server = support::start_server(Config {
+        proxy_allow: vec![target],
+        connect_timeout: Duration::from_secs(1),
+        ..Config::default()
+    });
+    let mut c = Client::connect(server.addr);
+    let start = Instant::now();
+    c.send(format!("CONNECT {dest} HTTP/1.1\r\n\r\n").as_bytes());
+    assert_eq!(c.read_response().status, 502);
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert!(c.is_closed());
+}
+
+fn split_upstream(name: &str) -> SplitGroup {
+    let rule = HeaderRule::set(&format!("X-Group: {name}")).unwrap();
+    let addr = support::start(Config { header_rules: vec![rule], ..Config::default() });
+    SplitGroup::parse(&format!("{name}=1@{addr}")).unwrap()
+}
+
+#[test]
+fn split_groups_take_traffic_by_weight_and_sticky_key() {
+    let split = Split {
+        groups: vec![split_upstream("stable"), split_upstream("canary")],
+        paths: vec!["/app".into()],
+        sticky: Some(Sticky::parse("cookie:uid").unwrap()),
+        affinity: None,
+    };
+    let server = support::start_server(Config { admin: true, split, ..Config::default() });
+    let group = |uid: &str| {
+        let mut c = Client::connect(server.addr);
+        c.send(format!("GET /app/x HTTP/1.1\r\nCookie: theme=dark; uid={uid}\r\n\r\n").as_bytes());
+        c.read_response().header("x-group").map(str::to_string)
+    };
+    let first = group("42");
+    assert!(first.is_some());
+    for _ in 0..5 {
+        assert_eq!(group("42"), first);
+    }
+    let seen: std::collections::HashSet<_> = (0..40).map(|i| group(&i.to_string())).collect();
+    assert_eq!(seen.len(), 2, "{seen:?}");
+
+    let mut local = Client::connect(server.addr);
+    assert_eq!(local.get("/other").header("x-group"), None);
+    assert_eq!(local.get("/__vrypt/version").status, 200);
+    let stats = server.shared.split.unwrap();
+    assert_eq!(stats.take(0) + stats.take(1), 46);
+
+    let groups = ["a=3@127.0.0.1:1", "b=1@127.0.0.1:1"].map(|g| SplitGroup::parse(g).unwrap());
+    let weighted = Split { groups: groups.to_vec(), ..Split::default() };
+    let picks: Vec<usize> = (0..8).map(|r| weighted.group_for(None, r)).collect();
+    assert_eq!(picks, [0, 0, 0, 1, 0, 0, 0, 1]);
+}
+
+#[test]
+fn split_affinity_keeps_a_client_address_on_its_first_group() {
+    let split = Split {
+        groups: vec![split_upstream("stable"), split_upstream("canary")],
+        sticky: Some(Sticky::Ip),
+        affinity: Some(Duration::from_secs(60)),
+        ..Split::default()
+    };
+    let addr = support::start(Config { split, ..Config::default() });
+    let groups: std::collections::HashSet<_> =
+        (0..10).map(|_| Client::connect(addr).get("/").header("x-group").map(str::to_string)).collect();
+    assert_eq!(groups.len(), 1, "{groups:?}");
+}
+
+#[test]
+fn relays_are_counted_per_tcp_upstream() {
+    let group = split_upstream("stable");
+    let server = support::start_server(Config { split: Split { groups: vec![group], ..Split::default() }, ..Config::default() });
+    let backends = server.shared.backends.expect("upstream stats");
+    assert_eq!(backends.sockets(), [format!("127.0.0.1:{}", server.shared.cfg.split.groups[0].target.port)]);
+
+    let mut c = Client::connect(server.addr);
+    assert_eq!(c.get("/").status, 200);
+    assert_eq!(backends.take(0, UpstreamStat::Opened), 1);
+    // The relay asked for `Connection: close`; once both ends are closed it is no longer busy.
+    drop(c);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while backends.take(0, UpstreamStat::Busy) != 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(backends.take(0, UpstreamStat::Busy), 0);
+    assert_eq!(backends.take(0, UpstreamStat::Idle), 0);
+}
+
+/// A shadow upstream that reports each request it gets, head and body, and never answers.
+fn silent_shadow() -> (SocketAddr, std::sync::mpsc::Receiver<String>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut held = Vec::new();
+        for mut s in listener.incoming().flatten() {
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 1024];
+            loop {
+                let text = String::from_utf8_lossy(&buf).into_owned();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length: usize = text[..end]
+                        .lines()
+                        .find_map(|l| l.strip_prefix("Content-Length: "))
+                        .map_or(0, |v| v.trim().parse().unwrap());
+                    if buf.len() >= end + 4 + length {
+                        let _ = tx.send(text);
+                        break;
+                    }
+                }
+                match std::io::Read::read(&mut s, &mut chunk) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                }
+            }
+            held.push(s);
+        }
+    });
+    (addr, rx)
+}
+
+#[test]
+fn mirrored_requests_reach_the_shadow_without_holding_up_the_response() {
+    let (shadow, mirrored) = silent_shadow();
+    let split = Split { groups: vec![split_upstream("stable")], ..Split::default() };
+    let mirror = Mirror::parse(&format!("100@{shadow}")).unwrap();
+    let server = support::start_server(Config { split, mirror: Some(mirror), ..Config::default() });
+
+    let mut c = Client::connect(server.addr);
+    let start = Instant::now();
+    c.send(b"POST /orders HTTP/1.1\r\nHost: shop\r\nContent-Length: 5\r\n\r\nhello");
+    let res = c.read_response();
+    assert_eq!((res.status, res.header("x-group")), (200, Some("stable")));
+    assert!(start.elapsed() < Duration::from_secs(1), "{:?}", start.elapsed());
+    let copy = mirrored.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(copy.starts_with("POST /orders HTTP/1.1\r\nHost: shop\r\n"), "{copy:?}");
+    assert!(copy.ends_with("Connection: close\r\n\r\nhello"), "{copy:?}");
+
+    // Part of the body is still to come, so only the real upstream gets it.
+    let mut c = Client::connect(server.addr);
+    c.send(b"POST /orders HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello");
+    std::thread::sleep(Duration::from_millis(50));
+    c.send(b"world");
+    assert_eq!(c.read_response().status, 200);
+    assert!(mirrored.recv_timeout(Duration::from_millis(200)).is_err());
+
+    let stats = server.shared.mirror.unwrap();
+    assert_eq!((stats.take(MirrorOutcome::Sent), stats.take(MirrorOutcome::Skipped)), (1, 1));
+}
@@ -0,0 +1,30 @@
+use vrypt_server::json::Json;
+
+fn string(value: &[u8]) -> String {
+    let mut out = Vec::new();
+    Json::new(&mut out).str(value);
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn members_are_separated_by_commas() {
+    let mut out = Vec::new();
+    let mut json = Json::new(&mut out);
+    json.begin_object().key("a").u64(1).key("b").begin_array();
+    json.bool(true).begin_object().end_object().begin_array().end_array().u64(2);
+    json.end_array().key("c").display("127.0.0.1:80").end_object();
+    assert_eq!(out, br#"{"a":1,"b":[true,{},[],2],"c":"127.0.0.1:80"}"#);
+}
+
+#[test]
+fn strings_are_escaped() {
+    assert_eq!(string(b"say \"hi\"\\\n\t\x01"), r#""say \"hi\"\\\n\t\u0001""#);
+    let mut out = Vec::new();
+    Json::new(&mut out).key("k\"").display("a\x1fb");
+    assert_eq!(out, br#""k\"":"a\u001fb""#);
+}
+
+#[test]
+fn invalid_utf8_is_replaced() {
+    assert_eq!(string(b"caf\xc3\xa9 \xff!"), "\"caf\u{e9} \u{fffd}!\"");
+}
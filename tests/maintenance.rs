@@ -0,0 +1,30 @@
+//! Maintenance mode is process-wide, so it is switched in a test binary of its own.
+
+mod support;
+
+use std::sync::atomic::Ordering;
+use support::Client;
+use vrypt_server::config::Config;
+use vrypt_server::signal;
+
+#[test]
+fn maintenance_spares_health_checks_and_follows_the_admin_toggle() {
+    let addr = support::start(Config { admin: true, health_path: Some("/healthz".into()), ..Config::default() });
+    // What `--maintenance` does at startup.
+    signal::MAINTENANCE.store(true, Ordering::Relaxed);
+    let mut c = Client::connect(addr);
+    assert_eq!(c.get("/").status, 503);
+    let res = c.get("/healthz");
+    assert_eq!(res.status, 200);
+    assert_eq!(res.body, b"OK");
+
+    c.send(b"PUT /__vrypt/toggles/maintenance?off HTTP/1.1\r\n\r\n");
+    assert!(c.read_response().body.ends_with(b"maintenance: off\n"));
+    assert!(!signal::maintenance());
+    assert_eq!(c.get("/").status, 200);
+
+    c.send(b"PUT /__vrypt/toggles/maintenance HTTP/1.1\r\n\r\n");
+    assert!(c.read_response().body.ends_with(b"maintenance: on\n"));
+    assert_eq!(c.get("/").status, 503);
+    assert_eq!(c.get("/healthz").status, 200);
+}
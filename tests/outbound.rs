@@ -0,0 +1,63 @@
+use socket2::{Domain, Socket, Type};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use vrypt_server::outbound::{Connect, Outbound};
+
+/// Polls `out` until it is no longer pending.
+fn settle(out: &mut Outbound) -> Connect {
+    let start = Instant::now();
+    loop {
+        match out.poll(Instant::now()) {
+            Connect::Pending if start.elapsed() < Duration::from_secs(5) => thread::sleep(Duration::from_millis(10)),
+            res => return res,
+        }
+    }
+}
+
+/// A listener whose accept queue is full, so further SYNs are dropped and connects hang.
+fn full_listener() -> (Socket, Vec<TcpStream>) {
+    let sock = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    sock.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+    sock.listen(0).unwrap();
+    let addr = sock.local_addr().unwrap().as_socket().unwrap();
+    let queued = (0..2).filter_map(|_| TcpStream::connect_timeout(&addr, Duration::from_millis(200)).ok()).collect();
+    (sock, queued)
+}
+
+#[test]
+fn connect_completes_without_blocking() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut out = Outbound::connect(addr, Duration::from_secs(5)).unwrap();
+    assert!(matches!(settle(&mut out), Connect::Ready));
+    assert!(out.is_connected());
+    assert_eq!(out.deadline(), None);
+    assert_eq!(listener.accept().unwrap().1, out.stream.local_addr().unwrap());
+}
+
+#[test]
+fn refused_connect_fails() {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut out = Outbound::connect(addr, Duration::from_secs(5)).unwrap();
+    match settle(&mut out) {
+        Connect::Failed(e) => assert_eq!(e.kind(), ErrorKind::ConnectionRefused),
+        res => panic!("expected a refused connect, got {res:?}"),
+    }
+}
+
+#[test]
+fn connect_times_out_at_its_deadline() {
+    let (listener, _queued) = full_listener();
+    let addr = listener.local_addr().unwrap().as_socket().unwrap();
+    let start = Instant::now();
+    let mut out = Outbound::connect(addr, Duration::from_millis(300)).unwrap();
+    assert!(out.deadline().is_some());
+    assert!(matches!(out.poll(Instant::now()), Connect::Pending));
+    match settle(&mut out) {
+        Connect::Failed(e) => assert_eq!(e.kind(), ErrorKind::TimedOut),
+        res => panic!("expected a timeout, got {res:?}"),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(300));
+}
@@ -0,0 +1,68 @@
+mod support;
+
+use std::fs::File;
+use std::time::{Duration, Instant};
+use support::Client;
+use vrypt_server::capture::{self, Direction, Record};
+use vrypt_server::config::Config;
+use vrypt_server::replay;
+
+const GET: &[u8] = b"GET / HTTP/1.1\r\nHost: test\r\n\r\n";
+
+fn request(conn: u64, micros: u64) -> Record {
+    Record { conn, dir: Direction::Request, micros, data: GET.to_vec() }
+}
+
+#[test]
+fn captured_requests_are_read_back_and_replayed() {
+    let path = std::env::temp_dir().join(format!("vrypt-replay-{}.cap", std::process::id()));
+    let recorded = support::start(Config { capture_path: Some(path.clone()), ..Config::default() });
+    let mut c = Client::connect(recorded);
+    assert_eq!(c.get("/").status, 200);
+    assert_eq!(c.get("/").status, 200);
+    drop(c);
+    std::thread::sleep(Duration::from_millis(100));
+
+    let records = capture::read_records(File::open(&path).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let requests: Vec<_> = records.iter().filter(|r| r.dir == Direction::Request).collect();
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().all(|r| r.data.starts_with(b"GET / HTTP/1.1\r\n") && r.conn == requests[0].conn));
+    assert!(requests[0].micros <= requests[1].micros);
+    assert!(records.iter().any(|r| r.dir == Direction::Response && r.data.starts_with(b"HTTP/1.1 200 OK\r\n")));
+
+    let target = support::start_default();
+    let report = replay::replay(&records, target, 0.0);
+    assert_eq!((report.conns, report.writes, report.failed), (1, 2, 0));
+    assert_eq!(report.sent_bytes, requests.iter().map(|r| r.data.len() as u64).sum::<u64>());
+    let response_bytes: usize = records.iter().filter(|r| r.dir == Direction::Response).map(|r| r.data.len()).sum();
+    assert!(report.received_bytes >= response_bytes as u64 - 64, "{report:?}");
+}
+
+#[test]
+fn speed_scales_the_captured_gaps() {
+    let target = support::start_default();
+    let records = [request(7, 1_000_000), request(7, 1_300_000), request(9, 1_100_000)];
+    let start = Instant::now();
+    let report = replay::replay(&records, target, 2.0);
+    let took = start.elapsed();
+    assert_eq!((report.conns, report.writes, report.failed), (2, 3, 0));
+    assert!(took >= Duration::from_millis(150), "{took:?}");
+    assert!(took < Duration::from_millis(300) + Duration::from_secs(1), "{took:?}");
+}
+
+#[test]
+fn truncated_record_ends_the_file_and_a_foreign_file_is_refused() {
+    let mut bytes = b"VRYPTCAP\x01".to_vec();
+    for (micros, data) in [(5u64, &b"GET /"[..]), (9, &b"GET /cut"[..])] {
+        bytes.extend_from_slice(&3u64.to_le_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(&micros.to_le_bytes());
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+    }
+    bytes.truncate(bytes.len() - 2);
+    let records = capture::read_records(&bytes[..]).unwrap();
+    assert_eq!(records, [Record { conn: 3, dir: Direction::Request, micros: 5, data: b"GET /".to_vec() }]);
+    assert!(capture::read_records(&b"GIF89a..."[..]).is_err());
+}
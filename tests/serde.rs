@@ -0,0 +1,55 @@
+use std::time::Duration;
+use vrypt_server::config::Config;
+use vrypt_server::conn::ConnState;
+use vrypt_server::counter::RpsCounter;
+use vrypt_server::listen::AcceptMode;
+use vrypt_server::toggle;
+
+#[test]
+fn config_loads_from_a_partial_document() {
+    let cfg: Config = serde_json::from_str(
+        r#"{
+            "accept_mode": "shared",
+            "faults": ["reset:0:0.1"],
+            "header_rules": ["X-Env: test", "-Server"],
+            "redirects": ["301 /old/(.*) /new/$1"],
+            "split": {"groups": ["a=1@127.0.0.1:9000", "b=3@127.0.0.1:9001"], "sticky": "cookie:uid"},
+            "keepalive_timeout": {"secs": 30, "nanos": 0},
+            "stats_sinks": ["stdout,every=5,prefix=edge", "scrape://127.0.0.1:9100,off"],
+            "tenants": ["accounts=prefix:/api/accounts,rate=100"],
+            "static_routes": ["accounts/users=/srv/users.json"]
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(cfg.accept_mode, AcceptMode::Shared);
+    assert_eq!(cfg.faults.len(), 1);
+    assert_eq!(cfg.header_rules.len(), 2);
+    assert_eq!(cfg.split.groups[1].weight, 3);
+    assert_eq!(cfg.keepalive_timeout, Duration::from_secs(30));
+    assert_eq!(cfg.header_timeout, Config::default().header_timeout);
+    assert_eq!(cfg.active_stats_sinks().len(), 1);
+    assert_eq!(cfg.active_stats_sinks()[0].interval, Duration::from_secs(5));
+    assert_eq!(cfg.tenants[0].rate, Some(100));
+    assert_eq!(cfg.static_routes[0].tenant.as_deref(), Some("accounts"));
+
+    let again: Config = serde_json::from_value(serde_json::to_value(&cfg).unwrap()).unwrap();
+    assert_eq!(serde_json::to_value(&again).unwrap(), serde_json::to_value(&cfg).unwrap());
+}
+
+#[test]
+fn invalid_rules_fail_to_load() {
+    let Err(err) = serde_json::from_str::<Config>(r#"{"faults": ["stall:2"]}"#) else { panic!("loaded") };
+    assert!(err.to_string().contains("invalid probability"), "{err}");
+}
+
+#[test]
+fn stats_snapshot_serializes() {
+    let counter = RpsCounter::new(2);
+    counter.increment(1);
+    counter.set_states(0, std::array::from_fn(|i| (ConnState::ALL[i] == ConnState::Idle) as u64));
+    let value = serde_json::to_value(counter.snapshot()).unwrap();
+    assert_eq!(value["requests"], 1);
+    assert_eq!(value["conns"]["idle"], 1);
+    assert_eq!(value["workers"][1]["requests"], 1);
+    assert_eq!(serde_json::to_value(toggle::states()).unwrap()[0], serde_json::json!(["debug-log", false]));
+}
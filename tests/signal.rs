@@ -0,0 +1,16 @@
+use std::time::{Duration, Instant};
+use vrypt_server::signal;
+
+#[test]
+fn a_reload_signal_wakes_the_watch_at_once() {
+    let mut watch = signal::watch().expect("watch");
+    assert!(signal::watch().is_err(), "a second watch would miss wakeups");
+    signal::install_reload_handler().expect("SIGHUP handler");
+
+    let started = Instant::now();
+    unsafe { libc::raise(libc::SIGHUP) };
+    watch.wait(Some(Duration::from_secs(5)));
+    assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+    assert!(signal::take_reload());
+    assert!(!signal::take_reload());
+}
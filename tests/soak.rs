@@ -0,0 +1,200 @@
+//! Soak test: hammers an in-process server with connection churn and randomized request
+//! shapes, then checks that RSS, open fds, live connections and timer wheel entries came
+//! back down or stayed flat. Built only with the `soak` feature:
+//!
+//! ```text
+//! VRYPT_SOAK_SECS=600 cargo test --release --features soak --test soak -- --nocapture
+//! ```
+
+mod support;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use support::Client;
+use vrypt_server::config::{Config, STATS_INTERVAL};
+use vrypt_server::counter::RpsCounter;
+use vrypt_server::server::Server;
+
+const DEFAULT_SECS: u64 = 300;
+const WORKERS: usize = 2;
+const CLIENTS: usize = 8;
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// Growth allowed between the halves of the run, on top of 10%: bytes, then timer entries.
+const BYTES_SLACK: u64 = 8 << 20;
+const TIMERS_SLACK: u64 = 1024;
+const FD_SLACK: u64 = 8;
+
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    rss: u64,
+    fds: u64,
+    active: u64,
+    timers: u64,
+    memory: u64,
+}
+
+fn sample(counter: &RpsCounter) -> Sample {
+    let statm = std::fs::read_to_string("/proc/self/statm").expect("statm");
+    let pages: u64 = statm.split(' ').nth(1).and_then(|s| s.parse().ok()).expect("resident pages");
+    // SAFETY: sysconf has no preconditions.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let active = counter.slots().iter().map(|s| s.active.load(Ordering::Relaxed)).sum();
+    Sample {
+        rss: pages * page_size,
+        fds: std::fs::read_dir("/proc/self/fd").expect("fd dir").count() as u64,
+        active,
+        timers: counter.timers(),
+        memory: counter.memory(),
+    }
+}
+
+/// One connection with a randomly chosen shape; returns the requests it had answered.
+fn interact(rng: &mut Rng, addr: SocketAddr) -> u64 {
+    let mut c = Client::connect(addr);
+    // Weighted towards requests that get answered; idle connections each hold a client for a second.
+    match rng.below(32) {
+        0..=7 => {
+            let n = 1 + rng.below(8);
+            for _ in 0..n {
+                assert_eq!(c.get("/").status, 200);
+            }
+            n
+        }
+        8..=15 => {
+            let n = 1 + rng.below(16);
+            c.send(b"GET / HTTP/1.1\r\n\r\n".repeat(n as usize).as_slice());
+            for _ in 0..n {
+                assert_eq!(c.read_response().status, 200);
+            }
+            n
+        }
+        16..=19 => {
+            let body = vec![b'x'; rng.below(64 * 1024) as usize];
+            c.send(format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes());
+            c.send(&body);
+            assert_eq!(c.read_response().status, 200);
+            1
+        }
+        20..=23 => {
+            c.send(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+            for _ in 0..rng.below(8) {
+                let chunk = vec![b'y'; 1 + rng.below(4096) as usize];
+                c.send(format!("{:x}\r\n", chunk.len()).as_bytes());
+                c.send(&chunk);
+                c.send(b"\r\n");
+            }
+            c.send(b"0\r\n\r\n");
+            assert_eq!(c.read_response().status, 200);
+            1
+        }
+        24..=25 => {
+            c.send(b"GET / HTTP/1.1\r\nBad Header: x\r\n\r\n");
+            assert_eq!(c.read_response().status, 400);
+            1
+        }
+        // Abandoned mid-head: left to the header timeout or noticed at close.
+        26..=27 => {
+            c.send(b"GET / HTTP/1.1\r\nHost: soak");
+            0
+        }
+        // Reset with a response on its way.
+        28..=29 => {
+            c.send(b"POST / HTTP/1.1\r\nContent-Length: 1\r\n\r\nz");
+            c.reset();
+            0
+        }
+        // Connects and hangs up without a byte.
+        30 => 0,
+        // Stays idle until the server times it out.
+        _ => {
+            assert!(c.is_closed());
+            0
+        }
+    }
+}
+
+/// Fails if the peak of `f` over `second` exceeds its peak over `first` by more than 10% plus `slack`.
+fn assert_flat(name: &str, first: &[Sample], second: &[Sample], f: impl Fn(&Sample) -> u64, slack: u64) {
+    let before = first.iter().map(&f).max().unwrap_or(0);
+    let after = second.iter().map(&f).max().unwrap_or(0);
+    eprintln!("soak: peak {name} {before} -> {after}");
+    assert!(after <= before + before / 10 + slack, "{name} kept growing: peak {before} then {after}");
+}
+
+#[test]
+fn soak_keeps_resources_bounded() {
+    let secs = std::env::var("VRYPT_SOAK_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(DEFAULT_SECS);
+    let cfg = Config {
+        addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        header_timeout: TIMEOUT,
+        body_timeout: TIMEOUT,
+        keepalive_timeout: TIMEOUT,
+        ..Config::default()
+    };
+    let server = Server::start(Box::leak(Box::new(cfg)), WORKERS).expect("start server");
+    let (addr, counter) = (server.addr, server.shared.counter);
+    thread::sleep(STATS_INTERVAL);
+    let baseline = sample(counter);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let answered = Arc::new(AtomicU64::new(0));
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|i| {
+            let (stop, answered) = (stop.clone(), answered.clone());
+            thread::spawn(move || {
+                let mut rng = Rng(0x9e37_79b9_7f4a_7c15 ^ (i as u64 + 1));
+                while !stop.load(Ordering::Relaxed) {
+                    answered.fetch_add(interact(&mut rng, addr), Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+
+    let start = Instant::now();
+    let mut samples = Vec::new();
+    while start.elapsed() < Duration::from_secs(secs) {
+        thread::sleep(STATS_INTERVAL);
+        samples.push(sample(counter));
+    }
+    stop.store(true, Ordering::Relaxed);
+    for c in clients {
+        c.join().expect("client thread");
+    }
+    // Every timeout fires and the workers sample their tables again.
+    thread::sleep(TIMEOUT * 2 + STATS_INTERVAL * 2);
+    let end = sample(counter);
+    eprintln!(
+        "soak: {secs}s, {} requests answered, {} connections accepted",
+        answered.load(Ordering::Relaxed),
+        counter.slots().iter().map(|s| s.accepted.load(Ordering::Relaxed)).sum::<u64>()
+    );
+    eprintln!("soak: baseline {baseline:?}\nsoak: end      {end:?}");
+
+    assert_eq!(end.active, 0, "connections left open");
+    assert_eq!(end.timers, 0, "timer entries left behind");
+    assert!(end.fds <= baseline.fds + FD_SLACK, "fds grew from {} to {}", baseline.fds, end.fds);
+
+    // Compare the second half of the run against the first, skipping a warm-up fifth.
+    let warm = samples.len() / 5;
+    let mid = warm + (samples.len() - warm) / 2;
+    if mid > warm && mid < samples.len() {
+        let (first, second) = (&samples[warm..mid], &samples[mid..]);
+        assert_flat("rss", first, second, |s| s.rss, BYTES_SLACK);
+        assert_flat("memory", first, second, |s| s.memory, BYTES_SLACK);
+        assert_flat("timers", first, second, |s| s.timers, TIMERS_SLACK);
+    }
+}
@@ -0,0 +1,36 @@
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use vrypt_server::sockopt::{self, parse_dscp};
+
+fn int_opt(sock: &TcpStream, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+    let mut value: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let ptr = &mut value as *mut _ as *mut libc::c_void;
+    let rc = unsafe { libc::getsockopt(sock.as_raw_fd(), level, name, ptr, &mut len) };
+    assert_eq!(rc, 0, "{}", std::io::Error::last_os_error());
+    value
+}
+
+#[test]
+fn dscp_accepts_numbers_and_class_names() {
+    assert_eq!(parse_dscp("46"), Ok(46));
+    assert_eq!(parse_dscp("EF"), Ok(46));
+    assert_eq!(parse_dscp("af41"), Ok(34));
+    assert_eq!(parse_dscp("cs1"), Ok(8));
+    assert!(parse_dscp("64").is_err());
+    assert!(parse_dscp("af44").is_err());
+}
+
+#[test]
+fn qos_marks_land_on_the_socket() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    sockopt::set_qos(&stream, false, Some(46), Some(3)).unwrap();
+    assert_eq!(int_opt(&stream, libc::IPPROTO_IP, libc::IP_TOS), 46 << 2);
+    assert_eq!(int_opt(&stream, libc::SOL_SOCKET, libc::SO_PRIORITY), 3);
+
+    let listener = TcpListener::bind("[::1]:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    sockopt::set_qos(&stream, true, Some(10), None).unwrap();
+    assert_eq!(int_opt(&stream, libc::IPPROTO_IPV6, libc::IPV6_TCLASS), 10 << 2);
+}
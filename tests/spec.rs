@@ -0,0 +1,82 @@
+use vrypt_server::close::CloseRule;
+use vrypt_server::error_page::ErrorPage;
+use vrypt_server::fault::FaultRule;
+use vrypt_server::headers::{HeaderRule, RouteHeader};
+use vrypt_server::limit::{ListenerLimit, RouteLimit};
+use vrypt_server::listen::AcceptMode;
+use vrypt_server::mirror::Mirror;
+use vrypt_server::redirect::RedirectRule;
+use vrypt_server::region::BufBacking;
+use vrypt_server::rewrite::PathRewrite;
+use vrypt_server::routes::StaticRoute;
+use vrypt_server::sink::SinkMode;
+use vrypt_server::spec::Spec;
+use vrypt_server::split::{SplitGroup, Sticky};
+use vrypt_server::statsd::{StatsSink, StatsTarget};
+use vrypt_server::tenant::Tenant;
+use vrypt_server::tunnel::ProxyTarget;
+
+fn round_trip<T: Spec>(specs: &[&str]) {
+    for spec in specs {
+        let value = T::from_spec(spec).unwrap_or_else(|e| panic!("{spec}: {e}"));
+        assert_eq!(value.to_spec(), *spec);
+    }
+}
+
+#[test]
+fn rules_round_trip_through_their_spec() {
+    round_trip::<FaultRule>(&["stall:0.5", "reset:100:1", "close-headers:0.01"]);
+    round_trip::<HeaderRule>(&["Cache-Control: no-store", "-Server"]);
+    round_trip::<RouteHeader>(&["/app.js=Cache-Control: max-age=60", "accounts/users=-Server"]);
+    round_trip::<PathRewrite>(&["/api=", "/=/v1/", "/old=/new"]);
+    round_trip::<ListenerLimit>(&["0.0.0.0:8080=100", "[::1]:9000=5"]);
+    round_trip::<RouteLimit>(&["/report=2", "billing/invoices=10"]);
+    round_trip::<RedirectRule>(&["301 /old/(.*) /new/$1", "308 /a(.*)b(.*) https://example.com/$2$1"]);
+    round_trip::<SinkMode>(&["discard", "hash", "store:/tmp/bodies"]);
+    round_trip::<AcceptMode>(&["reuseport", "shared", "thread"]);
+    round_trip::<BufBacking>(&["heap", "mmap", "thp", "hugetlb"]);
+    round_trip::<StatsTarget>(&["udp://127.0.0.1:8125", "http://[::1]:9091/metrics/job/vrypt", "scrape://0.0.0.0:9100", "stdout"]);
+    round_trip::<StatsSink>(&["udp://127.0.0.1:8125", "stdout,every=10,prefix=edge.a", "scrape://[::1]:9100,off"]);
+    round_trip::<ProxyTarget>(&["127.0.0.1:8080", "[::1]:443"]);
+    round_trip::<SplitGroup>(&["canary=5@127.0.0.1:9000"]);
+    round_trip::<Mirror>(&["10@127.0.0.1:9000", "0.5@[::1]:8080"]);
+    round_trip::<Sticky>(&["ip", "header:X-User", "cookie:uid"]);
+    round_trip::<ErrorPage>(&["503=busy", "shop.example/413=@/srv/too-large.html"]);
+    round_trip::<StaticRoute>(&[
+        "/app.js=/srv/app.js",
+        "protobuf:/reply=/srv/reply.bin",
+        "grpc:/echo.Echo/Reply=/srv/reply.bin",
+        "accounts/users=/srv/users.json",
+        "grpc:billing/echo.Echo/Reply=/srv/reply.bin",
+        "GET,POST /api/users=/srv/users.json",
+        "grpc:POST billing/echo.Echo/Reply=/srv/reply.bin",
+        "PURGE /cache=/srv/my cache.bin",
+        "exec:GET /time=/bin/date -u",
+        "fastcgi:/index.php=/run/php/fpm.sock /srv/www/index.php",
+        "scgi:POST /app=/run/app.sock",
+    ]);
+    round_trip::<Tenant>(&["accounts=prefix:/api/accounts", "billing=host:billing.example,max-inflight=100,rate=500"]);
+    round_trip::<CloseRule>(&["timeout=rst", "error=fin", "policy=drain"]);
+}
+
+#[test]
+fn invalid_specs_are_refused() {
+    assert!(HeaderRule::from_spec("-Content-Length").is_err());
+    assert!(RouteHeader::from_spec("app.js=X-A: 1").is_err());
+    assert!(RouteHeader::from_spec("/app.js=-Transfer-Encoding").is_err());
+    assert!(PathRewrite::from_spec("api=/v1").is_err());
+    assert!(ListenerLimit::from_spec("8080=10").is_err());
+    assert!(RouteLimit::from_spec("/report=0").is_err());
+    assert!(PathRewrite::from_spec("/api=v1").is_err());
+    assert!(RedirectRule::from_spec("301 /old").is_err());
+    assert!(ErrorPage::from_spec("404=gone").is_err());
+    assert!(StaticRoute::from_spec("app.js=/srv/app.js").is_err());
+    assert!(StaticRoute::from_spec("/app.js?v=1=/srv/app.js").is_err());
+    assert!(StaticRoute::from_spec("GET;POST /app.js=/srv/app.js").is_err());
+    assert!(Tenant::from_spec("root=prefix:/").is_err());
+    assert!(Tenant::from_spec("a.b=host:a.example").is_err());
+    assert!(Tenant::from_spec("api=prefix:/api,burst=5").is_err());
+    assert!(CloseRule::from_spec("timeout").is_err());
+    assert!(CloseRule::from_spec("idle=rst").is_err());
+    assert!(CloseRule::from_spec("error=linger").is_err());
+}
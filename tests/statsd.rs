@@ -0,0 +1,211 @@
+mod support;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, UdpSocket};
+use std::sync::atomic::AtomicBool;
+use std::thread;
+use std::time::Duration;
+use vrypt_server::config::{RESTART_BACKOFF_MIN, STATS_MAX_PACKET};
+use vrypt_server::counter::{spawn_stats_pusher, RpsCounter, StatsSources};
+use vrypt_server::statsd::{StatsClient, StatsSink, StatsTarget, Transport};
+
+fn udp_collector() -> (UdpSocket, StatsTarget) {
+    let sock = UdpSocket::bind("127.0.0.1:0").expect("bind collector");
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let target = StatsTarget::parse(&sock.local_addr().unwrap().to_string()).expect("target");
+    (sock, target)
+}
+
+fn recv(sock: &UdpSocket) -> String {
+    let mut buf = [0u8; 65536];
+    let n = sock.recv(&mut buf).expect("datagram");
+    String::from_utf8(buf[..n].to_vec()).unwrap()
+}
+
+#[test]
+fn targets_parse_with_optional_transport() {
+    let t = StatsTarget::parse("127.0.0.1:8125").unwrap();
+    assert_eq!((t.transport, t.host.as_str(), t.port), (Transport::Udp, "127.0.0.1", 8125));
+    let t = StatsTarget::parse("tcp://statsd.local:8126").unwrap();
+    assert_eq!((t.transport, t.host.as_str(), t.port), (Transport::Tcp, "statsd.local", 8126));
+    let t = StatsTarget::parse("udp://[::1]:8125").unwrap();
+    assert_eq!((t.host.as_str(), t.to_string().as_str()), ("::1", "udp://[::1]:8125"));
+    let t = StatsTarget::parse("http://gateway").unwrap();
+    assert_eq!((t.transport, t.port, t.path.as_str()), (Transport::Pushgateway, 9091, "/metrics/job/vrypt"));
+    let t = StatsTarget::parse("http://gateway:8080/metrics/job/edge/instance/a").unwrap();
+    assert_eq!(t.to_string(), "http://gateway:8080/metrics/job/edge/instance/a");
+    for bad in ["statsd", "https://statsd:80", "statsd:0", ":8125", "statsd:port", "tcp://[::1]8125"] {
+        assert!(StatsTarget::parse(bad).is_err(), "{bad}");
+    }
+}
+
+#[test]
+fn gauges_are_batched_into_one_datagram() {
+    let (sock, target) = udp_collector();
+    let mut stats = StatsClient::new(target);
+    stats.gauge(format_args!("vrypt.rps"), 12);
+    stats.gauge(format_args!("vrypt.worker.{}.rps", 0), 7);
+    stats.gauge(format_args!("vrypt.timers"), 3);
+    stats.flush();
+    assert_eq!(recv(&sock), "vrypt.rps:12|g\nvrypt.worker.0.rps:7|g\nvrypt.timers:3|g\n");
+}
+
+#[test]
+fn batches_split_at_the_packet_size() {
+    let (sock, target) = udp_collector();
+    let mut stats = StatsClient::new(target);
+    for i in 0..200 {
+        stats.gauge(format_args!("vrypt.worker.{i}.memory_bytes"), 1 << 20);
+    }
+    stats.flush();
+    let mut lines = 0;
+    while lines < 200 {
+        let packet = recv(&sock);
+        assert!(packet.len() <= STATS_MAX_PACKET, "{} byte packet", packet.len());
+        assert!(packet.ends_with('\n'));
+        lines += packet.lines().count();
+    }
+    assert_eq!(lines, 200);
+}
+
+#[test]
+fn tcp_target_gets_newline_delimited_lines() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = StatsTarget::parse(&format!("tcp://{}", listener.local_addr().unwrap())).unwrap();
+    let mut stats = StatsClient::new(target);
+    stats.gauge(format_args!("vrypt.rps"), 1);
+    stats.gauge(format_args!("vrypt.memory_bytes"), 2);
+    stats.flush();
+    let (conn, _) = listener.accept().unwrap();
+    let mut lines = BufReader::new(conn).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "vrypt.rps:1|g");
+    assert_eq!(lines.next().unwrap().unwrap(), "vrypt.memory_bytes:2|g");
+}
+
+#[test]
+fn unreachable_collector_is_retried_after_backoff() {
+    let addr = support::free_addr();
+    let mut stats = StatsClient::new(StatsTarget::parse(&format!("tcp://{addr}")).unwrap());
+    stats.gauge(format_args!("vrypt.rps"), 1);
+    stats.flush();
+    assert!(stats.failing());
+
+    let listener = TcpListener::bind(addr).expect("rebind probed port");
+    listener.set_nonblocking(true).unwrap();
+    stats.gauge(format_args!("vrypt.rps"), 2);
+    stats.flush();
+    assert!(stats.failing(), "retried before the backoff passed");
+    assert!(listener.accept().is_err());
+
+    thread::sleep(RESTART_BACKOFF_MIN * 2);
+    stats.gauge(format_args!("vrypt.rps"), 3);
+    stats.flush();
+    assert!(!stats.failing());
+    let (conn, _) = listener.accept().expect("reconnected");
+    conn.set_nonblocking(false).unwrap();
+    let mut line = String::new();
+    BufReader::new(conn).read_line(&mut line).unwrap();
+    assert_eq!(line, "vrypt.rps:3|g\n");
+}
+
+/// Every datagram the collector has been sent so far, joined.
+fn drain(sock: &UdpSocket) -> String {
+    sock.set_nonblocking(true).unwrap();
+    let mut all = String::new();
+    let mut buf = [0u8; 65536];
+    while let Ok(n) = sock.recv(&mut buf) {
+        all.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+    }
+    all
+}
+
+#[test]
+fn stopped_pusher_flushes_the_partial_interval_then_zeroes_gauges() {
+    static STOP: AtomicBool = AtomicBool::new(true);
+    for zero_on_exit in [false, true] {
+        let (sock, target) = udp_collector();
+        let counter = RpsCounter::new(1);
+        for _ in 0..5 {
+            counter.increment(0);
+        }
+        let sink = StatsSink::new(target);
+        spawn_stats_pusher(counter, StatsSources::default(), sink, &STOP, zero_on_exit).join().unwrap();
+        let sent = drain(&sock);
+        let rps: Vec<&str> = sent.lines().filter(|l| l.starts_with("vrypt.rps:")).collect();
+        let expected: &[&str] = if zero_on_exit { &["vrypt.rps:5|g", "vrypt.rps:0|g"] } else { &["vrypt.rps:5|g"] };
+        assert_eq!(rps, expected);
+    }
+}
+
+#[test]
+fn sink_options_parse() {
+    let sink = StatsSink::parse("tcp://statsd.local:8126,every=15,prefix=edge,off").unwrap();
+    assert_eq!(sink.target.transport, Transport::Tcp);
+    assert_eq!((sink.interval, sink.prefix.as_str(), sink.enabled), (Duration::from_secs(15), "edge", false));
+    let sink = StatsSink::parse("stdout").unwrap();
+    assert_eq!((sink.interval, sink.prefix.as_str(), sink.enabled), (Duration::from_secs(1), "vrypt", true));
+    for bad in ["stdout,every=0", "stdout,every=x", "stdout,prefix=", "stdout,prefix=a b", "stdout,prefix=.a", "stdout,on"] {
+        assert!(StatsSink::parse(bad).is_err(), "{bad}");
+    }
+}
+
+#[test]
+fn prefix_replaces_the_leading_vrypt() {
+    let (sock, target) = udp_collector();
+    let mut stats = StatsClient::new(target).with_prefix("edge.a");
+    stats.gauge(format_args!("vrypt.rps"), 12);
+    stats.gauge(format_args!("vrypt.worker.{}.rps", 0), 7);
+    stats.flush();
+    assert_eq!(recv(&sock), "edge.a.rps:12|g\nedge.a.worker.0.rps:7|g\n");
+}
+
+#[test]
+fn scrape_target_serves_the_last_interval() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut stats = StatsClient::new(StatsTarget::parse(&format!("scrape://127.0.0.1:{port}")).unwrap());
+    stats.gauge(format_args!("vrypt.rps"), 12);
+    stats.flush();
+    stats.gauge(format_args!("vrypt.rps"), 30);
+    stats.flush();
+
+    let mut conn = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    conn.write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+    let mut resp = String::new();
+    conn.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+    assert!(resp.ends_with("\r\n\r\n# TYPE vrypt_rps gauge\nvrypt_rps 30\n"), "{resp}");
+}
+
+#[test]
+fn pushgateway_gets_the_interval_in_one_put() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let spec = format!("http://{}/metrics/job/test", listener.local_addr().unwrap());
+    let mut stats = StatsClient::new(StatsTarget::parse(&spec).unwrap());
+    stats.gauge(format_args!("vrypt.rps"), 12);
+    stats.gauge(format_args!("vrypt.worker.{}.active_conns", 0), 3);
+    stats.flush();
+
+    let (mut conn, _) = listener.accept().unwrap();
+    conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut req = Vec::new();
+    let mut buf = [0u8; 4096];
+    let (head, body) = loop {
+        let n = conn.read(&mut buf).unwrap();
+        assert!(n > 0, "request ended early");
+        req.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8(req.clone()).unwrap();
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let len = head.lines().find_map(|l| l.strip_prefix("Content-Length: ")).unwrap();
+            if body.len() == len.parse::<usize>().unwrap() {
+                break (head.to_string(), body.to_string());
+            }
+        }
+    };
+    conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+    assert!(head.starts_with("PUT /metrics/job/test HTTP/1.1\r\n"), "{head}");
+    assert_eq!(
+        body,
+        "# TYPE vrypt_rps gauge\nvrypt_rps 12\n# TYPE vrypt_worker_0_active_conns gauge\nvrypt_worker_0_active_conns 3\n"
+    );
+}
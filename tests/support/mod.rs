@@ -0,0 +1,146 @@
+//! In-process server and a blocking client for integration tests.
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use vrypt_server::clock::Clock;
+use vrypt_server::config::Config;
+use vrypt_server::server::Server;
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts a single-worker server on an ephemeral loopback port. Workers run until the
+/// test process exits.
+pub fn start(cfg: Config) -> SocketAddr {
+    start_server(cfg).addr
+}
+
+/// Like `start`, but hands back the server for tests that reach into its shared state.
+pub fn start_server(cfg: Config) -> Server {
+    let cfg = Box::leak(Box::new(Config { addr: SocketAddr::from(([127, 0, 0, 1], 0)), ..cfg }));
+    Server::start(cfg, 1).expect("start server")
+}
+
+/// Like `start`, with the worker's timeouts running on `clock`.
+pub fn start_with_clock(cfg: Config, clock: &'static dyn Clock) -> SocketAddr {
+    let cfg = Box::leak(Box::new(Config { addr: SocketAddr::from(([127, 0, 0, 1], 0)), ..cfg }));
+    Server::start_with_clock(cfg, 1, clock).expect("start server").addr
+}
+
+/// A loopback address with a port that was free a moment ago.
+pub fn free_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0").and_then(|l| l.local_addr()).expect("probe port")
+}
+
+pub fn start_default() -> SocketAddr {
+    start(Config::default())
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+pub struct Client {
+    stream: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl Client {
+    pub fn connect(addr: SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).expect("connect");
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        Self { stream, buf: Vec::new() }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.stream.local_addr().expect("local address")
+    }
+
+    pub fn send(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).expect("send");
+    }
+
+    pub fn get(&mut self, path: &str) -> Response {
+        self.send(format!("GET {path} HTTP/1.1\r\nHost: test\r\n\r\n").as_bytes());
+        self.read_response()
+    }
+
+    /// Reads one `Content-Length` framed response, keeping any bytes after it for the next call.
+    pub fn read_response(&mut self) -> Response {
+        let head_end = loop {
+            if let Some(p) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break p;
+            }
+            self.fill();
+        };
+        let head = String::from_utf8(self.buf[..head_end].to_vec()).expect("utf-8 head");
+        let mut lines = head.split("\r\n");
+        let status = lines.next().and_then(|l| l.split(' ').nth(1)).and_then(|s| s.parse().ok()).expect("status");
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|l| l.split_once(':'))
+            .map(|(n, v)| (n.to_string(), v.trim().to_string()))
+            .collect();
+        let len: usize = headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+            .map(|(_, v)| v.parse().expect("content-length"))
+            .or((status == 204).then_some(0))
+            .expect("response without Content-Length");
+        let total = head_end + 4 + len;
+        while self.buf.len() < total {
+            self.fill();
+        }
+        let body = self.buf[head_end + 4..total].to_vec();
+        self.buf.drain(..total);
+        Response { status, headers, body }
+    }
+
+    /// Waits for the server to close the connection; false if it stays open past the client timeout.
+    pub fn is_closed(&mut self) -> bool {
+        let mut tmp = [0u8; 1024];
+        loop {
+            match self.stream.read(&mut tmp) {
+                Ok(0) => return true,
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Closes the connection with a RST instead of a FIN.
+    pub fn reset(self) {
+        socket2::SockRef::from(&self.stream).set_linger(Some(Duration::ZERO)).expect("SO_LINGER");
+    }
+
+    /// Reads exactly `n` bytes, whatever their framing.
+    pub fn read_exact(&mut self, n: usize) -> Vec<u8> {
+        while self.buf.len() < n {
+            self.fill();
+        }
+        self.buf.drain(..n).collect()
+    }
+
+    /// Returns everything the server sends until it closes the connection.
+    pub fn read_to_close(&mut self) -> Vec<u8> {
+        let mut out = std::mem::take(&mut self.buf);
+        self.stream.read_to_end(&mut out).expect("read until close");
+        out
+    }
+
+    fn fill(&mut self) {
+        let mut tmp = [0u8; 4096];
+        let n = self.stream.read(&mut tmp).expect("read");
+        assert!(n > 0, "connection closed mid-response");
+        self.buf.extend_from_slice(&tmp[..n]);
+    }
+}
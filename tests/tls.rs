@@ -0,0 +1,215 @@
+mod support;
+
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use support::Client;
+use vrypt_server::config::Config;
+
+/// A self-signed certificate for `localhost`, written out for `--tls-cert` and `--tls-key`,
+/// and a client config that trusts it.
+fn certificate(name: &str) -> (PathBuf, PathBuf, Arc<ClientConfig>) {
+    let dir = std::env::temp_dir().join(format!("vrypt-tls-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert, certified.cert.pem()).unwrap();
+    std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from(certified.cert.der().to_vec())).unwrap();
+    let client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    (cert, key, Arc::new(client))
+}
+
+fn start(name: &str, cfg: Config) -> (SocketAddr, Arc<ClientConfig>) {
+    let (cert, key, client) = certificate(name);
+    (support::start(Config { tls_cert: Some(cert), tls_key: Some(key), ..cfg }), client)
+}
+
+/// Opens a TLS connection to `addr`, after sending `preamble` in the clear.
+fn connect(addr: SocketAddr, client: &Arc<ClientConfig>, preamble: &[u8]) -> StreamOwned<ClientConnection, TcpStream> {
+    let mut tcp = TcpStream::connect(addr).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    tcp.write_all(preamble).unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    StreamOwned::new(ClientConnection::new(Arc::clone(client), name).unwrap(), tcp)
+}
+
+/// Sends `requests` and reads `count` responses back, each as far as its `Content-Length`.
+fn exchange(stream: &mut StreamOwned<ClientConnection, TcpStream>, requests: &str, count: usize) -> Vec<(String, String)> {
+    stream.write_all(requests.as_bytes()).unwrap();
+    let (mut buf, mut responses) = (Vec::new(), Vec::new());
+    let mut chunk = [0u8; 4096];
+    while responses.len() < count {
+        let text = String::from_utf8_lossy(&buf).into_owned();
+        if let Some(end) = text.find("\r\n\r\n") {
+            let head = &text[..end];
+            let length: usize = head
+                .lines()
+                .find_map(|l| l.strip_prefix("Content-Length: "))
+                .map_or(0, |v| v.trim().parse().unwrap());
+            if buf.len() >= end + 4 + length {
+                let body = String::from_utf8_lossy(&buf[end + 4..end + 4 + length]).into_owned();
+                responses.push((head.lines().next().unwrap().to_string(), body));
+                buf.drain(..end + 4 + length);
+                continue;
+            }
+        }
+        let n = stream.read(&mut chunk).unwrap();
+        assert!(n > 0, "connection closed after {} responses", responses.len());
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    responses
+}
+
+/// The server's `handshake_failures` stats once they read `expected`, or after 5 seconds.
+fn handshake_failures(addr: SocketAddr, expected: &str) -> String {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let body = String::from_utf8(Client::connect(addr).get("/__vrypt/stats").body).unwrap();
+        let start = body.find(r#""handshake_failures":"#).unwrap() + r#""handshake_failures":"#.len();
+        let failures = body[start..start + body[start..].find('}').unwrap() + 1].to_string();
+        if failures == expected || Instant::now() >= deadline {
+            return failures;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Opens a connection that sends the start of a ClientHello and nothing more.
+fn stalled_handshake(addr: SocketAddr) -> TcpStream {
+    let mut tcp = TcpStream::connect(addr).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    tcp.write_all(&[0x16, 0x03, 0x01]).unwrap();
+    tcp
+}
+
+#[test]
+fn one_port_serves_tls_and_plaintext_clients() {
+    let (addr, client) = start("port", Config::default());
+
+    let mut tls = connect(addr, &client, b"");
+    for (status, body) in exchange(&mut tls, "GET / HTTP/1.1\r\nHost: t\r\n\r\nGET / HTTP/1.1\r\nHost: t\r\n\r\n", 2) {
+        assert_eq!((status.as_str(), body.as_str()), ("HTTP/1.1 200 OK", "Vrypt"));
+    }
+
+    let mut plain = Client::connect(addr);
+    assert_eq!(plain.get("/").body, b"Vrypt");
+}
+
+#[test]
+fn tls_follows_a_proxy_protocol_preamble() {
+    let template = std::env::temp_dir().join(format!("vrypt-tls-template-{}.txt", std::process::id()));
+    std::fs::write(&template, "{{remote_addr}}").unwrap();
+    let cfg = Config { proxy_protocol: true, template: Some(template), ..Config::default() };
+    let (addr, client) = start("proxy", cfg);
+
+    let mut tls = connect(addr, &client, b"PROXY TCP4 203.0.113.7 10.0.0.1 40000 443\r\n");
+    let responses = exchange(&mut tls, "GET / HTTP/1.1\r\nHost: t\r\n\r\n", 1);
+    assert_eq!(responses, [("HTTP/1.1 200 OK".to_string(), "203.0.113.7".to_string())]);
+}
+
+#[test]
+fn handshakes_time_out_from_the_client_hello() {
+    let cfg = Config { admin: true, tls_handshake_timeout: Duration::from_secs(1), ..Config::default() };
+    let (addr, _) = start("timeout", cfg);
+
+    let mut stalled = stalled_handshake(addr);
+    let start = Instant::now();
+    let _ = stalled.read_to_end(&mut Vec::new());
+    assert!(start.elapsed() < Duration::from_secs(4), "{:?}", start.elapsed());
+    let expected = r#"{"timeout":1,"busy":0,"closed":0,"alert":0,"incompatible":0,"malformed":0}"#;
+    assert_eq!(handshake_failures(addr, expected), expected);
+}
+
+#[test]
+fn handshakes_past_the_cap_are_refused_until_one_finishes() {
+    let (addr, client) = start("busy", Config { admin: true, tls_max_handshakes: 1, ..Config::default() });
+
+    let stalled = stalled_handshake(addr);
+    let mut refused = stalled_handshake(addr);
+    assert!(matches!(refused.read(&mut [0; 64]), Ok(0) | Err(_)));
+    let expected = r#"{"timeout":0,"busy":1,"closed":0,"alert":0,"incompatible":0,"malformed":0}"#;
+    assert_eq!(handshake_failures(addr, expected), expected);
+
+    drop(stalled);
+    let expected = r#"{"timeout":0,"busy":1,"closed":1,"alert":0,"incompatible":0,"malformed":0}"#;
+    assert_eq!(handshake_failures(addr, expected), expected);
+    let mut tls = connect(addr, &client, b"");
+    assert_eq!(exchange(&mut tls, "GET / HTTP/1.1\r\nHost: t\r\n\r\n", 1)[0].1, "Vrypt");
+}
+
+#[test]
+fn a_client_refusing_the_certificate_counts_as_an_alert() {
+    let (addr, _) = start("alert", Config { admin: true, ..Config::default() });
+    let distrusting = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth();
+
+    let mut tls = connect(addr, &Arc::new(distrusting), b"");
+    assert!(tls.write_all(b"GET / HTTP/1.1\r\n\r\n").and_then(|_| tls.flush()).is_err());
+    let expected = r#"{"timeout":0,"busy":0,"closed":0,"alert":1,"incompatible":0,"malformed":0}"#;
+    assert_eq!(handshake_failures(addr, expected), expected);
+}
+
+#[test]
+fn alpn_prefers_http1_and_turns_h2_only_clients_away() {
+    let (addr, client) = start("alpn", Config { admin: true, ..Config::default() });
+    let offering = |protocols: &[&[u8]]| {
+        let mut config = ClientConfig::clone(&client);
+        config.alpn_protocols = protocols.iter().map(|p| p.to_vec()).collect();
+        Arc::new(config)
+    };
+
+    let mut both = connect(addr, &offering(&[b"h2", b"http/1.1"]), b"");
+    assert_eq!(exchange(&mut both, "GET / HTTP/1.1\r\nHost: t\r\n\r\n", 1)[0].1, "Vrypt");
+    assert_eq!(both.conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+
+    let mut h2 = connect(addr, &offering(&[b"h2"]), b"");
+    h2.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").unwrap();
+    let mut frames = Vec::new();
+    let _ = h2.read_to_end(&mut frames);
+    assert_eq!(h2.conn.alpn_protocol(), Some(&b"h2"[..]));
+    // An empty SETTINGS frame, then GOAWAY with HTTP_1_1_REQUIRED.
+    assert_eq!(frames.len(), 9 + 17, "{frames:?}");
+    assert_eq!((frames[12], frames[25]), (0x07, 0x0d), "{frames:?}");
+
+    let body = String::from_utf8(Client::connect(addr).get("/__vrypt/stats").body).unwrap();
+    assert!(body.contains(r#""protocol_errors":{"tls":0,"garbage":0,"h2c":0,"h2":1}"#), "{body}");
+}
+
+#[test]
+fn check_loads_the_certificate_and_key() {
+    let (cert, key, _) = certificate("check");
+    let cfg = |key| Config { addr: support::free_addr(), tls_cert: Some(cert.clone()), tls_key: Some(key), ..Config::default() };
+    assert!(vrypt_server::check::run(&cfg(key), 0));
+    // The certificate is not a private key.
+    assert!(!vrypt_server::check::run(&cfg(cert.clone()), 0));
+}
+
+#[test]
+fn force_https_redirects_plaintext_requests_only() {
+    let cfg = Config { force_https: Some(308), health_path: Some("/healthz".into()), ..Config::default() };
+    let (addr, client) = start("force", cfg);
+
+    let mut plain = Client::connect(addr);
+    let res = plain.get("/a/b?c=1");
+    assert_eq!(res.status, 308);
+    assert_eq!(res.header("location"), Some("https://test/a/b?c=1"));
+    // Probes keep working over plaintext.
+    assert_eq!(plain.get("/healthz").status, 200);
+
+    let mut tls = connect(addr, &client, b"");
+    assert_eq!(exchange(&mut tls, "GET /a/b HTTP/1.1\r\nHost: t\r\n\r\n", 1)[0].1, "Vrypt");
+}
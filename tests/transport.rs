@@ -0,0 +1,126 @@
+//! The connection state machine on scripted in-memory streams: partial reads, `WouldBlock`
+//! between them, short and blocked writes. Built only with the `mock-transport` feature:
+//!
+//! ```text
+//! cargo test --features mock-transport --test transport
+//! ```
+
+use std::io;
+use std::net::Shutdown;
+use std::time::Duration;
+use vrypt_server::close::CloseRule;
+use vrypt_server::config::Config;
+use vrypt_server::conn::ConnState;
+use vrypt_server::transport::{Drive, Machine, ScriptedStream};
+use vrypt_server::tunnel::ProxyTarget;
+
+const GET: &[u8] = b"GET / HTTP/1.1\r\nHost: test\r\n\r\n";
+
+fn responses(written: &[u8]) -> usize {
+    written.windows(9).filter(|w| *w == b"HTTP/1.1 ").count()
+}
+
+#[test]
+fn head_split_across_reads_is_answered_once_complete() {
+    let stream = ScriptedStream::new()
+        .read(&GET[..5])
+        .read_blocks()
+        .read(&GET[5..20])
+        .read_blocks()
+        .read(&GET[20..]);
+    let mut m = Machine::new(Config::default(), stream);
+
+    assert_eq!(m.drive(), Drive::Blocked);
+    assert!(m.stream().written().is_empty());
+    assert_eq!(m.conn.state(), ConnState::ReadingHeaders);
+    assert_eq!(m.drive(), Drive::Blocked);
+    assert!(m.stream().written().is_empty());
+
+    assert_eq!(m.drive(), Drive::Blocked);
+    assert!(m.stream().written().starts_with(b"HTTP/1.1 200 OK\r\n"));
+    assert_eq!(m.conn.state(), ConnState::Idle);
+}
+
+#[test]
+fn pipelined_requests_in_one_read_get_a_response_each() {
+    let stream = ScriptedStream::new().read(&[GET, GET, GET].concat());
+    let mut m = Machine::new(Config::default(), stream);
+    assert_eq!(m.drive(), Drive::Blocked);
+    assert_eq!(responses(m.stream().written()), 3);
+    assert_eq!(m.conn.read_len, 0);
+}
+
+#[test]
+fn short_and_blocked_writes_resume_where_they_stopped() {
+    let stream = ScriptedStream::new().read(GET).write_up_to(7).write_up_to(3).write_blocks();
+    let mut m = Machine::new(Config::default(), stream);
+
+    assert_eq!(m.drive(), Drive::Blocked);
+    assert_eq!(m.stream().written(), b"HTTP/1.1 2");
+    assert_eq!(m.conn.state(), ConnState::Writing);
+    assert_eq!(m.conn.write_pos, 10);
+
+    assert_eq!(m.drive(), Drive::Blocked);
+    assert!(m.stream().written().starts_with(b"HTTP/1.1 200 OK\r\n"));
+    assert_eq!(responses(m.stream().written()), 1);
+    assert_eq!(m.conn.state(), ConnState::Idle);
+}
+
+#[test]
+fn eof_before_the_head_is_complete_closes() {
+    let stream = ScriptedStream::new().read(&GET[..10]).eof();
+    let mut m = Machine::new(Config::default(), stream);
+    assert_eq!(m.drive(), Drive::Closed);
+    assert!(m.stream().written().is_empty());
+}
+
+#[test]
+fn read_error_closes() {
+    let stream = ScriptedStream::new().read(&GET[..10]).read_error(io::ErrorKind::ConnectionReset);
+    let mut m = Machine::new(Config::default(), stream);
+    assert_eq!(m.drive(), Drive::Closed);
+}
+
+#[test]
+fn error_response_shuts_down_writes_and_drains() {
+    let stream = ScriptedStream::new().read(b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n").read(b"junk");
+    let mut m = Machine::new(Config::default(), stream);
+    assert_eq!(m.drive(), Drive::Blocked);
+    assert!(m.stream().written().starts_with(b"HTTP/1.1 400"));
+    assert_eq!(m.stream().shut_down(), Some(Shutdown::Write));
+    assert_eq!(m.conn.state(), ConnState::Draining);
+}
+
+#[test]
+fn error_response_closes_at_once_with_a_fin_close_rule() {
+    let stream = ScriptedStream::new().read(b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n").read(b"junk");
+    let cfg = Config { close_rules: vec![CloseRule::parse("error=fin").unwrap()], ..Config::default() };
+    let mut m = Machine::new(cfg, stream);
+    assert_eq!(m.drive(), Drive::Closed);
+    assert!(m.stream().written().starts_with(b"HTTP/1.1 400"));
+    assert_eq!(m.stream().shut_down(), None);
+}
+
+#[test]
+fn answered_requests_are_counted_and_arm_the_keep_alive_timer() {
+    let stream = ScriptedStream::new().read(&GET[..10]).read_blocks().read(&GET[10..]).read(GET);
+    let cfg = Config { header_timeout: Duration::from_secs(3), keepalive_timeout: Duration::from_secs(7), ..Config::default() };
+    let mut m = Machine::new(cfg, stream);
+
+    assert_eq!(m.drive(), Drive::Blocked);
+    assert_eq!(m.timer, Some(Duration::from_secs(3)));
+    assert_eq!(m.requests(), 0);
+
+    assert_eq!(m.drive(), Drive::Blocked);
+    assert_eq!((m.requests(), m.conn.requests), (2, 2));
+    assert_eq!(m.timer, Some(Duration::from_secs(7)));
+}
+
+#[test]
+fn forward_proxy_requests_close_without_a_socket_to_tunnel_from() {
+    let stream = ScriptedStream::new().read(b"GET http://127.0.0.1:9/ HTTP/1.1\r\nHost: 127.0.0.1:9\r\n\r\n");
+    let target = ProxyTarget::parse("127.0.0.1:9").unwrap();
+    let mut m = Machine::new(Config { proxy_allow: vec![target], ..Config::default() }, stream);
+    assert_eq!(m.drive(), Drive::Closed);
+    assert_eq!(m.conn.state(), ConnState::Closing);
+}
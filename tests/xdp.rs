@@ -0,0 +1,110 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use vrypt_server::xdp::{self, AbuseTracker, Bans, DropList, Offence, XdpMap};
+
+#[derive(Default)]
+struct FakeList {
+    entries: Vec<IpAddr>,
+    removed: Vec<IpAddr>,
+}
+
+impl DropList for FakeList {
+    fn insert(&mut self, ip: IpAddr, _ttl: Duration) -> io::Result<()> {
+        self.entries.push(ip);
+        Ok(())
+    }
+
+    fn remove(&mut self, ip: IpAddr) -> io::Result<()> {
+        self.entries.retain(|e| *e != ip);
+        self.removed.push(ip);
+        Ok(())
+    }
+}
+
+#[test]
+fn tracker_reports_an_address_once_per_window_over_the_threshold() {
+    let (tracker, rx) = AbuseTracker::new(3, None);
+    let noisy = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    let quiet = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+    let start = Instant::now();
+    for _ in 0..10 {
+        tracker.record(noisy, start);
+    }
+    for _ in 0..3 {
+        tracker.record(quiet, start);
+    }
+    assert_eq!(rx.try_iter().collect::<Vec<_>>(), [(noisy, Offence::Connections)]);
+
+    // A new window starts the count over.
+    let later = start + Duration::from_secs(1);
+    for _ in 0..4 {
+        tracker.record(noisy, later);
+    }
+    assert_eq!(rx.try_iter().collect::<Vec<_>>(), [(noisy, Offence::Connections)]);
+
+    assert_eq!(tracker.tracked(), 2);
+    tracker.prune(later + Duration::from_millis(500));
+    assert_eq!(tracker.tracked(), 1);
+    tracker.prune(later + Duration::from_secs(1));
+    assert_eq!(tracker.tracked(), 0);
+}
+
+#[test]
+fn suspicious_requests_are_reported_past_their_own_threshold() {
+    let (tracker, rx) = AbuseTracker::new(100, Some(2));
+    let junk = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7));
+    let start = Instant::now();
+    for _ in 0..3 {
+        tracker.record(junk, start);
+        tracker.record_suspicious(junk, start);
+    }
+    assert_eq!(rx.try_iter().collect::<Vec<_>>(), [(junk, Offence::Suspicious)]);
+
+    // Without a threshold suspicious requests are not counted at all.
+    let (tracker, rx) = AbuseTracker::new(100, None);
+    for _ in 0..10 {
+        tracker.record_suspicious(junk, start);
+    }
+    assert!(rx.try_recv().is_err());
+    assert_eq!(tracker.tracked(), 0);
+}
+
+#[test]
+fn bans_are_lifted_in_order_once_their_ttl_runs_out() {
+    let ttl = Duration::from_secs(60);
+    let mut bans = Bans::new(FakeList::default(), ttl);
+    let a = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+    let b = IpAddr::V6(Ipv6Addr::LOCALHOST);
+    let start = Instant::now();
+    assert!(bans.ban(a, start).unwrap());
+    assert!(bans.ban(b, start + Duration::from_secs(10)).unwrap());
+    assert!(!bans.ban(a, start + Duration::from_secs(20)).unwrap(), "already banned");
+    assert_eq!(bans.list().entries, [a, b]);
+
+    assert_eq!(bans.expire(start + Duration::from_secs(59)), 0);
+    assert_eq!(bans.expire(start + ttl), 1);
+    assert_eq!(bans.list().entries, [b]);
+    assert_eq!(bans.len(), 1);
+
+    // Once lifted, an address can be banned again.
+    assert!(bans.ban(a, start + ttl).unwrap());
+    assert_eq!(bans.expire(start + ttl * 3), 2);
+    assert!(bans.is_empty());
+    assert_eq!(bans.list().removed, [a, b, a]);
+}
+
+#[test]
+fn map_keys_are_ipv6_with_ipv4_mapped() {
+    let v4 = xdp::key(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3)));
+    assert_eq!(v4, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 1, 2, 3]);
+    let v6 = xdp::key(IpAddr::V6(Ipv6Addr::LOCALHOST));
+    assert_eq!(v6[15], 1);
+    assert_eq!(v6[..15], [0; 15]);
+}
+
+#[test]
+fn opening_a_missing_map_fails() {
+    assert!(XdpMap::open(Path::new("/nonexistent/vrypt-drop")).is_err());
+}